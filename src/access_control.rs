@@ -1,4 +1,9 @@
+use crate::bans::BanList;
+use crate::config::{AccessRule, BanTrigger, Config, ForwardingRule, RuleType};
+use crate::ip_list::{IpLists, ListFile};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
 
 #[derive(Debug, Clone)]
 pub struct IpPattern {
@@ -91,9 +96,159 @@ impl IpPattern {
     }
 }
 
+/// What one access rule matches: its pattern or the list in its file.
+#[derive(Debug, Clone)]
+enum Matcher {
+    Pattern(IpPattern),
+    List(Arc<ListFile>),
+}
+
+impl Matcher {
+    fn matches(&self, ip: IpAddr) -> bool {
+        match self {
+            Matcher::Pattern(pattern) => pattern.matches(ip),
+            Matcher::List(list) => list.contains(ip),
+        }
+    }
+}
+
+/// The allow and deny rules of one level of access rules.
+#[derive(Debug, Clone, Default)]
+struct AccessLevel {
+    allow: Vec<Matcher>,
+    deny: Vec<Matcher>,
+}
+
+impl AccessLevel {
+    fn new(rules: &[AccessRule], lists: &IpLists) -> Self {
+        let mut level = AccessLevel::default();
+        for rule in rules {
+            let matcher = match &rule.file {
+                Some(file) => Matcher::List(lists.get(Path::new(file))),
+                None => Matcher::Pattern(IpPattern { pattern: rule.pattern.clone() }),
+            };
+            match rule.rule_type {
+                RuleType::Allow => level.allow.push(matcher),
+                RuleType::Deny => level.deny.push(matcher),
+            }
+        }
+        level
+    }
+
+    fn is_allowed(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|p| p.matches(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|p| p.matches(ip))
+    }
+}
+
+/// The access rules that apply to one forwarding rule, following rinetd:
+/// the global rules are checked first, then the rule's own. At each level a
+/// client is refused if it matches a deny pattern, or if allow patterns
+/// exist and it matches none of them. Banned clients are refused before
+/// either.
+#[derive(Debug, Clone, Default)]
+pub struct AccessPolicy {
+    global: AccessLevel,
+    rule: AccessLevel,
+    bans: BanList,
+    /// Set while the forwarding rule is paused.
+    refuse_new: bool,
+}
+
+impl AccessPolicy {
+    pub fn new(global_rules: &[AccessRule], rules: &[AccessRule]) -> Self {
+        Self::with_lists(global_rules, rules, &IpLists::new())
+    }
+
+    /// Like `new`, taking the list files of rules with a `file` from
+    /// `lists`, so that they are shared with other policies and refreshed
+    /// with them.
+    pub fn with_lists(global_rules: &[AccessRule], rules: &[AccessRule], lists: &IpLists) -> Self {
+        AccessPolicy {
+            global: AccessLevel::new(global_rules, lists),
+            rule: AccessLevel::new(rules, lists),
+            bans: BanList::default(),
+            refuse_new: false,
+        }
+    }
+
+    /// The policy of `rule` in `config`, with its list files from `lists`.
+    /// Unless the config sets `enforce_access_rules`, every client is let
+    /// through.
+    pub fn for_rule(config: &Config, rule: &ForwardingRule, lists: &IpLists) -> Self {
+        if config.enforce_access_rules {
+            Self::with_lists(&config.global_rules, &rule.rules, lists)
+        } else {
+            Self::allow_all()
+        }
+    }
+
+    /// The same policy, refusing the clients banned on `bans` and counting
+    /// the refusals of its access rules as strikes there, see
+    /// `count_refusal`.
+    pub fn with_bans(self, bans: BanList) -> Self {
+        AccessPolicy { bans, ..self }
+    }
+
+    /// The same policy, refusing every new client while letting clients
+    /// with an open UDP session carry on.
+    pub fn refusing_new(self) -> Self {
+        AccessPolicy { refuse_new: true, ..self }
+    }
+
+    /// A policy without any rules, letting every client through.
+    pub fn allow_all() -> Self {
+        Self::default()
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        !self.bans.is_banned(ip) && self.allowed_by_rules(ip)
+    }
+
+    fn allowed_by_rules(&self, ip: IpAddr) -> bool {
+        self.global.is_allowed(ip) && self.rule.is_allowed(ip)
+    }
+
+    /// Whether a new connection or UDP session from `ip` may start.
+    pub fn admits(&self, ip: IpAddr) -> bool {
+        !self.refuse_new && self.is_allowed(ip)
+    }
+
+    /// Counts a strike against `ip` if the access rules refuse it. Refusals
+    /// of banned clients and of paused rules are no strikes. Called once per
+    /// reported refusal, not for every datagram of a refused UDP client.
+    pub fn count_refusal(&self, ip: IpAddr) {
+        if !self.refuse_new && !self.bans.is_banned(ip) && !self.allowed_by_rules(ip) {
+            self.bans.strike(ip, BanTrigger::Denied);
+        }
+    }
+}
+
+/// An `AccessPolicy` shared with a running listener. Replacing it takes
+/// effect atomically for every client checked afterwards.
+#[derive(Debug, Clone, Default)]
+pub struct SharedAccessPolicy(Arc<RwLock<Arc<AccessPolicy>>>);
+
+impl SharedAccessPolicy {
+    pub fn new(policy: AccessPolicy) -> Self {
+        SharedAccessPolicy(Arc::new(RwLock::new(Arc::new(policy))))
+    }
+
+    pub fn load(&self) -> Arc<AccessPolicy> {
+        self.0.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn store(&self, policy: AccessPolicy) {
+        *self.0.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(policy);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::AutoBanConfig;
     use std::net::{Ipv4Addr, Ipv6Addr};
     use test_case::test_case;
     
@@ -242,4 +397,162 @@ mod tests {
         let pattern = IpPattern { pattern: "192.168.*.0/24".to_string() };
         assert!(!pattern.matches(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10))));
     }
-}
\ No newline at end of file
+
+    fn rule(rule_type: RuleType, pattern: &str) -> AccessRule {
+        AccessRule { rule_type, pattern: pattern.to_string(), file: None }
+    }
+
+    fn list_rule(rule_type: RuleType, file: &std::path::Path) -> AccessRule {
+        AccessRule { rule_type, pattern: String::new(), file: Some(file.display().to_string()) }
+    }
+
+    fn v4(a: u8, b: u8, c: u8, d: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(a, b, c, d))
+    }
+
+    #[test]
+    fn policy_without_rules_allows_everyone() {
+        let policy = AccessPolicy::allow_all();
+        assert!(policy.is_allowed(v4(1, 2, 3, 4)));
+        assert!(policy.is_allowed(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+    }
+
+    #[test]
+    fn policy_for_rule_applies_rules_only_when_enforced() {
+        let mut config: Config = toml::from_str(
+            r#"forwarding_rules = [{ bind_address = "127.0.0.1", bind_port = 80, connect_address = "127.0.0.1", connect_port = 8080 }]
+
+[[global_rules]]
+type = "deny"
+pattern = "10.0.0.1"
+"#,
+        )
+        .unwrap();
+        assert!(!config.enforce_access_rules);
+        assert!(AccessPolicy::for_rule(&config, &config.forwarding_rules[0], &IpLists::new()).is_allowed(v4(10, 0, 0, 1)));
+
+        config.enforce_access_rules = true;
+        assert!(!AccessPolicy::for_rule(&config, &config.forwarding_rules[0], &IpLists::new()).is_allowed(v4(10, 0, 0, 1)));
+    }
+
+    #[test]
+    fn policy_allow_rules_exclude_everyone_else() {
+        let policy = AccessPolicy::new(&[rule(RuleType::Allow, "10.0.0.0/8")], &[]);
+        assert!(policy.is_allowed(v4(10, 1, 2, 3)));
+        assert!(!policy.is_allowed(v4(127, 0, 0, 1)));
+    }
+
+    #[test]
+    fn policy_deny_wins_over_allow() {
+        let policy = AccessPolicy::new(
+            &[rule(RuleType::Allow, "192.168.1.*"), rule(RuleType::Deny, "192.168.1.50")],
+            &[],
+        );
+        assert!(policy.is_allowed(v4(192, 168, 1, 10)));
+        assert!(!policy.is_allowed(v4(192, 168, 1, 50)));
+    }
+
+    #[test]
+    fn policy_deny_only_allows_the_rest() {
+        let policy = AccessPolicy::new(&[rule(RuleType::Deny, "10.0.0.1")], &[]);
+        assert!(!policy.is_allowed(v4(10, 0, 0, 1)));
+        assert!(policy.is_allowed(v4(10, 0, 0, 2)));
+    }
+
+    #[test]
+    fn policy_checks_global_and_rule_levels() {
+        let policy = AccessPolicy::new(
+            &[rule(RuleType::Allow, "10.0.0.0/8")],
+            &[rule(RuleType::Deny, "10.0.0.42")],
+        );
+        assert!(policy.is_allowed(v4(10, 0, 0, 1)));
+        assert!(!policy.is_allowed(v4(10, 0, 0, 42)));
+        assert!(!policy.is_allowed(v4(192, 168, 1, 1)));
+
+        // A rule-level allow cannot widen what the global rules refuse.
+        let policy = AccessPolicy::new(
+            &[rule(RuleType::Deny, "10.0.0.42")],
+            &[rule(RuleType::Allow, "10.0.0.0/8")],
+        );
+        assert!(!policy.is_allowed(v4(10, 0, 0, 42)));
+        assert!(!policy.is_allowed(v4(192, 168, 1, 1)));
+    }
+
+    #[test]
+    fn policy_list_files_follow_their_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let blocklist = dir.path().join("blocklist.txt");
+        let allowlist = dir.path().join("allowlist.txt");
+        std::fs::write(&blocklist, "# known scanners\n10.0.0.42\n2001:db8:bad::/48\n").unwrap();
+        std::fs::write(&allowlist, "10.0.0.0/8\n2001:db8::/32\n").unwrap();
+        let lists = IpLists::new();
+        let policy = AccessPolicy::with_lists(
+            &[list_rule(RuleType::Deny, &blocklist)],
+            &[list_rule(RuleType::Allow, &allowlist), rule(RuleType::Deny, "10.0.0.7")],
+            &lists,
+        );
+        assert!(policy.is_allowed(v4(10, 0, 0, 1)));
+        assert!(!policy.is_allowed(v4(10, 0, 0, 42)));
+        assert!(!policy.is_allowed(v4(10, 0, 0, 7)));
+        assert!(!policy.is_allowed(v4(192, 168, 1, 1)));
+        assert!(policy.is_allowed("2001:db8::1".parse().unwrap()));
+        assert!(!policy.is_allowed("2001:db8:bad::1".parse().unwrap()));
+
+        std::fs::write(&blocklist, "10.0.0.0/24\n").unwrap();
+        lists.refresh();
+        assert!(!policy.is_allowed(v4(10, 0, 0, 1)));
+        assert!(policy.is_allowed(v4(10, 0, 1, 1)));
+        assert!(policy.is_allowed("2001:db8:bad::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn shared_policy_store_replaces_policy() {
+        let shared = SharedAccessPolicy::new(AccessPolicy::allow_all());
+        let listener_view = shared.clone();
+        assert!(listener_view.load().is_allowed(v4(10, 0, 0, 1)));
+
+        shared.store(AccessPolicy::new(&[rule(RuleType::Deny, "10.0.0.1")], &[]));
+        assert!(!listener_view.load().is_allowed(v4(10, 0, 0, 1)));
+    }
+
+    #[test]
+    fn refusing_policy_admits_no_new_clients() {
+        let policy = AccessPolicy::new(&[], &[rule(RuleType::Deny, "10.0.0.42")]);
+        assert!(policy.admits(v4(10, 0, 0, 1)));
+        assert!(!policy.admits(v4(10, 0, 0, 42)));
+
+        let refusing = policy.refusing_new();
+        assert!(!refusing.admits(v4(10, 0, 0, 1)));
+        assert!(refusing.is_allowed(v4(10, 0, 0, 1)));
+        assert!(!refusing.is_allowed(v4(10, 0, 0, 42)));
+    }
+
+    #[test]
+    fn banned_clients_are_refused_before_the_rules() {
+        let bans = BanList::new();
+        bans.set(Some(&AutoBanConfig {
+            strikes: 2,
+            window: 60,
+            duration: 600,
+            triggers: vec![BanTrigger::Denied],
+            state_file: None,
+        }));
+        let policy = AccessPolicy::new(&[rule(RuleType::Deny, "10.0.0.42")], &[]).with_bans(bans.clone());
+        let paused = policy.clone().refusing_new();
+        // Refusals while paused are no strikes
+        assert!(!paused.admits(v4(10, 0, 0, 42)));
+        paused.count_refusal(v4(10, 0, 0, 42));
+        assert!(!policy.admits(v4(10, 0, 0, 42)));
+        assert!(!bans.is_banned(v4(10, 0, 0, 42)));
+        policy.count_refusal(v4(10, 0, 0, 42));
+        assert!(!bans.is_banned(v4(10, 0, 0, 42)));
+        policy.count_refusal(v4(10, 0, 0, 42));
+        assert!(bans.is_banned(v4(10, 0, 0, 42)));
+        assert!(!policy.admits(v4(10, 0, 0, 42)));
+
+        let allowing = AccessPolicy::new(&[rule(RuleType::Allow, "10.0.0.0/8")], &[]).with_bans(bans.clone());
+        assert!(!allowing.admits(v4(10, 0, 0, 42)));
+        assert!(!allowing.is_allowed(v4(10, 0, 0, 42)));
+        assert!(allowing.admits(v4(10, 0, 0, 1)));
+    }
+}
@@ -0,0 +1,267 @@
+//! Token bucket limits on how many bytes a forwarding rule relays: per
+//! connection, per client IP address and over the whole rule.
+
+use crate::config::BandwidthConfig;
+use crate::rate_limit::{Rate, TokenBucket};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+struct Client {
+    /// Made on first use, and dropped when the per-client rate changes.
+    bucket: Option<TokenBucket>,
+    connections: usize,
+}
+
+#[derive(Default)]
+struct Buckets {
+    config: BandwidthConfig,
+    rule: Option<(Rate, TokenBucket)>,
+    client_rate: Option<Rate>,
+    connection_rate: Option<Rate>,
+    /// Every client with an open connection.
+    clients: HashMap<IpAddr, Client>,
+}
+
+impl Buckets {
+    fn new(config: BandwidthConfig, clients: HashMap<IpAddr, Client>) -> Self {
+        let now = Instant::now();
+        Buckets {
+            rule: Rate::bytes(config.rate, config.burst).map(|rate| (rate, TokenBucket::full(rate, now))),
+            client_rate: Rate::bytes(config.per_ip_rate, config.per_ip_burst),
+            connection_rate: Rate::bytes(config.per_connection_rate, config.per_connection_burst),
+            config,
+            clients,
+        }
+    }
+}
+
+/// The bandwidth limits of one forwarding rule. Clones share the same
+/// buckets.
+#[derive(Clone, Default)]
+pub struct Bandwidth {
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl Bandwidth {
+    pub fn new(config: Option<&BandwidthConfig>) -> Self {
+        let bandwidth = Bandwidth::default();
+        bandwidth.set(config);
+        bandwidth
+    }
+
+    /// Replaces the limits, also of the connections already open. The
+    /// buckets are kept if the limits did not change.
+    pub fn set(&self, config: Option<&BandwidthConfig>) {
+        let config = config.cloned().unwrap_or_default();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.config == config {
+            return;
+        }
+        let mut clients = std::mem::take(&mut buckets.clients);
+        for client in clients.values_mut() {
+            client.bucket = None;
+        }
+        *buckets = Buckets::new(config, clients);
+    }
+
+    /// The shaper of a new connection from `client`.
+    pub fn shaper(&self, client: IpAddr) -> Shaper {
+        self.buckets.lock().unwrap().clients.entry(client).or_default().connections += 1;
+        Shaper {
+            buckets: self.buckets.clone(),
+            client,
+            own: Mutex::new(None),
+        }
+    }
+}
+
+/// Holds the data of one connection to its rule's bandwidth limits.
+pub struct Shaper {
+    buckets: Arc<Mutex<Buckets>>,
+    client: IpAddr,
+    /// The connection's own bucket, and the rate it was made for.
+    own: Mutex<Option<(Rate, TokenBucket)>>,
+}
+
+impl Shaper {
+    /// Unlimited, and not counted against any rule.
+    pub fn unlimited(client: IpAddr) -> Self {
+        Bandwidth::default().shaper(client)
+    }
+
+    /// Takes `bytes` from every bucket, then waits until none of them is in
+    /// debt any more.
+    pub async fn throttle(&self, bytes: usize) {
+        let wait = self.spend(bytes, Instant::now());
+        if !wait.is_zero() {
+            smol::Timer::after(wait).await;
+        }
+    }
+
+    /// The most bytes to write at once: the smallest burst of the limited
+    /// buckets, so that a single write never goes over what the limits
+    /// allow at once.
+    pub fn chunk_size(&self) -> usize {
+        self.with_buckets(Instant::now(), |buckets| {
+            buckets.iter().map(|(rate, _)| rate.burst() as usize).min().unwrap_or(usize::MAX)
+        })
+    }
+
+    /// Takes `bytes` from every bucket and returns how long the deepest
+    /// debt takes to pay back.
+    fn spend(&self, bytes: usize, now: Instant) -> Duration {
+        self.with_buckets(now, |buckets| {
+            buckets
+                .iter_mut()
+                .map(|(rate, bucket)| bucket.spend(*rate, now, bytes as f64))
+                .max()
+                .unwrap_or_default()
+        })
+    }
+
+    /// Takes `bytes` from every bucket if each has them, or is full, for a
+    /// datagram that is dropped otherwise.
+    pub fn admit(&self, bytes: usize) -> bool {
+        let now = Instant::now();
+        self.with_buckets(now, |buckets| {
+            if !buckets.iter_mut().all(|(rate, bucket)| bucket.holds(*rate, now, bytes as f64)) {
+                return false;
+            }
+            for (rate, bucket) in buckets.iter_mut() {
+                bucket.spend(*rate, now, bytes as f64);
+            }
+            true
+        })
+    }
+
+    /// Calls `use_them` with the rule's, the client's and the connection's
+    /// bucket, those that are limited.
+    fn with_buckets<T>(&self, now: Instant, use_them: impl FnOnce(&mut Vec<(Rate, &mut TokenBucket)>) -> T) -> T {
+        let mut guard = self.buckets.lock().unwrap();
+        let shared = &mut *guard;
+        let mut own = self.own.lock().unwrap();
+        if own.as_ref().map(|(rate, _)| *rate) != shared.connection_rate {
+            *own = shared.connection_rate.map(|rate| (rate, TokenBucket::full(rate, now)));
+        }
+
+        let mut buckets = Vec::with_capacity(3);
+        if let Some((rate, bucket)) = &mut shared.rule {
+            buckets.push((*rate, bucket));
+        }
+        if let Some(rate) = shared.client_rate
+            && let Some(client) = shared.clients.get_mut(&self.client)
+        {
+            buckets.push((rate, client.bucket.get_or_insert_with(|| TokenBucket::full(rate, now))));
+        }
+        if let Some((rate, bucket)) = &mut *own {
+            buckets.push((*rate, bucket));
+        }
+        use_them(&mut buckets)
+    }
+}
+
+impl Drop for Shaper {
+    fn drop(&mut self) {
+        let mut buckets = self.buckets.lock().unwrap();
+        if let Some(client) = buckets.clients.get_mut(&self.client) {
+            client.connections -= 1;
+            if client.connections == 0 {
+                buckets.clients.remove(&self.client);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::observer::tests::ip;
+
+    fn config(rate: Option<u64>, per_ip_rate: Option<u64>, per_connection_rate: Option<u64>) -> BandwidthConfig {
+        BandwidthConfig {
+            rate,
+            per_ip_rate,
+            per_connection_rate,
+            ..BandwidthConfig::default()
+        }
+    }
+
+    #[test]
+    fn the_deepest_debt_sets_the_wait() {
+        let now = Instant::now();
+        let shaper = Bandwidth::new(Some(&config(None, None, Some(100)))).shaper(ip(1));
+        assert_eq!(shaper.spend(100, now), Duration::ZERO);
+        // 100 bytes over the burst, at 100 bytes per second
+        assert_eq!(shaper.spend(100, now).as_millis(), 1000);
+
+        let bandwidth = Bandwidth::new(Some(&config(Some(1000), Some(500), None)));
+        let first = bandwidth.shaper(ip(1));
+        let second = bandwidth.shaper(ip(1));
+        assert_eq!(first.spend(400, now), Duration::ZERO);
+        // The client's connections share its 500 bytes
+        assert_eq!(second.spend(200, now).as_millis(), 200);
+        // And the rule's 1000 are shared by every client
+        let stranger = bandwidth.shaper(ip(2));
+        assert_eq!(stranger.spend(500, now).as_millis(), 100);
+    }
+
+    #[test]
+    fn writes_are_cut_to_the_smallest_burst() {
+        assert_eq!(Shaper::unlimited(ip(1)).chunk_size(), usize::MAX);
+        let bandwidth = Bandwidth::new(Some(&BandwidthConfig {
+            rate: Some(10_000),
+            per_connection_rate: Some(1000),
+            per_connection_burst: Some(500),
+            ..BandwidthConfig::default()
+        }));
+        assert_eq!(bandwidth.shaper(ip(1)).chunk_size(), 500);
+    }
+
+    #[test]
+    fn datagrams_over_a_limit_are_dropped() {
+        let bandwidth = Bandwidth::new(Some(&BandwidthConfig {
+            per_ip_rate: Some(100),
+            per_ip_burst: Some(200),
+            ..BandwidthConfig::default()
+        }));
+        let shaper = bandwidth.shaper(ip(1));
+        assert!(shaper.admit(150));
+        assert!(!shaper.admit(100));
+        assert!(shaper.admit(50));
+        assert!(Shaper::unlimited(ip(1)).admit(1 << 20));
+
+        // A full bucket lets a datagram larger than its burst through
+        let other = bandwidth.shaper(ip(2));
+        assert!(other.admit(1000));
+        assert!(!other.admit(1));
+    }
+
+    #[test]
+    fn buckets_are_kept_until_the_limits_change() {
+        let bandwidth = Bandwidth::new(Some(&config(None, Some(100), None)));
+        let shaper = bandwidth.shaper(ip(1));
+        assert!(shaper.admit(100));
+        bandwidth.set(Some(&config(None, Some(100), None)));
+        assert!(!shaper.admit(100));
+        bandwidth.set(Some(&config(None, Some(200), None)));
+        assert!(shaper.admit(200));
+        bandwidth.set(None);
+        assert!(shaper.admit(1 << 20));
+    }
+
+    #[test]
+    fn clients_are_forgotten_with_their_last_connection() {
+        let bandwidth = Bandwidth::new(Some(&config(None, Some(100), None)));
+        let first = bandwidth.shaper(ip(1));
+        let second = bandwidth.shaper(ip(1));
+        assert!(first.admit(100));
+        drop(first);
+        assert!(!second.admit(100));
+        drop(second);
+        assert!(bandwidth.buckets.lock().unwrap().clients.is_empty());
+        assert!(bandwidth.shaper(ip(1)).admit(100));
+    }
+}
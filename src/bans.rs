@@ -0,0 +1,388 @@
+//! Temporary bans of clients that keep getting refused, like fail2ban: a
+//! client with enough strikes within the window of `AutoBanConfig` is
+//! refused by every rule until its ban runs out.
+//!
+//! Access denials are counted by `AccessPolicy::count_refusal`, rate
+//! limit hits and failed upstream connects by the `ConnectionObserver`
+//! implementation of `BanList`.
+
+use crate::config::{AutoBanConfig, BanTrigger};
+use crate::error::ProxyError;
+use crate::limits::Limit;
+use crate::logging;
+use crate::observer::{CloseReason, ConnectionInfo, ConnectionObserver, TransferTotals};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How many clients with strikes are remembered at most. Past this, the
+/// client whose last strike is the oldest is forgotten.
+const MAX_CLIENTS_WITH_STRIKES: usize = 65_536;
+
+/// A ban as saved in the `state_file`.
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedBan {
+    client: IpAddr,
+    /// When the ban ends, in seconds since the Unix epoch.
+    until: u64,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    config: Option<AutoBanConfig>,
+    /// The recent strikes of every client, oldest first.
+    strikes: HashMap<IpAddr, VecDeque<Instant>>,
+    /// When the ban of every banned client ends.
+    bans: HashMap<IpAddr, SystemTime>,
+    max_clients: usize,
+}
+
+impl State {
+    fn is_banned(&mut self, client: IpAddr, now: SystemTime) -> bool {
+        match self.bans.get(&client) {
+            Some(until) if *until > now => true,
+            Some(_) => {
+                self.bans.remove(&client);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Counts a strike against `client` and bans it if that was one too
+    /// many. Returns the length of the new ban.
+    fn strike(&mut self, client: IpAddr, trigger: BanTrigger, now: Instant, wall: SystemTime) -> Option<Duration> {
+        let config = self.config.as_ref()?;
+        if !config.triggers.contains(&trigger) {
+            return None;
+        }
+        let (max_strikes, window) = (config.strikes.max(1) as usize, Duration::from_secs(config.window));
+        let duration = Duration::from_secs(config.duration);
+        if self.is_banned(client, wall) {
+            return None;
+        }
+        if !self.strikes.contains_key(&client) {
+            self.make_room(now, window);
+        }
+        let strikes = self.strikes.entry(client).or_default();
+        while strikes.front().is_some_and(|at| now.saturating_duration_since(*at) >= window) {
+            strikes.pop_front();
+        }
+        strikes.push_back(now);
+        if strikes.len() < max_strikes {
+            return None;
+        }
+        self.strikes.remove(&client);
+        self.bans.insert(client, wall + duration);
+        Some(duration)
+    }
+
+    /// Forgets the clients whose strikes are all older than `window` when
+    /// there is no room for another, and if that is not enough, the one
+    /// whose last strike is the oldest.
+    fn make_room(&mut self, now: Instant, window: Duration) {
+        if self.strikes.len() < self.max_clients {
+            return;
+        }
+        self.strikes
+            .retain(|_, strikes| strikes.back().is_some_and(|at| now.saturating_duration_since(*at) < window));
+        if self.strikes.len() >= self.max_clients {
+            let oldest = self.strikes.iter().min_by_key(|(_, strikes)| strikes.back().copied()).map(|(ip, _)| *ip);
+            if let Some(oldest) = oldest {
+                self.strikes.remove(&oldest);
+            }
+        }
+    }
+
+    fn state_file(&self) -> Option<&str> {
+        self.config.as_ref()?.state_file.as_deref()
+    }
+
+    /// Writes the bans to the `state_file`, if there is one.
+    fn save(&mut self) {
+        let Some(path) = self.state_file().map(str::to_string) else {
+            return;
+        };
+        let now = SystemTime::now();
+        self.bans.retain(|_, until| *until > now);
+        let saved: Vec<_> = self
+            .bans
+            .iter()
+            .map(|(client, until)| SavedBan {
+                client: *client,
+                until: until.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs()),
+            })
+            .collect();
+        // Written next to the file and renamed, so a crash never leaves
+        // half of it behind
+        let temporary = format!("{}.tmp", path);
+        let written = serde_json::to_vec_pretty(&saved)
+            .map_err(std::io::Error::from)
+            .and_then(|json| std::fs::write(&temporary, json))
+            .and_then(|()| std::fs::rename(&temporary, &path));
+        if let Err(e) = written {
+            file_error(format!("Cannot save bans to {}: {}", path, e), &path, &e);
+        }
+    }
+
+    /// Adds the bans in the `state_file` that have not run out yet.
+    fn load(&mut self) {
+        let Some(path) = self.state_file().map(str::to_string) else {
+            return;
+        };
+        let saved: Vec<SavedBan> = match std::fs::read(&path) {
+            Ok(json) => match serde_json::from_slice(&json) {
+                Ok(saved) => saved,
+                Err(e) => return file_error(format!("Cannot read bans from {}: {}", path, e), &path, &e),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+            Err(e) => return file_error(format!("Cannot read bans from {}: {}", path, e), &path, &e),
+        };
+        let now = SystemTime::now();
+        let mut loaded = 0;
+        for ban in saved {
+            let until = UNIX_EPOCH + Duration::from_secs(ban.until);
+            if until > now {
+                let current = self.bans.entry(ban.client).or_insert(until);
+                *current = (*current).max(until);
+                loaded += 1;
+            }
+        }
+        logging::info("bans_loaded", format!("Loaded {} bans from {}", loaded, path))
+            .field("path", path)
+            .field("bans", loaded)
+            .log();
+    }
+}
+
+fn file_error(message: String, path: &str, error: &dyn std::error::Error) {
+    logging::warn("ban_file_error", message)
+        .field("path", path)
+        .field("error", error.to_string())
+        .log();
+}
+
+/// The banned clients and their strikes, shared by every rule of a server.
+/// Clones share the same bans.
+#[derive(Debug, Clone)]
+pub struct BanList(Arc<Mutex<State>>);
+
+impl Default for BanList {
+    fn default() -> Self {
+        BanList(Arc::new(Mutex::new(State {
+            max_clients: MAX_CLIENTS_WITH_STRIKES,
+            ..State::default()
+        })))
+    }
+}
+
+impl BanList {
+    /// A ban list that counts no strikes until it is configured with `set`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the config. The bans in a newly configured `state_file` are
+    /// added; without a config every ban is lifted.
+    pub fn set(&self, config: Option<&AutoBanConfig>) {
+        let mut state = self.0.lock().unwrap();
+        let old_file = state.state_file().map(str::to_string);
+        state.config = config.cloned();
+        if config.is_none() {
+            state.bans.clear();
+            state.strikes.clear();
+        } else if state.state_file().is_some() && state.state_file() != old_file.as_deref() {
+            state.load();
+        }
+    }
+
+    pub fn is_banned(&self, client: IpAddr) -> bool {
+        self.0.lock().unwrap().is_banned(client, SystemTime::now())
+    }
+
+    /// The banned clients and when their bans end, the soonest first.
+    pub fn bans(&self) -> Vec<(IpAddr, SystemTime)> {
+        let now = SystemTime::now();
+        let mut state = self.0.lock().unwrap();
+        state.bans.retain(|_, until| *until > now);
+        let mut bans: Vec<_> = state.bans.iter().map(|(client, until)| (*client, *until)).collect();
+        bans.sort_by_key(|(client, until)| (*until, *client));
+        bans
+    }
+
+    /// Counts a strike of `trigger` against `client`, if the config counts
+    /// those, and bans the client once it has enough of them.
+    pub fn strike(&self, client: IpAddr, trigger: BanTrigger) {
+        let mut state = self.0.lock().unwrap();
+        let Some(duration) = state.strike(client, trigger, Instant::now(), SystemTime::now()) else {
+            return;
+        };
+        logging::warn("client_banned", format!("Banned {} for {}s", client, duration.as_secs()))
+            .field("client", client.to_string())
+            .field("duration", duration.as_secs())
+            .log();
+        state.save();
+    }
+
+    /// Lifts the ban of `client` and forgets its strikes. Returns whether
+    /// it was banned.
+    pub fn unban(&self, client: IpAddr) -> bool {
+        let mut state = self.0.lock().unwrap();
+        state.strikes.remove(&client);
+        let banned = state.bans.remove(&client).is_some();
+        if banned {
+            unbanned(client);
+            state.save();
+        }
+        banned
+    }
+
+    /// Lifts every ban and forgets every strike, and returns the clients
+    /// that were banned.
+    pub fn clear(&self) -> Vec<IpAddr> {
+        let mut state = self.0.lock().unwrap();
+        state.strikes.clear();
+        let mut cleared: Vec<_> = state.bans.drain().map(|(client, _)| client).collect();
+        cleared.sort();
+        cleared.iter().for_each(|client| unbanned(*client));
+        state.save();
+        cleared
+    }
+}
+
+fn unbanned(client: IpAddr) {
+    logging::info("client_unbanned", format!("Lifted the ban of {}", client))
+        .field("client", client.to_string())
+        .log();
+}
+
+impl ConnectionObserver for BanList {
+    fn on_connect(&self, conn: &ConnectionInfo, result: Result<Duration, &ProxyError>) {
+        if result.is_err() {
+            self.strike(conn.client_addr.ip(), BanTrigger::UpstreamFailed);
+        }
+    }
+
+    fn on_close(&self, conn: &ConnectionInfo, reason: &CloseReason, _totals: &TransferTotals) {
+        if let CloseReason::Limited(Limit::ClientRate) = reason {
+            self.strike(conn.client_addr.ip(), BanTrigger::RateLimited);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::observer::tests::ip;
+
+    fn config(state_file: Option<String>) -> AutoBanConfig {
+        AutoBanConfig {
+            strikes: 3,
+            window: 60,
+            duration: 600,
+            triggers: vec![BanTrigger::Denied, BanTrigger::UpstreamFailed],
+            state_file,
+        }
+    }
+
+    #[test]
+    fn enough_strikes_within_the_window_ban() {
+        let bans = BanList::new();
+        bans.set(Some(&config(None)));
+        let mut state = bans.0.lock().unwrap();
+        let (now, wall) = (Instant::now(), SystemTime::now());
+        assert_eq!(state.strike(ip(1), BanTrigger::Denied, now, wall), None);
+        assert_eq!(state.strike(ip(1), BanTrigger::UpstreamFailed, now, wall), None);
+        // Not counted by this config
+        assert_eq!(state.strike(ip(1), BanTrigger::RateLimited, now, wall), None);
+        // The first strike is out of the window by now
+        let later = now + Duration::from_secs(61);
+        assert_eq!(state.strike(ip(1), BanTrigger::Denied, later, wall), None);
+        assert_eq!(state.strike(ip(1), BanTrigger::Denied, later, wall), None);
+        assert_eq!(state.strike(ip(1), BanTrigger::Denied, later, wall), Some(Duration::from_secs(600)));
+
+        assert!(state.is_banned(ip(1), wall));
+        assert!(!state.is_banned(ip(2), wall));
+        assert!(state.strikes.is_empty());
+        assert!(!state.is_banned(ip(1), wall + Duration::from_secs(600)));
+        assert!(state.bans.is_empty());
+    }
+
+    #[test]
+    fn unconfigured_lists_ban_nobody() {
+        let bans = BanList::new();
+        for _ in 0..10 {
+            bans.strike(ip(1), BanTrigger::Denied);
+        }
+        assert!(!bans.is_banned(ip(1)));
+
+        bans.set(Some(&config(None)));
+        for _ in 0..3 {
+            bans.strike(ip(1), BanTrigger::Denied);
+        }
+        assert!(bans.is_banned(ip(1)));
+        bans.set(None);
+        assert!(!bans.is_banned(ip(1)));
+    }
+
+    #[test]
+    fn bans_can_be_lifted() {
+        let bans = BanList::new();
+        bans.set(Some(&config(None)));
+        for client in [ip(2), ip(1)] {
+            for _ in 0..3 {
+                bans.strike(client, BanTrigger::Denied);
+            }
+        }
+        assert_eq!(bans.bans().len(), 2);
+        assert!(bans.unban(ip(2)));
+        assert!(!bans.unban(ip(2)));
+        assert_eq!(bans.clear(), [ip(1)]);
+        assert!(bans.bans().is_empty());
+    }
+
+    #[test]
+    fn bans_are_kept_in_the_state_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bans.json").to_str().unwrap().to_string();
+        let bans = BanList::new();
+        bans.set(Some(&config(Some(path.clone()))));
+        for _ in 0..3 {
+            bans.strike(ip(1), BanTrigger::Denied);
+        }
+
+        let restarted = BanList::new();
+        restarted.set(Some(&config(Some(path.clone()))));
+        assert!(restarted.is_banned(ip(1)));
+        restarted.unban(ip(1));
+        let saved: Vec<SavedBan> = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert!(saved.is_empty());
+
+        // A broken file is reported and skipped
+        std::fs::write(&path, "not json").unwrap();
+        BanList::new().set(Some(&config(Some(path))));
+    }
+
+    #[test]
+    fn clients_with_strikes_are_bounded() {
+        let bans = BanList::new();
+        bans.set(Some(&config(None)));
+        let mut state = bans.0.lock().unwrap();
+        state.max_clients = 2;
+        let (now, wall) = (Instant::now(), SystemTime::now());
+        state.strike(ip(1), BanTrigger::Denied, now, wall);
+        state.strike(ip(2), BanTrigger::Denied, now + Duration::from_secs(1), wall);
+        state.strike(ip(3), BanTrigger::Denied, now + Duration::from_secs(2), wall);
+        assert_eq!(state.strikes.len(), 2);
+        assert!(!state.strikes.contains_key(&ip(1)));
+
+        // Strikes out of the window are forgotten first
+        let later = now + Duration::from_secs(62);
+        state.strike(ip(3), BanTrigger::Denied, later, wall);
+        state.strike(ip(4), BanTrigger::Denied, later, wall);
+        assert!(state.strikes.contains_key(&ip(3)));
+        assert!(state.strikes.contains_key(&ip(4)));
+    }
+}
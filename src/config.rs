@@ -1,4 +1,5 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fmt;
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
@@ -12,8 +13,10 @@ pub enum Protocol {
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct ForwardingRule {
     pub bind_address: String,
+    #[serde(deserialize_with = "deserialize_port")]
     pub bind_port: u16,
     pub connect_address: String,
+    #[serde(deserialize_with = "deserialize_port")]
     pub connect_port: u16,
     #[serde(default)]
     pub protocol: Protocol,
@@ -23,13 +26,82 @@ pub struct ForwardingRule {
     pub source_address: Option<String>,
     #[serde(default)]
     pub rules: Vec<AccessRule>,
+    /// Filters applied to the forwarded bytes, in order. Only used by the
+    /// `tcp`, `tcptoudp` and `udptotcp` protocols.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub filters: Vec<FilterConfig>,
+    /// Disabled rules get no listener until they are resumed through the
+    /// control socket.
+    #[serde(default = "enabled_by_default", skip_serializing_if = "is_enabled")]
+    pub enabled: bool,
+    /// The most TCP connections or UDP sessions this rule keeps open at once.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<usize>,
+    /// Overrides the global `limit_action` for this rule.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit_action: Option<LimitAction>,
+    /// How fast the rule takes new connections and UDP sessions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<RateLimitConfig>,
+    /// How fast the rule relays data.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bandwidth: Option<BandwidthConfig>,
 }
 
+fn enabled_by_default() -> bool {
+    true
+}
+
+fn is_enabled(enabled: &bool) -> bool {
+    *enabled
+}
+
+/// Accepts a port written either as an integer or as a string holding one,
+/// so that ports can be filled in through `${VAR}` interpolation.
+fn deserialize_port<'de, D>(deserializer: D) -> Result<u16, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct PortVisitor;
+
+    impl serde::de::Visitor<'_> for PortVisitor {
+        type Value = u16;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a port number between 0 and 65535")
+        }
+
+        fn visit_u64<E: serde::de::Error>(self, value: u64) -> Result<u16, E> {
+            u16::try_from(value).map_err(|_| E::custom(format!("port out of range: {}", value)))
+        }
+
+        fn visit_i64<E: serde::de::Error>(self, value: i64) -> Result<u16, E> {
+            u16::try_from(value).map_err(|_| E::custom(format!("port out of range: {}", value)))
+        }
+
+        fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<u16, E> {
+            value
+                .trim()
+                .parse()
+                .map_err(|_| E::custom(format!("invalid port: {}", value)))
+        }
+    }
+
+    deserializer.deserialize_any(PortVisitor)
+}
+
+/// An allow or deny rule matching either `pattern` or every address and
+/// CIDR block listed in `file`, which is read again when it changes.
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct AccessRule {
     #[serde(rename = "type")]
     pub rule_type: RuleType,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub pattern: String,
+    /// A list file, relative to the directory of the config file that
+    /// declares the rule.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
@@ -39,11 +111,206 @@ pub enum RuleType {
     Deny,
 }
 
+/// A filter in the `filters` list of a forwarding rule.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FilterConfig {
+    /// Prints the bytes a connection carried in each direction when it closes.
+    ByteCount,
+    /// Prints every chunk of forwarded data as a hex dump.
+    HexDump,
+    /// Closes connections that carry more than `max_bytes` in one direction.
+    SizeLimit { max_bytes: u64 },
+    /// A filter registered in code under `name`, see `ProxyBuilder::filter`.
+    Custom { name: String },
+}
+
+/// Token bucket limits on new TCP connections and UDP sessions of a rule.
+/// Clients over them are always refused, whatever the `limit_action`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct RateLimitConfig {
+    /// New connections per second, over all clients.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate: Option<f64>,
+    /// How many new connections may arrive at once. Defaults to `rate`,
+    /// rounded up.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub burst: Option<u32>,
+    /// New connections per second from one client IP address.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub per_ip_rate: Option<f64>,
+    /// How many new connections one client may open at once. Defaults to
+    /// `per_ip_rate`, rounded up.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub per_ip_burst: Option<u32>,
+}
+
+/// Token bucket limits on the bytes a rule relays, in both directions
+/// together. Rates are in bytes per second and at least one.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BandwidthConfig {
+    /// Over all connections of the rule.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate: Option<u64>,
+    /// How many bytes may go through at once. Defaults to one second's
+    /// worth, like the other bursts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub burst: Option<u64>,
+    /// Over all connections of one client IP address.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub per_ip_rate: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub per_ip_burst: Option<u64>,
+    /// Of each TCP connection or UDP session.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub per_connection_rate: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub per_connection_burst: Option<u64>,
+}
+
+/// What happens to a client that would exceed a connection limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitAction {
+    /// Close the connection right after accept, or drop the datagram.
+    #[default]
+    Refuse,
+    /// Stop accepting while the rule or the server is full, so new TCP
+    /// clients wait in the listen backlog. Clients over the per-client
+    /// limit and UDP datagrams are refused as with `Refuse`.
+    Wait,
+}
+
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum LogFormat {
     Rinetd,
     Common,
+    /// One JSON object per event, see `logging::Event::to_json`.
+    Json,
+}
+
+/// Where and how log messages are sent to syslog.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct SyslogConfig {
+    /// The path of a local Unix datagram socket, or `udp://host:port` or
+    /// `tcp://host:port` for a remote daemon. Defaults to `/dev/log`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    #[serde(default)]
+    pub format: SyslogFormat,
+    #[serde(default)]
+    pub facility: SyslogFacility,
+    /// The program name messages are tagged with. Defaults to `oi`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ident: Option<String>,
+}
+
+/// When the log file is rotated, and how many old files are kept.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct LogRotateConfig {
+    /// Rotate before the file would grow past this many bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+    /// Rotate once the file has been open for this many seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval: Option<u64>,
+    /// How many rotated files to keep, as `<log_file>.1` (the newest) up to
+    /// `<log_file>.<keep>`. Defaults to 5.
+    #[serde(default = "default_log_keep")]
+    pub keep: u32,
+}
+
+fn default_log_keep() -> u32 {
+    5
+}
+
+/// When clients are banned, and for how long. A banned client is refused
+/// by every rule, before the access rules are checked.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct AutoBanConfig {
+    /// How many strikes within `window` get a client banned. Defaults to 5.
+    #[serde(default = "default_ban_strikes")]
+    pub strikes: u32,
+    /// In seconds. Defaults to 60.
+    #[serde(default = "default_ban_window")]
+    pub window: u64,
+    /// How long a ban lasts, in seconds. Defaults to 600.
+    #[serde(default = "default_ban_duration")]
+    pub duration: u64,
+    /// What counts as a strike. Defaults to access denials and per-client
+    /// rate limit hits.
+    #[serde(default = "default_ban_triggers")]
+    pub triggers: Vec<BanTrigger>,
+    /// Where the bans are saved, to keep them across restarts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state_file: Option<String>,
+}
+
+fn default_ban_strikes() -> u32 {
+    5
+}
+
+fn default_ban_window() -> u64 {
+    60
+}
+
+fn default_ban_duration() -> u64 {
+    600
+}
+
+fn default_ban_triggers() -> Vec<BanTrigger> {
+    vec![BanTrigger::Denied, BanTrigger::RateLimited]
+}
+
+/// Something a client did that counts towards a ban.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BanTrigger {
+    /// It was refused by the access rules.
+    Denied,
+    /// It was refused by the `per_ip_rate` of a rule's `rate_limit`.
+    RateLimited,
+    /// Connecting upstream for it failed.
+    UpstreamFailed,
+}
+
+/// The syslog message header.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogFormat {
+    /// The BSD format: `<30>Oct 18 12:34:56 oi[42]: message`.
+    #[default]
+    Rfc3164,
+    /// `<30>1 2026-10-18T12:34:56.789Z host oi 42 event - message`.
+    Rfc5424,
+}
+
+/// The syslog facility, in the order of their codes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogFacility {
+    Kern,
+    User,
+    Mail,
+    #[default]
+    Daemon,
+    Auth,
+    Syslog,
+    Lpr,
+    News,
+    Uucp,
+    Cron,
+    Authpriv,
+    Ftp,
+    Local0 = 16,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
 }
 
 impl Default for LogFormat {
@@ -58,10 +325,35 @@ impl Default for Protocol {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+            Protocol::UdpToTcp => write!(f, "udptotcp"),
+            Protocol::TcpToUdp => write!(f, "tcptoudp"),
+        }
+    }
+}
+
+impl fmt::Display for ForwardingRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}:{} -> {}:{}",
+            self.protocol, self.bind_address, self.bind_port, self.connect_address, self.connect_port
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct Config {
     #[serde(default)]
     pub global_rules: Vec<AccessRule>,
+    /// Apply `global_rules` and the `rules` of every forwarding rule.
+    /// Without it they are parsed but every client is let through.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub enforce_access_rules: bool,
     pub forwarding_rules: Vec<ForwardingRule>,
     #[serde(default)]
     pub log_file: Option<String>,
@@ -69,6 +361,64 @@ pub struct Config {
     pub pid_file: Option<String>,
     #[serde(default)]
     pub log_format: LogFormat,
+    /// Glob patterns of further config files whose `forwarding_rules` and
+    /// `global_rules` are merged into this config.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    /// Seconds to wait on shutdown for open connections before closing them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub drain_timeout: Option<u64>,
+    /// Address of the HTTP listener serving Prometheus metrics at `/metrics`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metrics_address: Option<String>,
+    /// Path of the Unix socket that `oi ctl` talks to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub control_socket: Option<String>,
+    /// Which events are logged, as a `logging::LevelFilter`, e.g.
+    /// `"warn,udp_handler=debug"`. Defaults to `info`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_level: Option<String>,
+    /// Also send log messages to syslog. `log_file = "syslog"` does the
+    /// same with the defaults.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub syslog: Option<SyslogConfig>,
+    /// Rotate `log_file` by size or age.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_rotate: Option<LogRotateConfig>,
+    /// The most TCP connections and UDP sessions open at once, over all rules.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<usize>,
+    /// The most TCP connections and UDP sessions one client IP address has
+    /// open at once, over all rules.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_connections_per_ip: Option<usize>,
+    /// What happens to clients over a limit, unless their rule says otherwise.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub limit_action: LimitAction,
+    /// Ban clients for a while that keep getting refused.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auto_ban: Option<AutoBanConfig>,
+}
+
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+impl Config {
+    /// The syslog settings, from the `syslog` section or from
+    /// `log_file = "syslog"`.
+    pub fn syslog(&self) -> Option<SyslogConfig> {
+        match (&self.syslog, self.log_file.as_deref()) {
+            (Some(syslog), _) => Some(syslog.clone()),
+            (None, Some("syslog")) => Some(SyslogConfig::default()),
+            _ => None,
+        }
+    }
+
+    /// The log file, unless `log_file` asks for syslog.
+    pub fn log_file_path(&self) -> Option<&str> {
+        self.log_file.as_deref().filter(|path| *path != "syslog")
+    }
 }
 
 #[cfg(test)]
@@ -101,6 +451,25 @@ protocol = "invalid""#);
         assert!(err.is_err());
     }
 
+    #[test_case(Protocol::Tcp, "tcp")]
+    #[test_case(Protocol::Udp, "udp")]
+    #[test_case(Protocol::UdpToTcp, "udptotcp")]
+    #[test_case(Protocol::TcpToUdp, "tcptoudp")]
+    fn protocol_display_matches_config_name(protocol: Protocol, expected: &str) {
+        assert_eq!(protocol.to_string(), expected);
+    }
+
+    #[test]
+    fn forwarding_rule_display() {
+        let rule: ForwardingRule = toml::from_str(r#"bind_address = "0.0.0.0"
+bind_port = 53
+connect_address = "8.8.8.8"
+connect_port = 53
+protocol = "udp""#)
+            .unwrap();
+        assert_eq!(rule.to_string(), "udp 0.0.0.0:53 -> 8.8.8.8:53");
+    }
+
     #[test]
     fn protocol_default_is_tcp() {
         assert!(matches!(Protocol::default(), Protocol::Tcp));
@@ -118,6 +487,7 @@ pattern = "192.168.1.*""#, input))
 
     #[test_case("rinetd", LogFormat::Rinetd)]
     #[test_case("common", LogFormat::Common)]
+    #[test_case("json", LogFormat::Json)]
     fn log_format_deser(input: &str, expected: LogFormat) {
         let config: Config =
             toml::from_str(&format!(r#"log_format = "{}"
@@ -142,6 +512,170 @@ connect_port = 9090"#)
         assert!(rule.timeout.is_none());
         assert!(rule.source_address.is_none());
         assert!(rule.rules.is_empty());
+        assert!(rule.filters.is_empty());
+        assert!(rule.enabled);
+        assert!(rule.max_connections.is_none());
+        assert!(rule.limit_action.is_none());
+        assert!(rule.rate_limit.is_none());
+        assert!(rule.bandwidth.is_none());
+    }
+
+    #[test]
+    fn connection_limits() {
+        let config: Config = toml::from_str(r#"
+max_connections = 1000
+max_connections_per_ip = 20
+limit_action = "wait"
+
+[[forwarding_rules]]
+bind_address = "0.0.0.0"
+bind_port = 8080
+connect_address = "127.0.0.1"
+connect_port = 9090
+max_connections = 100
+limit_action = "refuse"
+"#)
+            .unwrap();
+        assert_eq!(config.max_connections, Some(1000));
+        assert_eq!(config.max_connections_per_ip, Some(20));
+        assert_eq!(config.limit_action, LimitAction::Wait);
+        assert_eq!(config.forwarding_rules[0].max_connections, Some(100));
+        assert_eq!(config.forwarding_rules[0].limit_action, Some(LimitAction::Refuse));
+        assert_eq!(Config::default().limit_action, LimitAction::Refuse);
+    }
+
+    #[test]
+    fn forwarding_rule_rate_limit() {
+        let rule: ForwardingRule = toml::from_str(r#"bind_address = "127.0.0.1"
+bind_port = 8080
+connect_address = "127.0.0.1"
+connect_port = 9090
+rate_limit = { rate = 100.0, per_ip_rate = 0.5, per_ip_burst = 5 }"#)
+            .unwrap();
+        let rate_limit = rule.rate_limit.unwrap();
+        assert_eq!(rate_limit.rate, Some(100.0));
+        assert_eq!(rate_limit.burst, None);
+        assert_eq!(rate_limit.per_ip_rate, Some(0.5));
+        assert_eq!(rate_limit.per_ip_burst, Some(5));
+    }
+
+    #[test]
+    fn forwarding_rule_bandwidth() {
+        let rule: ForwardingRule = toml::from_str(r#"bind_address = "127.0.0.1"
+bind_port = 8080
+connect_address = "127.0.0.1"
+connect_port = 9090
+
+[bandwidth]
+rate = 10_000_000
+per_ip_rate = 1_000_000
+per_connection_rate = 250_000
+per_connection_burst = 1_000_000"#)
+            .unwrap();
+        let bandwidth = rule.bandwidth.unwrap();
+        assert_eq!(bandwidth.rate, Some(10_000_000));
+        assert_eq!(bandwidth.burst, None);
+        assert_eq!(bandwidth.per_ip_rate, Some(1_000_000));
+        assert_eq!(bandwidth.per_connection_rate, Some(250_000));
+        assert_eq!(bandwidth.per_connection_burst, Some(1_000_000));
+    }
+
+    #[test]
+    fn auto_ban_section() {
+        let config: Config = toml::from_str(r#"forwarding_rules = []
+
+[auto_ban]
+strikes = 3
+triggers = ["denied", "upstream_failed"]
+state_file = "/var/lib/oi/bans.json"
+"#)
+            .unwrap();
+        let auto_ban = config.auto_ban.unwrap();
+        assert_eq!(auto_ban.strikes, 3);
+        assert_eq!(auto_ban.window, 60);
+        assert_eq!(auto_ban.duration, 600);
+        assert_eq!(auto_ban.triggers, [BanTrigger::Denied, BanTrigger::UpstreamFailed]);
+        assert_eq!(auto_ban.state_file.as_deref(), Some("/var/lib/oi/bans.json"));
+    }
+
+    #[test]
+    fn access_rule_file() {
+        let rule: AccessRule = toml::from_str(r#"type = "deny"
+file = "/etc/oi/blocklist.txt""#)
+            .unwrap();
+        assert!(rule.pattern.is_empty());
+        assert_eq!(rule.file.as_deref(), Some("/etc/oi/blocklist.txt"));
+        assert_eq!(toml::to_string(&rule).unwrap(), "type = \"deny\"\nfile = \"/etc/oi/blocklist.txt\"\n");
+    }
+
+    #[test]
+    fn forwarding_rule_disabled() {
+        let rule: ForwardingRule = toml::from_str(r#"bind_address = "127.0.0.1"
+bind_port = 8080
+connect_address = "127.0.0.1"
+connect_port = 9090
+enabled = false"#)
+            .unwrap();
+        assert!(!rule.enabled);
+        assert!(toml::to_string(&rule).unwrap().contains("enabled = false"));
+    }
+
+    #[test]
+    fn forwarding_rule_filters() {
+        let rule: ForwardingRule = toml::from_str(r#"bind_address = "127.0.0.1"
+bind_port = 8080
+connect_address = "127.0.0.1"
+connect_port = 9090
+filters = [
+    { type = "byte_count" },
+    { type = "hex_dump" },
+    { type = "size_limit", max_bytes = 1024 },
+    { type = "custom", name = "audit" },
+]"#)
+            .unwrap();
+        assert_eq!(
+            rule.filters,
+            vec![
+                FilterConfig::ByteCount,
+                FilterConfig::HexDump,
+                FilterConfig::SizeLimit { max_bytes: 1024 },
+                FilterConfig::Custom { name: "audit".to_string() },
+            ]
+        );
+    }
+
+    #[test_case(r#"[{ type = "gzip" }]"#)]
+    #[test_case(r#"[{ type = "size_limit" }]"#)]
+    fn forwarding_rule_invalid_filters_error(filters: &str) {
+        let err = toml::from_str::<ForwardingRule>(&format!(r#"bind_address = "127.0.0.1"
+bind_port = 8080
+connect_address = "127.0.0.1"
+connect_port = 9090
+filters = {}"#, filters));
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn forwarding_rule_ports_as_strings() {
+        let rule: ForwardingRule = toml::from_str(r#"bind_address = "127.0.0.1"
+bind_port = "8080"
+connect_address = "127.0.0.1"
+connect_port = " 9090 ""#)
+            .unwrap();
+        assert_eq!(rule.bind_port, 8080);
+        assert_eq!(rule.connect_port, 9090);
+    }
+
+    #[test_case("\"http\"")]
+    #[test_case("\"70000\"")]
+    #[test_case("70000")]
+    #[test_case("-1")]
+    fn forwarding_rule_invalid_port_errors(port: &str) {
+        let err = toml::from_str::<ForwardingRule>(&format!(r#"bind_address = "127.0.0.1"
+bind_port = {}
+connect_address = "127.0.0.1"
+connect_port = 9090"#, port));
+        assert!(err.is_err());
     }
 
     #[test]
@@ -178,6 +712,9 @@ pattern = "10.0.0.42""#)
 log_file = "/var/log/oi.log"
 pid_file = "/var/run/oi.pid"
 log_format = "common"
+drain_timeout = 30
+control_socket = "/run/oi.sock"
+log_level = "warn,server=info"
 
 [[global_rules]]
 type = "allow"
@@ -195,6 +732,55 @@ connect_port = 9090
         assert_eq!(config.log_file.as_deref(), Some("/var/log/oi.log"));
         assert_eq!(config.pid_file.as_deref(), Some("/var/run/oi.pid"));
         assert!(matches!(config.log_format, LogFormat::Common));
+        assert_eq!(config.drain_timeout, Some(30));
+        assert_eq!(config.control_socket.as_deref(), Some("/run/oi.sock"));
+        assert_eq!(config.log_level.as_deref(), Some("warn,server=info"));
+    }
+
+    #[test]
+    fn config_syslog_section_and_shorthand() {
+        let config: Config = toml::from_str(r#"
+log_file = "/var/log/oi.log"
+forwarding_rules = []
+
+[syslog]
+address = "udp://10.0.0.5:514"
+format = "rfc5424"
+facility = "local3"
+ident = "edge-proxy"
+"#)
+            .unwrap();
+        let syslog = config.syslog().unwrap();
+        assert_eq!(syslog.address.as_deref(), Some("udp://10.0.0.5:514"));
+        assert_eq!(syslog.format, SyslogFormat::Rfc5424);
+        assert_eq!(syslog.facility, SyslogFacility::Local3);
+        assert_eq!(syslog.ident.as_deref(), Some("edge-proxy"));
+        assert_eq!(config.log_file_path(), Some("/var/log/oi.log"));
+
+        let config: Config = toml::from_str("log_file = \"syslog\"\nforwarding_rules = []").unwrap();
+        assert_eq!(config.syslog(), Some(SyslogConfig::default()));
+        assert_eq!(config.log_file_path(), None);
+        assert!(toml::from_str::<Config>("forwarding_rules = []\n[syslog]\nfacility = \"local9\"").is_err());
+    }
+
+    #[test]
+    fn config_log_rotate_section() {
+        let config: Config = toml::from_str(r#"
+log_file = "/var/log/oi.log"
+forwarding_rules = []
+
+[log_rotate]
+max_bytes = 1048576
+interval = 86400
+"#)
+            .unwrap();
+        let rotate = config.log_rotate.unwrap();
+        assert_eq!(rotate.max_bytes, Some(1_048_576));
+        assert_eq!(rotate.interval, Some(86400));
+        assert_eq!(rotate.keep, 5);
+
+        let config: Config = toml::from_str("forwarding_rules = []\n[log_rotate]\nkeep = 2").unwrap();
+        assert_eq!(config.log_rotate.unwrap().keep, 2);
     }
 
     #[test]
@@ -212,6 +798,7 @@ connect_port = 9090
         assert!(config.log_file.is_none());
         assert!(config.pid_file.is_none());
         assert!(matches!(config.log_format, LogFormat::Rinetd));
+        assert!(config.drain_timeout.is_none());
     }
 
     #[test]
@@ -1,13 +1,25 @@
 use crate::config::{Config, ForwardingRule, AccessRule, RuleType, Protocol, LogFormat};
+use crate::interpolation::{expand_json, expand_legacy, expand_toml, expand_yaml};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashSet;
 use std::fmt;
 use std::fs;
-use std::net::ToSocketAddrs;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 #[derive(Debug)]
 pub enum ConfigError {
     IoError(std::io::Error),
     ParseError(String),
     TomlError(toml::de::Error),
+    JsonError(serde_json::Error),
+    YamlError(serde_yaml::Error),
+    /// A `${VAR}` reference to an unset variable without a default.
+    UndefinedVariable(String),
+    /// An error in a file pulled in through `include`.
+    Included { path: String, error: Box<ConfigError> },
 }
 
 impl fmt::Display for ConfigError {
@@ -16,6 +28,10 @@ impl fmt::Display for ConfigError {
             ConfigError::IoError(e) => write!(f, "I/O error: {}", e),
             ConfigError::ParseError(msg) => write!(f, "Parse error: {}", msg),
             ConfigError::TomlError(e) => write!(f, "TOML error: {}", e),
+            ConfigError::JsonError(e) => write!(f, "JSON error: {}", e),
+            ConfigError::YamlError(e) => write!(f, "YAML error: {}", e),
+            ConfigError::UndefinedVariable(name) => write!(f, "Undefined environment variable: {}", name),
+            ConfigError::Included { path, error } => write!(f, "{}: {}", path, error),
         }
     }
 }
@@ -34,93 +50,504 @@ impl From<toml::de::Error> for ConfigError {
     }
 }
 
+impl From<serde_json::Error> for ConfigError {
+    fn from(error: serde_json::Error) -> Self {
+        ConfigError::JsonError(error)
+    }
+}
+
+impl From<serde_yaml::Error> for ConfigError {
+    fn from(error: serde_yaml::Error) -> Self {
+        ConfigError::YamlError(error)
+    }
+}
+
+/// On-disk configuration formats understood by `Config::load_from_file`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Legacy,
+    Json,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// Picks the format from an unambiguous file extension (`.toml`,
+    /// `.json`, `.yaml`/`.yml`). Returns `None` for anything else, including
+    /// `.conf`, which is used for TOML files as often as for legacy ones.
+    pub fn from_extension(path: &str) -> Option<Self> {
+        let extension = std::path::Path::new(path).extension()?.to_str()?;
+        match extension.to_lowercase().as_str() {
+            "toml" => Some(ConfigFormat::Toml),
+            "json" => Some(ConfigFormat::Json),
+            "yaml" | "yml" => Some(ConfigFormat::Yaml),
+            _ => None,
+        }
+    }
+
+    /// Guesses the format from the first line that is neither empty nor a
+    /// comment. Content without such a line is treated as legacy, which
+    /// loads as an empty config.
+    pub fn sniff(content: &str) -> Self {
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.starts_with('{') {
+                return ConfigFormat::Json;
+            }
+            if line.starts_with('[') {
+                return ConfigFormat::Toml;
+            }
+            if line.starts_with("---") || line.starts_with("- ") {
+                return ConfigFormat::Yaml;
+            }
+
+            let key_len = line
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+                .unwrap_or(line.len());
+            if key_len > 0 {
+                let rest = &line[key_len..];
+                if rest.trim_start().starts_with('=') {
+                    return ConfigFormat::Toml;
+                }
+                // A YAML key is followed by whitespace, unlike the groups of
+                // an IPv6 address such as `2001:db8::1` or `fe80::1`
+                if let Some(value) = rest.strip_prefix(':')
+                    && (value.is_empty() || value.starts_with(char::is_whitespace))
+                {
+                    return ConfigFormat::Yaml;
+                }
+            }
+            return ConfigFormat::Legacy;
+        }
+        ConfigFormat::Legacy
+    }
+
+    /// Picks the format for a file: by extension when it is unambiguous,
+    /// otherwise by sniffing the content.
+    pub fn detect(path: &str, content: &str) -> Self {
+        Self::from_extension(path).unwrap_or_else(|| Self::sniff(content))
+    }
+}
+
+impl fmt::Display for ConfigFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigFormat::Toml => write!(f, "toml"),
+            ConfigFormat::Legacy => write!(f, "conf"),
+            ConfigFormat::Json => write!(f, "json"),
+            ConfigFormat::Yaml => write!(f, "yaml"),
+        }
+    }
+}
+
+impl FromStr for ConfigFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "toml" => Ok(ConfigFormat::Toml),
+            "conf" | "legacy" => Ok(ConfigFormat::Legacy),
+            "json" => Ok(ConfigFormat::Json),
+            "yaml" | "yml" => Ok(ConfigFormat::Yaml),
+            _ => Err(format!("unknown config format: {}", s)),
+        }
+    }
+}
+
+/// A meaningful (non-empty, non-comment) line of a legacy `.conf` file.
+#[derive(Debug)]
+pub(crate) enum LegacyLine {
+    Forward(Box<ForwardingRule>),
+    Access(AccessRule),
+    Include(String),
+    /// A line whose token count the legacy format does not define. The
+    /// loader skips these silently.
+    Ignored,
+}
+
+/// Parses a single legacy config line. Returns `None` for empty lines and
+/// comments.
+pub(crate) fn parse_legacy_line(line: &str) -> Result<Option<LegacyLine>, ConfigError> {
+    let line = line.trim();
+
+    // Skip empty lines and comments
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    let parts: Vec<&str> = line.split_whitespace().collect();
+
+    // Check if this is a bind/connect rule (4 parts)
+    if parts.len() == 4 {
+        let bind_address = parts[0].to_string();
+        let bind_port = parts[1].parse::<u16>()
+            .map_err(|_| ConfigError::ParseError(format!("Invalid bind port: {}", parts[1])))?;
+        let connect_address = parts[2].to_string();
+        let connect_port = parts[3].parse::<u16>()
+            .map_err(|_| ConfigError::ParseError(format!("Invalid connect port: {}", parts[3])))?;
+
+        // Validate addresses
+        let _ = (bind_address.as_str(), bind_port).to_socket_addrs()
+            .map_err(|_| ConfigError::ParseError(format!("Invalid bind address: {}:{}", bind_address, bind_port)))?;
+        let _ = (connect_address.as_str(), connect_port).to_socket_addrs()
+            .map_err(|_| ConfigError::ParseError(format!("Invalid connect address: {}:{}", connect_address, connect_port)))?;
+
+        Ok(Some(LegacyLine::Forward(Box::new(ForwardingRule {
+            bind_address,
+            bind_port,
+            connect_address,
+            connect_port,
+            protocol: Protocol::Tcp, // Default to TCP
+            timeout: None,
+            source_address: None,
+            rules: Vec::new(),
+            filters: Vec::new(),
+            enabled: true,
+            max_connections: None,
+            limit_action: None,
+            rate_limit: None,
+            bandwidth: None,
+        }))))
+    }
+    // Handle allow/deny rules and includes (2 parts)
+    else if parts.len() == 2 {
+        if parts[0].eq_ignore_ascii_case("include") {
+            return Ok(Some(LegacyLine::Include(parts[1].to_string())));
+        }
+
+        let rule_type = match parts[0].to_lowercase().as_str() {
+            "allow" => RuleType::Allow,
+            "deny" => RuleType::Deny,
+            _ => return Err(ConfigError::ParseError(format!("Unknown rule type: {}", parts[0]))),
+        };
+
+        Ok(Some(LegacyLine::Access(AccessRule {
+            rule_type,
+            pattern: parts[1].to_string(),
+            file: None,
+        })))
+    } else {
+        Ok(Some(LegacyLine::Ignored))
+    }
+}
+
 impl Config {
     pub fn load_from_file(path: &str) -> Result<Self, ConfigError> {
-        if path.ends_with(".toml") {
-            Self::parse_toml_config(path)
-        } else {
-            // Parse legacy .conf format
-            Self::parse_legacy_conf(path)
-        }
+        Self::load_from_file_as(path, None)
     }
-    
-    fn parse_toml_config(path: &str) -> Result<Self, ConfigError> {
-        let content = fs::read_to_string(path)?;
-        let config: Config = toml::from_str(&content)?;
-        Ok(config)
+
+    /// Loads a config file in the given format, or in the format detected by
+    /// `ConfigFormat::detect` when `format` is `None`. Files named by
+    /// `include` are detected independently and their rules appended.
+    /// `${VAR}` references in string values are expanded from the
+    /// environment.
+    pub fn load_from_file_as(path: &str, format: Option<ConfigFormat>) -> Result<Self, ConfigError> {
+        Self::load_with_sources(path, format).map(|(config, _)| config)
     }
-    
-    fn parse_legacy_conf(path: &str) -> Result<Self, ConfigError> {
+
+    /// Like `load_from_file_as`, also returning the files the config was
+    /// assembled from.
+    pub fn load_with_sources(path: &str, format: Option<ConfigFormat>) -> Result<(Self, ConfigSources), ConfigError> {
         let content = fs::read_to_string(path)?;
+        let format = format.unwrap_or_else(|| ConfigFormat::detect(path, &content));
+        let mut config: Config = parse_interpolated(&content, format)?;
+        resolve_list_files(Path::new(path), &mut config.global_rules, &mut config.forwarding_rules);
+
+        let mut loader = IncludeLoader::new(path, &config);
+        let include = config.include.clone();
+        loader.expand(Path::new(path), &include, &mut config)?;
+        loader.check_duplicate_binds(&config)?;
+        check_access_rules(&config)?;
+        Ok((config, loader.sources))
+    }
+
+    /// Parses config text as-is, without expanding `${VAR}` references or
+    /// resolving includes.
+    pub fn parse_str(content: &str, format: ConfigFormat) -> Result<Self, ConfigError> {
+        match format {
+            ConfigFormat::Toml => Ok(toml::from_str(content)?),
+            ConfigFormat::Json => Ok(serde_json::from_str(content)?),
+            ConfigFormat::Yaml => Ok(serde_yaml::from_str(content)?),
+            // Parse legacy .conf format
+            ConfigFormat::Legacy => Self::parse_legacy_str(content),
+        }
+    }
+
+    pub(crate) fn parse_legacy_str(content: &str) -> Result<Self, ConfigError> {
         let mut global_rules = Vec::new();
         let mut forwarding_rules = Vec::new();
+        let mut include = Vec::new();
         
         for line in content.lines() {
-            let line = line.trim();
-            
-            // Skip empty lines and comments
-            if line.is_empty() || line.starts_with('#') {
-                continue;
-            }
-            
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            
-            // Check if this is a bind/connect rule (4 parts)
-            if parts.len() == 4 {
-                let bind_address = parts[0].to_string();
-                let bind_port = parts[1].parse::<u16>()
-                    .map_err(|_| ConfigError::ParseError(format!("Invalid bind port: {}", parts[1])))?;
-                let connect_address = parts[2].to_string();
-                let connect_port = parts[3].parse::<u16>()
-                    .map_err(|_| ConfigError::ParseError(format!("Invalid connect port: {}", parts[3])))?;
-                
-                // Validate addresses
-                let _ = (bind_address.as_str(), bind_port).to_socket_addrs()
-                    .map_err(|_| ConfigError::ParseError(format!("Invalid bind address: {}:{}", bind_address, bind_port)))?;
-                let _ = (connect_address.as_str(), connect_port).to_socket_addrs()
-                    .map_err(|_| ConfigError::ParseError(format!("Invalid connect address: {}:{}", connect_address, connect_port)))?;
-                
-                forwarding_rules.push(ForwardingRule {
-                    bind_address,
-                    bind_port,
-                    connect_address,
-                    connect_port,
-                    protocol: Protocol::Tcp, // Default to TCP
-                    timeout: None,
-                    source_address: None,
-                    rules: Vec::new(),
-                });
-            }
-            // Handle allow/deny rules (2 parts)
-            else if parts.len() == 2 {
-                let rule_type = match parts[0].to_lowercase().as_str() {
-                    "allow" => RuleType::Allow,
-                    "deny" => RuleType::Deny,
-                    _ => return Err(ConfigError::ParseError(format!("Unknown rule type: {}", parts[0]))),
-                };
-                
-                global_rules.push(AccessRule {
-                    rule_type,
-                    pattern: parts[1].to_string(),
-                });
+            match parse_legacy_line(line)? {
+                Some(LegacyLine::Forward(rule)) => forwarding_rules.push(*rule),
+                Some(LegacyLine::Access(rule)) => global_rules.push(rule),
+                Some(LegacyLine::Include(pattern)) => include.push(pattern),
+                Some(LegacyLine::Ignored) | None => {}
             }
         }
         
         Ok(Config {
             global_rules,
             forwarding_rules,
-            log_file: None,
-            pid_file: None,
+            include,
             log_format: LogFormat::Rinetd,
+            ..Config::default()
         })
     }
 }
 
+/// The parts of an included file that are merged into the including
+/// config. Other top-level settings are only honoured in the main file.
+#[derive(Debug, Deserialize)]
+struct Fragment {
+    #[serde(default)]
+    global_rules: Vec<AccessRule>,
+    #[serde(default)]
+    forwarding_rules: Vec<ForwardingRule>,
+    #[serde(default)]
+    include: Vec<String>,
+}
+
+impl Fragment {
+    fn load(path: &Path) -> Result<Self, ConfigError> {
+        let content = fs::read_to_string(path)?;
+        let format = ConfigFormat::detect(&path.to_string_lossy(), &content);
+        parse_interpolated(&content, format)
+    }
+}
+
+impl From<Config> for Fragment {
+    fn from(config: Config) -> Self {
+        Fragment {
+            global_rules: config.global_rules,
+            forwarding_rules: config.forwarding_rules,
+            include: config.include,
+        }
+    }
+}
+
+/// Parses config text, expanding `${VAR}` references in its string values
+/// before deserializing.
+fn parse_interpolated<T>(content: &str, format: ConfigFormat) -> Result<T, ConfigError>
+where
+    T: DeserializeOwned + From<Config>,
+{
+    match format {
+        ConfigFormat::Toml => {
+            let mut value = toml::Value::Table(toml::from_str(content)?);
+            expand_toml(&mut value)?;
+            Ok(value.try_into()?)
+        }
+        ConfigFormat::Json => {
+            let mut value: serde_json::Value = serde_json::from_str(content)?;
+            expand_json(&mut value)?;
+            Ok(serde_json::from_value(value)?)
+        }
+        ConfigFormat::Yaml => {
+            let mut value: serde_yaml::Value = serde_yaml::from_str(content)?;
+            expand_yaml(&mut value)?;
+            Ok(serde_yaml::from_value(value)?)
+        }
+        ConfigFormat::Legacy => Ok(Config::parse_legacy_str(&expand_legacy(content)?)?.into()),
+    }
+}
+
+/// Makes the relative `file` of access rules relative to the directory of
+/// `declared_in`, the config file that declares them.
+fn resolve_list_files(declared_in: &Path, global_rules: &mut [AccessRule], forwarding_rules: &mut [ForwardingRule]) {
+    let base = declared_in.parent().unwrap_or_else(|| Path::new(""));
+    let rules = global_rules.iter_mut().chain(forwarding_rules.iter_mut().flat_map(|rule| rule.rules.iter_mut()));
+    for file in rules.filter_map(|rule| rule.file.as_mut()) {
+        *file = base.join(&*file).to_string_lossy().into_owned();
+    }
+}
+
+/// Rejects access rules that set both or neither of `pattern` and `file`,
+/// and list files that cannot be read.
+fn check_access_rules(config: &Config) -> Result<(), ConfigError> {
+    let rules = config.global_rules.iter().chain(config.forwarding_rules.iter().flat_map(|rule| &rule.rules));
+    for rule in rules {
+        match &rule.file {
+            None if rule.pattern.is_empty() => {
+                return Err(ConfigError::ParseError("Access rule needs a pattern or a file".to_string()));
+            }
+            Some(file) if !rule.pattern.is_empty() => {
+                return Err(ConfigError::ParseError(format!(
+                    "Access rule sets both pattern {} and file {}",
+                    rule.pattern, file
+                )));
+            }
+            Some(file) => {
+                fs::File::open(file)
+                    .map_err(|e| ConfigError::ParseError(format!("Cannot read IP list {}: {}", file, e)))?;
+            }
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+/// The files a config was loaded from: the main file, every included file,
+/// and the include patterns that were expanded to find them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConfigSources {
+    pub files: Vec<PathBuf>,
+    /// Include patterns, relative to the working directory.
+    pub include_patterns: Vec<String>,
+}
+
+impl ConfigSources {
+    /// Files currently matched by the include patterns, which may differ from
+    /// `files` once files have been added or removed.
+    pub fn matched_files(&self) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = self
+            .include_patterns
+            .iter()
+            .filter_map(|pattern| glob::glob(pattern).ok())
+            .flat_map(|paths| paths.filter_map(Result::ok))
+            .collect();
+        files.sort();
+        files
+    }
+}
+
+/// Resolves `include` patterns recursively, remembering which file every
+/// forwarding rule came from so that conflicts can name both files.
+struct IncludeLoader {
+    visited: HashSet<PathBuf>,
+    origins: Vec<String>,
+    sources: ConfigSources,
+}
+
+impl IncludeLoader {
+    fn new(path: &str, config: &Config) -> Self {
+        let mut visited = HashSet::new();
+        visited.insert(fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path)));
+        IncludeLoader {
+            visited,
+            origins: vec![path.to_string(); config.forwarding_rules.len()],
+            sources: ConfigSources {
+                files: vec![PathBuf::from(path)],
+                include_patterns: Vec::new(),
+            },
+        }
+    }
+
+    /// Appends the rules of every file matched by `patterns`, which are
+    /// relative to the directory of `including_file`. Files are merged in
+    /// sorted order and each file at most once.
+    fn expand(&mut self, including_file: &Path, patterns: &[String], config: &mut Config) -> Result<(), ConfigError> {
+        let base = including_file.parent().unwrap_or_else(|| Path::new(""));
+        for pattern in patterns {
+            let full_pattern = base.join(pattern);
+            let full_pattern = full_pattern.to_string_lossy();
+            self.sources.include_patterns.push(full_pattern.to_string());
+            let mut files: Vec<PathBuf> = glob::glob(&full_pattern)
+                .map_err(|e| ConfigError::Included {
+                    path: including_file.display().to_string(),
+                    error: Box::new(ConfigError::ParseError(format!("Invalid include pattern {}: {}", pattern, e))),
+                })?
+                .filter_map(Result::ok)
+                .collect();
+            files.sort();
+
+            // A plain file name must exist; a glob may match nothing.
+            if files.is_empty() && !pattern.contains(['*', '?', '[']) {
+                return Err(ConfigError::Included {
+                    path: including_file.display().to_string(),
+                    error: Box::new(ConfigError::ParseError(format!("Included file not found: {}", pattern))),
+                });
+            }
+
+            for file in files {
+                let canonical = fs::canonicalize(&file).unwrap_or_else(|_| file.clone());
+                if !self.visited.insert(canonical) {
+                    continue;
+                }
+                self.sources.files.push(file.clone());
+
+                let mut fragment = Fragment::load(&file).map_err(|e| ConfigError::Included {
+                    path: file.display().to_string(),
+                    error: Box::new(e),
+                })?;
+                resolve_list_files(&file, &mut fragment.global_rules, &mut fragment.forwarding_rules);
+                self.origins
+                    .extend(std::iter::repeat_n(file.display().to_string(), fragment.forwarding_rules.len()));
+                config.global_rules.extend(fragment.global_rules);
+                config.forwarding_rules.extend(fragment.forwarding_rules);
+                self.expand(&file, &fragment.include, config)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rejects two rules that bind the same address, port and transport.
+    /// Addresses are compared as IPs, host names by what they resolve to,
+    /// and a wildcard address conflicts with every address it covers.
+    /// Port 0 binds an ephemeral port and never conflicts.
+    fn check_duplicate_binds(&self, config: &Config) -> Result<(), ConfigError> {
+        let mut seen: Vec<(&ForwardingRule, Vec<IpAddr>, bool, &str)> = Vec::new();
+        for (rule, origin) in config.forwarding_rules.iter().zip(&self.origins) {
+            if rule.bind_port == 0 {
+                continue;
+            }
+            let udp = matches!(rule.protocol, Protocol::Udp | Protocol::UdpToTcp);
+            let ips = bind_ips(rule);
+            let duplicate = seen.iter().find(|(other, other_ips, other_udp, _)| {
+                other.bind_port == rule.bind_port
+                    && *other_udp == udp
+                    && (other.bind_address.eq_ignore_ascii_case(&rule.bind_address)
+                        || ips.iter().any(|ip| other_ips.iter().any(|other| binds_overlap(*ip, *other))))
+            });
+            if let Some((other, _, _, first)) = duplicate {
+                return Err(ConfigError::ParseError(format!(
+                    "Duplicate bind {}:{}/{} in {} (already bound to {} in {})",
+                    rule.bind_address,
+                    rule.bind_port,
+                    if udp { "udp" } else { "tcp" },
+                    origin,
+                    other.bind_address,
+                    first
+                )));
+            }
+            seen.push((rule, ips, udp, origin));
+        }
+        Ok(())
+    }
+}
+
+/// The addresses `rule` binds: its bind address, or what the host name
+/// resolves to. Names that do not resolve give none.
+fn bind_ips(rule: &ForwardingRule) -> Vec<IpAddr> {
+    let host = rule.bind_address.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return vec![ip];
+    }
+    (host, rule.bind_port)
+        .to_socket_addrs()
+        .map(|addrs| addrs.map(|addr| addr.ip()).collect())
+        .unwrap_or_default()
+}
+
+/// True when sockets bound to `a` and `b` on the same port would clash:
+/// the addresses are equal, or one is a wildcard covering the other. `::`
+/// also takes the IPv4 addresses, as dual-stack sockets do.
+fn binds_overlap(a: IpAddr, b: IpAddr) -> bool {
+    let covers = |wildcard: IpAddr, other: IpAddr| wildcard.is_unspecified() && (wildcard.is_ipv6() || other.is_ipv4());
+    let (a, b) = (a.to_canonical(), b.to_canonical());
+    a == b || covers(a, b) || covers(b, a)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::config::RuleType;
     use std::io::Write;
+    use test_case::test_case;
 
     fn write_temp_file(content: &str) -> (tempfile::TempDir, String) {
         let dir = tempfile::tempdir().unwrap();
@@ -168,6 +595,61 @@ mod tests {
         assert!(matches!(err, ConfigError::TomlError(_)));
     }
 
+    #[test]
+    fn config_error_display_json() {
+        let json_err = serde_json::from_str::<Config>("{").unwrap_err();
+        let err: ConfigError = json_err.into();
+        assert!(matches!(err, ConfigError::JsonError(_)));
+        assert!(err.to_string().contains("JSON error"));
+    }
+
+    #[test]
+    fn config_error_display_yaml() {
+        let yaml_err = serde_yaml::from_str::<Config>("forwarding_rules: 3").unwrap_err();
+        let err: ConfigError = yaml_err.into();
+        assert!(matches!(err, ConfigError::YamlError(_)));
+        assert!(err.to_string().contains("YAML error"));
+    }
+
+    #[test_case("proxy.toml", Some(ConfigFormat::Toml))]
+    #[test_case("proxy.json", Some(ConfigFormat::Json))]
+    #[test_case("proxy.yaml", Some(ConfigFormat::Yaml))]
+    #[test_case("proxy.YML", Some(ConfigFormat::Yaml))]
+    #[test_case("rinetd.conf", None)]
+    #[test_case("rules.cfg", None)]
+    #[test_case("noextension", None)]
+    fn config_format_from_extension(path: &str, expected: Option<ConfigFormat>) {
+        assert_eq!(ConfigFormat::from_extension(path), expected);
+    }
+
+    #[test_case("[[forwarding_rules]]\nbind_port = 1", ConfigFormat::Toml)]
+    #[test_case("# comment\nlog_file = \"/tmp/oi.log\"", ConfigFormat::Toml)]
+    #[test_case("forwarding_rules = []", ConfigFormat::Toml)]
+    #[test_case("{\"forwarding_rules\": []}", ConfigFormat::Json)]
+    #[test_case("---\nforwarding_rules: []", ConfigFormat::Yaml)]
+    #[test_case("forwarding_rules:\n  - bind_port: 1", ConfigFormat::Yaml)]
+    #[test_case("allow 10.0.0.1\n", ConfigFormat::Legacy)]
+    #[test_case("0.0.0.0 80 127.0.0.1 8080", ConfigFormat::Legacy)]
+    #[test_case("::1 80 ::1 8080", ConfigFormat::Legacy)]
+    #[test_case("2001:db8::1 80 10.0.0.1 80", ConfigFormat::Legacy)]
+    #[test_case("fe80::1 80 10.0.0.1 80", ConfigFormat::Legacy)]
+    #[test_case("2001:0db8:0:0:0:0:0:1 80 ::1 8080", ConfigFormat::Legacy)]
+    #[test_case("bind_port: 1", ConfigFormat::Yaml)]
+    #[test_case("# only comments\n\n", ConfigFormat::Legacy)]
+    fn config_format_sniff(content: &str, expected: ConfigFormat) {
+        assert_eq!(ConfigFormat::sniff(content), expected);
+    }
+
+    #[test]
+    fn config_format_from_str() {
+        assert_eq!("toml".parse::<ConfigFormat>(), Ok(ConfigFormat::Toml));
+        assert_eq!("CONF".parse::<ConfigFormat>(), Ok(ConfigFormat::Legacy));
+        assert_eq!("legacy".parse::<ConfigFormat>(), Ok(ConfigFormat::Legacy));
+        assert_eq!("json".parse::<ConfigFormat>(), Ok(ConfigFormat::Json));
+        assert_eq!("yml".parse::<ConfigFormat>(), Ok(ConfigFormat::Yaml));
+        assert!("xml".parse::<ConfigFormat>().is_err());
+    }
+
     #[test]
     fn load_from_file_dispatches_toml() {
         let dir = tempfile::tempdir().unwrap();
@@ -193,6 +675,320 @@ connect_port = 9090
         assert_eq!(config.forwarding_rules.len(), 1);
     }
 
+    #[test]
+    fn load_from_file_sniffs_toml_in_conf_file() {
+        let (_dir, path) = write_temp_file(r#"
+[[forwarding_rules]]
+bind_address = "127.0.0.1"
+bind_port = 8080
+connect_address = "127.0.0.1"
+connect_port = 9090
+protocol = "udp"
+"#);
+        let config = Config::load_from_file(&path).unwrap();
+        assert_eq!(config.forwarding_rules.len(), 1);
+        assert!(matches!(config.forwarding_rules[0].protocol, Protocol::Udp));
+    }
+
+    #[test]
+    fn load_from_file_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("proxy.json");
+        fs::write(&path, r#"{
+  "global_rules": [{"type": "deny", "pattern": "10.0.0.1"}],
+  "forwarding_rules": [{
+    "bind_address": "127.0.0.1",
+    "bind_port": 8080,
+    "connect_address": "127.0.0.1",
+    "connect_port": 9090,
+    "protocol": "tcptoudp"
+  }]
+}"#)
+        .unwrap();
+
+        let config = Config::load_from_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(config.global_rules.len(), 1);
+        assert!(matches!(config.global_rules[0].rule_type, RuleType::Deny));
+        assert!(matches!(config.forwarding_rules[0].protocol, Protocol::TcpToUdp));
+    }
+
+    #[test]
+    fn load_from_file_yaml() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("proxy.yaml");
+        fs::write(&path, r#"
+log_format: common
+forwarding_rules:
+  - bind_address: 127.0.0.1
+    bind_port: 8080
+    connect_address: 127.0.0.1
+    connect_port: 9090
+    timeout: 30
+    rules:
+      - type: allow
+        pattern: 127.0.0.*
+"#)
+        .unwrap();
+
+        let config = Config::load_from_file(path.to_str().unwrap()).unwrap();
+        assert!(matches!(config.log_format, LogFormat::Common));
+        assert_eq!(config.forwarding_rules[0].timeout, Some(30));
+        assert_eq!(config.forwarding_rules[0].rules[0].pattern, "127.0.0.*");
+    }
+
+    #[test]
+    fn load_from_file_as_overrides_detection() {
+        // Legacy content would be sniffed as legacy; forcing TOML must fail.
+        let (_dir, path) = write_temp_file("127.0.0.1 80 127.0.0.1 8080\n");
+        let err = Config::load_from_file_as(&path, Some(ConfigFormat::Toml)).unwrap_err();
+        assert!(matches!(err, ConfigError::TomlError(_)));
+
+        let config = Config::load_from_file_as(&path, Some(ConfigFormat::Legacy)).unwrap();
+        assert_eq!(config.forwarding_rules.len(), 1);
+    }
+
+    #[test]
+    fn load_from_file_invalid_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bad.json");
+        fs::write(&path, "{\"forwarding_rules\": [}").unwrap();
+        let err = Config::load_from_file(path.to_str().unwrap()).unwrap_err();
+        assert!(matches!(err, ConfigError::JsonError(_)));
+    }
+
+    fn rule_toml(port: u16) -> String {
+        format!(r#"
+[[forwarding_rules]]
+bind_address = "127.0.0.1"
+bind_port = {}
+connect_address = "127.0.0.1"
+connect_port = 9090
+"#, port)
+    }
+
+    #[test]
+    fn include_merges_fragments_in_sorted_order() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("conf.d")).unwrap();
+        fs::write(dir.path().join("conf.d/b.toml"), rule_toml(8082)).unwrap();
+        fs::write(dir.path().join("conf.d/a.toml"), rule_toml(8081)).unwrap();
+        fs::write(dir.path().join("conf.d/rules.toml"), "[[global_rules]]\ntype = \"deny\"\npattern = \"10.0.0.1\"\n").unwrap();
+        let main = dir.path().join("oi.toml");
+        fs::write(&main, format!("include = [\"conf.d/*.toml\"]\n{}", rule_toml(8080))).unwrap();
+
+        let config = Config::load_from_file(main.to_str().unwrap()).unwrap();
+        let ports: Vec<u16> = config.forwarding_rules.iter().map(|r| r.bind_port).collect();
+        assert_eq!(ports, vec![8080, 8081, 8082]);
+        assert_eq!(config.global_rules.len(), 1);
+        assert_eq!(config.include, vec!["conf.d/*.toml".to_string()]);
+    }
+
+    #[test]
+    fn load_with_sources_lists_included_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("conf.d")).unwrap();
+        fs::write(dir.path().join("conf.d/a.toml"), rule_toml(8081)).unwrap();
+        let main = dir.path().join("oi.toml");
+        fs::write(&main, format!("include = [\"conf.d/*.toml\"]\n{}", rule_toml(8080))).unwrap();
+
+        let (_, sources) = Config::load_with_sources(main.to_str().unwrap(), None).unwrap();
+        assert_eq!(sources.files, vec![main.clone(), dir.path().join("conf.d/a.toml")]);
+        assert_eq!(sources.matched_files(), vec![dir.path().join("conf.d/a.toml")]);
+
+        fs::write(dir.path().join("conf.d/b.toml"), rule_toml(8082)).unwrap();
+        assert_eq!(sources.matched_files().len(), 2);
+    }
+
+    #[test]
+    fn list_files_are_relative_to_the_declaring_file() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("conf.d")).unwrap();
+        fs::write(dir.path().join("blocklist.txt"), "10.0.0.1\n").unwrap();
+        fs::write(dir.path().join("conf.d/allowlist.txt"), "10.0.0.0/8\n").unwrap();
+        fs::write(
+            dir.path().join("conf.d/a.toml"),
+            format!("{}[[forwarding_rules.rules]]\ntype = \"allow\"\nfile = \"allowlist.txt\"\n", rule_toml(8081)),
+        )
+        .unwrap();
+        let main = dir.path().join("oi.toml");
+        fs::write(
+            &main,
+            "include = [\"conf.d/*.toml\"]\nforwarding_rules = []\n[[global_rules]]\ntype = \"deny\"\nfile = \"blocklist.txt\"\n",
+        )
+        .unwrap();
+
+        let config = Config::load_from_file(main.to_str().unwrap()).unwrap();
+        let blocklist = dir.path().join("blocklist.txt").display().to_string();
+        let allowlist = dir.path().join("conf.d/allowlist.txt").display().to_string();
+        assert_eq!(config.global_rules[0].file.as_deref(), Some(blocklist.as_str()));
+        assert_eq!(config.forwarding_rules[0].rules[0].file.as_deref(), Some(allowlist.as_str()));
+    }
+
+    #[test]
+    fn access_rules_need_one_readable_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let main = dir.path().join("oi.toml");
+        let load = |rule: &str| {
+            fs::write(&main, format!("forwarding_rules = []\n[[global_rules]]\ntype = \"deny\"\n{}", rule)).unwrap();
+            Config::load_from_file(main.to_str().unwrap())
+        };
+        let err = load("").unwrap_err();
+        assert!(err.to_string().contains("needs a pattern or a file"), "{}", err);
+        fs::write(dir.path().join("blocklist.txt"), "10.0.0.1\n").unwrap();
+        let err = load("pattern = \"10.0.0.2\"\nfile = \"blocklist.txt\"\n").unwrap_err();
+        assert!(err.to_string().contains("both"), "{}", err);
+        let err = load("file = \"missing.txt\"\n").unwrap_err();
+        assert!(err.to_string().contains("missing.txt"), "{}", err);
+        assert!(load("file = \"blocklist.txt\"\n").is_ok());
+    }
+
+    #[test]
+    fn include_from_legacy_conf() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("web.conf"), "allow 127.0.0.*\n127.0.0.1 8081 127.0.0.1 9090\n").unwrap();
+        let main = dir.path().join("rinetd.conf");
+        fs::write(&main, "include web.conf\n127.0.0.1 8080 127.0.0.1 9090\n").unwrap();
+
+        let config = Config::load_from_file(main.to_str().unwrap()).unwrap();
+        assert_eq!(config.forwarding_rules.len(), 2);
+        assert_eq!(config.global_rules.len(), 1);
+    }
+
+    #[test]
+    fn include_nested_and_self_include_loads_once() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("inner.toml"), format!("include = [\"*.toml\"]\n{}", rule_toml(8081))).unwrap();
+        let main = dir.path().join("oi.toml");
+        fs::write(&main, format!("include = [\"inner.toml\"]\n{}", rule_toml(8080))).unwrap();
+
+        let config = Config::load_from_file(main.to_str().unwrap()).unwrap();
+        assert_eq!(config.forwarding_rules.len(), 2);
+    }
+
+    #[test]
+    fn include_duplicate_bind_names_both_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("dup.toml"), rule_toml(8080)).unwrap();
+        let main = dir.path().join("oi.toml");
+        fs::write(&main, format!("include = [\"dup.toml\"]\n{}", rule_toml(8080))).unwrap();
+
+        let err = Config::load_from_file(main.to_str().unwrap()).unwrap_err();
+        let msg = err.to_string();
+        assert!(msg.contains("Duplicate bind 127.0.0.1:8080/tcp"), "{}", msg);
+        assert!(msg.contains("dup.toml"), "{}", msg);
+        assert!(msg.contains("oi.toml"), "{}", msg);
+    }
+
+    #[test_case("::1", "0:0:0:0:0:0:0:1", true)]
+    #[test_case("0.0.0.0", "127.0.0.1", true)]
+    #[test_case("127.0.0.1", "::", true)]
+    #[test_case("::", "0.0.0.0", true)]
+    #[test_case("::ffff:127.0.0.1", "127.0.0.1", true)]
+    #[test_case("localhost", "127.0.0.1", true)]
+    #[test_case("127.0.0.1", "127.0.0.2", false)]
+    #[test_case("0.0.0.0", "::1", false)]
+    fn duplicate_bind_compares_addresses(first: &str, second: &str, duplicate: bool) {
+        let rules = [first, second].map(|addr| rule_toml(8080).replace("bind_address = \"127.0.0.1\"", &format!("bind_address = {:?}", addr)));
+        let (_dir, path) = write_temp_file(&rules.concat());
+        let result = Config::load_from_file(&path);
+        assert_eq!(result.is_err(), duplicate, "{:?}", result.err());
+        if let Err(err) = result {
+            assert!(err.to_string().contains(&format!("Duplicate bind {}:8080/tcp", second)), "{}", err);
+        }
+    }
+
+    #[test]
+    fn duplicate_bind_different_transport_is_allowed() {
+        let (_dir, path) = write_temp_file(&format!("{}{}protocol = \"udp\"\n", rule_toml(8080), rule_toml(8080)));
+        let config = Config::load_from_file(&path).unwrap();
+        assert_eq!(config.forwarding_rules.len(), 2);
+    }
+
+    #[test]
+    fn include_error_names_included_file() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("broken.toml"), "[[forwarding_rules]\n").unwrap();
+        let main = dir.path().join("oi.toml");
+        fs::write(&main, "include = [\"broken.toml\"]\nforwarding_rules = []\n").unwrap();
+
+        let err = Config::load_from_file(main.to_str().unwrap()).unwrap_err();
+        match err {
+            ConfigError::Included { path, error } => {
+                assert!(path.ends_with("broken.toml"));
+                assert!(matches!(*error, ConfigError::TomlError(_)));
+            }
+            other => panic!("expected included-file error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn include_missing_file_errors_but_empty_glob_does_not() {
+        let dir = tempfile::tempdir().unwrap();
+        let main = dir.path().join("oi.toml");
+        fs::write(&main, "include = [\"conf.d/*.toml\"]\nforwarding_rules = []\n").unwrap();
+        assert!(Config::load_from_file(main.to_str().unwrap()).is_ok());
+
+        fs::write(&main, "include = [\"missing.toml\"]\nforwarding_rules = []\n").unwrap();
+        let err = Config::load_from_file(main.to_str().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("Included file not found: missing.toml"));
+    }
+
+    #[test]
+    fn load_from_file_expands_defaults() {
+        let (_dir, path) = write_temp_file(r#"
+log_file = "${OI_TEST_UNSET_LOG_DIR:-/var/log}/oi.log"
+
+[[forwarding_rules]]
+bind_address = "${OI_TEST_UNSET_BIND:-0.0.0.0}"
+bind_port = "${OI_TEST_UNSET_PORT:-8080}"
+connect_address = "${OI_TEST_UNSET_BACKEND:-10.0.0.5}"
+connect_port = 9090
+"#);
+        let config = Config::load_from_file(&path).unwrap();
+        assert_eq!(config.log_file.as_deref(), Some("/var/log/oi.log"));
+        let rule = &config.forwarding_rules[0];
+        assert_eq!(rule.bind_address, "0.0.0.0");
+        assert_eq!(rule.bind_port, 8080);
+        assert_eq!(rule.connect_address, "10.0.0.5");
+    }
+
+    #[test]
+    fn load_from_file_undefined_variable_errors() {
+        let (_dir, path) = write_temp_file(r#"
+[[forwarding_rules]]
+bind_address = "127.0.0.1"
+bind_port = 8080
+connect_address = "${OI_TEST_UNSET_BACKEND}"
+connect_port = 9090
+"#);
+        let err = Config::load_from_file(&path).unwrap_err();
+        assert!(matches!(err, ConfigError::UndefinedVariable(name) if name == "OI_TEST_UNSET_BACKEND"));
+    }
+
+    #[test]
+    fn load_from_file_expands_yaml_and_legacy() {
+        let dir = tempfile::tempdir().unwrap();
+        let yaml = dir.path().join("proxy.yaml");
+        fs::write(&yaml, "forwarding_rules:\n  - bind_address: 127.0.0.1\n    bind_port: ${OI_TEST_UNSET_PORT:-8080}\n    connect_address: 127.0.0.1\n    connect_port: 9090\n").unwrap();
+        let config = Config::load_from_file(yaml.to_str().unwrap()).unwrap();
+        assert_eq!(config.forwarding_rules[0].bind_port, 8080);
+
+        let (_dir, legacy) = write_temp_file("${OI_TEST_UNSET_BIND:-127.0.0.1} 80 127.0.0.1 ${OI_TEST_UNSET_PORT:-8080}\n");
+        let config = Config::load_from_file(&legacy).unwrap();
+        assert_eq!(config.forwarding_rules[0].bind_address, "127.0.0.1");
+        assert_eq!(config.forwarding_rules[0].connect_port, 8080);
+    }
+
+    #[test]
+    fn parse_str_does_not_expand() {
+        let config = Config::parse_str(
+            "[[forwarding_rules]]\nbind_address = \"${BIND}\"\nbind_port = 1\nconnect_address = \"x\"\nconnect_port = 2\n",
+            ConfigFormat::Toml,
+        )
+        .unwrap();
+        assert_eq!(config.forwarding_rules[0].bind_address, "${BIND}");
+    }
+
     #[test]
     fn load_from_file_file_not_found() {
         let err = Config::load_from_file("C:/nonexistent/dir/proxy.toml").unwrap_err();
@@ -0,0 +1,287 @@
+//! Bookkeeping of open TCP connections and UDP sessions, so that shutdown
+//! can wait for them to finish and close whatever is left, and so that
+//! single connections can be killed.
+
+use crate::observer::{CloseReason, ConnectionInfo};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Which connections `ConnectionTracker::kill` ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KillTarget {
+    /// The connection or UDP session with this `ConnectionInfo::id`.
+    Id(u64),
+    /// Every connection from this client address.
+    Client(IpAddr),
+    /// Every connection accepted by the listener bound to this address.
+    Listener(SocketAddr),
+}
+
+impl KillTarget {
+    pub fn matches(&self, conn: &ConnectionInfo) -> bool {
+        match self {
+            KillTarget::Id(id) => conn.id == *id,
+            KillTarget::Client(ip) => conn.client_addr.ip() == *ip,
+            KillTarget::Listener(addr) => conn.local_addr == *addr,
+        }
+    }
+}
+
+struct Registered {
+    info: ConnectionInfo,
+    /// Closed to kill the connection.
+    kill_tx: async_channel::Sender<()>,
+}
+
+struct Inner {
+    active: AtomicUsize,
+    open: Mutex<HashMap<u64, Registered>>,
+    draining: AtomicBool,
+    /// Receives a message whenever the last open connection ends.
+    idle_tx: async_channel::Sender<()>,
+    idle_rx: async_channel::Receiver<()>,
+    /// Closed to tell every open connection to end.
+    close_tx: async_channel::Sender<()>,
+    close_rx: async_channel::Receiver<()>,
+}
+
+/// Counts open connections. Clones share the same count.
+#[derive(Clone)]
+pub struct ConnectionTracker {
+    inner: Arc<Inner>,
+}
+
+impl ConnectionTracker {
+    pub fn new() -> Self {
+        let (idle_tx, idle_rx) = async_channel::bounded(1);
+        let (close_tx, close_rx) = async_channel::bounded(1);
+        ConnectionTracker {
+            inner: Arc::new(Inner {
+                active: AtomicUsize::new(0),
+                open: Mutex::new(HashMap::new()),
+                draining: AtomicBool::new(false),
+                idle_tx,
+                idle_rx,
+                close_tx,
+                close_rx,
+            }),
+        }
+    }
+
+    /// Registers a new connection, which counts as open until the returned
+    /// guard is dropped.
+    pub fn open(&self, conn: &ConnectionInfo) -> TrackedConnection {
+        let (kill_tx, kill_rx) = async_channel::bounded(1);
+        let registered = Registered {
+            info: conn.clone(),
+            kill_tx,
+        };
+        self.inner.open.lock().unwrap().insert(conn.id, registered);
+        self.inner.active.fetch_add(1, Ordering::SeqCst);
+        TrackedConnection {
+            inner: self.inner.clone(),
+            id: conn.id,
+            kill_rx,
+        }
+    }
+
+    /// The number of open connections.
+    pub fn active(&self) -> usize {
+        self.inner.active.load(Ordering::SeqCst)
+    }
+
+    /// Marks the end of accepting: listeners that cannot simply be closed,
+    /// like UDP sockets that still carry open sessions, stop taking new
+    /// clients once this is set.
+    pub fn start_draining(&self) {
+        self.inner.draining.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.inner.draining.load(Ordering::SeqCst)
+    }
+
+    /// Waits until no connection is open.
+    pub async fn wait_idle(&self) {
+        while self.active() > 0 {
+            let _ = self.inner.idle_rx.recv().await;
+        }
+    }
+
+    /// Tells every open connection, and every connection opened later, to
+    /// end now.
+    pub fn close_all(&self) {
+        self.inner.close_tx.close();
+    }
+
+    /// The number of open connections that match `target`.
+    pub fn count(&self, target: KillTarget) -> usize {
+        let open = self.inner.open.lock().unwrap();
+        open.values().filter(|registered| target.matches(&registered.info)).count()
+    }
+
+    /// Tells the open connections that match `target` to end now, and
+    /// returns them.
+    pub fn kill(&self, target: KillTarget) -> Vec<ConnectionInfo> {
+        let open = self.inner.open.lock().unwrap();
+        open.values()
+            .filter(|registered| target.matches(&registered.info))
+            .map(|registered| {
+                registered.kill_tx.close();
+                registered.info.clone()
+            })
+            .collect()
+    }
+}
+
+impl Default for ConnectionTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An open connection of a `ConnectionTracker`.
+pub struct TrackedConnection {
+    inner: Arc<Inner>,
+    id: u64,
+    kill_rx: async_channel::Receiver<()>,
+}
+
+impl TrackedConnection {
+    /// Resolves once `ConnectionTracker::close_all` was called, with
+    /// `Closed`, or once the connection was killed, with `Killed`.
+    pub async fn closed(&self) -> CloseReason {
+        futures_lite::future::or(
+            async {
+                let _ = self.inner.close_rx.recv().await;
+                CloseReason::Closed
+            },
+            async {
+                let _ = self.kill_rx.recv().await;
+                CloseReason::Killed
+            },
+        )
+        .await
+    }
+
+    /// True once `ConnectionTracker::kill` picked this connection.
+    pub fn is_killed(&self) -> bool {
+        self.kill_rx.is_closed()
+    }
+}
+
+impl Drop for TrackedConnection {
+    fn drop(&mut self) {
+        self.inner.open.lock().unwrap().remove(&self.id);
+        if self.inner.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+            let _ = self.inner.idle_tx.try_send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::observer::tests::conn;
+    use futures_lite::future;
+    use std::time::Duration;
+
+    #[test]
+    fn open_and_drop_update_active_count() {
+        let tracker = ConnectionTracker::new();
+        let first = tracker.open(&conn());
+        let second = tracker.clone().open(&conn());
+        assert_eq!(tracker.active(), 2);
+        drop(first);
+        assert_eq!(tracker.active(), 1);
+        drop(second);
+        assert_eq!(tracker.active(), 0);
+    }
+
+    #[test]
+    fn wait_idle_returns_when_last_connection_ends() {
+        let tracker = ConnectionTracker::new();
+        smol::block_on(tracker.wait_idle());
+
+        let connection = tracker.open(&conn());
+        let closer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            drop(connection);
+        });
+        smol::block_on(tracker.wait_idle());
+        closer.join().unwrap();
+        assert_eq!(tracker.active(), 0);
+    }
+
+    #[test]
+    fn wait_idle_keeps_waiting_while_connections_remain() {
+        let tracker = ConnectionTracker::new();
+        let first = tracker.open(&conn());
+        let _second = tracker.open(&conn());
+        drop(first);
+        let idle = smol::block_on(future::or(
+            async {
+                tracker.wait_idle().await;
+                true
+            },
+            async {
+                smol::Timer::after(Duration::from_millis(50)).await;
+                false
+            },
+        ));
+        assert!(!idle);
+    }
+
+    #[test]
+    fn close_all_wakes_open_and_later_connections() {
+        let tracker = ConnectionTracker::new();
+        let connection = tracker.open(&conn());
+        tracker.close_all();
+        assert!(matches!(smol::block_on(connection.closed()), CloseReason::Closed));
+        assert!(matches!(
+            smol::block_on(tracker.open(&conn()).closed()),
+            CloseReason::Closed
+        ));
+    }
+
+    #[test]
+    fn draining_flag() {
+        let tracker = ConnectionTracker::new();
+        assert!(!tracker.is_draining());
+        tracker.clone().start_draining();
+        assert!(tracker.is_draining());
+    }
+
+    #[test]
+    fn kill_ends_matching_connections() {
+        let tracker = ConnectionTracker::new();
+        let first = tracker.open(&conn());
+        let second = tracker.open(&ConnectionInfo { client_addr: "10.0.0.1:4001".parse().unwrap(), ..conn() });
+        let other = tracker.open(&ConnectionInfo { client_addr: "10.0.0.9:4000".parse().unwrap(), ..conn() });
+
+        let client = KillTarget::Client("10.0.0.1".parse().unwrap());
+        assert_eq!(tracker.count(client), 2);
+        let killed = tracker.kill(client);
+        assert_eq!(killed.len(), 2);
+        assert!(first.is_killed() && second.is_killed());
+        assert!(!other.is_killed());
+        assert!(matches!(smol::block_on(first.closed()), CloseReason::Killed));
+    }
+
+    #[test]
+    fn kill_by_id_and_listener() {
+        let tracker = ConnectionTracker::new();
+        let info = conn();
+        let connection = tracker.open(&info);
+        assert!(tracker.kill(KillTarget::Id(info.id + 1)).is_empty());
+        assert!(tracker.kill(KillTarget::Listener("127.0.0.1:9090".parse().unwrap())).is_empty());
+        assert!(!connection.is_killed());
+        assert_eq!(tracker.kill(KillTarget::Listener(info.local_addr))[0].id, info.id);
+        assert!(connection.is_killed());
+
+        drop(connection);
+        assert!(tracker.kill(KillTarget::Id(info.id)).is_empty());
+    }
+}
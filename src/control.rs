@@ -0,0 +1,612 @@
+//! The admin control socket: a Unix socket on which a running `oi` answers
+//! questions about its rules and connections, used by `oi ctl`.
+//!
+//! A client writes one command per connection as a line of text and reads
+//! back one line of JSON:
+//!
+//! - `rules`: every configured rule and whether its listener is running
+//! - `connections`: open TCP connections and UDP sessions
+//! - `closed`: recently closed connections, with why they ended and what
+//!   they transferred
+//! - `counters`: totals since startup
+//! - `reload`: re-read the configuration, as on `SIGHUP`
+//! - `kill id <id>`, `kill client <ip>`, `kill rule <address:port>`: end
+//!   one connection, every connection from a client, or every connection
+//!   accepted by the listener bound to the address
+//! - `pause <address:port> [refuse] [drain <seconds>]`: pause the rules
+//!   bound to the address, closing their listener or, with `refuse`,
+//!   refusing new clients; with `drain`, open connections get that long to
+//!   finish before they are killed
+//! - `resume <address:port>`: resume paused or disabled rules
+//! - `bans`: clients banned by `auto_ban`, and when their bans end
+//! - `unban <ip>`, `unban all`: lift the ban of one client, or every ban
+//!
+//! Failures are answered with `{"error": "..."}`.
+
+use crate::config::Config;
+use crate::connections::KillTarget;
+use crate::logging;
+use crate::observer::{CloseReason, ConnectionInfo, ConnectionObserver, Direction, TransferTotals};
+use crate::proxy::ProxyHandle;
+use crate::server::{PauseMode, RuleState, RuleStatus};
+use serde_json::{Value, json};
+use std::collections::{BTreeMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// A request to reload the configuration.
+#[derive(Default)]
+pub struct ReloadRequest {
+    /// The new config, already loaded and validated. `None` reads it again
+    /// from the config file.
+    pub config: Option<Config>,
+    /// Where to report whether the new config was applied. `None` asks for
+    /// a reload without waiting for the outcome.
+    pub reply: Option<async_channel::Sender<bool>>,
+}
+
+/// Totals since startup, over all rules.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counters {
+    /// Connections and UDP sessions allowed by the access rules.
+    pub accepted: u64,
+    /// Connections and datagrams refused by the access rules.
+    pub denied: u64,
+    /// Connections and datagrams refused by a connection limit.
+    pub limited: u64,
+    /// Connections that ended with an error.
+    pub failed: u64,
+    /// Bytes forwarded from clients to the upstream.
+    pub bytes_in: u64,
+    /// Bytes forwarded from the upstream to clients.
+    pub bytes_out: u64,
+    /// Datagrams and TCP reads forwarded from clients to the upstream.
+    pub packets_in: u64,
+    /// Datagrams and TCP reads forwarded from the upstream to clients.
+    pub packets_out: u64,
+}
+
+/// How many closed connections `ConnectionTable::closed` remembers.
+const CLOSED_HISTORY: usize = 100;
+
+/// How long `serve_control` waits before accepting again after an accept
+/// failed, e.g. because the process ran out of file descriptors.
+#[cfg(unix)]
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// A connection that has ended.
+#[derive(Debug, Clone)]
+pub struct ClosedConnection {
+    pub info: ConnectionInfo,
+    /// The `CloseReason`, as displayed.
+    pub reason: String,
+    pub totals: TransferTotals,
+}
+
+#[derive(Default)]
+struct Table {
+    open: BTreeMap<u64, (ConnectionInfo, TransferTotals)>,
+    closed: VecDeque<ClosedConnection>,
+    counters: Counters,
+}
+
+/// A `ConnectionObserver` that keeps the open connections and the totals
+/// the control socket reports.
+#[derive(Default)]
+pub struct ConnectionTable(Mutex<Table>);
+
+impl ConnectionTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The open connections, oldest first, with what they transferred so
+    /// far and how long they have been open.
+    pub fn open(&self) -> Vec<(ConnectionInfo, TransferTotals)> {
+        self.0
+            .lock()
+            .unwrap()
+            .open
+            .values()
+            .map(|(info, totals)| {
+                let duration = info.started.elapsed();
+                (info.clone(), TransferTotals { duration, ..*totals })
+            })
+            .collect()
+    }
+
+    /// The most recently closed connections, oldest first.
+    pub fn closed(&self) -> Vec<ClosedConnection> {
+        self.0.lock().unwrap().closed.iter().cloned().collect()
+    }
+
+    pub fn counters(&self) -> Counters {
+        self.0.lock().unwrap().counters
+    }
+}
+
+impl ConnectionObserver for ConnectionTable {
+    fn on_access(&self, conn: &ConnectionInfo, allowed: bool) {
+        let mut table = self.0.lock().unwrap();
+        if allowed {
+            table.counters.accepted += 1;
+            table.open.insert(conn.id, (conn.clone(), TransferTotals::default()));
+        } else {
+            table.counters.denied += 1;
+        }
+    }
+
+    fn on_transfer(&self, conn: &ConnectionInfo, direction: Direction, bytes: usize) {
+        let mut guard = self.0.lock().unwrap();
+        let table = &mut *guard;
+        let bytes = bytes as u64;
+        if let Some((_, totals)) = table.open.get_mut(&conn.id) {
+            totals.add(direction, bytes);
+        }
+        match direction {
+            Direction::ClientToUpstream => {
+                table.counters.bytes_in += bytes;
+                table.counters.packets_in += 1;
+            }
+            Direction::UpstreamToClient => {
+                table.counters.bytes_out += bytes;
+                table.counters.packets_out += 1;
+            }
+        }
+    }
+
+    fn on_close(&self, conn: &ConnectionInfo, reason: &CloseReason, totals: &TransferTotals) {
+        let mut table = self.0.lock().unwrap();
+        let opened = table.open.remove(&conn.id);
+        match reason {
+            CloseReason::Error(_) => table.counters.failed += 1,
+            CloseReason::Limited(_) => table.counters.limited += 1,
+            _ => {}
+        }
+        // Refused clients were never open
+        if opened.is_none() {
+            return;
+        }
+        if table.closed.len() == CLOSED_HISTORY {
+            table.closed.pop_front();
+        }
+        table.closed.push_back(ClosedConnection {
+            info: conn.clone(),
+            reason: reason.to_string(),
+            totals: *totals,
+        });
+    }
+}
+
+/// What the control socket serves from.
+pub struct Control {
+    pub proxy: ProxyHandle,
+    pub connections: Arc<ConnectionTable>,
+    /// Where `reload` commands are sent. Without it they are refused.
+    pub reload: Option<async_channel::Sender<ReloadRequest>>,
+}
+
+impl Control {
+    /// Runs one command and returns its JSON answer.
+    pub async fn execute(&self, command: &str) -> Value {
+        let (name, arguments) = command.split_once(' ').unwrap_or((command, ""));
+        let answer = match name {
+            "kill" => parse_kill_target(arguments).map(|target| self.kill(target)),
+            "pause" => match parse_pause(arguments) {
+                Ok((addr, mode, drain)) => self.pause(addr, mode, drain).await,
+                Err(error) => Err(error),
+            },
+            "resume" => match arguments.trim().parse() {
+                Ok(addr) => self.resume(addr).await,
+                Err(_) => Err("usage: resume <address:port>".to_string()),
+            },
+            "unban" => self.unban(arguments.trim()),
+            _ => return self.query(command).await,
+        };
+        answer.unwrap_or_else(|error| json!({ "error": error }))
+    }
+
+    /// Runs a command without arguments.
+    async fn query(&self, command: &str) -> Value {
+        match command {
+            "rules" => json!({ "rules": self.rules() }),
+            "connections" => json!({ "connections": self.connections() }),
+            "closed" => json!({ "closed": self.closed() }),
+            "bans" => json!({ "bans": self.bans() }),
+            "counters" => {
+                let counters = self.connections.counters();
+                json!({
+                    "active": self.connections.open().len(),
+                    "accepted": counters.accepted,
+                    "denied": counters.denied,
+                    "limited": counters.limited,
+                    "failed": counters.failed,
+                    "bytes_in": counters.bytes_in,
+                    "bytes_out": counters.bytes_out,
+                    "packets_in": counters.packets_in,
+                    "packets_out": counters.packets_out,
+                })
+            }
+            "reload" => match self.reload().await {
+                Some(reloaded) => json!({ "reloaded": reloaded }),
+                None => json!({ "error": "reloading is not available" }),
+            },
+            "reopen" => match logging::reopen_log_file() {
+                Ok(Some(path)) => {
+                    logging::info("log_reopened", format!("Reopened log file {}", path.display()))
+                        .field("path", path.display().to_string())
+                        .log();
+                    json!({ "reopened": path })
+                }
+                Ok(None) => json!({ "error": "no log file is configured" }),
+                Err(e) => json!({ "error": format!("cannot reopen log file: {}", e) }),
+            },
+            "" => json!({ "error": "empty command" }),
+            _ => json!({ "error": format!("unknown command {}", command) }),
+        }
+    }
+
+    fn rules(&self) -> Vec<Value> {
+        self.proxy
+            .rules()
+            .iter()
+            .map(rule_json)
+            .collect()
+    }
+
+    fn connections(&self) -> Vec<Value> {
+        self.connections
+            .open()
+            .iter()
+            .map(|(info, totals)| {
+                let mut connection = totals_json(info, totals);
+                connection["age_secs"] = json!(totals.duration.as_secs_f64());
+                connection
+            })
+            .collect()
+    }
+
+    fn closed(&self) -> Vec<Value> {
+        self.connections
+            .closed()
+            .iter()
+            .map(|closed| {
+                let mut connection = totals_json(&closed.info, &closed.totals);
+                connection["reason"] = json!(closed.reason);
+                connection["duration_secs"] = json!(closed.totals.duration.as_secs_f64());
+                connection
+            })
+            .collect()
+    }
+
+    fn bans(&self) -> Vec<Value> {
+        let now = SystemTime::now();
+        self.proxy
+            .bans()
+            .bans()
+            .iter()
+            .map(|(client, until)| {
+                let expires_in = until.duration_since(now).unwrap_or_default();
+                json!({ "client": client.to_string(), "expires_in_secs": expires_in.as_secs_f64() })
+            })
+            .collect()
+    }
+
+    fn unban(&self, client: &str) -> Result<Value, String> {
+        let bans = self.proxy.bans();
+        let unbanned = match client {
+            "all" => bans.clear(),
+            _ => {
+                let client = client.parse().map_err(|_| "usage: unban <ip> | unban all".to_string())?;
+                if bans.unban(client) { vec![client] } else { Vec::new() }
+            }
+        };
+        let unbanned: Vec<_> = unbanned.iter().map(|client| client.to_string()).collect();
+        Ok(json!({ "unbanned": unbanned }))
+    }
+
+    fn kill(&self, target: KillTarget) -> Value {
+        let killed: Vec<_> = self.proxy.kill(target).iter().map(killed_json).collect();
+        json!({ "killed": killed })
+    }
+
+    async fn pause(&self, addr: SocketAddr, mode: PauseMode, drain: Option<Duration>) -> Result<Value, String> {
+        let paused = self.proxy.pause(addr, mode).await.ok_or("the proxy is shutting down")?;
+        if paused.is_empty() {
+            return Err(format!("no rule binds {}", addr));
+        }
+        let killed = match drain {
+            Some(timeout) => self.proxy.drain(addr, timeout).await,
+            None => Vec::new(),
+        };
+        Ok(json!({
+            "rules": paused.iter().map(rule_json).collect::<Vec<_>>(),
+            "killed": killed.iter().map(killed_json).collect::<Vec<_>>(),
+        }))
+    }
+
+    async fn resume(&self, addr: SocketAddr) -> Result<Value, String> {
+        let resumed = self.proxy.resume(addr).await.ok_or("the proxy is shutting down")?;
+        if resumed.is_empty() {
+            return Err(format!("no rule binds {}", addr));
+        }
+        Ok(json!({ "rules": resumed.iter().map(rule_json).collect::<Vec<_>>() }))
+    }
+
+    async fn reload(&self) -> Option<bool> {
+        let (reply_tx, reply_rx) = async_channel::bounded(1);
+        let request = ReloadRequest {
+            config: None,
+            reply: Some(reply_tx),
+        };
+        self.reload.as_ref()?.send(request).await.ok()?;
+        reply_rx.recv().await.ok()
+    }
+}
+
+fn rule_json(status: &RuleStatus) -> Value {
+    let state = match status.state {
+        RuleState::Listening => "listening",
+        RuleState::Paused(PauseMode::Close) => "paused",
+        RuleState::Paused(PauseMode::Refuse) => "refusing",
+        RuleState::Failed => "failed",
+    };
+    json!({
+        "rule": status.rule.to_string(),
+        "state": state,
+        "local_addr": status.local_addr.map(|addr| addr.to_string()),
+    })
+}
+
+/// A connection and what it transferred.
+fn totals_json(info: &ConnectionInfo, totals: &TransferTotals) -> Value {
+    let mut connection = killed_json(info);
+    connection["bytes_in"] = json!(totals.client_to_upstream);
+    connection["bytes_out"] = json!(totals.upstream_to_client);
+    connection["packets_in"] = json!(totals.client_to_upstream_packets);
+    connection["packets_out"] = json!(totals.upstream_to_client_packets);
+    connection
+}
+
+fn killed_json(info: &ConnectionInfo) -> Value {
+    json!({
+        "id": info.id,
+        "protocol": info.protocol.to_string(),
+        "client": info.client_addr.to_string(),
+        "local": info.local_addr.to_string(),
+        "backend": info.upstream,
+    })
+}
+
+/// Parses the arguments of a `pause` command.
+fn parse_pause(arguments: &str) -> Result<(SocketAddr, PauseMode, Option<Duration>), String> {
+    let usage = || "usage: pause <address:port> [refuse] [drain <seconds>]".to_string();
+    let mut words = arguments.split_whitespace();
+    let addr = words.next().and_then(|addr| addr.parse().ok()).ok_or_else(usage)?;
+    let mut mode = PauseMode::Close;
+    let mut drain = None;
+    while let Some(word) = words.next() {
+        match word {
+            "refuse" => mode = PauseMode::Refuse,
+            "drain" => {
+                let seconds = words.next().and_then(|seconds| seconds.parse().ok()).ok_or_else(usage)?;
+                drain = Some(Duration::from_secs(seconds));
+            }
+            _ => return Err(usage()),
+        }
+    }
+    Ok((addr, mode, drain))
+}
+
+/// Parses the arguments of a `kill` command.
+fn parse_kill_target(arguments: &str) -> Result<KillTarget, String> {
+    let mut words = arguments.split_whitespace();
+    let (kind, value) = match (words.next(), words.next(), words.next()) {
+        (Some(kind), Some(value), None) => (kind, value),
+        _ => return Err("usage: kill id <id> | kill client <ip> | kill rule <address:port>".to_string()),
+    };
+    match kind {
+        "id" => value.parse().map(KillTarget::Id).map_err(|_| format!("invalid connection id {}", value)),
+        "client" => value
+            .parse()
+            .map(KillTarget::Client)
+            .map_err(|_| format!("invalid client address {}", value)),
+        "rule" => value
+            .parse()
+            .map(KillTarget::Listener)
+            .map_err(|_| format!("invalid listener address {}", value)),
+        _ => Err(format!("cannot kill by {}", kind)),
+    }
+}
+
+/// Answers the commands sent to `listener`. Failed accepts are logged and
+/// retried after a short pause, so the socket stays up for later clients.
+#[cfg(unix)]
+pub async fn serve_control(listener: smol::net::unix::UnixListener, control: Arc<Control>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                logging::error("control_error", format!("Control socket accept error: {}", e))
+                    .field("error", e.to_string())
+                    .log();
+                smol::Timer::after(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        let control = control.clone();
+        smol::spawn(async move {
+            if let Err(e) = handle_command(stream, &control).await {
+                logging::warn("control_error", format!("Control socket error: {}", e))
+                    .field("error", e.to_string())
+                    .log();
+            }
+        })
+        .detach();
+    }
+}
+
+#[cfg(unix)]
+async fn handle_command(mut stream: smol::net::unix::UnixStream, control: &Control) -> std::io::Result<()> {
+    use smol::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    let mut command = String::new();
+    BufReader::new(&mut stream).take(1024).read_line(&mut command).await?;
+    let mut answer = control.execute(command.trim()).await.to_string();
+    answer.push('\n');
+    stream.write_all(answer.as_bytes()).await?;
+    stream.flush().await
+}
+
+/// Sends `command` to the control socket at `path` and returns the answer.
+#[cfg(unix)]
+pub fn request(path: &str, command: &str) -> std::io::Result<Value> {
+    use std::io::{BufRead, BufReader, Write};
+
+    let mut stream = std::os::unix::net::UnixStream::connect(path)?;
+    writeln!(stream, "{}", command)?;
+    let mut answer = String::new();
+    BufReader::new(stream).read_line(&mut answer)?;
+    Ok(serde_json::from_str(&answer)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, ForwardingRule, Protocol};
+    use crate::error::ProxyError;
+    use crate::limits::Limit;
+    use crate::observer::ObservedConnection;
+    use crate::observer::tests::conn;
+    use crate::proxy::Proxy;
+
+    #[test]
+    fn table_tracks_open_connections_and_counters() {
+        let table = Arc::new(ConnectionTable::new());
+        let open = ObservedConnection::accept(table.clone(), conn());
+        open.access(true);
+        open.transferred(Direction::ClientToUpstream, 5);
+        open.transferred(Direction::UpstreamToClient, 7);
+        ObservedConnection::accept(table.clone(), conn()).close(CloseReason::Error(ProxyError::Timeout));
+        ObservedConnection::accept(table.clone(), conn()).access(false);
+        ObservedConnection::accept(table.clone(), conn()).close(CloseReason::Limited(Limit::Client));
+
+        let connections = table.open();
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections[0].0.id, open.info().id);
+        assert_eq!(connections[0].1.client_to_upstream, 5);
+        assert_eq!(connections[0].1.upstream_to_client, 7);
+        assert_eq!(
+            table.counters(),
+            Counters {
+                accepted: 1,
+                denied: 1,
+                limited: 1,
+                failed: 1,
+                bytes_in: 5,
+                bytes_out: 7,
+                packets_in: 1,
+                packets_out: 1,
+            }
+        );
+
+        let id = open.info().id;
+        drop(open);
+        assert!(table.open().is_empty());
+        let closed = table.closed();
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].info.id, id);
+        assert_eq!(closed[0].reason, "closed");
+        assert_eq!(closed[0].totals.client_to_upstream_packets, 1);
+    }
+
+    #[test]
+    fn execute_answers_commands() {
+        smol::block_on(async {
+            let rule = ForwardingRule {
+                bind_address: "127.0.0.1".to_string(),
+                bind_port: 0,
+                connect_address: "127.0.0.1".to_string(),
+                connect_port: 9,
+                protocol: Protocol::Tcp,
+                timeout: None,
+                source_address: None,
+                rules: Vec::new(),
+                filters: Vec::new(),
+                enabled: true,
+                max_connections: None,
+                limit_action: None,
+                rate_limit: None,
+                bandwidth: None,
+            };
+            let proxy = Proxy::builder()
+                .config(Config {
+                    forwarding_rules: vec![rule],
+                    ..Config::default()
+                })
+                .start()
+                .await;
+            let control = Control {
+                proxy: proxy.handle(),
+                connections: Arc::new(ConnectionTable::new()),
+                reload: None,
+            };
+
+            let rules = control.execute("rules").await;
+            assert_eq!(rules["rules"][0]["state"], "listening");
+            assert_eq!(rules["rules"][0]["rule"], "tcp 127.0.0.1:0 -> 127.0.0.1:9");
+            assert_eq!(control.execute("connections").await, json!({ "connections": [] }));
+            assert_eq!(control.execute("closed").await, json!({ "closed": [] }));
+            assert_eq!(control.execute("counters").await["accepted"], 0);
+            assert!(control.execute("reload").await["error"].is_string());
+            assert_eq!(control.execute("reopen").await, json!({ "error": "no log file is configured" }));
+            assert_eq!(control.execute("kill id 0").await, json!({ "killed": [] }));
+            assert!(control.execute("kill everything").await["error"].is_string());
+            let addr = proxy.handle().local_addrs()[0].1;
+            let paused = control.execute(&format!("pause {} refuse", addr)).await;
+            assert_eq!(paused["rules"][0]["state"], "refusing");
+            assert_eq!(paused["killed"], json!([]));
+            let resumed = control.execute(&format!("resume {}", addr)).await;
+            assert_eq!(resumed["rules"][0]["state"], "listening");
+            assert_eq!(control.execute("pause 127.0.0.1:1").await, json!({ "error": "no rule binds 127.0.0.1:1" }));
+            assert!(control.execute("resume nowhere").await["error"].is_string());
+            assert_eq!(control.execute("bans").await, json!({ "bans": [] }));
+            assert_eq!(control.execute("unban 10.0.0.1").await, json!({ "unbanned": [] }));
+            assert_eq!(control.execute("unban all").await, json!({ "unbanned": [] }));
+            assert!(control.execute("unban someone").await["error"].is_string());
+            assert_eq!(control.execute("bogus").await, json!({ "error": "unknown command bogus" }));
+            proxy.shutdown().await;
+        });
+    }
+
+    #[test]
+    fn parse_kill_targets() {
+        assert_eq!(parse_kill_target("id 42"), Ok(KillTarget::Id(42)));
+        assert_eq!(
+            parse_kill_target("client 10.0.0.1"),
+            Ok(KillTarget::Client("10.0.0.1".parse().unwrap()))
+        );
+        assert_eq!(
+            parse_kill_target("rule 127.0.0.1:8080"),
+            Ok(KillTarget::Listener("127.0.0.1:8080".parse().unwrap()))
+        );
+        assert!(parse_kill_target("id x").is_err());
+        assert!(parse_kill_target("client 10.0.0.1 extra").is_err());
+        assert!(parse_kill_target("").is_err());
+        assert!(parse_kill_target("port 80").is_err());
+    }
+
+    #[test]
+    fn parse_pause_arguments() {
+        let addr: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        assert_eq!(parse_pause("127.0.0.1:8080"), Ok((addr, PauseMode::Close, None)));
+        assert_eq!(
+            parse_pause("127.0.0.1:8080 refuse drain 30"),
+            Ok((addr, PauseMode::Refuse, Some(Duration::from_secs(30))))
+        );
+        assert!(parse_pause("").is_err());
+        assert!(parse_pause("127.0.0.1:8080 drain").is_err());
+        assert!(parse_pause("127.0.0.1:8080 later").is_err());
+    }
+}
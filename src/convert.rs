@@ -0,0 +1,565 @@
+//! Conversion between the config formats, mainly the legacy rinetd `.conf`
+//! format and TOML.
+//!
+//! All formats load into the same `Config`, but converting through `Config`
+//! alone would lose the comments of the source file. The legacy/TOML
+//! converters walk the source line by line instead, carrying each comment
+//! block over to the entry that follows it. Conversions involving JSON or
+//! YAML go through `Config` and drop comments.
+
+use crate::config::{AccessRule, Config, ForwardingRule, LimitAction, LogFormat, Protocol, RuleType};
+use crate::config_parser::{parse_legacy_line, ConfigError, ConfigFormat, LegacyLine};
+use serde::Serialize;
+
+/// The converted config text plus everything that could not be carried over.
+#[derive(Debug)]
+pub struct Conversion {
+    pub output: String,
+    pub warnings: Vec<String>,
+}
+
+/// Converts `content` from one config format to another.
+pub fn convert(content: &str, from: ConfigFormat, to: ConfigFormat) -> Result<Conversion, ConfigError> {
+    match (from, to) {
+        _ if from == to => Err(ConfigError::ParseError(format!(
+            "cannot convert from {} to {}",
+            from, to
+        ))),
+        (ConfigFormat::Legacy, ConfigFormat::Toml) => legacy_to_toml(content),
+        (ConfigFormat::Toml, ConfigFormat::Legacy) => toml_to_legacy(content),
+        _ => {
+            let config = Config::parse_str(content, from)?;
+            let mut conversion = match to {
+                ConfigFormat::Legacy => config_to_legacy(&config, &[], &[]),
+                ConfigFormat::Toml => Conversion {
+                    output: to_toml(&config)?,
+                    warnings: Vec::new(),
+                },
+                ConfigFormat::Json => Conversion {
+                    output: format!("{}\n", serde_json::to_string_pretty(&config)?),
+                    warnings: Vec::new(),
+                },
+                ConfigFormat::Yaml => Conversion {
+                    output: serde_yaml::to_string(&config)?,
+                    warnings: Vec::new(),
+                },
+            };
+            if content.lines().any(|line| line.trim().starts_with('#')) {
+                conversion
+                    .warnings
+                    .insert(0, format!("comments are not carried over from {} to {}", from, to));
+            }
+            Ok(conversion)
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ForwardingEntry<'a> {
+    forwarding_rules: [&'a ForwardingRule; 1],
+}
+
+#[derive(Serialize)]
+struct GlobalEntry<'a> {
+    global_rules: [&'a AccessRule; 1],
+}
+
+#[derive(Serialize)]
+struct IncludeEntry<'a> {
+    include: &'a [String],
+}
+
+/// Converts a legacy `.conf` file to TOML. Comments directly above a rule
+/// line are emitted above the matching TOML table; lines the legacy loader
+/// would skip are kept as comments and reported as warnings. `include`
+/// lines become a single top-level `include` array, since TOML requires
+/// top-level keys to precede all tables. Without forward lines an empty
+/// `forwarding_rules` array is added there too, as the TOML format needs it.
+pub fn legacy_to_toml(content: &str) -> Result<Conversion, ConfigError> {
+    let mut output = String::new();
+    let mut warnings = Vec::new();
+    let mut comments: Vec<String> = Vec::new();
+    let mut includes = Vec::new();
+    let mut include_comments = Vec::new();
+    let mut forwards = false;
+
+    for (index, line) in content.lines().enumerate() {
+        let line_no = index + 1;
+        let trimmed = line.trim();
+        if trimmed.starts_with('#') {
+            comments.push(trimmed.to_string());
+            continue;
+        }
+
+        let parsed = parse_legacy_line(trimmed)
+            .map_err(|e| ConfigError::ParseError(format!("line {}: {}", line_no, e)))?;
+        let table = match parsed {
+            None => continue,
+            Some(LegacyLine::Forward(rule)) => {
+                forwards = true;
+                to_toml(&ForwardingEntry { forwarding_rules: [&*rule] })?
+            }
+            Some(LegacyLine::Access(rule)) => to_toml(&GlobalEntry { global_rules: [&rule] })?,
+            Some(LegacyLine::Include(pattern)) => {
+                includes.push(pattern);
+                include_comments.append(&mut comments);
+                continue;
+            }
+            Some(LegacyLine::Ignored) => {
+                warnings.push(format!(
+                    "line {}: `{}` is not a valid rule and was kept as a comment",
+                    line_no, trimmed
+                ));
+                comments.push(format!("# {}", trimmed));
+                continue;
+            }
+        };
+
+        push_block(&mut output, &comments, &table);
+        comments.clear();
+    }
+
+    if !comments.is_empty() {
+        push_block(&mut output, &comments, "");
+    }
+
+    if !includes.is_empty() || !forwards {
+        let mut header = String::new();
+        if !includes.is_empty() {
+            push_block(&mut header, &include_comments, &to_toml(&IncludeEntry { include: &includes })?);
+        }
+        if !forwards {
+            header.push_str("forwarding_rules = []\n");
+        }
+        if !output.is_empty() {
+            header.push('\n');
+        }
+        output.insert_str(0, &header);
+    }
+
+    Ok(Conversion { output, warnings })
+}
+
+/// Converts a TOML config to the legacy `.conf` format. Only plain TCP rules
+/// and global access rules exist in that format: everything else is either
+/// dropped or commented out, with a warning for each loss.
+pub fn toml_to_legacy(content: &str) -> Result<Conversion, ConfigError> {
+    let config: Config = toml::from_str(content)?;
+    let (global_comments, forwarding_comments) = header_comments(content);
+    Ok(config_to_legacy(&config, &global_comments, &forwarding_comments))
+}
+
+/// Writes `config` in the legacy format, placing the given comment blocks
+/// above the global and forwarding rules with the same index.
+fn config_to_legacy(
+    config: &Config,
+    global_comments: &[Vec<String>],
+    forwarding_comments: &[Vec<String>],
+) -> Conversion {
+    let mut output = String::new();
+    let mut warnings = Vec::new();
+
+    if let Some(log_file) = &config.log_file {
+        warnings.push(format!("log_file \"{}\" has no legacy equivalent and was dropped", log_file));
+    }
+    if let Some(pid_file) = &config.pid_file {
+        warnings.push(format!("pid_file \"{}\" has no legacy equivalent and was dropped", pid_file));
+    }
+    if let Some(drain_timeout) = config.drain_timeout {
+        warnings.push(format!("drain_timeout {} has no legacy equivalent and was dropped", drain_timeout));
+    }
+    if let Some(metrics_address) = &config.metrics_address {
+        warnings.push(format!(
+            "metrics_address \"{}\" has no legacy equivalent and was dropped",
+            metrics_address
+        ));
+    }
+    if let Some(control_socket) = &config.control_socket {
+        warnings.push(format!(
+            "control_socket \"{}\" has no legacy equivalent and was dropped",
+            control_socket
+        ));
+    }
+    if config.syslog.is_some() {
+        warnings.push("the syslog section has no legacy equivalent and was dropped".to_string());
+    }
+    if config.log_rotate.is_some() {
+        warnings.push("the log_rotate section has no legacy equivalent and was dropped".to_string());
+    }
+    if let Some(max_connections) = config.max_connections {
+        warnings.push(format!("max_connections {} has no legacy equivalent and was dropped", max_connections));
+    }
+    if let Some(max_connections_per_ip) = config.max_connections_per_ip {
+        warnings.push(format!(
+            "max_connections_per_ip {} has no legacy equivalent and was dropped",
+            max_connections_per_ip
+        ));
+    }
+    if config.limit_action != LimitAction::Refuse {
+        warnings.push("limit_action \"wait\" has no legacy equivalent and was dropped".to_string());
+    }
+    if config.auto_ban.is_some() {
+        warnings.push("the auto_ban section has no legacy equivalent and was dropped".to_string());
+    }
+    if let Some(log_level) = &config.log_level {
+        warnings.push(format!("log_level \"{}\" has no legacy equivalent and was dropped", log_level));
+    }
+    let log_format = match config.log_format {
+        LogFormat::Rinetd => None,
+        LogFormat::Common => Some("common"),
+        LogFormat::Json => Some("json"),
+    };
+    if let Some(log_format) = log_format {
+        warnings.push(format!("log_format \"{}\" has no legacy equivalent and was dropped", log_format));
+    }
+    if config.enforce_access_rules {
+        warnings.push(
+            "enforce_access_rules has no legacy equivalent and was dropped; the access rules will not be applied"
+                .to_string(),
+        );
+    }
+
+    for pattern in &config.include {
+        output.push_str(&format!("include {}\n", pattern));
+    }
+
+    for (index, rule) in config.global_rules.iter().enumerate() {
+        if let Some(file) = &rule.file {
+            warnings.push(format!("global rule {}: the IP list file \"{}\" has no legacy equivalent and was dropped", index + 1, file));
+            continue;
+        }
+        let comments = global_comments.get(index).map(Vec::as_slice).unwrap_or_default();
+        push_block(&mut output, comments, &format!("{}\n", legacy_access_line(rule)));
+    }
+
+    for (index, rule) in config.forwarding_rules.iter().enumerate() {
+        let name = format!(
+            "forwarding rule {} ({}:{})",
+            index + 1,
+            rule.bind_address,
+            rule.bind_port
+        );
+        let mut line = format!(
+            "{} {} {} {}",
+            rule.bind_address, rule.bind_port, rule.connect_address, rule.connect_port
+        );
+
+        if rule.protocol != Protocol::Tcp {
+            warnings.push(format!(
+                "{}: protocol \"{}\" is not supported by the legacy format; the rule was commented out",
+                name, rule.protocol
+            ));
+            line = format!("# {}", line);
+        }
+        if !rule.enabled {
+            warnings.push(format!(
+                "{}: disabled rules are not supported by the legacy format; the rule was commented out",
+                name
+            ));
+            if !line.starts_with('#') {
+                line = format!("# {}", line);
+            }
+        }
+        if let Some(timeout) = rule.timeout {
+            warnings.push(format!("{}: timeout {} was dropped", name, timeout));
+        }
+        if let Some(source_address) = &rule.source_address {
+            warnings.push(format!("{}: source_address \"{}\" was dropped", name, source_address));
+        }
+        if let Some(max_connections) = rule.max_connections {
+            warnings.push(format!("{}: max_connections {} was dropped", name, max_connections));
+        }
+        if rule.limit_action.is_some() {
+            warnings.push(format!("{}: limit_action was dropped", name));
+        }
+        if rule.rate_limit.is_some() {
+            warnings.push(format!("{}: rate_limit was dropped", name));
+        }
+        if rule.bandwidth.is_some() {
+            warnings.push(format!("{}: bandwidth was dropped", name));
+        }
+        if !rule.rules.is_empty() {
+            warnings.push(format!(
+                "{}: {} per-rule access rule(s) were dropped; legacy access rules are always global",
+                name,
+                rule.rules.len()
+            ));
+        }
+        if !rule.filters.is_empty() {
+            warnings.push(format!("{}: {} filter(s) were dropped", name, rule.filters.len()));
+        }
+
+        let comments = forwarding_comments.get(index).map(Vec::as_slice).unwrap_or_default();
+        push_block(&mut output, comments, &format!("{}\n", line));
+    }
+
+    Conversion { output, warnings }
+}
+
+fn to_toml<T: Serialize>(value: &T) -> Result<String, ConfigError> {
+    toml::to_string(value).map_err(|e| ConfigError::ParseError(e.to_string()))
+}
+
+/// Appends a comment block followed by `body`, separating it from the
+/// previous block with an empty line.
+fn push_block(output: &mut String, comments: &[String], body: &str) {
+    if !output.is_empty() {
+        output.push('\n');
+    }
+    for comment in comments {
+        output.push_str(comment);
+        output.push('\n');
+    }
+    output.push_str(body);
+}
+
+fn legacy_access_line(rule: &AccessRule) -> String {
+    let keyword = match rule.rule_type {
+        RuleType::Allow => "allow",
+        RuleType::Deny => "deny",
+    };
+    format!("{} {}", keyword, rule.pattern)
+}
+
+/// Collects the comment block directly above each `[[global_rules]]` and
+/// `[[forwarding_rules]]` header, in file order.
+fn header_comments(content: &str) -> (Vec<Vec<String>>, Vec<Vec<String>>) {
+    let mut global = Vec::new();
+    let mut forwarding = Vec::new();
+    let mut pending = Vec::new();
+
+    for line in content.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('#') {
+            pending.push(trimmed.to_string());
+        } else if trimmed == "[[global_rules]]" {
+            global.push(std::mem::take(&mut pending));
+        } else if trimmed == "[[forwarding_rules]]" {
+            forwarding.push(std::mem::take(&mut pending));
+        } else if !trimmed.is_empty() {
+            pending.clear();
+        }
+    }
+
+    (global, forwarding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_to_toml_round_trips_through_config() {
+        let conversion = legacy_to_toml(
+            "allow 192.168.1.*\ndeny 192.168.1.50\n0.0.0.0 80 127.0.0.1 8080\n",
+        )
+        .unwrap();
+        assert!(conversion.warnings.is_empty());
+
+        let config: Config = toml::from_str(&conversion.output).unwrap();
+        assert_eq!(config.global_rules.len(), 2);
+        assert_eq!(config.global_rules[1].rule_type, RuleType::Deny);
+        assert_eq!(config.global_rules[1].pattern, "192.168.1.50");
+        assert_eq!(config.forwarding_rules.len(), 1);
+        assert_eq!(config.forwarding_rules[0].bind_port, 80);
+        assert_eq!(config.forwarding_rules[0].connect_port, 8080);
+    }
+
+    #[test]
+    fn legacy_to_toml_keeps_comments_above_entries() {
+        let conversion = legacy_to_toml(
+            "# web frontend\n0.0.0.0 80 127.0.0.1 8080\n\n# trailing note\n",
+        )
+        .unwrap();
+        let comment = conversion.output.find("# web frontend").unwrap();
+        let table = conversion.output.find("[[forwarding_rules]]").unwrap();
+        assert!(comment < table);
+        assert!(conversion.output.trim_end().ends_with("# trailing note"));
+    }
+
+    #[test]
+    fn legacy_to_toml_warns_about_skipped_lines() {
+        let conversion = legacy_to_toml("0.0.0.0 80 127.0.0.1\n").unwrap();
+        assert_eq!(conversion.warnings.len(), 1);
+        assert!(conversion.warnings[0].contains("line 1"));
+        assert!(conversion.output.contains("# 0.0.0.0 80 127.0.0.1"));
+    }
+
+    #[test]
+    fn legacy_to_toml_reports_line_of_invalid_rule() {
+        let err = legacy_to_toml("# header\nforward 10.0.0.1\n").unwrap_err();
+        assert!(matches!(err, ConfigError::ParseError(msg) if msg.contains("line 2")));
+    }
+
+    #[test]
+    fn legacy_to_toml_hoists_includes() {
+        let conversion = legacy_to_toml(
+            "0.0.0.0 80 127.0.0.1 8080\n# per-service rules\ninclude conf.d/*.conf\n",
+        )
+        .unwrap();
+        assert!(conversion.output.starts_with("# per-service rules\ninclude = [\"conf.d/*.conf\"]\n"));
+
+        let config: Config = toml::from_str(&conversion.output).unwrap();
+        assert_eq!(config.include, vec!["conf.d/*.conf".to_string()]);
+        assert_eq!(config.forwarding_rules.len(), 1);
+    }
+
+    #[test]
+    fn legacy_to_toml_without_forward_lines_loads() {
+        let conversion = legacy_to_toml("include conf.d/*.conf\ndeny 10.0.0.1\n").unwrap();
+        assert!(conversion.output.starts_with("include = [\"conf.d/*.conf\"]\nforwarding_rules = []\n"));
+
+        let config: Config = toml::from_str(&conversion.output).unwrap();
+        assert_eq!(config.include, vec!["conf.d/*.conf".to_string()]);
+        assert_eq!(config.global_rules.len(), 1);
+        assert!(config.forwarding_rules.is_empty());
+
+        let config: Config = toml::from_str(&legacy_to_toml("# nothing yet\n").unwrap().output).unwrap();
+        assert!(config.forwarding_rules.is_empty());
+    }
+
+    #[test]
+    fn toml_to_legacy_keeps_includes() {
+        let conversion = toml_to_legacy("include = [\"conf.d/*.conf\"]\nforwarding_rules = []\n").unwrap();
+        assert_eq!(conversion.output, "include conf.d/*.conf\n");
+    }
+
+    #[test]
+    fn toml_to_legacy_plain_rules() {
+        let conversion = toml_to_legacy(
+            r#"
+# trusted network
+[[global_rules]]
+type = "allow"
+pattern = "10.0.0.0/8"
+
+# web frontend
+[[forwarding_rules]]
+bind_address = "0.0.0.0"
+bind_port = 80
+connect_address = "127.0.0.1"
+connect_port = 8080
+"#,
+        )
+        .unwrap();
+        assert!(conversion.warnings.is_empty());
+        assert_eq!(
+            conversion.output,
+            "# trusted network\nallow 10.0.0.0/8\n\n# web frontend\n0.0.0.0 80 127.0.0.1 8080\n"
+        );
+
+        let config = Config::parse_legacy_str(&conversion.output).unwrap();
+        assert_eq!(config.global_rules.len(), 1);
+        assert_eq!(config.forwarding_rules.len(), 1);
+    }
+
+    #[test]
+    fn toml_to_legacy_comments_out_non_tcp_rules() {
+        let conversion = toml_to_legacy(
+            r#"
+[[forwarding_rules]]
+bind_address = "0.0.0.0"
+bind_port = 53
+connect_address = "8.8.8.8"
+connect_port = 53
+protocol = "udp"
+timeout = 30
+"#,
+        )
+        .unwrap();
+        assert_eq!(conversion.output, "# 0.0.0.0 53 8.8.8.8 53\n");
+        assert_eq!(conversion.warnings.len(), 2);
+        assert!(conversion.warnings[0].contains("protocol \"udp\""));
+        assert!(conversion.warnings[1].contains("timeout 30"));
+    }
+
+    #[test]
+    fn toml_to_legacy_warns_about_dropped_settings() {
+        let conversion = toml_to_legacy(
+            r#"
+log_file = "/var/log/oi.log"
+pid_file = "/run/oi.pid"
+log_format = "common"
+
+[[forwarding_rules]]
+bind_address = "0.0.0.0"
+bind_port = 80
+connect_address = "127.0.0.1"
+connect_port = 8080
+source_address = "127.0.0.2"
+
+[[forwarding_rules.rules]]
+type = "deny"
+pattern = "10.0.0.1"
+"#,
+        )
+        .unwrap();
+        assert_eq!(conversion.output, "0.0.0.0 80 127.0.0.1 8080\n");
+        assert_eq!(conversion.warnings.len(), 5);
+    }
+
+    #[test]
+    fn toml_to_legacy_drops_list_file_rules() {
+        let conversion = toml_to_legacy(
+            r#"forwarding_rules = []
+
+[[global_rules]]
+type = "deny"
+file = "/etc/oi/blocklist.txt"
+
+[[global_rules]]
+type = "deny"
+pattern = "10.0.0.1"
+"#,
+        )
+        .unwrap();
+        assert_eq!(conversion.output, "deny 10.0.0.1\n");
+        assert_eq!(conversion.warnings.len(), 1);
+        assert!(conversion.warnings[0].contains("/etc/oi/blocklist.txt"));
+    }
+
+    #[test]
+    fn convert_json_to_toml() {
+        let conversion = convert(
+            r#"{"forwarding_rules": [{"bind_address": "0.0.0.0", "bind_port": 80,
+                "connect_address": "127.0.0.1", "connect_port": 8080, "protocol": "udp"}]}"#,
+            ConfigFormat::Json,
+            ConfigFormat::Toml,
+        )
+        .unwrap();
+        assert!(conversion.warnings.is_empty());
+        let config: Config = toml::from_str(&conversion.output).unwrap();
+        assert_eq!(config.forwarding_rules[0].protocol, Protocol::Udp);
+    }
+
+    #[test]
+    fn convert_legacy_to_yaml_warns_about_comments() {
+        let conversion = convert(
+            "# web\n0.0.0.0 80 127.0.0.1 8080\n",
+            ConfigFormat::Legacy,
+            ConfigFormat::Yaml,
+        )
+        .unwrap();
+        assert_eq!(conversion.warnings.len(), 1);
+        assert!(conversion.warnings[0].contains("comments"));
+        let config = Config::parse_str(&conversion.output, ConfigFormat::Yaml).unwrap();
+        assert_eq!(config.forwarding_rules[0].connect_port, 8080);
+    }
+
+    #[test]
+    fn convert_yaml_to_legacy() {
+        let conversion = convert(
+            "forwarding_rules:\n  - bind_address: 0.0.0.0\n    bind_port: 80\n    connect_address: 127.0.0.1\n    connect_port: 8080\n",
+            ConfigFormat::Yaml,
+            ConfigFormat::Legacy,
+        )
+        .unwrap();
+        assert_eq!(conversion.output, "0.0.0.0 80 127.0.0.1 8080\n");
+    }
+
+    #[test]
+    fn convert_rejects_same_format() {
+        let err = convert("", ConfigFormat::Toml, ConfigFormat::Toml).unwrap_err();
+        assert!(matches!(err, ConfigError::ParseError(_)));
+    }
+}
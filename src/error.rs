@@ -0,0 +1,123 @@
+use crate::config::Protocol;
+use std::fmt;
+use std::io;
+use std::net::{AddrParseError, SocketAddr};
+
+/// Errors of the TCP and UDP forwarding handlers.
+#[derive(Debug)]
+pub enum ProxyError {
+    /// The listening socket could not be bound.
+    Bind { addr: SocketAddr, error: io::Error },
+    /// A configured address is not a valid socket address.
+    InvalidAddress { addr: String, error: AddrParseError },
+    /// The backend could not be reached.
+    Connect { addr: String, error: io::Error },
+    /// The client is refused by the access rules.
+    AccessDenied(SocketAddr),
+    /// An operation did not finish in time.
+    Timeout,
+    /// A handler was asked to forward a protocol it does not handle.
+    ProtocolMismatch { handler: &'static str, protocol: Protocol },
+    /// A rule refers to a custom filter that was not registered.
+    UnknownFilter(String),
+    /// A filter closed the connection.
+    ClosedByFilter,
+    /// Any other socket error while forwarding.
+    Io(io::Error),
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyError::Bind { addr, error } => write!(f, "Cannot bind {}: {}", addr, error),
+            ProxyError::InvalidAddress { addr, error } => write!(f, "Invalid address {}: {}", addr, error),
+            ProxyError::Connect { addr, error } => write!(f, "Cannot connect to {}: {}", addr, error),
+            ProxyError::AccessDenied(addr) => write!(f, "Connection from {} denied", addr),
+            ProxyError::Timeout => write!(f, "Timed out"),
+            ProxyError::ProtocolMismatch { handler, protocol } => {
+                write!(f, "Invalid protocol for {} handler: {}", handler, protocol)
+            }
+            ProxyError::UnknownFilter(name) => write!(f, "Unknown filter {}", name),
+            ProxyError::ClosedByFilter => write!(f, "Closed by a filter"),
+            ProxyError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ProxyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProxyError::Bind { error, .. } | ProxyError::Connect { error, .. } | ProxyError::Io(error) => Some(error),
+            ProxyError::InvalidAddress { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ProxyError {
+    fn from(error: io::Error) -> Self {
+        if error.kind() == io::ErrorKind::TimedOut {
+            return ProxyError::Timeout;
+        }
+        ProxyError::Io(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn display_bind() {
+        let err = ProxyError::Bind {
+            addr: "127.0.0.1:80".parse().unwrap(),
+            error: io::Error::new(io::ErrorKind::AddrInUse, "in use"),
+        };
+        assert_eq!(err.to_string(), "Cannot bind 127.0.0.1:80: in use");
+        assert!(err.source().is_some());
+    }
+
+    #[test]
+    fn display_connect() {
+        let err = ProxyError::Connect {
+            addr: "10.0.0.1:80".to_string(),
+            error: io::Error::new(io::ErrorKind::ConnectionRefused, "refused"),
+        };
+        assert_eq!(err.to_string(), "Cannot connect to 10.0.0.1:80: refused");
+    }
+
+    #[test]
+    fn display_invalid_address() {
+        let error = "nope".parse::<SocketAddr>().unwrap_err();
+        let err = ProxyError::InvalidAddress { addr: "nope".to_string(), error };
+        assert!(err.to_string().starts_with("Invalid address nope: "));
+        assert!(err.source().is_some());
+    }
+
+    #[test]
+    fn display_access_denied() {
+        let err = ProxyError::AccessDenied("192.168.1.5:4000".parse().unwrap());
+        assert_eq!(err.to_string(), "Connection from 192.168.1.5:4000 denied");
+        assert!(err.source().is_none());
+    }
+
+    #[test]
+    fn display_protocol_mismatch() {
+        let err = ProxyError::ProtocolMismatch { handler: "TCP", protocol: Protocol::Udp };
+        assert_eq!(err.to_string(), "Invalid protocol for TCP handler: udp");
+    }
+
+    #[test]
+    fn from_io_error() {
+        let err = ProxyError::from(io::Error::other("boom"));
+        assert!(matches!(err, ProxyError::Io(_)));
+        assert_eq!(err.to_string(), "I/O error: boom");
+    }
+
+    #[test]
+    fn from_timed_out_io_error() {
+        let err = ProxyError::from(io::Error::new(io::ErrorKind::TimedOut, "slow"));
+        assert!(matches!(err, ProxyError::Timeout));
+    }
+}
@@ -0,0 +1,353 @@
+//! Filters that see, and may change, the bytes forwarded by a rule.
+//!
+//! Each forwarding rule has a `FilterPipeline` built from its `filters`
+//! list. Every connection gets its own `FilterChain` with a fresh instance
+//! of each filter, which is handed every chunk of data in both directions.
+
+use crate::config::FilterConfig;
+use crate::error::ProxyError;
+use crate::logging;
+use crate::observer::{ConnectionInfo, Direction};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+/// What to do with a chunk of data after a filter has seen it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterAction {
+    /// Pass the (possibly modified) data on to the next filter.
+    Forward,
+    /// Discard the data.
+    Drop,
+    /// Close the connection.
+    Close,
+}
+
+/// The per-connection state of a filter.
+pub trait StreamFilter: Send {
+    /// Called with every chunk read in `direction`. `data` may be changed
+    /// in place, including to a different length.
+    fn filter(&mut self, conn: &ConnectionInfo, direction: Direction, data: &mut Vec<u8>) -> FilterAction;
+}
+
+/// Creates the `StreamFilter` of each new connection.
+pub trait FilterFactory: Send + Sync {
+    fn create(&self, conn: &ConnectionInfo) -> Box<dyn StreamFilter>;
+}
+
+impl<F> FilterFactory for F
+where
+    F: Fn(&ConnectionInfo) -> Box<dyn StreamFilter> + Send + Sync,
+{
+    fn create(&self, conn: &ConnectionInfo) -> Box<dyn StreamFilter> {
+        self(conn)
+    }
+}
+
+/// Filters registered in code, referenced from configs as
+/// `{ type = "custom", name = "..." }`.
+#[derive(Clone, Default)]
+pub struct FilterRegistry(HashMap<String, Arc<dyn FilterFactory>>);
+
+impl FilterRegistry {
+    pub fn register(&mut self, name: impl Into<String>, factory: Arc<dyn FilterFactory>) {
+        self.0.insert(name.into(), factory);
+    }
+
+    /// Builds the pipeline of a rule's `filters` list.
+    pub fn pipeline(&self, filters: &[FilterConfig]) -> Result<FilterPipeline, ProxyError> {
+        let factories = filters
+            .iter()
+            .map(|filter| match filter {
+                FilterConfig::ByteCount => Ok(Arc::new(|conn: &ConnectionInfo| {
+                    Box::new(ByteCount::new(conn.client_addr)) as Box<dyn StreamFilter>
+                }) as Arc<dyn FilterFactory>),
+                FilterConfig::HexDump => Ok(Arc::new(|_: &ConnectionInfo| {
+                    Box::new(HexDump) as Box<dyn StreamFilter>
+                }) as Arc<dyn FilterFactory>),
+                FilterConfig::SizeLimit { max_bytes } => {
+                    let max_bytes = *max_bytes;
+                    Ok(Arc::new(move |_: &ConnectionInfo| {
+                        Box::new(SizeLimit::new(max_bytes)) as Box<dyn StreamFilter>
+                    }) as Arc<dyn FilterFactory>)
+                }
+                FilterConfig::Custom { name } => {
+                    self.0.get(name).cloned().ok_or_else(|| ProxyError::UnknownFilter(name.clone()))
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(FilterPipeline(Arc::new(factories)))
+    }
+}
+
+/// The filters of a rule. Empty by default, which forwards everything
+/// unchanged.
+#[derive(Clone, Default)]
+pub struct FilterPipeline(Arc<Vec<Arc<dyn FilterFactory>>>);
+
+impl FilterPipeline {
+    /// Creates the filters of a new connection.
+    pub fn start(&self, conn: &ConnectionInfo) -> FilterChain {
+        FilterChain {
+            conn: conn.clone(),
+            empty: self.0.is_empty(),
+            filters: Mutex::new(self.0.iter().map(|factory| factory.create(conn)).collect()),
+        }
+    }
+}
+
+/// The filters of one connection, applied in order.
+pub struct FilterChain {
+    conn: ConnectionInfo,
+    empty: bool,
+    filters: Mutex<Vec<Box<dyn StreamFilter>>>,
+}
+
+impl FilterChain {
+    pub fn is_empty(&self) -> bool {
+        self.empty
+    }
+
+    /// Runs `data` through every filter until one drops it or closes the
+    /// connection.
+    pub fn apply(&self, direction: Direction, data: &mut Vec<u8>) -> FilterAction {
+        for filter in self.filters.lock().unwrap().iter_mut() {
+            match filter.filter(&self.conn, direction, data) {
+                FilterAction::Forward => {}
+                action => return action,
+            }
+        }
+        FilterAction::Forward
+    }
+
+    /// Runs a chunk read in `direction` through the chain. Returns the bytes
+    /// to forward, `None` if a filter dropped them, or `ClosedByFilter` if a
+    /// filter closed the connection.
+    pub fn process<'a>(&self, direction: Direction, data: &'a [u8]) -> Result<Option<Cow<'a, [u8]>>, ProxyError> {
+        if self.empty {
+            return Ok(Some(Cow::Borrowed(data)));
+        }
+        let mut data = data.to_vec();
+        match self.apply(direction, &mut data) {
+            FilterAction::Forward => Ok(Some(Cow::Owned(data))),
+            FilterAction::Drop => Ok(None),
+            FilterAction::Close => Err(ProxyError::ClosedByFilter),
+        }
+    }
+}
+
+/// Prints the bytes a connection carried in each direction when it closes.
+pub struct ByteCount {
+    client: SocketAddr,
+    client_to_upstream: u64,
+    upstream_to_client: u64,
+}
+
+impl ByteCount {
+    pub fn new(client: SocketAddr) -> Self {
+        ByteCount {
+            client,
+            client_to_upstream: 0,
+            upstream_to_client: 0,
+        }
+    }
+}
+
+impl StreamFilter for ByteCount {
+    fn filter(&mut self, _conn: &ConnectionInfo, direction: Direction, data: &mut Vec<u8>) -> FilterAction {
+        match direction {
+            Direction::ClientToUpstream => self.client_to_upstream += data.len() as u64,
+            Direction::UpstreamToClient => self.upstream_to_client += data.len() as u64,
+        }
+        FilterAction::Forward
+    }
+}
+
+impl Drop for ByteCount {
+    fn drop(&mut self) {
+        let message = format!(
+            "Connection from {} sent {} bytes and received {} bytes",
+            self.client, self.client_to_upstream, self.upstream_to_client
+        );
+        logging::info("byte_count", message)
+            .field("client", self.client.to_string())
+            .field("bytes_in", self.client_to_upstream)
+            .field("bytes_out", self.upstream_to_client)
+            .log();
+    }
+}
+
+/// Prints every chunk as a hex dump.
+pub struct HexDump;
+
+impl StreamFilter for HexDump {
+    fn filter(&mut self, conn: &ConnectionInfo, direction: Direction, data: &mut Vec<u8>) -> FilterAction {
+        let arrow = match direction {
+            Direction::ClientToUpstream => "->",
+            Direction::UpstreamToClient => "<-",
+        };
+        let dump = hex_dump(data);
+        let message = format!("{} {} {} ({} bytes)\n{}", conn.client_addr, arrow, conn.upstream, data.len(), dump.trim_end());
+        logging::info("hex_dump", message)
+            .field("id", conn.id)
+            .field("bytes", data.len())
+            .log();
+        FilterAction::Forward
+    }
+}
+
+/// Formats `data` as lines of 16 bytes: offset, hex and printable ASCII.
+pub fn hex_dump(data: &[u8]) -> String {
+    let mut out = String::new();
+    for (index, chunk) in data.chunks(16).enumerate() {
+        let _ = write!(out, "{:08x} ", index * 16);
+        for column in 0..16 {
+            match chunk.get(column) {
+                Some(byte) => {
+                    let _ = write!(out, " {:02x}", byte);
+                }
+                None => out.push_str("   "),
+            }
+        }
+        out.push_str("  |");
+        out.extend(chunk.iter().map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' }));
+        out.push_str("|\n");
+    }
+    out
+}
+
+/// Closes connections that carry more than `max_bytes` in one direction.
+pub struct SizeLimit {
+    max_bytes: u64,
+    client_to_upstream: u64,
+    upstream_to_client: u64,
+}
+
+impl SizeLimit {
+    pub fn new(max_bytes: u64) -> Self {
+        SizeLimit {
+            max_bytes,
+            client_to_upstream: 0,
+            upstream_to_client: 0,
+        }
+    }
+}
+
+impl StreamFilter for SizeLimit {
+    fn filter(&mut self, conn: &ConnectionInfo, direction: Direction, data: &mut Vec<u8>) -> FilterAction {
+        let total = match direction {
+            Direction::ClientToUpstream => &mut self.client_to_upstream,
+            Direction::UpstreamToClient => &mut self.upstream_to_client,
+        };
+        *total += data.len() as u64;
+        if *total > self.max_bytes {
+            let message = format!("Connection from {} exceeded the limit of {} bytes", conn.client_addr, self.max_bytes);
+            logging::info("size_limit_exceeded", message)
+                .field("id", conn.id)
+                .field("client", conn.client_addr.to_string())
+                .field("max_bytes", self.max_bytes)
+                .log();
+            return FilterAction::Close;
+        }
+        FilterAction::Forward
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::observer::tests::conn;
+
+    struct Upper;
+
+    impl StreamFilter for Upper {
+        fn filter(&mut self, _conn: &ConnectionInfo, _direction: Direction, data: &mut Vec<u8>) -> FilterAction {
+            data.make_ascii_uppercase();
+            FilterAction::Forward
+        }
+    }
+
+    struct DropAll;
+
+    impl StreamFilter for DropAll {
+        fn filter(&mut self, _conn: &ConnectionInfo, _direction: Direction, _data: &mut Vec<u8>) -> FilterAction {
+            FilterAction::Drop
+        }
+    }
+
+    fn registry() -> FilterRegistry {
+        let mut registry = FilterRegistry::default();
+        registry.register("upper", Arc::new(|_: &ConnectionInfo| Box::new(Upper) as Box<dyn StreamFilter>));
+        registry.register("drop", Arc::new(|_: &ConnectionInfo| Box::new(DropAll) as Box<dyn StreamFilter>));
+        registry
+    }
+
+    fn custom(name: &str) -> FilterConfig {
+        FilterConfig::Custom { name: name.to_string() }
+    }
+
+    #[test]
+    fn empty_pipeline_forwards_unchanged() {
+        let chain = FilterPipeline::default().start(&conn());
+        let mut data = b"abc".to_vec();
+        assert!(chain.is_empty());
+        assert_eq!(chain.apply(Direction::ClientToUpstream, &mut data), FilterAction::Forward);
+        assert_eq!(data, b"abc");
+    }
+
+    #[test]
+    fn filters_run_in_order_until_one_drops() {
+        let pipeline = registry().pipeline(&[custom("upper"), custom("drop")]).unwrap();
+        let chain = pipeline.start(&conn());
+        let mut data = b"abc".to_vec();
+        assert_eq!(chain.apply(Direction::ClientToUpstream, &mut data), FilterAction::Drop);
+        assert_eq!(data, b"ABC");
+    }
+
+    #[test]
+    fn unknown_custom_filter_is_an_error() {
+        let err = registry().pipeline(&[custom("missing")]).err().unwrap();
+        assert!(matches!(err, ProxyError::UnknownFilter(name) if name == "missing"));
+    }
+
+    #[test]
+    fn process_borrows_without_filters_and_reports_close() {
+        let chain = FilterPipeline::default().start(&conn());
+        assert!(matches!(chain.process(Direction::ClientToUpstream, b"abc"), Ok(Some(Cow::Borrowed(b"abc")))));
+
+        let chain = registry().pipeline(&[custom("drop")]).unwrap().start(&conn());
+        assert!(matches!(chain.process(Direction::ClientToUpstream, b"abc"), Ok(None)));
+
+        let chain = registry().pipeline(&[FilterConfig::SizeLimit { max_bytes: 1 }]).unwrap().start(&conn());
+        assert!(matches!(chain.process(Direction::UpstreamToClient, b"abc"), Err(ProxyError::ClosedByFilter)));
+    }
+
+    #[test]
+    fn size_limit_closes_after_max_bytes_per_direction() {
+        let chain = registry().pipeline(&[FilterConfig::SizeLimit { max_bytes: 4 }]).unwrap().start(&conn());
+        assert_eq!(chain.apply(Direction::ClientToUpstream, &mut b"abc".to_vec()), FilterAction::Forward);
+        assert_eq!(chain.apply(Direction::UpstreamToClient, &mut b"abcd".to_vec()), FilterAction::Forward);
+        assert_eq!(chain.apply(Direction::ClientToUpstream, &mut b"de".to_vec()), FilterAction::Close);
+    }
+
+    #[test]
+    fn each_connection_gets_its_own_filter_state() {
+        let pipeline = registry().pipeline(&[FilterConfig::SizeLimit { max_bytes: 4 }]).unwrap();
+        let first = pipeline.start(&conn());
+        let second = pipeline.start(&conn());
+        assert_eq!(first.apply(Direction::ClientToUpstream, &mut b"abcd".to_vec()), FilterAction::Forward);
+        assert_eq!(second.apply(Direction::ClientToUpstream, &mut b"abcd".to_vec()), FilterAction::Forward);
+    }
+
+    #[test]
+    fn hex_dump_formats_offset_hex_and_ascii() {
+        let dump = hex_dump(b"hello, world!\x00\x01\x02\x03");
+        assert_eq!(
+            dump,
+            "00000000  68 65 6c 6c 6f 2c 20 77 6f 72 6c 64 21 00 01 02  |hello, world!...|\n\
+             00000010  03                                               |.|\n"
+        );
+    }
+}
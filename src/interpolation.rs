@@ -0,0 +1,191 @@
+//! `${VAR}` and `${VAR:-default}` expansion of config values.
+//!
+//! Expansion runs on the parsed value tree of a config file, before it is
+//! deserialized into `Config`, so every string value can reference the
+//! environment while comments and keys are left alone. `$${` produces a
+//! literal `${`.
+
+use crate::config_parser::ConfigError;
+
+/// Expands every variable reference in `input`, looking names up with
+/// `lookup`. A default after `:-` is used when the variable is unset or
+/// empty; an unset variable without a default is an error.
+pub fn expand_vars<F>(input: &str, lookup: F) -> Result<String, ConfigError>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+
+    while let Some(start) = rest.find('$') {
+        output.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+
+        if let Some(escaped) = after.strip_prefix("${") {
+            output.push_str("${");
+            rest = escaped;
+            continue;
+        }
+        if !after.starts_with('{') {
+            output.push('$');
+            rest = after;
+            continue;
+        }
+
+        let end = after.find('}').ok_or_else(|| {
+            ConfigError::ParseError(format!("Unterminated variable reference in \"{}\"", input))
+        })?;
+        let reference = &after[1..end];
+        let (name, default) = match reference.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (reference, None),
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(ConfigError::ParseError(format!(
+                "Invalid variable reference ${{{}}}",
+                reference
+            )));
+        }
+
+        match (lookup(name), default) {
+            (Some(value), Some(default)) if value.is_empty() => output.push_str(default),
+            (Some(value), _) => output.push_str(&value),
+            (None, Some(default)) => output.push_str(default),
+            (None, None) => return Err(ConfigError::UndefinedVariable(name.to_string())),
+        }
+        rest = &after[end + 1..];
+    }
+
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// Expands variables using the process environment.
+pub fn expand_env(input: &str) -> Result<String, ConfigError> {
+    expand_vars(input, |name| std::env::var(name).ok())
+}
+
+pub(crate) fn expand_toml(value: &mut toml::Value) -> Result<(), ConfigError> {
+    match value {
+        toml::Value::String(s) => *s = expand_env(s)?,
+        toml::Value::Array(items) => {
+            for item in items {
+                expand_toml(item)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (_, item) in table.iter_mut() {
+                expand_toml(item)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+pub(crate) fn expand_json(value: &mut serde_json::Value) -> Result<(), ConfigError> {
+    match value {
+        serde_json::Value::String(s) => *s = expand_env(s)?,
+        serde_json::Value::Array(items) => {
+            for item in items {
+                expand_json(item)?;
+            }
+        }
+        serde_json::Value::Object(map) => {
+            for (_, item) in map.iter_mut() {
+                expand_json(item)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+pub(crate) fn expand_yaml(value: &mut serde_yaml::Value) -> Result<(), ConfigError> {
+    match value {
+        serde_yaml::Value::String(s) => *s = expand_env(s)?,
+        serde_yaml::Value::Sequence(items) => {
+            for item in items {
+                expand_yaml(item)?;
+            }
+        }
+        serde_yaml::Value::Mapping(map) => {
+            for (_, item) in map.iter_mut() {
+                expand_yaml(item)?;
+            }
+        }
+        serde_yaml::Value::Tagged(tagged) => expand_yaml(&mut tagged.value)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Expands variables on every line of a legacy config except comments.
+pub(crate) fn expand_legacy(content: &str) -> Result<String, ConfigError> {
+    let mut output = String::with_capacity(content.len());
+    for line in content.lines() {
+        if line.trim_start().starts_with('#') {
+            output.push_str(line);
+        } else {
+            output.push_str(&expand_env(line)?);
+        }
+        output.push('\n');
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "BACKEND" => Some("10.0.0.5".to_string()),
+            "PORT" => Some("8080".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        }
+    }
+
+    #[test_case("plain text", "plain text")]
+    #[test_case("${BACKEND}", "10.0.0.5")]
+    #[test_case("${BACKEND}:${PORT}", "10.0.0.5:8080")]
+    #[test_case("${MISSING:-127.0.0.1}", "127.0.0.1")]
+    #[test_case("${BACKEND:-127.0.0.1}", "10.0.0.5")]
+    #[test_case("${EMPTY:-fallback}", "fallback")]
+    #[test_case("${EMPTY}", "")]
+    #[test_case("${MISSING:-}", "")]
+    #[test_case("cost: $5", "cost: $5")]
+    #[test_case("$${BACKEND}", "${BACKEND}")]
+    #[test_case("trailing $", "trailing $")]
+    fn expand_vars_cases(input: &str, expected: &str) {
+        assert_eq!(expand_vars(input, lookup).unwrap(), expected);
+    }
+
+    #[test]
+    fn expand_vars_undefined_without_default_errors() {
+        let err = expand_vars("${MISSING}", lookup).unwrap_err();
+        assert!(matches!(err, ConfigError::UndefinedVariable(name) if name == "MISSING"));
+    }
+
+    #[test]
+    fn expand_vars_unterminated_errors() {
+        let err = expand_vars("${BACKEND", lookup).unwrap_err();
+        assert!(matches!(err, ConfigError::ParseError(msg) if msg.contains("Unterminated")));
+    }
+
+    #[test_case("${}")]
+    #[test_case("${:-x}")]
+    #[test_case("${BAD-NAME}")]
+    fn expand_vars_invalid_reference_errors(input: &str) {
+        let err = expand_vars(input, lookup).unwrap_err();
+        assert!(matches!(err, ConfigError::ParseError(msg) if msg.contains("Invalid variable reference")));
+    }
+
+    #[test]
+    fn expand_legacy_skips_comments() {
+        let expanded = expand_legacy("# ${MISSING}\n0.0.0.0 80 ${OI_TEST_UNSET_HOST:-127.0.0.1} 8080\n").unwrap();
+        assert_eq!(expanded, "# ${MISSING}\n0.0.0.0 80 127.0.0.1 8080\n");
+    }
+}
@@ -0,0 +1,304 @@
+//! Lists of addresses and CIDR blocks read from files, for access rules
+//! with a `file`. The lists are read again when their file changes.
+//!
+//! A list file has one IPv4 or IPv6 address or CIDR block per line. `#`
+//! starts a comment, and blank lines are skipped, as are lines that hold no
+//! address, which are reported.
+
+use crate::logging;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+/// How often list files are checked for changes.
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A set of addresses and CIDR blocks. Lookups take one hash lookup per
+/// distinct prefix length in the list.
+#[derive(Debug, Clone, Default)]
+pub struct IpList {
+    /// The networks of every prefix length, masked to it.
+    v4: BTreeMap<u8, HashSet<u32>>,
+    v6: BTreeMap<u8, HashSet<u128>>,
+    len: usize,
+}
+
+impl IpList {
+    /// Parses the text of a list file. Returns the list and the lines that
+    /// hold no address or CIDR block, with their line numbers.
+    pub fn parse(text: &str) -> (IpList, Vec<(usize, String)>) {
+        let mut list = IpList::default();
+        let mut invalid = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let entry = line.split('#').next().unwrap_or_default().trim();
+            if entry.is_empty() {
+                continue;
+            }
+            if !list.insert(entry) {
+                invalid.push((index + 1, line.trim().to_string()));
+            }
+        }
+        (list, invalid)
+    }
+
+    /// Adds an address or CIDR block. Returns false if `entry` is neither.
+    fn insert(&mut self, entry: &str) -> bool {
+        let (address, prefix) = match entry.split_once('/') {
+            Some((address, prefix)) => match prefix.parse::<u8>() {
+                Ok(prefix) => (address, Some(prefix)),
+                Err(_) => return false,
+            },
+            None => (entry, None),
+        };
+        let inserted = match address.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => match prefix.unwrap_or(32) {
+                prefix @ 0..=32 => self.v4.entry(prefix).or_default().insert(mask_v4(ip.into(), prefix)),
+                _ => return false,
+            },
+            Ok(IpAddr::V6(ip)) => match prefix.unwrap_or(128) {
+                prefix @ 0..=128 => self.v6.entry(prefix).or_default().insert(mask_v6(ip.into(), prefix)),
+                _ => return false,
+            },
+            Err(_) => return false,
+        };
+        if inserted {
+            self.len += 1;
+        }
+        true
+    }
+
+    /// Whether `ip` is in one of the listed networks. IPv4 clients seen as
+    /// IPv4-mapped IPv6 addresses match the IPv4 entries.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => {
+                let bits = u32::from(ip);
+                self.v4.iter().any(|(prefix, networks)| networks.contains(&mask_v4(bits, *prefix)))
+            }
+            IpAddr::V6(ip) => {
+                let bits = u128::from(ip);
+                self.v6.iter().any(|(prefix, networks)| networks.contains(&mask_v6(bits, *prefix)))
+            }
+        }
+    }
+
+    /// The number of distinct entries.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+fn mask_v4(bits: u32, prefix: u8) -> u32 {
+    if prefix == 0 { 0 } else { bits & (u32::MAX << (32 - prefix)) }
+}
+
+fn mask_v6(bits: u128, prefix: u8) -> u128 {
+    if prefix == 0 { 0 } else { bits & (u128::MAX << (128 - prefix)) }
+}
+
+/// Modification time and size of a list file.
+type Stamp = (SystemTime, u64);
+
+fn stamp(path: &Path) -> Option<Stamp> {
+    let meta = fs::metadata(path).ok()?;
+    Some((meta.modified().unwrap_or(SystemTime::UNIX_EPOCH), meta.len()))
+}
+
+/// The list in one file, as last read.
+pub struct ListFile {
+    path: PathBuf,
+    list: RwLock<Arc<IpList>>,
+    read: Mutex<LastRead>,
+}
+
+#[derive(Default)]
+struct LastRead {
+    /// The file as it was read, `None` until it could be.
+    stamp: Option<Stamp>,
+    /// Whether the last attempt failed, so failures are reported once.
+    failed: bool,
+}
+
+impl ListFile {
+    fn open(path: &Path) -> Self {
+        let file = ListFile {
+            path: path.to_path_buf(),
+            list: RwLock::new(Arc::new(IpList::default())),
+            read: Mutex::default(),
+        };
+        file.refresh();
+        file
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.list.read().unwrap_or_else(|e| e.into_inner()).contains(ip)
+    }
+
+    /// Reads the file again if it changed since it was last read. If it
+    /// cannot be read, the list stays as it was.
+    fn refresh(&self) {
+        let current = stamp(&self.path);
+        let mut last = self.read.lock().unwrap();
+        if current.is_some() && current == last.stamp {
+            return;
+        }
+        let path = self.path.display().to_string();
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) => {
+                if !last.failed {
+                    list_error(format!("Cannot read IP list {}: {}", path, e), &path);
+                }
+                *last = LastRead { stamp: None, failed: true };
+                return;
+            }
+        };
+        let (list, invalid) = IpList::parse(&text);
+        if let Some((line, entry)) = invalid.first() {
+            list_error(
+                format!("Skipped {} invalid lines in IP list {}, the first is line {}: {}", invalid.len(), path, line, entry),
+                &path,
+            );
+        }
+        logging::info("ip_list_loaded", format!("Loaded {} entries from IP list {}", list.len(), path))
+            .field("path", path.as_str())
+            .field("entries", list.len())
+            .log();
+        *self.list.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(list);
+        *last = LastRead { stamp: current, failed: false };
+    }
+}
+
+impl fmt::Debug for ListFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ListFile").field("path", &self.path).finish_non_exhaustive()
+    }
+}
+
+fn list_error(message: String, path: &str) {
+    logging::warn("ip_list_error", message).field("path", path).log();
+}
+
+/// The list files in use, each read once however many access rules use it.
+/// Clones share the same files.
+#[derive(Debug, Clone, Default)]
+pub struct IpLists(Arc<Mutex<HashMap<PathBuf, Arc<ListFile>>>>);
+
+impl IpLists {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The list in `path`, read now unless it is already known.
+    pub fn get(&self, path: &Path) -> Arc<ListFile> {
+        let mut files = self.0.lock().unwrap();
+        files.entry(path.to_path_buf()).or_insert_with(|| Arc::new(ListFile::open(path))).clone()
+    }
+
+    /// Forgets the lists that no access rule uses any more.
+    pub fn retain_used(&self) {
+        self.0.lock().unwrap().retain(|_, file| Arc::strong_count(file) > 1);
+    }
+
+    /// Reads the lists whose files changed again.
+    pub fn refresh(&self) {
+        let files: Vec<_> = self.0.lock().unwrap().values().cloned().collect();
+        files.iter().for_each(|file| file.refresh());
+    }
+
+    /// Refreshes the lists every `interval`, without blocking the executor
+    /// while files are read.
+    pub async fn refresh_every(&self, interval: Duration) {
+        loop {
+            smol::Timer::after(interval).await;
+            let lists = self.clone();
+            smol::unblock(move || lists.refresh()).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn lists_match_addresses_and_cidr_blocks() {
+        let (list, invalid) = IpList::parse(
+            "# Published blocklist\n\
+             203.0.113.7\n\
+             198.51.100.0/24   # a whole network\n\
+             \n\
+             2001:db8::/32\n\
+             ::1\n\
+             198.51.100.9/24\n",
+        );
+        assert!(invalid.is_empty());
+        // The two /24 entries are the same network
+        assert_eq!(list.len(), 4);
+        assert!(list.contains(ip("203.0.113.7")));
+        assert!(!list.contains(ip("203.0.113.8")));
+        assert!(list.contains(ip("198.51.100.200")));
+        assert!(!list.contains(ip("198.51.101.1")));
+        assert!(list.contains(ip("2001:db8:1::5")));
+        assert!(!list.contains(ip("2001:db9::5")));
+        assert!(list.contains(ip("::1")));
+        assert!(list.contains(ip("::ffff:203.0.113.7")));
+    }
+
+    #[test]
+    fn zero_prefixes_match_everything() {
+        let (list, _) = IpList::parse("0.0.0.0/0\n");
+        assert!(list.contains(ip("192.0.2.1")));
+        assert!(!list.contains(ip("2001:db8::1")));
+        let (list, _) = IpList::parse("::/0\n");
+        assert!(list.contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn invalid_lines_are_reported() {
+        let (list, invalid) = IpList::parse("10.0.0.1\nexample.com\n10.0.0.0/33\n10.0.0.0/x\n192.168.1.*\n");
+        assert_eq!(list.len(), 1);
+        let lines: Vec<_> = invalid.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, [2, 3, 4, 5]);
+        assert_eq!(invalid[0].1, "example.com");
+    }
+
+    #[test]
+    fn list_files_are_read_again_when_they_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blocklist.txt");
+        fs::write(&path, "10.0.0.1\n").unwrap();
+        let lists = IpLists::new();
+        let file = lists.get(&path);
+        assert!(Arc::ptr_eq(&file, &lists.get(&path)));
+        assert!(file.contains(ip("10.0.0.1")));
+
+        fs::write(&path, "10.0.0.0/8\n10.0.0.2\n").unwrap();
+        lists.refresh();
+        assert!(file.contains(ip("10.1.2.3")));
+
+        // A missing file keeps the list as it was
+        fs::remove_file(&path).unwrap();
+        lists.refresh();
+        assert!(file.contains(ip("10.1.2.3")));
+        fs::write(&path, "192.0.2.1\n").unwrap();
+        lists.refresh();
+        assert!(!file.contains(ip("10.1.2.3")));
+
+        drop(file);
+        lists.retain_used();
+        assert!(lists.0.lock().unwrap().is_empty());
+    }
+}
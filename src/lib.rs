@@ -1,5 +1,24 @@
 pub mod access_control;
+pub mod bandwidth;
+pub mod bans;
 pub mod config;
 pub mod config_parser;
+pub mod connections;
+pub mod control;
+pub mod convert;
+pub mod error;
+pub mod filter;
+pub mod interpolation;
+pub mod ip_list;
+pub mod limits;
+pub mod logging;
+pub mod metrics;
+pub mod observer;
+pub mod proxy;
+pub mod rate_limit;
+pub mod server;
+pub mod syslog;
+pub mod target;
 pub mod tcp_handler;
 pub mod udp_handler;
+pub mod watch;
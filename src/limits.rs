@@ -0,0 +1,364 @@
+//! Caps on the number of open TCP connections and UDP sessions: over the
+//! whole server, per forwarding rule and per client IP address.
+
+use crate::bandwidth::{Bandwidth, Shaper};
+use crate::config::{BandwidthConfig, LimitAction, RateLimitConfig};
+use crate::rate_limit::RateLimiter;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// The limit a client ran into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    /// `max_connections` of the config.
+    Server,
+    /// `max_connections` of the forwarding rule.
+    Rule,
+    /// `max_connections_per_ip`.
+    Client,
+    /// The `rate` of the rule's `rate_limit`.
+    RuleRate,
+    /// The `per_ip_rate` of the rule's `rate_limit`.
+    ClientRate,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Limit::Server => "the server's connection limit",
+            Limit::Rule => "the rule's connection limit",
+            Limit::Client => "the per-client connection limit",
+            Limit::RuleRate => "the rule's connection rate limit",
+            Limit::ClientRate => "the per-client connection rate limit",
+        })
+    }
+}
+
+struct RuleCount {
+    max: Option<usize>,
+    action: LimitAction,
+    open: usize,
+    rate_limit: Option<(RateLimitConfig, RateLimiter)>,
+}
+
+struct State {
+    max_connections: Option<usize>,
+    max_per_client: Option<usize>,
+    open: usize,
+    per_client: HashMap<IpAddr, usize>,
+    /// Closed and replaced whenever a connection ends, to wake everyone
+    /// waiting for room.
+    freed_tx: async_channel::Sender<()>,
+    freed_rx: async_channel::Receiver<()>,
+}
+
+impl State {
+    /// The limit opening one more connection of `rule` for `client` would
+    /// exceed, if any. `None` for `client` only checks the server and the
+    /// rule.
+    fn exceeded(&self, rule: &RuleCount, client: Option<IpAddr>) -> Option<Limit> {
+        let full = |max: Option<usize>, open: usize| max.is_some_and(|max| open >= max);
+        if full(self.max_connections, self.open) {
+            Some(Limit::Server)
+        } else if full(rule.max, rule.open) {
+            Some(Limit::Rule)
+        } else if client.is_some_and(|ip| full(self.max_per_client, self.per_client.get(&ip).copied().unwrap_or(0))) {
+            Some(Limit::Client)
+        } else {
+            None
+        }
+    }
+}
+
+/// The server-wide limits and counts, shared by every listener. Clones
+/// share the same counts.
+#[derive(Clone)]
+pub struct ConnectionLimits {
+    state: Arc<Mutex<State>>,
+}
+
+impl ConnectionLimits {
+    pub fn new(max_connections: Option<usize>, max_per_client: Option<usize>) -> Self {
+        let (freed_tx, freed_rx) = async_channel::bounded(1);
+        ConnectionLimits {
+            state: Arc::new(Mutex::new(State {
+                max_connections,
+                max_per_client,
+                open: 0,
+                per_client: HashMap::new(),
+                freed_tx,
+                freed_rx,
+            })),
+        }
+    }
+
+    /// Replaces the limits. Connections already open over the new limits
+    /// stay open.
+    pub fn set(&self, max_connections: Option<usize>, max_per_client: Option<usize>) {
+        let mut state = self.state.lock().unwrap();
+        state.max_connections = max_connections;
+        state.max_per_client = max_per_client;
+        notify(&mut state);
+    }
+
+    /// The number of open connections over all rules.
+    pub fn open(&self) -> usize {
+        self.state.lock().unwrap().open
+    }
+
+    /// The limit of one forwarding rule, counted against these limits too.
+    pub fn rule(&self, max_connections: Option<usize>, action: LimitAction) -> RuleLimit {
+        RuleLimit {
+            state: self.state.clone(),
+            rule: Arc::new(Mutex::new(RuleCount {
+                max: max_connections,
+                action,
+                open: 0,
+                rate_limit: None,
+            })),
+            bandwidth: Bandwidth::default(),
+        }
+    }
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self::new(None, None)
+    }
+}
+
+fn notify(state: &mut State) {
+    state.freed_tx.close();
+    (state.freed_tx, state.freed_rx) = async_channel::bounded(1);
+}
+
+/// The limit of one forwarding rule. Clones share the same count.
+#[derive(Clone)]
+pub struct RuleLimit {
+    state: Arc<Mutex<State>>,
+    rule: Arc<Mutex<RuleCount>>,
+    bandwidth: Bandwidth,
+}
+
+impl RuleLimit {
+    /// Unlimited, and not counted against any server.
+    pub fn unlimited() -> Self {
+        ConnectionLimits::default().rule(None, LimitAction::Refuse)
+    }
+
+    /// Replaces the rule's limit and action. Connections already open over
+    /// the new limit stay open.
+    pub fn set(&self, max_connections: Option<usize>, action: LimitAction) {
+        let mut state = self.state.lock().unwrap();
+        let mut rule = self.rule.lock().unwrap();
+        rule.max = max_connections;
+        rule.action = action;
+        notify(&mut state);
+    }
+
+    pub fn action(&self) -> LimitAction {
+        self.rule.lock().unwrap().action
+    }
+
+    /// Replaces the rule's rate limit. The buckets are kept if it did not
+    /// change.
+    pub fn set_rate_limit(&self, config: Option<&RateLimitConfig>) {
+        let mut rule = self.rule.lock().unwrap();
+        if rule.rate_limit.as_ref().map(|(current, _)| current) != config {
+            rule.rate_limit = config.map(|config| (config.clone(), RateLimiter::new(config)));
+        }
+    }
+
+    /// Takes a token from the rule's rate limit for a new connection from
+    /// `client`. Refused clients are refused whatever the limit action.
+    pub fn check_rate(&self, client: IpAddr) -> Result<(), Limit> {
+        match &mut self.rule.lock().unwrap().rate_limit {
+            Some((_, limiter)) => limiter.check(client, Instant::now()),
+            None => Ok(()),
+        }
+    }
+
+    /// Replaces the rule's bandwidth limits, see `Bandwidth::set`.
+    pub fn set_bandwidth(&self, config: Option<&BandwidthConfig>) {
+        self.bandwidth.set(config);
+    }
+
+    /// The shaper of a new connection from `client`, for the rule's
+    /// bandwidth limits.
+    pub fn shaper(&self, client: IpAddr) -> Shaper {
+        self.bandwidth.shaper(client)
+    }
+
+    /// Opens a connection for `client`, unless that exceeds a limit.
+    pub fn try_acquire(&self, client: IpAddr) -> Result<Permit, Limit> {
+        self.acquire_or_wait(client).map_err(|(limit, _)| limit)
+    }
+
+    /// Opens a connection for `client`, waiting until no limit is exceeded.
+    pub async fn acquire(&self, client: IpAddr) -> Permit {
+        loop {
+            match self.acquire_or_wait(client) {
+                Ok(permit) => return permit,
+                Err((_, freed)) => {
+                    let _ = freed.recv().await;
+                }
+            }
+        }
+    }
+
+    /// Waits until neither the server nor the rule is full, or until the
+    /// rule's action is no longer `LimitAction::Wait`.
+    pub async fn room(&self) {
+        loop {
+            let freed = {
+                let state = self.state.lock().unwrap();
+                let rule = self.rule.lock().unwrap();
+                if rule.action != LimitAction::Wait || state.exceeded(&rule, None).is_none() {
+                    return;
+                }
+                state.freed_rx.clone()
+            };
+            let _ = freed.recv().await;
+        }
+    }
+
+    /// Opens a connection, or returns the limit in the way and a channel
+    /// that closes once a connection ends.
+    fn acquire_or_wait(&self, client: IpAddr) -> Result<Permit, (Limit, async_channel::Receiver<()>)> {
+        let mut state = self.state.lock().unwrap();
+        let mut rule = self.rule.lock().unwrap();
+        if let Some(limit) = state.exceeded(&rule, Some(client)) {
+            return Err((limit, state.freed_rx.clone()));
+        }
+        state.open += 1;
+        *state.per_client.entry(client).or_default() += 1;
+        rule.open += 1;
+        Ok(Permit {
+            limit: self.clone(),
+            client,
+        })
+    }
+}
+
+/// An open connection as counted by a `RuleLimit`, until it is dropped.
+pub struct Permit {
+    limit: RuleLimit,
+    client: IpAddr,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.limit.state.lock().unwrap();
+        state.open -= 1;
+        if let Some(count) = state.per_client.get_mut(&self.client) {
+            *count -= 1;
+            if *count == 0 {
+                state.per_client.remove(&self.client);
+            }
+        }
+        self.limit.rule.lock().unwrap().open -= 1;
+        notify(&mut state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::observer::tests::ip;
+    use futures_lite::future;
+    use std::time::Duration;
+
+    #[test]
+    fn limits_are_checked_in_order() {
+        let limits = ConnectionLimits::new(Some(3), Some(1));
+        let first = limits.rule(Some(2), LimitAction::Refuse);
+        let second = limits.rule(None, LimitAction::Refuse);
+
+        let _a = first.try_acquire(ip(1)).unwrap();
+        assert_eq!(first.try_acquire(ip(1)).err(), Some(Limit::Client));
+        let _b = first.try_acquire(ip(2)).unwrap();
+        assert_eq!(first.try_acquire(ip(3)).err(), Some(Limit::Rule));
+        let _c = second.try_acquire(ip(3)).unwrap();
+        assert_eq!(second.try_acquire(ip(4)).err(), Some(Limit::Server));
+        assert_eq!(limits.open(), 3);
+    }
+
+    #[test]
+    fn dropped_permits_free_their_slots() {
+        let limits = ConnectionLimits::new(None, Some(1));
+        let rule = limits.rule(Some(1), LimitAction::Refuse);
+        let permit = rule.try_acquire(ip(1)).unwrap();
+        assert!(rule.try_acquire(ip(2)).is_err());
+        drop(permit);
+        assert_eq!(limits.open(), 0);
+        drop(rule.try_acquire(ip(1)).unwrap());
+        assert!(RuleLimit::unlimited().try_acquire(ip(1)).is_ok());
+    }
+
+    #[test]
+    fn waiters_get_freed_slots() {
+        let limits = ConnectionLimits::new(None, Some(1));
+        let rule = limits.rule(None, LimitAction::Wait);
+        let permit = rule.try_acquire(ip(1)).unwrap();
+        let releaser = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            drop(permit);
+        });
+        let _second = smol::block_on(rule.acquire(ip(1)));
+        releaser.join().unwrap();
+        assert_eq!(limits.open(), 1);
+    }
+
+    #[test]
+    fn room_waits_for_the_rule_and_server_only() {
+        let limits = ConnectionLimits::new(Some(2), Some(1));
+        let rule = limits.rule(Some(1), LimitAction::Wait);
+        let other = limits.rule(None, LimitAction::Wait);
+        let _other = other.try_acquire(ip(1)).unwrap();
+        // Only the client's limit is reached, which room does not know about
+        smol::block_on(rule.room());
+
+        let _permit = rule.try_acquire(ip(2)).unwrap();
+        let has_room = smol::block_on(future::or(
+            async {
+                rule.room().await;
+                true
+            },
+            async {
+                smol::Timer::after(Duration::from_millis(50)).await;
+                false
+            },
+        ));
+        assert!(!has_room);
+        rule.set(Some(2), LimitAction::Wait);
+        // Now the server is full
+        assert_eq!(rule.try_acquire(ip(3)).err(), Some(Limit::Server));
+        limits.set(None, None);
+        smol::block_on(rule.room());
+
+        rule.set(Some(1), LimitAction::Refuse);
+        assert_eq!(rule.action(), LimitAction::Refuse);
+        smol::block_on(rule.room());
+    }
+
+    #[test]
+    fn rate_limits_survive_unchanged_reloads() {
+        let rule = RuleLimit::unlimited();
+        assert_eq!(rule.check_rate(ip(1)), Ok(()));
+        let config = RateLimitConfig {
+            rate: None,
+            burst: None,
+            per_ip_rate: Some(0.001),
+            per_ip_burst: None,
+        };
+        rule.set_rate_limit(Some(&config));
+        assert_eq!(rule.check_rate(ip(1)), Ok(()));
+        rule.set_rate_limit(Some(&config));
+        assert_eq!(rule.check_rate(ip(1)), Err(Limit::ClientRate));
+        rule.set_rate_limit(None);
+        assert_eq!(rule.check_rate(ip(1)), Ok(()));
+    }
+}
@@ -0,0 +1,698 @@
+//! Logging for `oi` and the library. Every message is an `Event`: a level,
+//! a name such as `connection_closed`, a human readable message and named
+//! fields. The global `Logger` writes events in the configured `LogFormat`,
+//! dropping those its `LevelFilter` does not let through.
+//!
+//! Until `set_logger` is called, events up to `Level::Info` are printed as
+//! plain messages: info and below to stdout, warnings and errors to stderr.
+
+use crate::config::{LogFormat, LogRotateConfig};
+use crate::syslog::{Syslog, SyslogWriter};
+use serde_json::{Map, Value};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+static LOGGER: RwLock<Option<Arc<Logger>>> = RwLock::new(None);
+
+/// How important an event is, most important first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+const LEVELS: [Level; 5] = [Level::Error, Level::Warn, Level::Info, Level::Debug, Level::Trace];
+
+impl Level {
+    /// The level `steps` more verbose than this one, or less verbose for
+    /// negative `steps`, clamped to `Error` and `Trace`.
+    pub fn adjusted(self, steps: i32) -> Level {
+        let index = LEVELS.iter().position(|level| *level == self).unwrap_or(0) as i32;
+        LEVELS[(index + steps).clamp(0, LEVELS.len() as i32 - 1) as usize]
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        })
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        LEVELS
+            .into_iter()
+            .find(|level| level.to_string().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown log level {}, expected error, warn, info, debug or trace", s))
+    }
+}
+
+/// Which events are logged: those up to a default level, with other levels
+/// for some modules. Written as `info` or `warn,udp_handler=debug`, where
+/// modules are named by their file, e.g. `server` or `main`. The events of
+/// a connection are filed under the module forwarding it, `tcp_handler` or
+/// `udp_handler`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LevelFilter {
+    pub default: Level,
+    pub modules: Vec<(String, Level)>,
+}
+
+impl Default for LevelFilter {
+    fn default() -> Self {
+        LevelFilter::new(Level::Info)
+    }
+}
+
+impl LevelFilter {
+    pub fn new(default: Level) -> Self {
+        LevelFilter {
+            default,
+            modules: Vec::new(),
+        }
+    }
+
+    /// The same filter with every level moved by `steps`, see
+    /// `Level::adjusted`.
+    pub fn adjusted(mut self, steps: i32) -> Self {
+        self.default = self.default.adjusted(steps);
+        for (_, level) in &mut self.modules {
+            *level = level.adjusted(steps);
+        }
+        self
+    }
+
+    /// The most verbose level logged for events of `module`.
+    pub fn level(&self, module: &str) -> Level {
+        self.modules
+            .iter()
+            .rev()
+            .find(|(name, _)| name == module)
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    pub fn allows(&self, event: &Event) -> bool {
+        event.level <= self.level(event.module)
+    }
+}
+
+impl FromStr for LevelFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filter = LevelFilter::default();
+        for directive in s.split(',').map(str::trim).filter(|directive| !directive.is_empty()) {
+            match directive.split_once('=') {
+                Some((module, level)) => filter.modules.push((module.trim().to_string(), level.trim().parse()?)),
+                None => filter.default = directive.parse()?,
+            }
+        }
+        Ok(filter)
+    }
+}
+
+impl fmt::Display for LevelFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.default)?;
+        for (module, level) in &self.modules {
+            write!(f, ",{}={}", module, level)?;
+        }
+        Ok(())
+    }
+}
+
+/// Something worth logging. Build one with `error`, `warn`, `info`,
+/// `debug` or `trace`, add fields and `log` it.
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub level: Level,
+    /// The module that logs the event, named by its file without the
+    /// extension, e.g. `tcp_handler`.
+    pub module: &'static str,
+    /// What happened, in snake case, e.g. `listener_started`.
+    pub name: &'static str,
+    pub message: String,
+    pub fields: Map<String, Value>,
+}
+
+#[track_caller]
+pub fn error(name: &'static str, message: impl Into<String>) -> Event {
+    Event::new(Level::Error, name, message)
+}
+
+#[track_caller]
+pub fn warn(name: &'static str, message: impl Into<String>) -> Event {
+    Event::new(Level::Warn, name, message)
+}
+
+#[track_caller]
+pub fn info(name: &'static str, message: impl Into<String>) -> Event {
+    Event::new(Level::Info, name, message)
+}
+
+#[track_caller]
+pub fn debug(name: &'static str, message: impl Into<String>) -> Event {
+    Event::new(Level::Debug, name, message)
+}
+
+#[track_caller]
+pub fn trace(name: &'static str, message: impl Into<String>) -> Event {
+    Event::new(Level::Trace, name, message)
+}
+
+impl Event {
+    /// An event of the module that calls this.
+    #[track_caller]
+    pub fn new(level: Level, name: &'static str, message: impl Into<String>) -> Self {
+        let file = std::panic::Location::caller().file();
+        let file_name = file.rsplit(['/', '\\']).next().unwrap_or(file);
+        Event {
+            level,
+            module: file_name.strip_suffix(".rs").unwrap_or(file_name),
+            name,
+            message: message.into(),
+            fields: Map::new(),
+        }
+    }
+
+    pub fn field(mut self, key: &str, value: impl Into<Value>) -> Self {
+        self.fields.insert(key.to_string(), value.into());
+        self
+    }
+
+    /// Writes the event with the global logger, unless its filter drops it.
+    pub fn log(self) {
+        let logger = LOGGER.read().unwrap().clone();
+        match logger {
+            Some(logger) => logger.log(&self),
+            None => Logger::default().log(&self),
+        }
+    }
+
+    /// The event as one line of JSON: an object with `time`, `level`,
+    /// `module`, `event`, `message` and the fields.
+    pub fn to_json(&self, time: SystemTime) -> String {
+        let mut object = Map::new();
+        object.insert("time".to_string(), Value::from(Timestamp::new(time).rfc3339()));
+        object.insert("level".to_string(), Value::from(self.level.to_string()));
+        object.insert("module".to_string(), Value::from(self.module));
+        object.insert("event".to_string(), Value::from(self.name));
+        object.insert("message".to_string(), Value::from(self.message.as_str()));
+        for (key, value) in &self.fields {
+            object.insert(key.clone(), value.clone());
+        }
+        Value::Object(object).to_string()
+    }
+
+    /// The line rinetd writes to its log file for a connection, in the
+    /// `rinetd` or `common` format. `None` for events that are not about
+    /// an ended or refused connection.
+    pub fn to_connection_line(&self, format: &LogFormat, time: SystemTime) -> Option<String> {
+        let text = |key: &str| self.fields.get(key).and_then(Value::as_str);
+        let count = |key: &str| self.fields.get(key).and_then(Value::as_u64).unwrap_or(0);
+        let reason = text("reason")?;
+        let (client, _) = split_addr(text("client")?);
+        let (bind_address, bind_port) = split_addr(text("local")?);
+        let (connect_address, connect_port) = split_addr(text("upstream")?);
+        let time = Timestamp::new(time);
+        let (bytes_in, bytes_out) = (count("bytes_in"), count("bytes_out"));
+        match format {
+            LogFormat::Common => Some(format!(
+                "{} - - [{} +0000] \"GET /rinetd-services/{}/{}/{}/{}/{} HTTP/1.0\" 200 {} - - - {}",
+                client,
+                time.clf(),
+                bind_address,
+                bind_port,
+                connect_address,
+                connect_port,
+                reason,
+                bytes_out,
+                bytes_in
+            )),
+            _ => Some(format!(
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                time.clf(),
+                client,
+                bind_address,
+                bind_port,
+                connect_address,
+                connect_port,
+                bytes_in,
+                bytes_out,
+                reason
+            )),
+        }
+    }
+}
+
+/// Splits `host:port`, dropping the brackets around IPv6 hosts.
+fn split_addr(addr: &str) -> (&str, &str) {
+    let (host, port) = addr.rsplit_once(':').unwrap_or((addr, ""));
+    (host.trim_start_matches('[').trim_end_matches(']'), port)
+}
+
+/// Writes events to the console and, if configured, a log file and syslog.
+///
+/// The console gets every event the filter allows: as plain messages for the
+/// `rinetd` and `common` formats, as JSON lines for `json`. The log file
+/// gets the same JSON lines for `json`; for `rinetd` and `common` it is a
+/// connection log in rinetd's format, with one line per closed or refused
+/// connection whatever the filter. Syslog gets what the log file gets, plus
+/// the plain messages of allowed warnings and errors.
+pub struct Logger {
+    format: LogFormat,
+    filter: LevelFilter,
+    file: Option<Mutex<LogFile>>,
+    rotate: Option<LogRotateConfig>,
+    syslog: Option<SyslogWriter>,
+}
+
+impl Default for Logger {
+    fn default() -> Self {
+        Logger::new(LogFormat::Rinetd)
+    }
+}
+
+impl Logger {
+    /// A logger writing to the console only, up to `Level::Info`.
+    pub fn new(format: LogFormat) -> Self {
+        Logger {
+            format,
+            filter: LevelFilter::default(),
+            file: None,
+            rotate: None,
+            syslog: None,
+        }
+    }
+
+    /// Only logs the events `filter` allows.
+    pub fn filter(mut self, filter: LevelFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Also appends to the file at `path`, creating it if needed.
+    pub fn log_file(mut self, path: &str) -> io::Result<Self> {
+        self.file = Some(Mutex::new(LogFile::open(path.into())?));
+        Ok(self)
+    }
+
+    /// Rotates the log file as `rotate` says.
+    pub fn log_rotate(mut self, rotate: LogRotateConfig) -> Self {
+        self.rotate = Some(rotate);
+        self
+    }
+
+    /// Closes and reopens the log file, for example after logrotate moved
+    /// it away. Returns its path, or `None` without a log file.
+    pub fn reopen(&self) -> io::Result<Option<PathBuf>> {
+        let Some(file) = &self.file else {
+            return Ok(None);
+        };
+        let mut file = file.lock().unwrap();
+        file.reopen()?;
+        Ok(Some(file.path.clone()))
+    }
+
+    /// Also sends to `syslog`, from a thread of its own. Messages are
+    /// dropped while the daemon is too slow to keep up.
+    pub fn syslog(mut self, syslog: Syslog) -> Self {
+        self.syslog = Some(SyslogWriter::spawn(syslog));
+        self
+    }
+
+    pub fn log(&self, event: &Event) {
+        let allowed = self.filter.allows(event);
+        let time = SystemTime::now();
+        let line = match self.format {
+            LogFormat::Json => event.to_json(time),
+            _ => event.message.clone(),
+        };
+        if allowed && event.level <= Level::Warn {
+            eprintln!("{}", line);
+        } else if allowed {
+            println!("{}", line);
+        }
+
+        if self.file.is_none() && self.syslog.is_none() {
+            return;
+        }
+        let line = match self.format {
+            LogFormat::Json => allowed.then_some(line),
+            _ => event.to_connection_line(&self.format, time),
+        };
+        // A failing log file or syslog daemon must not take the proxy down
+        if let (Some(file), Some(line)) = (&self.file, &line) {
+            let _ = file.lock().unwrap().write_line(line, self.rotate.as_ref());
+        }
+        let Some(syslog) = &self.syslog else {
+            return;
+        };
+        let (level, message) = match (&self.format, line) {
+            (LogFormat::Json, Some(line)) => (event.level, line),
+            (_, Some(connection_line)) => (Level::Info, connection_line),
+            (LogFormat::Json, None) => return,
+            (_, None) if allowed && event.level <= Level::Warn => (event.level, event.message.clone()),
+            (_, None) => return,
+        };
+        syslog.send(level, event.name, message, time);
+    }
+}
+
+/// Whether the global logger logs events of `module` at `level`, to skip
+/// building events it would drop.
+pub fn enabled(level: Level, module: &str) -> bool {
+    match LOGGER.read().unwrap().as_ref() {
+        Some(logger) => level <= logger.filter.level(module),
+        None => level <= LevelFilter::default().level(module),
+    }
+}
+
+/// Makes `logger` the global logger used by `Event::log`.
+pub fn set_logger(logger: Logger) {
+    *LOGGER.write().unwrap() = Some(Arc::new(logger));
+}
+
+/// Reopens the log file of the global logger, see `Logger::reopen`.
+pub fn reopen_log_file() -> io::Result<Option<PathBuf>> {
+    let logger = LOGGER.read().unwrap().clone();
+    logger.map_or(Ok(None), |logger| logger.reopen())
+}
+
+/// An open log file and what its rotation depends on.
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    opened: Instant,
+}
+
+impl LogFile {
+    fn open(path: PathBuf) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(LogFile {
+            size: file.metadata()?.len(),
+            path,
+            file,
+            opened: Instant::now(),
+        })
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        *self = LogFile::open(self.path.clone())?;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str, rotate: Option<&LogRotateConfig>) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if let Some(rotate) = rotate {
+            let too_big = rotate.max_bytes.is_some_and(|max| self.size > 0 && self.size + len > max);
+            let too_old = rotate.interval.is_some_and(|secs| self.opened.elapsed().as_secs() >= secs);
+            if too_big || too_old {
+                self.rotate(rotate.keep)?;
+            }
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// Moves `<path>.N` to `<path>.N+1` and the file itself to `<path>.1`,
+    /// dropping what would go past `<path>.<keep>`, and starts a new file.
+    fn rotate(&mut self, keep: u32) -> io::Result<()> {
+        if keep == 0 {
+            remove_if_exists(&self.path)?;
+        } else {
+            remove_if_exists(&rotated_path(&self.path, keep))?;
+            for n in (1..keep).rev() {
+                let from = rotated_path(&self.path, n);
+                if from.exists() {
+                    std::fs::rename(from, rotated_path(&self.path, n + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+        self.reopen()
+    }
+}
+
+fn rotated_path(path: &Path, n: u32) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", n));
+    rotated.into()
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// A point in time, broken down into UTC calendar fields.
+pub(crate) struct Timestamp {
+    year: i64,
+    month: u32,
+    day: u32,
+    seconds_of_day: u64,
+    millis: u32,
+}
+
+impl Timestamp {
+    pub(crate) fn new(time: SystemTime) -> Self {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = since_epoch.as_secs();
+        // Howard Hinnant's days-to-civil algorithm
+        let days = (secs / 86400) as i64 + 719_468;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days.rem_euclid(146_097);
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+        let month = if month_index < 10 { month_index + 3 } else { month_index - 9 } as u32;
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+        Timestamp {
+            year,
+            month,
+            day,
+            seconds_of_day: secs % 86400,
+            millis: since_epoch.subsec_millis(),
+        }
+    }
+
+    fn time_of_day(&self) -> String {
+        let secs = self.seconds_of_day;
+        format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    }
+
+    /// `2026-10-18T12:34:56.789Z`
+    pub(crate) fn rfc3339(&self) -> String {
+        format!(
+            "{:04}-{:02}-{:02}T{}.{:03}Z",
+            self.year,
+            self.month,
+            self.day,
+            self.time_of_day(),
+            self.millis
+        )
+    }
+
+    /// `18/Oct/2026:12:34:56`, as in rinetd and the common log format.
+    fn clf(&self) -> String {
+        format!(
+            "{:02}/{}/{:04}:{}",
+            self.day,
+            MONTHS[self.month as usize - 1],
+            self.year,
+            self.time_of_day()
+        )
+    }
+
+    /// `Oct 18 12:34:56`, as in RFC 3164 syslog headers.
+    pub(crate) fn rfc3164(&self) -> String {
+        format!("{} {:>2} {}", MONTHS[self.month as usize - 1], self.day, self.time_of_day())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // 2026-10-18T12:34:56.789Z
+    fn time() -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(1_792_326_896_789)
+    }
+
+    fn closed() -> Event {
+        info("connection_closed", "Connection 1 from 10.0.0.1:4000 finished")
+            .field("client", "10.0.0.1:4000")
+            .field("local", "127.0.0.1:8080")
+            .field("upstream", "10.0.0.2:80")
+            .field("reason", "finished")
+            .field("bytes_in", 5)
+            .field("bytes_out", 7)
+    }
+
+    #[test]
+    fn timestamps_are_utc_calendar_dates() {
+        let time = Timestamp::new(time());
+        assert_eq!(time.rfc3339(), "2026-10-18T12:34:56.789Z");
+        assert_eq!(time.clf(), "18/Oct/2026:12:34:56");
+        assert_eq!(Timestamp::new(UNIX_EPOCH).rfc3339(), "1970-01-01T00:00:00.000Z");
+        let leap_day = UNIX_EPOCH + Duration::from_secs(951_782_400);
+        assert_eq!(Timestamp::new(leap_day).rfc3339(), "2000-02-29T00:00:00.000Z");
+    }
+
+    #[test]
+    fn json_lines_carry_event_and_fields() {
+        let line: Value = serde_json::from_str(&closed().to_json(time())).unwrap();
+        assert_eq!(line["time"], "2026-10-18T12:34:56.789Z");
+        assert_eq!(line["level"], "info");
+        assert_eq!(line["event"], "connection_closed");
+        assert_eq!(line["message"], "Connection 1 from 10.0.0.1:4000 finished");
+        assert_eq!(line["client"], "10.0.0.1:4000");
+        assert_eq!(line["bytes_out"], 7);
+    }
+
+    #[test]
+    fn connection_lines_follow_rinetd() {
+        assert_eq!(
+            closed().to_connection_line(&LogFormat::Rinetd, time()).unwrap(),
+            "18/Oct/2026:12:34:56\t10.0.0.1\t127.0.0.1\t8080\t10.0.0.2\t80\t5\t7\tfinished"
+        );
+        assert_eq!(
+            closed().to_connection_line(&LogFormat::Common, time()).unwrap(),
+            "10.0.0.1 - - [18/Oct/2026:12:34:56 +0000] \
+             \"GET /rinetd-services/127.0.0.1/8080/10.0.0.2/80/finished HTTP/1.0\" 200 7 - - - 5"
+        );
+        assert!(info("startup", "Loaded 1 forwarding rules").to_connection_line(&LogFormat::Rinetd, time()).is_none());
+    }
+
+    #[test]
+    fn events_know_their_module() {
+        assert_eq!(info("startup", "").module, "logging");
+    }
+
+    #[test]
+    fn level_filters_parse_and_apply_per_module() {
+        let filter: LevelFilter = "warn, udp_handler=debug".parse().unwrap();
+        assert_eq!(filter.level("udp_handler"), Level::Debug);
+        assert_eq!(filter.level("tcp_handler"), Level::Warn);
+        assert_eq!(filter.to_string(), "warn,udp_handler=debug");
+        assert!(!filter.allows(&info("startup", "")));
+        assert!(LevelFilter::new(Level::Trace).allows(&trace("transfer", "")));
+        assert!("loud".parse::<LevelFilter>().is_err());
+        assert!("udp_handler=".parse::<LevelFilter>().is_err());
+    }
+
+    #[test]
+    fn levels_adjust_within_bounds() {
+        assert_eq!(Level::Info.adjusted(1), Level::Debug);
+        assert_eq!(Level::Info.adjusted(2), Level::Trace);
+        assert_eq!(Level::Info.adjusted(5), Level::Trace);
+        assert_eq!(Level::Info.adjusted(-1), Level::Warn);
+        assert_eq!(Level::Info.adjusted(-5), Level::Error);
+        let filter: LevelFilter = "info,server=warn".parse().unwrap();
+        assert_eq!(filter.adjusted(-1).to_string(), "warn,server=error");
+    }
+
+    #[test]
+    fn ipv6_addresses_lose_their_brackets() {
+        assert_eq!(split_addr("[::1]:8080"), ("::1", "8080"));
+        assert_eq!(split_addr("backend:80"), ("backend", "80"));
+    }
+
+    #[test]
+    fn log_file_gets_connection_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("oi.log");
+        let logger = Logger::new(LogFormat::Rinetd).log_file(path.to_str().unwrap()).unwrap();
+        logger.log(&info("startup", "Loaded 1 forwarding rules"));
+        logger.log(&closed());
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(content.lines().count(), 1);
+        assert!(content.ends_with("\t5\t7\tfinished\n"));
+
+        let logger = Logger::new(LogFormat::Json).log_file(path.to_str().unwrap()).unwrap();
+        logger.log(&info("startup", "Loaded 1 forwarding rules"));
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.lines().last().unwrap().contains("\"event\":\"startup\""));
+    }
+
+    #[test]
+    fn log_file_is_reopened_after_a_move() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("oi.log");
+        let logger = Logger::new(LogFormat::Rinetd).log_file(path.to_str().unwrap()).unwrap();
+        logger.log(&closed());
+        std::fs::rename(&path, dir.path().join("oi.log.old")).unwrap();
+        assert_eq!(logger.reopen().unwrap(), Some(path.clone()));
+        logger.log(&closed());
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+        assert_eq!(std::fs::read_to_string(dir.path().join("oi.log.old")).unwrap().lines().count(), 1);
+        assert_eq!(Logger::new(LogFormat::Rinetd).reopen().unwrap(), None);
+    }
+
+    #[test]
+    fn log_file_rotates_by_size_and_keeps_the_newest() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("oi.log");
+        let line_len = closed().to_connection_line(&LogFormat::Rinetd, time()).unwrap().len() as u64 + 1;
+        let rotate = LogRotateConfig {
+            max_bytes: Some(line_len * 2),
+            interval: None,
+            keep: 2,
+        };
+        let logger = Logger::new(LogFormat::Rinetd)
+            .log_file(path.to_str().unwrap())
+            .unwrap()
+            .log_rotate(rotate);
+        for _ in 0..7 {
+            logger.log(&closed());
+        }
+        let lines = |path: PathBuf| std::fs::read_to_string(path).unwrap().lines().count();
+        assert_eq!(lines(path.clone()), 1);
+        assert_eq!(lines(dir.path().join("oi.log.1")), 2);
+        assert_eq!(lines(dir.path().join("oi.log.2")), 2);
+        assert!(!dir.path().join("oi.log.3").exists());
+    }
+
+    #[test]
+    fn log_file_rotates_by_age() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("oi.log");
+        let rotate = LogRotateConfig {
+            max_bytes: None,
+            interval: Some(0),
+            keep: 0,
+        };
+        let logger = Logger::new(LogFormat::Rinetd)
+            .log_file(path.to_str().unwrap())
+            .unwrap()
+            .log_rotate(rotate);
+        logger.log(&closed());
+        logger.log(&closed());
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+        assert!(!dir.path().join("oi.log.1").exists());
+    }
+}
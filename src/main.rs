@@ -1,117 +1,454 @@
-use clap::Parser;
-use oxidinetd::config::{Config, Protocol};
+use clap::{ArgAction, Parser, Subcommand};
+use futures_lite::future;
+use oxidinetd::config::{Config, LogFormat, Protocol};
+use oxidinetd::config_parser::ConfigFormat;
+use oxidinetd::control::{ConnectionTable, Control, ReloadRequest};
+use oxidinetd::error::ProxyError;
+use oxidinetd::logging::{self, LevelFilter, Logger};
+use oxidinetd::metrics::{Metrics, serve_metrics};
+use oxidinetd::observer::LogObserver;
+use oxidinetd::proxy::{Proxy, ProxyHandle};
+use oxidinetd::syslog::Syslog;
+use oxidinetd::server::ListenerError;
+use oxidinetd::watch::ConfigWatcher;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Parser)]
-#[clap(name = "oxidinted", version = "0.1.0")]
+#[clap(name = "oxidinted", version = "0.1.0", subcommand_negates_reqs = true)]
 struct Args {
     /// Configuration file path
-    #[clap(short, long)]
-    config: String,
+    #[clap(short, long, required = true)]
+    config: Option<String>,
 
-    /// Verbose mode
-    #[clap(short, long)]
-    verbose: bool,
+    /// Configuration format (toml, conf, json or yaml); detected from the
+    /// file extension or content when omitted
+    #[clap(long)]
+    format: Option<ConfigFormat>,
+
+    /// Log more: -v adds debug messages, -vv also traces every transfer
+    #[clap(short, long, action = ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Log less: -q logs only warnings and errors, -qq only errors
+    #[clap(short, long, action = ArgAction::Count)]
+    quiet: u8,
+
+    /// Which messages to log, e.g. "debug" or "warn,udp_handler=debug";
+    /// overrides `log_level` in the configuration (default info). -v and -q
+    /// adjust every level of the filter
+    #[clap(long, value_name = "FILTER")]
+    log_level: Option<LevelFilter>,
+
+    /// Reload the configuration when it or any included file changes
+    #[clap(long)]
+    watch: bool,
+
+    /// How often --watch checks the configuration files, in milliseconds
+    #[clap(long, default_value_t = 1000, value_name = "MS")]
+    watch_interval: u64,
+
+    /// Seconds to let open connections finish on shutdown before closing
+    /// them; overrides `drain_timeout` in the configuration (default 5)
+    #[clap(long, value_name = "SECONDS")]
+    drain_timeout: Option<u64>,
+
+    /// Serve Prometheus metrics at http://ADDRESS/metrics; overrides
+    /// `metrics_address` in the configuration
+    #[clap(long, value_name = "ADDRESS")]
+    metrics_address: Option<String>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Convert a configuration file between the legacy .conf and TOML formats
+    Convert {
+        /// Configuration file to convert
+        input: String,
+
+        /// Write the converted configuration to this file instead of stdout
+        #[clap(short, long)]
+        output: Option<String>,
+
+        /// Input format; detected from the file extension or content when omitted
+        #[clap(long)]
+        from: Option<ConfigFormat>,
+
+        /// Target format (toml, conf, json or yaml); defaults to conf for TOML
+        /// input and to toml otherwise
+        #[clap(long)]
+        to: Option<ConfigFormat>,
+    },
+    /// Ask a running instance to reload its configuration (sends SIGHUP)
+    Reload {
+        /// Pid file of the running instance; defaults to `pid_file` from the
+        /// configuration given with --config
+        #[clap(long)]
+        pid_file: Option<String>,
+    },
+    /// Query or control a running instance through its control socket
+    Ctl {
+        #[clap(subcommand)]
+        command: CtlCommand,
+
+        /// Control socket of the running instance; defaults to
+        /// `control_socket` from the configuration given with --config
+        #[clap(long, global = true)]
+        socket: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum CtlCommand {
+    /// List the forwarding rules and the state of their listeners
+    Rules,
+    /// List open TCP connections and UDP sessions
+    Connections,
+    /// List recently closed connections with their totals
+    Closed,
+    /// Show the totals since startup
+    Counters,
+    /// Reload the configuration
+    Reload,
+    /// Reopen the log file, e.g. after logrotate moved it
+    Reopen,
+    /// Close open TCP connections and UDP sessions
+    #[clap(group(clap::ArgGroup::new("target").required(true)))]
+    Kill {
+        /// The connection with this id, as listed by `connections`
+        #[clap(long, group = "target")]
+        id: Option<u64>,
+
+        /// Every connection from this client IP address
+        #[clap(long, group = "target")]
+        client: Option<std::net::IpAddr>,
+
+        /// Every connection accepted by the listener bound to this address
+        #[clap(long, group = "target", value_name = "ADDRESS:PORT")]
+        rule: Option<std::net::SocketAddr>,
+    },
+    /// Stop forwarding for the rules bound to an address
+    Pause {
+        /// The bind address of the rules
+        #[clap(value_name = "ADDRESS:PORT")]
+        rule: std::net::SocketAddr,
+
+        /// Keep the port bound and refuse new clients instead of closing it
+        #[clap(long)]
+        refuse: bool,
+
+        /// Let open connections finish for up to this many seconds, then
+        /// close them; by default they are left running
+        #[clap(long, value_name = "SECONDS")]
+        drain: Option<u64>,
+    },
+    /// Resume paused or disabled rules bound to an address
+    Resume {
+        /// The bind address of the rules
+        #[clap(value_name = "ADDRESS:PORT")]
+        rule: std::net::SocketAddr,
+    },
+    /// List the clients banned by auto_ban
+    Bans,
+    /// Lift bans of clients
+    #[clap(group(clap::ArgGroup::new("target").required(true)))]
+    Unban {
+        /// The client IP address
+        #[clap(group = "target")]
+        client: Option<std::net::IpAddr>,
+
+        /// Lift every ban
+        #[clap(long, group = "target")]
+        all: bool,
+    },
+}
+
+impl CtlCommand {
+    /// The command line sent to the control socket.
+    fn request(&self) -> String {
+        match self {
+            CtlCommand::Rules => "rules".to_string(),
+            CtlCommand::Connections => "connections".to_string(),
+            CtlCommand::Closed => "closed".to_string(),
+            CtlCommand::Counters => "counters".to_string(),
+            CtlCommand::Reload => "reload".to_string(),
+            CtlCommand::Reopen => "reopen".to_string(),
+            CtlCommand::Kill { id: Some(id), .. } => format!("kill id {}", id),
+            CtlCommand::Kill { client: Some(client), .. } => format!("kill client {}", client),
+            CtlCommand::Kill { rule, .. } => format!("kill rule {}", rule.expect("clap requires a kill target")),
+            CtlCommand::Pause { rule, refuse, drain } => {
+                let mut request = format!("pause {}", rule);
+                if *refuse {
+                    request.push_str(" refuse");
+                }
+                if let Some(seconds) = drain {
+                    request.push_str(&format!(" drain {}", seconds));
+                }
+                request
+            }
+            CtlCommand::Resume { rule } => format!("resume {}", rule),
+            CtlCommand::Bans => "bans".to_string(),
+            CtlCommand::Unban { client: Some(client), .. } => format!("unban {}", client),
+            CtlCommand::Unban { .. } => "unban all".to_string(),
+        }
+    }
+}
+
+fn run_convert(
+    input: &str,
+    output: Option<&str>,
+    from: Option<ConfigFormat>,
+    to: Option<ConfigFormat>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let content = std::fs::read_to_string(input)?;
+    let from = from.unwrap_or_else(|| ConfigFormat::detect(input, &content));
+    let to = to.unwrap_or(match from {
+        ConfigFormat::Toml => ConfigFormat::Legacy,
+        _ => ConfigFormat::Toml,
+    });
+
+    let conversion = oxidinetd::convert::convert(&content, from, to)?;
+    for warning in &conversion.warnings {
+        eprintln!("Warning: {}", warning);
+    }
+
+    match output {
+        Some(path) => std::fs::write(path, conversion.output)?,
+        None => print!("{}", conversion.output),
+    }
+    Ok(())
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
 
-    if args.verbose {
-        println!("Loading configuration from {}", args.config);
+    match &args.command {
+        Some(Command::Convert { input, output, from, to }) => {
+            if let Err(e) = run_convert(input, output.as_deref(), *from, *to) {
+                eprintln!("Error converting config: {}", e);
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::Reload { pid_file }) => {
+            if let Err(e) = reload_pid_file(pid_file.as_deref(), &args).and_then(|path| run_reload(&path)) {
+                eprintln!("Error reloading: {}", e);
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::Ctl { command, socket }) => {
+            if let Err(e) = control_socket_path(socket.as_deref(), &args).and_then(|path| run_ctl(&path, command)) {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        None => {}
     }
 
+    let config_path = args.config.expect("--config is required without a subcommand");
+
+    // Until the config is loaded, log as the command line asks
+    let verbosity = i32::from(args.verbose) - i32::from(args.quiet);
+    let filter = args.log_level.clone().unwrap_or_default();
+    logging::set_logger(Logger::new(LogFormat::default()).filter(filter.clone().adjusted(verbosity)));
+    logging::debug("config_loading", format!("Loading configuration from {}", config_path))
+        .field("path", config_path.as_str())
+        .log();
+
     // Load configuration
-    let config = match Config::load_from_file(&args.config) {
-        Ok(config) => config,
+    let (config, sources) = match Config::load_with_sources(&config_path, args.format) {
+        Ok(loaded) => loaded,
         Err(e) => {
-            eprintln!("Error loading config: {:?}", e);
+            logging::error("config_error", format!("Error loading config: {:?}", e))
+                .field("path", config_path.as_str())
+                .field("error", e.to_string())
+                .log();
             std::process::exit(1);
         }
     };
 
-    println!("Loaded {} forwarding rules", config.forwarding_rules.len());
+    let filter = match (&args.log_level, &config.log_level) {
+        (None, Some(log_level)) => match log_level.parse::<LevelFilter>() {
+            Ok(filter) => filter,
+            Err(e) => {
+                logging::error("config_error", format!("Error loading config: invalid log_level: {}", e))
+                    .field("path", config_path.as_str())
+                    .field("error", e)
+                    .log();
+                std::process::exit(1);
+            }
+        },
+        _ => filter,
+    };
+    let mut logger = Logger::new(config.log_format.clone()).filter(filter.adjusted(verbosity));
+    if let Some(rotate) = &config.log_rotate {
+        logger = logger.log_rotate(rotate.clone());
+    }
+    if let Some(log_file) = config.log_file_path() {
+        logger = match logger.log_file(log_file) {
+            Ok(logger) => logger,
+            Err(e) => {
+                logging::error("log_file_error", format!("Cannot open log file {}: {}", log_file, e))
+                    .field("path", log_file)
+                    .field("error", e.to_string())
+                    .log();
+                std::process::exit(1);
+            }
+        };
+    }
+    if let Some(syslog) = config.syslog() {
+        let address = syslog.address.clone().unwrap_or_else(|| "/dev/log".to_string());
+        logger = match Syslog::connect(&syslog) {
+            Ok(syslog) => logger.syslog(syslog),
+            Err(e) => {
+                logging::error("syslog_error", format!("Cannot connect to syslog at {}: {}", address, e))
+                    .field("address", address)
+                    .field("error", e.to_string())
+                    .log();
+                std::process::exit(1);
+            }
+        };
+    }
+    logging::set_logger(logger);
 
-    // Run the async runtime
-    match smol::block_on(async {
-        // Create a vector to hold our tasks
-        let mut tasks = Vec::new();
+    logging::info("startup", format!("Loaded {} forwarding rules", config.forwarding_rules.len()))
+        .field("version", env!("CARGO_PKG_VERSION"))
+        .field("path", config_path.as_str())
+        .field("rules", config.forwarding_rules.len())
+        .log();
 
+    if let Some(pid_file) = &config.pid_file
+        && let Err(e) = std::fs::write(pid_file, format!("{}\n", std::process::id()))
+    {
+        logging::error("pid_file_error", format!("Error writing pid file {}: {}", pid_file, e))
+            .field("path", pid_file.as_str())
+            .field("error", e.to_string())
+            .log();
+    }
+
+    // Set once the control socket is ours to remove on exit
+    let mut control_started = false;
+
+    // Run the async runtime
+    let result = smol::block_on(async {
         // Set up signal handler for graceful shutdown
         let (shutdown_tx, shutdown_rx) = async_channel::bounded(1);
         let shutdown_tx_clone = shutdown_tx.clone();
 
         ctrlc::set_handler(move || {
-            println!("Received Ctrl+C, shutting down...");
+            logging::info("shutdown_requested", "Received Ctrl+C, shutting down...")
+                .field("signal", "SIGINT")
+                .log();
             let _ = shutdown_tx_clone.try_send(());
         })
         .expect("Error setting Ctrl+C handler");
 
+        let (reload_tx, reload_rx) = async_channel::bounded::<ReloadRequest>(1);
+        #[cfg(unix)]
+        spawn_signal_handler(reload_tx.clone(), shutdown_tx.clone())?;
+        let _watch_task = args.watch.then(|| {
+            let watcher = ConfigWatcher::new(&config_path, args.format, sources);
+            smol::spawn(watch_config(watcher, Duration::from_millis(args.watch_interval), reload_tx.clone()))
+        });
+
         // Start all forwarding rules
-        for rule in &config.forwarding_rules {
-            let bind_addr = format!("{}:{}", rule.bind_address, rule.bind_port);
-            let connect_addr = format!("{}:{}", rule.connect_address, rule.connect_port);
-
-            // Resolve bind address
-            let bind_socket_addr = match bind_addr.parse::<std::net::SocketAddr>() {
-                Ok(addr) => addr,
-                Err(e) => {
-                    eprintln!("Error parsing bind address {}: {}", bind_addr, e);
-                    continue;
-                }
+        let mut builder = Proxy::builder()
+            .config(config.clone())
+            .observer(Arc::new(LogObserver));
+        if let Some(secs) = args.drain_timeout {
+            builder = builder.drain_timeout(Duration::from_secs(secs));
+        }
+        let metrics = match args.metrics_address.as_ref().or(config.metrics_address.as_ref()) {
+            Some(address) => {
+                let metrics = Arc::new(Metrics::new());
+                let listener = smol::net::TcpListener::bind(address.as_str())
+                    .await
+                    .map_err(|e| format!("Cannot bind metrics address {}: {}", address, e))?;
+                let local_addr = listener.local_addr()?;
+                logging::info("metrics_started", format!("Serving metrics on http://{}/metrics", local_addr))
+                    .field("local", local_addr.to_string())
+                    .log();
+                smol::spawn(serve_metrics(listener, metrics.clone())).detach();
+                builder = builder.observer(metrics.clone());
+                Some(metrics)
+            }
+            None => None,
+        };
+        let connections = config.control_socket.as_ref().map(|_| Arc::new(ConnectionTable::new()));
+        if let Some(connections) = &connections {
+            builder = builder.observer(connections.clone());
+        }
+        let proxy = builder.start().await;
+        let handle = proxy.handle();
+        let _errors_task = smol::spawn(report_listener_errors(handle.errors()));
+        if let (Some(path), Some(connections)) = (&config.control_socket, connections) {
+            let control = Control {
+                proxy: handle.clone(),
+                connections,
+                reload: Some(reload_tx.clone()),
             };
+            start_control_socket(path, control)?;
+            control_started = true;
+        }
 
-            match rule.protocol {
-                Protocol::Tcp | Protocol::TcpToUdp => {
-                    println!(
-                        "Starting TCP forwarding from {} to {}",
-                        bind_addr, connect_addr
-                    );
-                    let connect_addr_clone = connect_addr.clone();
-                    let protocol_clone = rule.protocol.clone();
-
-                    let task = smol::spawn(async move {
-                        if let Err(e) =
-                            oxidinetd::tcp_handler::start_tcp_forwarding(bind_socket_addr, connect_addr_clone, protocol_clone)
-                                .await
-                        {
-                            eprintln!("TCP forwarding error: {}", e);
-                        }
-                    });
-
-                    tasks.push(task);
-                }
-                Protocol::Udp | Protocol::UdpToTcp => {
-                    println!(
-                        "Starting UDP forwarding from {} to {}",
-                        bind_addr, connect_addr
-                    );
-                    let connect_addr_clone = connect_addr.clone();
-                    let timeout = rule.timeout;
-                    let protocol_clone = rule.protocol.clone();
-
-                    let task = smol::spawn(async move {
-                        if let Err(e) =
-                            oxidinetd::udp_handler::start_udp_forwarding(bind_socket_addr, connect_addr_clone, timeout, protocol_clone)
-                                .await
-                        {
-                            eprintln!("UDP forwarding error: {}", e);
-                        }
-                    });
-
-                    tasks.push(task);
-                }
+        // Apply reloads until a shutdown is requested
+        while let Some(request) = future::or(async { reload_rx.recv().await.ok() }, async {
+            let _ = shutdown_rx.recv().await;
+            None
+        })
+        .await
+        {
+            let reloaded = reload_config(&handle, &config_path, args.format, request.config).await;
+            if let Some(metrics) = &metrics {
+                metrics.record_reload(reloaded);
+            }
+            if let Some(reply) = request.reply {
+                let _ = reply.try_send(reloaded);
             }
         }
+        drop(reload_tx);
 
-        // Wait for shutdown signal
-        let _ = shutdown_rx.recv().await;
-        println!("Shutting down...");
+        logging::info("shutdown", "Shutting down...").log();
+        handle.shutdown();
+        // A second signal skips the rest of the drain period
+        let _skip_drain = smol::spawn(async move {
+            if shutdown_rx.recv().await.is_ok() {
+                handle.shutdown();
+            }
+        });
+        let report = proxy.wait().await;
+        if report.tcp_connections > 0 || report.udp_sessions > 0 {
+            let message = format!(
+                "Closing {} TCP connections and {} UDP sessions still open after draining",
+                report.tcp_connections, report.udp_sessions
+            );
+            logging::info("drain_incomplete", message)
+                .field("tcp_connections", report.tcp_connections)
+                .field("udp_sessions", report.udp_sessions)
+                .log();
+        }
 
         Ok::<(), Box<dyn std::error::Error>>(())
-    }) {
-        Ok(_) => println!("Server shut down successfully"),
+    });
+
+    if let Some(pid_file) = &config.pid_file {
+        let _ = std::fs::remove_file(pid_file);
+    }
+    if control_started && let Some(control_socket) = &config.control_socket {
+        let _ = std::fs::remove_file(control_socket);
+    }
+
+    match result {
+        Ok(_) => logging::info("stopped", "Server shut down successfully").log(),
         Err(e) => {
-            eprintln!("Server error: {}", e);
+            logging::error("server_error", format!("Server error: {}", e))
+                .field("error", e.to_string())
+                .log();
             std::process::exit(1);
         }
     }
@@ -119,6 +456,266 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+/// Applies `config` to the running listeners, or else re-reads the config
+/// file first. A config that fails to load is rejected and the running one
+/// kept. Returns whether the new config was applied.
+async fn reload_config(
+    proxy: &ProxyHandle,
+    config_path: &str,
+    format: Option<ConfigFormat>,
+    config: Option<Config>,
+) -> bool {
+    logging::info("reload_started", format!("Reloading configuration from {}", config_path))
+        .field("path", config_path)
+        .log();
+    let config = match config.map_or_else(|| Config::load_from_file_as(config_path, format), Ok) {
+        Ok(config) => config,
+        Err(e) => {
+            reload_failed(&e.to_string());
+            return false;
+        }
+    };
+
+    let rule_count = config.forwarding_rules.len();
+    let Some(summary) = proxy.reload(config).await else {
+        return false;
+    };
+    let rule_events = [
+        (&summary.added, logging::Level::Info, "rule_added", "Added"),
+        (&summary.removed, logging::Level::Info, "rule_removed", "Removed"),
+        (&summary.changed, logging::Level::Info, "rule_changed", "Changed"),
+        (&summary.failed, logging::Level::Error, "rule_failed", "Failed to start"),
+    ];
+    for (rules, level, name, action) in rule_events {
+        for rule in rules {
+            logging::Event::new(level, name, format!("{} rule {}", action, rule))
+                .field("rule", rule.to_string())
+                .log();
+        }
+    }
+    let message = format!(
+        "Reloaded {} forwarding rules ({} added, {} removed, {} changed, {} unchanged)",
+        rule_count,
+        summary.added.len(),
+        summary.removed.len(),
+        summary.changed.len(),
+        summary.unchanged
+    );
+    logging::info("config_reloaded", message)
+        .field("rules", rule_count)
+        .field("added", summary.added.len())
+        .field("removed", summary.removed.len())
+        .field("changed", summary.changed.len())
+        .field("unchanged", summary.unchanged)
+        .log();
+    true
+}
+
+fn reload_failed(error: &str) {
+    logging::error("reload_failed", format!("Error reloading config, keeping the current one: {}", error))
+        .field("error", error)
+        .log();
+}
+
+/// Logs the errors of listeners that failed to start or stopped.
+async fn report_listener_errors(errors: async_channel::Receiver<ListenerError>) {
+    while let Ok(ListenerError { rule, error }) = errors.recv().await {
+        let message = match (&error, &rule.protocol) {
+            (ProxyError::InvalidAddress { addr, error }, _) => {
+                format!("Error parsing bind address {}: {}", addr, error)
+            }
+            (_, Protocol::Tcp | Protocol::TcpToUdp) => format!("TCP forwarding error: {}", error),
+            (_, Protocol::Udp | Protocol::UdpToTcp) => format!("UDP forwarding error: {}", error),
+        };
+        logging::error("listener_error", message)
+            .field("rule", rule.to_string())
+            .field("error", error.to_string())
+            .log();
+    }
+}
+
+/// Requests a reload of the new config whenever the watched files change
+/// and it is valid. Invalid configs are reported and otherwise ignored
+/// until the files change again.
+async fn watch_config(mut watcher: ConfigWatcher, interval: Duration, reload_tx: async_channel::Sender<ReloadRequest>) {
+    loop {
+        watcher.changed(interval).await;
+        match watcher.load() {
+            Ok(config) => {
+                logging::info("config_changed", "Configuration files changed").log();
+                let request = ReloadRequest {
+                    config: Some(config),
+                    reply: None,
+                };
+                let _ = reload_tx.send(request).await;
+            }
+            Err(e) => reload_failed(&e.to_string()),
+        }
+    }
+}
+
+/// Forwards SIGHUP to `reload_tx` and SIGTERM to `shutdown_tx`, and reopens
+/// the log file on SIGUSR1, from a dedicated thread. SIGINT is left to the
+/// Ctrl+C handler.
+#[cfg(unix)]
+fn spawn_signal_handler(
+    reload_tx: async_channel::Sender<ReloadRequest>,
+    shutdown_tx: async_channel::Sender<()>,
+) -> std::io::Result<()> {
+    use signal_hook::consts::{SIGHUP, SIGTERM, SIGUSR1};
+
+    let mut signals = signal_hook::iterator::Signals::new([SIGHUP, SIGTERM, SIGUSR1])?;
+    std::thread::spawn(move || {
+        for signal in signals.forever() {
+            match signal {
+                SIGTERM => {
+                    logging::info("shutdown_requested", "Received SIGTERM, shutting down...")
+                        .field("signal", "SIGTERM")
+                        .log();
+                    let _ = shutdown_tx.try_send(());
+                }
+                SIGUSR1 => reopen_log_file(),
+                // A reload that is already queued will pick up this change too.
+                _ => {
+                    let _ = reload_tx.try_send(ReloadRequest::default());
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Reopens the log file and logs the outcome.
+#[cfg(unix)]
+fn reopen_log_file() {
+    match logging::reopen_log_file() {
+        Ok(Some(path)) => logging::info("log_reopened", format!("Reopened log file {}", path.display()))
+            .field("path", path.display().to_string())
+            .log(),
+        Ok(None) => logging::debug("log_reopened", "Received SIGUSR1, but there is no log file to reopen").log(),
+        Err(e) => logging::error("log_file_error", format!("Cannot reopen log file: {}", e))
+            .field("error", e.to_string())
+            .log(),
+    }
+}
+
+/// The pid file named on the command line, or else the one configured in
+/// the --config file.
+fn reload_pid_file(pid_file: Option<&str>, args: &Args) -> Result<String, Box<dyn std::error::Error>> {
+    if let Some(path) = pid_file {
+        return Ok(path.to_string());
+    }
+    let config_path = args
+        .config
+        .as_deref()
+        .ok_or("either --pid-file or --config is required")?;
+    let config = Config::load_from_file_as(config_path, args.format)?;
+    config
+        .pid_file
+        .ok_or_else(|| format!("{} does not set pid_file", config_path).into())
+}
+
+/// Sends SIGHUP to the oi process whose pid is stored in `pid_file`.
+#[cfg(unix)]
+fn run_reload(pid_file: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let content = std::fs::read_to_string(pid_file)
+        .map_err(|e| format!("cannot read pid file {}: {}", pid_file, e))?;
+    let pid: libc::pid_t = content
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid pid in {}: {}", pid_file, content.trim()))?;
+    // SAFETY: kill has no memory-safety preconditions.
+    if unsafe { libc::kill(pid, libc::SIGHUP) } != 0 {
+        return Err(format!("cannot signal process {}: {}", pid, std::io::Error::last_os_error()).into());
+    }
+    println!("Sent reload signal to process {}", pid);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn run_reload(_pid_file: &str) -> Result<(), Box<dyn std::error::Error>> {
+    Err("reloading a running instance is only supported on Unix".into())
+}
+
+/// Binds the control socket at `path`, readable and writable by the owner
+/// only, and serves `control` on it. A socket left behind by an earlier run
+/// is replaced; a socket another instance still listens on, or any other
+/// file, is left alone and the proxy refuses to start.
+#[cfg(unix)]
+fn start_control_socket(path: &str, control: Control) -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.file_type().is_socket() => {
+            return Err(format!("Cannot bind control socket {}: the file exists and is not a socket", path).into());
+        }
+        Ok(_) => match std::os::unix::net::UnixStream::connect(path) {
+            Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => std::fs::remove_file(path)
+                .map_err(|e| format!("Cannot remove stale control socket {}: {}", path, e))?,
+            Ok(_) => {
+                return Err(format!("Cannot bind control socket {}: another instance is listening on it", path).into());
+            }
+            Err(e) => return Err(format!("Cannot bind control socket {}: {}", path, e).into()),
+        },
+        Err(_) => {}
+    }
+    // Created as 0600 from the start, so other users never get a window to
+    // connect before the permissions are narrowed
+    let umask = unsafe { libc::umask(0o177) };
+    let bound = smol::net::unix::UnixListener::bind(path);
+    unsafe { libc::umask(umask) };
+    let listener = bound.map_err(|e| format!("Cannot bind control socket {}: {}", path, e))?;
+    logging::info("control_started", format!("Control socket listening on {}", path))
+        .field("path", path)
+        .log();
+    smol::spawn(oxidinetd::control::serve_control(listener, Arc::new(control))).detach();
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn start_control_socket(path: &str, _control: Control) -> Result<(), Box<dyn std::error::Error>> {
+    logging::warn(
+        "control_unsupported",
+        format!("Ignoring control_socket {}: control sockets are only supported on Unix", path),
+    )
+    .field("path", path)
+    .log();
+    Ok(())
+}
+
+/// The control socket named on the command line, or else the one
+/// configured in the --config file.
+fn control_socket_path(socket: Option<&str>, args: &Args) -> Result<String, Box<dyn std::error::Error>> {
+    if let Some(path) = socket {
+        return Ok(path.to_string());
+    }
+    let config_path = args
+        .config
+        .as_deref()
+        .ok_or("either --socket or --config is required")?;
+    let config = Config::load_from_file_as(config_path, args.format)?;
+    config
+        .control_socket
+        .ok_or_else(|| format!("{} does not set control_socket", config_path).into())
+}
+
+/// Sends `command` to the control socket and prints the answer.
+#[cfg(unix)]
+fn run_ctl(socket: &str, command: &CtlCommand) -> Result<(), Box<dyn std::error::Error>> {
+    let answer = oxidinetd::control::request(socket, &command.request())
+        .map_err(|e| format!("cannot reach control socket {}: {}", socket, e))?;
+    if let Some(error) = answer["error"].as_str() {
+        return Err(error.into());
+    }
+    println!("{}", serde_json::to_string_pretty(&answer)?);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn run_ctl(_socket: &str, _command: &CtlCommand) -> Result<(), Box<dyn std::error::Error>> {
+    Err("control sockets are only supported on Unix".into())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -127,34 +724,44 @@ mod tests {
     #[test]
     fn args_parse_config_short() {
         let args = Args::parse_from(["oi", "-c", "proxy.toml"]);
-        assert_eq!(args.config, "proxy.toml");
-        assert!(!args.verbose);
+        assert_eq!(args.config.as_deref(), Some("proxy.toml"));
+        assert_eq!(args.verbose, 0);
     }
 
     #[test]
     fn args_parse_config_long() {
         let args = Args::parse_from(["oi", "--config", "proxy.toml"]);
-        assert_eq!(args.config, "proxy.toml");
-        assert!(!args.verbose);
+        assert_eq!(args.config.as_deref(), Some("proxy.toml"));
+        assert_eq!(args.verbose, 0);
     }
 
     #[test]
     fn args_parse_verbose_short() {
         let args = Args::parse_from(["oi", "-c", "proxy.toml", "-v"]);
-        assert!(args.verbose);
+        assert_eq!(args.verbose, 1);
     }
 
     #[test]
     fn args_parse_verbose_long() {
         let args = Args::parse_from(["oi", "-c", "proxy.toml", "--verbose"]);
-        assert!(args.verbose);
+        assert_eq!(args.verbose, 1);
     }
 
     #[test]
     fn args_parse_combined() {
         let args = Args::parse_from(["oi", "-c", "proxy.toml", "-v"]);
-        assert_eq!(args.config, "proxy.toml");
-        assert!(args.verbose);
+        assert_eq!(args.config.as_deref(), Some("proxy.toml"));
+        assert_eq!(args.verbose, 1);
+    }
+
+    #[test]
+    fn args_parse_verbosity_and_log_level() {
+        let args = Args::parse_from(["oi", "-c", "proxy.toml", "-vv", "--log-level", "warn,server=info"]);
+        assert_eq!(args.verbose, 2);
+        assert_eq!(args.log_level.unwrap().to_string(), "warn,server=info");
+        assert_eq!(Args::parse_from(["oi", "-c", "proxy.toml", "-qq"]).quiet, 2);
+        assert!(Args::try_parse_from(["oi", "-c", "proxy.toml", "-v", "-q"]).is_err());
+        assert!(Args::try_parse_from(["oi", "-c", "proxy.toml", "--log-level", "loud"]).is_err());
     }
 
     #[test]
@@ -174,4 +781,169 @@ mod tests {
         let err = Args::try_parse_from(["oi", "-c", "proxy.toml", "--bogus"]);
         assert!(err.is_err());
     }
+
+    #[test]
+    fn args_parse_convert_without_config() {
+        let args = Args::parse_from(["oi", "convert", "rinetd.conf"]);
+        assert!(args.config.is_none());
+        match args.command {
+            Some(Command::Convert { input, output, from, to }) => {
+                assert_eq!(input, "rinetd.conf");
+                assert!(output.is_none());
+                assert!(from.is_none());
+                assert!(to.is_none());
+            }
+            _ => panic!("expected convert subcommand"),
+        }
+    }
+
+    #[test]
+    fn args_parse_convert_with_target_and_output() {
+        let args = Args::parse_from(["oi", "convert", "proxy.toml", "--to", "conf", "-o", "out.conf"]);
+        match args.command {
+            Some(Command::Convert { output, to, .. }) => {
+                assert_eq!(output.as_deref(), Some("out.conf"));
+                assert_eq!(to, Some(ConfigFormat::Legacy));
+            }
+            _ => panic!("expected convert subcommand"),
+        }
+    }
+
+    #[test]
+    fn args_parse_format_override() {
+        let args = Args::parse_from(["oi", "-c", "oi.conf", "--format", "toml"]);
+        assert_eq!(args.format, Some(ConfigFormat::Toml));
+    }
+
+    #[test]
+    fn args_parse_format_defaults_to_detection() {
+        let args = Args::parse_from(["oi", "-c", "oi.conf"]);
+        assert!(args.format.is_none());
+    }
+
+    #[test]
+    fn args_parse_unknown_format_errors() {
+        let err = Args::try_parse_from(["oi", "-c", "oi.conf", "--format", "ini"]);
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn args_parse_convert_unknown_target_errors() {
+        let err = Args::try_parse_from(["oi", "convert", "proxy.toml", "--to", "xml"]);
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn args_parse_watch_defaults() {
+        let args = Args::parse_from(["oi", "-c", "proxy.toml"]);
+        assert!(!args.watch);
+        assert_eq!(args.watch_interval, 1000);
+    }
+
+    #[test]
+    fn args_parse_watch_with_interval() {
+        let args = Args::parse_from(["oi", "-c", "proxy.toml", "--watch", "--watch-interval", "200"]);
+        assert!(args.watch);
+        assert_eq!(args.watch_interval, 200);
+    }
+
+    #[test]
+    fn args_parse_drain_timeout() {
+        let args = Args::parse_from(["oi", "-c", "proxy.toml", "--drain-timeout", "30"]);
+        assert_eq!(args.drain_timeout, Some(30));
+        let args = Args::parse_from(["oi", "-c", "proxy.toml"]);
+        assert!(args.drain_timeout.is_none());
+    }
+
+    #[test]
+    fn args_parse_reload_with_pid_file() {
+        let args = Args::parse_from(["oi", "reload", "--pid-file", "/run/oi.pid"]);
+        match args.command {
+            Some(Command::Reload { pid_file }) => assert_eq!(pid_file.as_deref(), Some("/run/oi.pid")),
+            _ => panic!("expected reload subcommand"),
+        }
+    }
+
+    #[test]
+    fn reload_pid_file_prefers_command_line() {
+        let args = Args::parse_from(["oi", "reload", "--pid-file", "/run/oi.pid"]);
+        assert_eq!(reload_pid_file(Some("/run/oi.pid"), &args).unwrap(), "/run/oi.pid");
+    }
+
+    #[test]
+    fn reload_pid_file_without_pid_file_or_config_errors() {
+        let args = Args::parse_from(["oi", "reload"]);
+        assert!(reload_pid_file(None, &args).is_err());
+    }
+
+    #[test]
+    fn args_parse_ctl_with_socket() {
+        let args = Args::parse_from(["oi", "ctl", "connections", "--socket", "/run/oi.sock"]);
+        match args.command {
+            Some(Command::Ctl { command, socket }) => {
+                assert_eq!(command.request(), "connections");
+                assert_eq!(socket.as_deref(), Some("/run/oi.sock"));
+            }
+            _ => panic!("expected ctl subcommand"),
+        }
+    }
+
+    #[test]
+    fn args_parse_ctl_kill_targets() {
+        for (args, request) in [
+            (["oi", "ctl", "kill", "--id", "7"], "kill id 7"),
+            (["oi", "ctl", "kill", "--client", "10.0.0.1"], "kill client 10.0.0.1"),
+            (["oi", "ctl", "kill", "--rule", "127.0.0.1:8080"], "kill rule 127.0.0.1:8080"),
+        ] {
+            match Args::parse_from(args).command {
+                Some(Command::Ctl { command, .. }) => assert_eq!(command.request(), request),
+                _ => panic!("expected ctl subcommand"),
+            }
+        }
+    }
+
+    #[test]
+    fn args_parse_ctl_pause_and_resume() {
+        for (args, request) in [
+            (vec!["oi", "ctl", "pause", "127.0.0.1:8080"], "pause 127.0.0.1:8080"),
+            (
+                vec!["oi", "ctl", "pause", "127.0.0.1:8080", "--refuse", "--drain", "30"],
+                "pause 127.0.0.1:8080 refuse drain 30",
+            ),
+            (vec!["oi", "ctl", "resume", "127.0.0.1:8080"], "resume 127.0.0.1:8080"),
+        ] {
+            match Args::parse_from(args).command {
+                Some(Command::Ctl { command, .. }) => assert_eq!(command.request(), request),
+                _ => panic!("expected ctl subcommand"),
+            }
+        }
+    }
+
+    #[test]
+    fn args_parse_ctl_bans() {
+        for (args, request) in [
+            (vec!["oi", "ctl", "bans"], "bans"),
+            (vec!["oi", "ctl", "unban", "10.0.0.1"], "unban 10.0.0.1"),
+            (vec!["oi", "ctl", "unban", "--all"], "unban all"),
+        ] {
+            match Args::parse_from(args).command {
+                Some(Command::Ctl { command, .. }) => assert_eq!(command.request(), request),
+                _ => panic!("expected ctl subcommand"),
+            }
+        }
+        assert!(Args::try_parse_from(["oi", "ctl", "unban"]).is_err());
+        assert!(Args::try_parse_from(["oi", "ctl", "unban", "10.0.0.1", "--all"]).is_err());
+    }
+
+    #[test]
+    fn args_parse_ctl_kill_needs_one_target() {
+        assert!(Args::try_parse_from(["oi", "ctl", "kill"]).is_err());
+        assert!(Args::try_parse_from(["oi", "ctl", "kill", "--id", "7", "--client", "10.0.0.1"]).is_err());
+    }
+
+    #[test]
+    fn args_parse_ctl_unknown_command_errors() {
+        let err = Args::try_parse_from(["oi", "ctl", "bogus"]);
+        assert!(err.is_err());
+    }
 }
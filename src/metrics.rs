@@ -0,0 +1,461 @@
+//! Prometheus metrics: a `ConnectionObserver` that keeps per-rule counters,
+//! and a minimal HTTP endpoint that serves them in the text format.
+
+use crate::config::Protocol;
+use crate::error::ProxyError;
+use crate::logging;
+use crate::observer::{CloseReason, ConnectionInfo, ConnectionObserver, Direction, TransferTotals};
+use smol::io::{AsyncReadExt, AsyncWriteExt};
+use smol::net::{TcpListener, TcpStream};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+/// How long a metrics client gets to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Upper bounds, in seconds, of the upstream connect latency buckets.
+const CONNECT_BUCKETS: [f64; 10] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0];
+
+/// Upper bounds, in seconds, of the connection duration buckets.
+const DURATION_BUCKETS: [f64; 9] = [0.1, 1.0, 5.0, 30.0, 60.0, 300.0, 900.0, 3600.0, 86400.0];
+
+/// A Prometheus histogram; the bucket bounds are passed in by the caller.
+struct Histogram<const N: usize> {
+    buckets: [AtomicU64; N],
+    count: AtomicU64,
+    micros: AtomicU64,
+}
+
+impl<const N: usize> Default for Histogram<N> {
+    fn default() -> Self {
+        Histogram {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            count: AtomicU64::new(0),
+            micros: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<const N: usize> Histogram<N> {
+    fn observe(&self, bounds: &[f64; N], elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        if let Some(index) = bounds.iter().position(|bound| seconds <= *bound) {
+            self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str, bounds: &[f64; N]) {
+        let mut cumulative = 0;
+        for (bound, bucket) in bounds.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, cumulative);
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count);
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+    }
+}
+
+/// Rules are told apart by the address they listen on and their protocol.
+type RuleKey = (SocketAddr, String);
+
+#[derive(Default)]
+struct RuleMetrics {
+    active: AtomicI64,
+    accepted: AtomicU64,
+    denied: AtomicU64,
+    limited: AtomicU64,
+    failed: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    packets_in: AtomicU64,
+    packets_out: AtomicU64,
+    udp_sessions: AtomicU64,
+    udp_expired: AtomicU64,
+    connect_latency: Histogram<{ CONNECT_BUCKETS.len() }>,
+    duration: Histogram<{ DURATION_BUCKETS.len() }>,
+}
+
+/// Counters of every rule that has seen a connection, and of config reloads.
+#[derive(Default)]
+pub struct Metrics {
+    rules: Mutex<BTreeMap<RuleKey, Arc<RuleMetrics>>>,
+    /// The rule of every open connection, by connection id, so that
+    /// transfers need not look it up in `rules`.
+    open: RwLock<HashMap<u64, Arc<RuleMetrics>>>,
+    reloads: AtomicU64,
+    failed_reloads: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn rule(&self, conn: &ConnectionInfo) -> Arc<RuleMetrics> {
+        let key = (conn.local_addr, conn.protocol.to_string());
+        self.rules.lock().unwrap().entry(key).or_default().clone()
+    }
+
+    /// The rule of `conn`, from `open` while the connection is.
+    fn open_rule(&self, conn: &ConnectionInfo) -> Arc<RuleMetrics> {
+        let open = self.open.read().unwrap_or_else(|e| e.into_inner());
+        match open.get(&conn.id) {
+            Some(rule) => rule.clone(),
+            None => {
+                drop(open);
+                self.rule(conn)
+            }
+        }
+    }
+
+    /// Counts a config reload, successful or not.
+    pub fn record_reload(&self, success: bool) {
+        if success {
+            self.reloads.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.failed_reloads.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Renders all metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let rules = self.rules.lock().unwrap().clone();
+        let mut out = String::new();
+
+        let mut counter = |name: &str, kind: &str, help: &str, value: &dyn Fn(&RuleMetrics) -> String| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for ((addr, protocol), rule) in &rules {
+                let _ = writeln!(out, "{}{{{}}} {}", name, labels(addr, protocol), value(rule));
+            }
+        };
+        let load = |value: &AtomicU64| value.load(Ordering::Relaxed).to_string();
+        counter(
+            "oi_connections_active",
+            "gauge",
+            "Open TCP connections and UDP sessions.",
+            &|rule| rule.active.load(Ordering::Relaxed).to_string(),
+        );
+        counter(
+            "oi_connections_accepted_total",
+            "counter",
+            "Connections and UDP sessions allowed by the access rules.",
+            &|rule| load(&rule.accepted),
+        );
+        counter(
+            "oi_connections_denied_total",
+            "counter",
+            "Connections and datagrams refused by the access rules.",
+            &|rule| load(&rule.denied),
+        );
+        counter(
+            "oi_connections_limited_total",
+            "counter",
+            "Connections and datagrams refused by a connection limit.",
+            &|rule| load(&rule.limited),
+        );
+        counter(
+            "oi_connections_failed_total",
+            "counter",
+            "Connections that ended with an error.",
+            &|rule| load(&rule.failed),
+        );
+        counter(
+            "oi_bytes_in_total",
+            "counter",
+            "Bytes forwarded from clients to the upstream.",
+            &|rule| load(&rule.bytes_in),
+        );
+        counter(
+            "oi_bytes_out_total",
+            "counter",
+            "Bytes forwarded from the upstream to clients.",
+            &|rule| load(&rule.bytes_out),
+        );
+        counter(
+            "oi_packets_in_total",
+            "counter",
+            "Datagrams and TCP reads forwarded from clients to the upstream.",
+            &|rule| load(&rule.packets_in),
+        );
+        counter(
+            "oi_packets_out_total",
+            "counter",
+            "Datagrams and TCP reads forwarded from the upstream to clients.",
+            &|rule| load(&rule.packets_out),
+        );
+        counter(
+            "oi_udp_sessions_total",
+            "counter",
+            "UDP sessions opened.",
+            &|rule| load(&rule.udp_sessions),
+        );
+        counter(
+            "oi_udp_sessions_expired_total",
+            "counter",
+            "UDP sessions closed after their idle timeout.",
+            &|rule| load(&rule.udp_expired),
+        );
+
+        let name = "oi_upstream_connect_seconds";
+        let _ = writeln!(out, "# HELP {} Time taken to connect to the upstream.", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for ((addr, protocol), rule) in &rules {
+            rule.connect_latency.render(&mut out, name, &labels(addr, protocol), &CONNECT_BUCKETS);
+        }
+
+        let name = "oi_connection_duration_seconds";
+        let _ = writeln!(out, "# HELP {} How long closed connections and UDP sessions were open.", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for ((addr, protocol), rule) in &rules {
+            rule.duration.render(&mut out, name, &labels(addr, protocol), &DURATION_BUCKETS);
+        }
+
+        let _ = writeln!(out, "# HELP oi_config_reloads_total Configuration reloads.");
+        let _ = writeln!(out, "# TYPE oi_config_reloads_total counter");
+        let _ = writeln!(out, "oi_config_reloads_total{{result=\"success\"}} {}", load(&self.reloads));
+        let _ = writeln!(out, "oi_config_reloads_total{{result=\"failure\"}} {}", load(&self.failed_reloads));
+        out
+    }
+}
+
+fn labels(addr: &SocketAddr, protocol: &str) -> String {
+    format!("bind_address=\"{}\",bind_port=\"{}\",protocol=\"{}\"", addr.ip(), addr.port(), protocol)
+}
+
+impl ConnectionObserver for Metrics {
+    fn on_access(&self, conn: &ConnectionInfo, allowed: bool) {
+        let rule = self.rule(conn);
+        if !allowed {
+            rule.denied.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        rule.accepted.fetch_add(1, Ordering::Relaxed);
+        rule.active.fetch_add(1, Ordering::Relaxed);
+        if matches!(conn.protocol, Protocol::Udp | Protocol::UdpToTcp) {
+            rule.udp_sessions.fetch_add(1, Ordering::Relaxed);
+        }
+        self.open.write().unwrap_or_else(|e| e.into_inner()).insert(conn.id, rule);
+    }
+
+    fn on_connect(&self, conn: &ConnectionInfo, result: Result<Duration, &ProxyError>) {
+        let Ok(elapsed) = result else {
+            return;
+        };
+        self.open_rule(conn).connect_latency.observe(&CONNECT_BUCKETS, elapsed);
+    }
+
+    fn on_transfer(&self, conn: &ConnectionInfo, direction: Direction, bytes: usize) {
+        let rule = self.open_rule(conn);
+        let (total, packets) = match direction {
+            Direction::ClientToUpstream => (&rule.bytes_in, &rule.packets_in),
+            Direction::UpstreamToClient => (&rule.bytes_out, &rule.packets_out),
+        };
+        total.fetch_add(bytes as u64, Ordering::Relaxed);
+        packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_close(&self, conn: &ConnectionInfo, reason: &CloseReason, totals: &TransferTotals) {
+        let opened = self.open.write().unwrap_or_else(|e| e.into_inner()).remove(&conn.id);
+        // UDP sessions whose client is no longer allowed close as denied
+        let was_open = opened.is_some();
+        let rule = opened.unwrap_or_else(|| self.rule(conn));
+        match reason {
+            CloseReason::Denied(_) if !was_open => return,
+            CloseReason::Limited(_) => {
+                rule.limited.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            _ => {}
+        }
+        rule.active.fetch_sub(1, Ordering::Relaxed);
+        rule.duration.observe(&DURATION_BUCKETS, totals.duration);
+        match reason {
+            CloseReason::Error(_) => {
+                rule.failed.fetch_add(1, Ordering::Relaxed);
+            }
+            CloseReason::Expired => {
+                rule.udp_expired.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Serves `metrics` at `GET /metrics` on `listener` until an accept fails.
+pub async fn serve_metrics(listener: TcpListener, metrics: Arc<Metrics>) -> std::io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let metrics = metrics.clone();
+        smol::spawn(async move {
+            if let Err(e) = handle_request(stream, &metrics).await {
+                logging::warn("metrics_error", format!("Metrics request error: {}", e))
+                    .field("error", e.to_string())
+                    .log();
+            }
+        })
+        .detach();
+    }
+}
+
+async fn handle_request(mut stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    // Only the request line matters; read until the end of the headers.
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    let read = async {
+        while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < 8192 {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+        Ok(())
+    };
+    let timeout = async {
+        smol::Timer::after(REQUEST_TIMEOUT).await;
+        Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "no request received"))
+    };
+    smol::future::or(read, timeout).await?;
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split_whitespace();
+    let response = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = metrics.render();
+            format!(
+                "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_string(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::limits::Limit;
+    use crate::observer::tests::conn;
+
+    const TCP_LABELS: &str = r#"bind_address="127.0.0.1",bind_port="8080",protocol="tcp""#;
+
+    fn line(metrics: &Metrics, name: &str) -> String {
+        let prefix = format!("{}{{", name);
+        metrics
+            .render()
+            .lines()
+            .find(|line| line.starts_with(&prefix))
+            .unwrap_or_else(|| panic!("no {} line", name))
+            .to_string()
+    }
+
+    #[test]
+    fn counts_connection_lifecycle() {
+        let metrics = Metrics::new();
+        let conn = conn();
+        metrics.on_access(&conn, true);
+        metrics.on_transfer(&conn, Direction::ClientToUpstream, 10);
+        metrics.on_transfer(&conn, Direction::UpstreamToClient, 20);
+        assert_eq!(line(&metrics, "oi_connections_active"), format!("oi_connections_active{{{}}} 1", TCP_LABELS));
+
+        metrics.on_close(&conn, &CloseReason::Error(ProxyError::Timeout), &TransferTotals::default());
+        assert_eq!(line(&metrics, "oi_connections_active"), format!("oi_connections_active{{{}}} 0", TCP_LABELS));
+        assert!(metrics.open.read().unwrap().is_empty());
+        assert!(line(&metrics, "oi_connections_accepted_total").ends_with(" 1"));
+        assert!(line(&metrics, "oi_connections_failed_total").ends_with(" 1"));
+        assert!(line(&metrics, "oi_bytes_in_total").ends_with(" 10"));
+        assert!(line(&metrics, "oi_bytes_out_total").ends_with(" 20"));
+        assert!(line(&metrics, "oi_packets_in_total").ends_with(" 1"));
+        assert!(line(&metrics, "oi_packets_out_total").ends_with(" 1"));
+    }
+
+    #[test]
+    fn records_connection_durations() {
+        let metrics = Metrics::new();
+        let conn = conn();
+        metrics.on_access(&conn, true);
+        let totals = TransferTotals { duration: Duration::from_secs(2), ..TransferTotals::default() };
+        metrics.on_close(&conn, &CloseReason::Finished, &totals);
+        let render = metrics.render();
+        assert!(render.contains(&format!("oi_connection_duration_seconds_bucket{{{},le=\"1\"}} 0", TCP_LABELS)));
+        assert!(render.contains(&format!("oi_connection_duration_seconds_bucket{{{},le=\"5\"}} 1", TCP_LABELS)));
+        assert!(render.contains(&format!("oi_connection_duration_seconds_sum{{{}}} 2", TCP_LABELS)));
+        assert!(render.contains(&format!("oi_connection_duration_seconds_count{{{}}} 1", TCP_LABELS)));
+    }
+
+    #[test]
+    fn denied_clients_are_not_active() {
+        let metrics = Metrics::new();
+        let conn = conn();
+        metrics.on_access(&conn, false);
+        let denied = CloseReason::Denied(ProxyError::AccessDenied(conn.client_addr));
+        metrics.on_close(&conn, &denied, &TransferTotals::default());
+        assert!(line(&metrics, "oi_connections_denied_total").ends_with(" 1"));
+        assert!(line(&metrics, "oi_connections_active").ends_with(" 0"));
+
+        metrics.on_close(&conn, &CloseReason::Limited(Limit::Rule), &TransferTotals::default());
+        assert!(line(&metrics, "oi_connections_limited_total").ends_with(" 1"));
+        assert!(line(&metrics, "oi_connections_active").ends_with(" 0"));
+    }
+
+    #[test]
+    fn denied_sessions_are_no_longer_active() {
+        let metrics = Metrics::new();
+        let conn = ConnectionInfo { protocol: Protocol::Udp, ..conn() };
+        metrics.on_access(&conn, true);
+        metrics.on_access(&conn, false);
+        let denied = CloseReason::Denied(ProxyError::AccessDenied(conn.client_addr));
+        metrics.on_close(&conn, &denied, &TransferTotals::default());
+        assert!(line(&metrics, "oi_connections_denied_total").ends_with(" 1"));
+        assert!(line(&metrics, "oi_connections_active").ends_with(" 0"));
+    }
+
+    #[test]
+    fn counts_udp_sessions_and_expirations() {
+        let metrics = Metrics::new();
+        let conn = ConnectionInfo { protocol: Protocol::Udp, ..conn() };
+        metrics.on_access(&conn, true);
+        metrics.on_close(&conn, &CloseReason::Expired, &TransferTotals::default());
+        assert!(line(&metrics, "oi_udp_sessions_total").ends_with(" 1"));
+        assert!(line(&metrics, "oi_udp_sessions_expired_total").ends_with(" 1"));
+    }
+
+    #[test]
+    fn connect_latency_histogram_is_cumulative() {
+        let metrics = Metrics::new();
+        let conn = conn();
+        metrics.on_connect(&conn, Ok(Duration::from_millis(3)));
+        metrics.on_connect(&conn, Ok(Duration::from_secs(2)));
+        let render = metrics.render();
+        assert!(render.contains(&format!("oi_upstream_connect_seconds_bucket{{{},le=\"0.0025\"}} 0", TCP_LABELS)));
+        assert!(render.contains(&format!("oi_upstream_connect_seconds_bucket{{{},le=\"0.005\"}} 1", TCP_LABELS)));
+        assert!(render.contains(&format!("oi_upstream_connect_seconds_bucket{{{},le=\"1\"}} 1", TCP_LABELS)));
+        assert!(render.contains(&format!("oi_upstream_connect_seconds_bucket{{{},le=\"+Inf\"}} 2", TCP_LABELS)));
+        assert!(render.contains(&format!("oi_upstream_connect_seconds_count{{{}}} 2", TCP_LABELS)));
+        assert!(render.contains(&format!("oi_upstream_connect_seconds_sum{{{}}} 2.003", TCP_LABELS)));
+    }
+
+    #[test]
+    fn counts_reloads() {
+        let metrics = Metrics::new();
+        metrics.record_reload(true);
+        metrics.record_reload(true);
+        metrics.record_reload(false);
+        let render = metrics.render();
+        assert!(render.contains("oi_config_reloads_total{result=\"success\"} 2"));
+        assert!(render.contains("oi_config_reloads_total{result=\"failure\"} 1"));
+    }
+}
@@ -0,0 +1,476 @@
+//! Hooks into the life of every TCP connection and UDP session: accept,
+//! access decision, upstream connect, transferred bytes and close.
+//!
+//! The connection messages of `oi` are logged by `LogObserver`; auditing,
+//! metrics or billing can be added by registering more observers with
+//! `ProxyBuilder::observer`.
+
+use crate::config::Protocol;
+use crate::error::ProxyError;
+use crate::limits::Limit;
+use crate::logging::{self, Event};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A TCP connection or UDP session, as seen by observers.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    /// Unique within the process.
+    pub id: u64,
+    /// The protocol of the forwarding rule.
+    pub protocol: Protocol,
+    pub client_addr: SocketAddr,
+    /// The address of the listener the client reached.
+    pub local_addr: SocketAddr,
+    /// The target of the forwarding rule.
+    pub upstream: String,
+    pub started: Instant,
+}
+
+impl ConnectionInfo {
+    pub fn new(protocol: Protocol, client_addr: SocketAddr, local_addr: SocketAddr, upstream: String) -> Self {
+        ConnectionInfo {
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            protocol,
+            client_addr,
+            local_addr,
+            upstream,
+            started: Instant::now(),
+        }
+    }
+
+    /// True for connections accepted by a TCP listener.
+    pub fn is_tcp(&self) -> bool {
+        matches!(self.protocol, Protocol::Tcp | Protocol::TcpToUdp)
+    }
+
+    /// The module that forwards the connection, `tcp_handler` or
+    /// `udp_handler`. Its log events are filed under that module.
+    pub fn module(&self) -> &'static str {
+        if self.is_tcp() { "tcp_handler" } else { "udp_handler" }
+    }
+}
+
+/// The direction of transferred bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    ClientToUpstream,
+    UpstreamToClient,
+}
+
+/// Why a connection ended.
+#[derive(Debug)]
+pub enum CloseReason {
+    /// The client was refused by the access rules, with the
+    /// `ProxyError::AccessDenied` naming it.
+    Denied(ProxyError),
+    /// The client was refused because it would exceed a connection limit.
+    Limited(Limit),
+    /// Both sides finished.
+    Finished,
+    /// The UDP session saw no traffic for the rule's timeout.
+    Expired,
+    /// The proxy closed it, e.g. at the end of shutdown.
+    Closed,
+    /// An administrator killed it, e.g. through the control socket.
+    Killed,
+    /// A filter closed it.
+    Filtered,
+    /// Forwarding failed.
+    Error(ProxyError),
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CloseReason::Denied(_) => "denied",
+            CloseReason::Limited(_) => "limited",
+            CloseReason::Finished => "finished",
+            CloseReason::Expired => "expired",
+            CloseReason::Closed => "closed",
+            CloseReason::Killed => "killed",
+            CloseReason::Filtered => "filtered",
+            CloseReason::Error(_) => "failed",
+        })
+    }
+}
+
+/// What a connection transferred over its lifetime. Each chunk read from a
+/// TCP stream and each datagram counts as one packet.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransferTotals {
+    pub client_to_upstream: u64,
+    pub upstream_to_client: u64,
+    pub client_to_upstream_packets: u64,
+    pub upstream_to_client_packets: u64,
+    pub duration: Duration,
+}
+
+impl TransferTotals {
+    /// Adds one packet of `bytes` in `direction`.
+    pub fn add(&mut self, direction: Direction, bytes: u64) {
+        let (total, packets) = match direction {
+            Direction::ClientToUpstream => (&mut self.client_to_upstream, &mut self.client_to_upstream_packets),
+            Direction::UpstreamToClient => (&mut self.upstream_to_client, &mut self.upstream_to_client_packets),
+        };
+        *total += bytes;
+        *packets += 1;
+    }
+}
+
+impl fmt::Display for TransferTotals {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes ({} packets) in, {} bytes ({} packets) out, {:.3}s",
+            self.client_to_upstream,
+            self.client_to_upstream_packets,
+            self.upstream_to_client,
+            self.upstream_to_client_packets,
+            self.duration.as_secs_f64()
+        )
+    }
+}
+
+/// Receives the events of every connection. All methods default to doing
+/// nothing. They are called from the forwarding tasks, so they should
+/// return quickly.
+pub trait ConnectionObserver: Send + Sync {
+    /// A TCP client connected, or a datagram came from a new UDP client.
+    fn on_accept(&self, _conn: &ConnectionInfo) {}
+
+    /// The access rules were checked for the client.
+    fn on_access(&self, _conn: &ConnectionInfo, _allowed: bool) {}
+
+    /// The upstream connection was attempted; `Ok` carries how long it took.
+    fn on_connect(&self, _conn: &ConnectionInfo, _result: Result<Duration, &ProxyError>) {}
+
+    /// Bytes were forwarded.
+    fn on_transfer(&self, _conn: &ConnectionInfo, _direction: Direction, _bytes: usize) {}
+
+    /// The connection ended. Called exactly once per accepted connection.
+    /// Clients refused with `Denied` or `Limited` never got `on_access`
+    /// with `true`.
+    fn on_close(&self, _conn: &ConnectionInfo, _reason: &CloseReason, _totals: &TransferTotals) {}
+}
+
+/// Passes every event on to each observer in turn. Empty by default, which
+/// observes nothing.
+#[derive(Clone, Default)]
+pub struct Observers(Vec<Arc<dyn ConnectionObserver>>);
+
+impl Observers {
+    pub fn push(&mut self, observer: Arc<dyn ConnectionObserver>) {
+        self.0.push(observer);
+    }
+}
+
+impl ConnectionObserver for Observers {
+    fn on_accept(&self, conn: &ConnectionInfo) {
+        self.0.iter().for_each(|observer| observer.on_accept(conn));
+    }
+
+    fn on_access(&self, conn: &ConnectionInfo, allowed: bool) {
+        self.0.iter().for_each(|observer| observer.on_access(conn, allowed));
+    }
+
+    fn on_connect(&self, conn: &ConnectionInfo, result: Result<Duration, &ProxyError>) {
+        self.0.iter().for_each(|observer| observer.on_connect(conn, result));
+    }
+
+    fn on_transfer(&self, conn: &ConnectionInfo, direction: Direction, bytes: usize) {
+        self.0.iter().for_each(|observer| observer.on_transfer(conn, direction, bytes));
+    }
+
+    fn on_close(&self, conn: &ConnectionInfo, reason: &CloseReason, totals: &TransferTotals) {
+        self.0.iter().for_each(|observer| observer.on_close(conn, reason, totals));
+    }
+}
+
+/// Logs every connection event through `logging`: refused clients,
+/// forwarding errors and the totals of closed connections at info level or
+/// above, accepted clients and upstream connects at debug level and every
+/// transfer at trace level.
+pub struct LogObserver;
+
+/// Adds the fields identifying `conn` to `event`.
+fn connection_event(event: Event, conn: &ConnectionInfo) -> Event {
+    Event { module: conn.module(), ..event }
+        .field("id", conn.id)
+        .field("protocol", conn.protocol.to_string())
+        .field("client", conn.client_addr.to_string())
+        .field("local", conn.local_addr.to_string())
+        .field("upstream", conn.upstream.as_str())
+}
+
+impl ConnectionObserver for LogObserver {
+    fn on_access(&self, conn: &ConnectionInfo, allowed: bool) {
+        let event = match (conn.is_tcp(), allowed) {
+            (true, true) => logging::debug("connection_accepted", format!("New connection from {}", conn.client_addr)),
+            (true, false) => logging::info("connection_denied", ProxyError::AccessDenied(conn.client_addr).to_string()),
+            (false, true) => logging::debug("udp_session_created", format!("New UDP session from {}", conn.client_addr)),
+            (false, false) => logging::info("connection_denied", format!("Datagram from {} denied", conn.client_addr)),
+        };
+        let event = if allowed { event } else { event.field("reason", "denied") };
+        connection_event(event, conn).log();
+    }
+
+    fn on_connect(&self, conn: &ConnectionInfo, result: Result<Duration, &ProxyError>) {
+        let event = match result {
+            Ok(elapsed) => logging::debug("upstream_connected", format!("Connected to {}", conn.upstream))
+                .field("connect_secs", elapsed.as_secs_f64()),
+            Err(error) => logging::error("upstream_connect_failed", error.to_string()).field("error", error.to_string()),
+        };
+        connection_event(event, conn).log();
+    }
+
+    fn on_transfer(&self, conn: &ConnectionInfo, direction: Direction, bytes: usize) {
+        // Called for every chunk, so skip building the event when it is dropped
+        if !logging::enabled(logging::Level::Trace, conn.module()) {
+            return;
+        }
+        let (arrow, direction) = match direction {
+            Direction::ClientToUpstream => ("->", "in"),
+            Direction::UpstreamToClient => ("<-", "out"),
+        };
+        let message = format!("{} {} {} ({} bytes)", conn.client_addr, arrow, conn.upstream, bytes);
+        Event { module: conn.module(), ..logging::trace("transfer", message) }
+            .field("id", conn.id)
+            .field("direction", direction)
+            .field("bytes", bytes)
+            .log();
+    }
+
+    fn on_close(&self, conn: &ConnectionInfo, reason: &CloseReason, totals: &TransferTotals) {
+        match reason {
+            // Already reported by on_access
+            CloseReason::Denied(_) => return,
+            CloseReason::Limited(limit) => {
+                let message = format!("Connection from {} refused: {} is reached", conn.client_addr, limit);
+                connection_event(logging::info("connection_limited", message), conn)
+                    .field("reason", reason.to_string())
+                    .field("limit", limit.to_string())
+                    .log();
+                return;
+            }
+            // Already reported by on_connect
+            CloseReason::Error(ProxyError::Connect { .. }) => {}
+            CloseReason::Error(error) => {
+                connection_event(logging::error("connection_error", format!("Connection error: {}", error)), conn)
+                    .field("error", error.to_string())
+                    .log();
+            }
+            _ => {}
+        }
+        let name = match reason {
+            CloseReason::Expired => "udp_session_expired",
+            _ => "connection_closed",
+        };
+        let message = format!("Connection {} from {} {}: {}", conn.id, conn.client_addr, reason, totals);
+        connection_event(logging::info(name, message), conn)
+            .field("reason", reason.to_string())
+            .field("bytes_in", totals.client_to_upstream)
+            .field("bytes_out", totals.upstream_to_client)
+            .field("packets_in", totals.client_to_upstream_packets)
+            .field("packets_out", totals.upstream_to_client_packets)
+            .field("duration_secs", totals.duration.as_secs_f64())
+            .log();
+    }
+}
+
+/// Reports the events of one connection to an observer and keeps its
+/// totals. Dropping it reports the close, as `Closed` unless another
+/// reason was set.
+pub struct ObservedConnection {
+    observer: Arc<dyn ConnectionObserver>,
+    info: ConnectionInfo,
+    client_to_upstream: AtomicU64,
+    upstream_to_client: AtomicU64,
+    client_to_upstream_packets: AtomicU64,
+    upstream_to_client_packets: AtomicU64,
+    reason: Option<CloseReason>,
+}
+
+impl ObservedConnection {
+    /// Reports the accept of `info`.
+    pub fn accept(observer: Arc<dyn ConnectionObserver>, info: ConnectionInfo) -> Self {
+        observer.on_accept(&info);
+        ObservedConnection {
+            observer,
+            info,
+            client_to_upstream: AtomicU64::new(0),
+            upstream_to_client: AtomicU64::new(0),
+            client_to_upstream_packets: AtomicU64::new(0),
+            upstream_to_client_packets: AtomicU64::new(0),
+            reason: None,
+        }
+    }
+
+    pub fn info(&self) -> &ConnectionInfo {
+        &self.info
+    }
+
+    pub fn access(&self, allowed: bool) {
+        self.observer.on_access(&self.info, allowed);
+    }
+
+    /// Reports a connect attempt that started at `started`.
+    pub fn connected<T>(&self, started: Instant, result: &Result<T, ProxyError>) {
+        let result = result.as_ref().map(|_| started.elapsed());
+        self.observer.on_connect(&self.info, result);
+    }
+
+    /// Reports one packet of `bytes` forwarded in `direction`.
+    pub fn transferred(&self, direction: Direction, bytes: usize) {
+        let (total, packets) = match direction {
+            Direction::ClientToUpstream => (&self.client_to_upstream, &self.client_to_upstream_packets),
+            Direction::UpstreamToClient => (&self.upstream_to_client, &self.upstream_to_client_packets),
+        };
+        total.fetch_add(bytes as u64, Ordering::Relaxed);
+        packets.fetch_add(1, Ordering::Relaxed);
+        self.observer.on_transfer(&self.info, direction, bytes);
+    }
+
+    pub fn totals(&self) -> TransferTotals {
+        TransferTotals {
+            client_to_upstream: self.client_to_upstream.load(Ordering::Relaxed),
+            upstream_to_client: self.upstream_to_client.load(Ordering::Relaxed),
+            client_to_upstream_packets: self.client_to_upstream_packets.load(Ordering::Relaxed),
+            upstream_to_client_packets: self.upstream_to_client_packets.load(Ordering::Relaxed),
+            duration: self.info.started.elapsed(),
+        }
+    }
+
+    /// Sets the reason reported when the connection is dropped.
+    pub fn set_close_reason(&mut self, reason: CloseReason) {
+        self.reason = Some(reason);
+    }
+
+    /// Ends the connection for `reason`.
+    pub fn close(mut self, reason: CloseReason) {
+        self.set_close_reason(reason);
+    }
+}
+
+impl Drop for ObservedConnection {
+    fn drop(&mut self) {
+        let reason = self.reason.take().unwrap_or(CloseReason::Closed);
+        self.observer.on_close(&self.info, &reason, &self.totals());
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::net::IpAddr;
+    use std::sync::Mutex;
+
+    /// A TCP connection from 10.0.0.1:4000 to a listener on 127.0.0.1:8080.
+    pub(crate) fn conn() -> ConnectionInfo {
+        ConnectionInfo::new(
+            Protocol::Tcp,
+            "10.0.0.1:4000".parse().unwrap(),
+            "127.0.0.1:8080".parse().unwrap(),
+            "10.0.0.2:80".to_string(),
+        )
+    }
+
+    /// The client address 10.0.0.`last`.
+    pub(crate) fn ip(last: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, last])
+    }
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl ConnectionObserver for Recorder {
+        fn on_accept(&self, conn: &ConnectionInfo) {
+            self.0.lock().unwrap().push(format!("accept {}", conn.client_addr));
+        }
+
+        fn on_access(&self, _conn: &ConnectionInfo, allowed: bool) {
+            self.0.lock().unwrap().push(format!("access {}", allowed));
+        }
+
+        fn on_transfer(&self, _conn: &ConnectionInfo, direction: Direction, bytes: usize) {
+            self.0.lock().unwrap().push(format!("transfer {:?} {}", direction, bytes));
+        }
+
+        fn on_close(&self, _conn: &ConnectionInfo, reason: &CloseReason, totals: &TransferTotals) {
+            self.0.lock().unwrap().push(format!(
+                "close {:?} {} {} {} {}",
+                reason,
+                totals.client_to_upstream,
+                totals.upstream_to_client,
+                totals.client_to_upstream_packets,
+                totals.upstream_to_client_packets
+            ));
+        }
+    }
+
+    #[test]
+    fn connections_are_filed_under_their_handler() {
+        assert_eq!(conn().module(), "tcp_handler");
+        assert_eq!(ConnectionInfo { protocol: Protocol::UdpToTcp, ..conn() }.module(), "udp_handler");
+    }
+
+    #[test]
+    fn connection_ids_are_unique() {
+        assert_ne!(conn().id, conn().id);
+    }
+
+    #[test]
+    fn observed_connection_reports_events_and_totals() {
+        let recorder = Arc::new(Recorder::default());
+        let conn = ObservedConnection::accept(recorder.clone(), conn());
+        conn.access(true);
+        conn.transferred(Direction::ClientToUpstream, 5);
+        conn.transferred(Direction::UpstreamToClient, 7);
+        conn.transferred(Direction::ClientToUpstream, 1);
+        conn.close(CloseReason::Finished);
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![
+                "accept 10.0.0.1:4000",
+                "access true",
+                "transfer ClientToUpstream 5",
+                "transfer UpstreamToClient 7",
+                "transfer ClientToUpstream 1",
+                "close Finished 6 7 2 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn dropped_connection_reports_closed() {
+        let recorder = Arc::new(Recorder::default());
+        drop(ObservedConnection::accept(recorder.clone(), conn()));
+        assert_eq!(recorder.0.lock().unwrap().last().unwrap(), "close Closed 0 0 0 0");
+    }
+
+    #[test]
+    fn observers_forward_to_each() {
+        let first = Arc::new(Recorder::default());
+        let second = Arc::new(Recorder::default());
+        let mut observers = Observers::default();
+        observers.push(first.clone());
+        observers.push(second.clone());
+        observers.on_access(&conn(), false);
+        assert_eq!(*first.0.lock().unwrap(), vec!["access false"]);
+        assert_eq!(*second.0.lock().unwrap(), vec!["access false"]);
+    }
+
+    #[test]
+    fn totals_count_bytes_and_packets() {
+        let mut totals = TransferTotals::default();
+        totals.add(Direction::ClientToUpstream, 10);
+        totals.add(Direction::ClientToUpstream, 5);
+        totals.add(Direction::UpstreamToClient, 7);
+        assert_eq!(totals.to_string(), "15 bytes (2 packets) in, 7 bytes (1 packets) out, 0.000s");
+    }
+}
@@ -0,0 +1,318 @@
+//! The embeddable proxy: runs a `Server` on a task of its own and controls
+//! it through a `ProxyHandle`, the way the `oi` binary does.
+//!
+//! ```no_run
+//! use oxidinetd::config::{ForwardingRule, Protocol};
+//! use oxidinetd::proxy::Proxy;
+//!
+//! smol::block_on(async {
+//!     let proxy = Proxy::builder()
+//!         .rule(ForwardingRule {
+//!             bind_address: "127.0.0.1".to_string(),
+//!             bind_port: 0,
+//!             connect_address: "127.0.0.1".to_string(),
+//!             connect_port: 8080,
+//!             protocol: Protocol::Tcp,
+//!             timeout: None,
+//!             source_address: None,
+//!             rules: Vec::new(),
+//!             filters: Vec::new(),
+//!             enabled: true,
+//!             max_connections: None,
+//!             limit_action: None,
+//!             rate_limit: None,
+//!             bandwidth: None,
+//!         })
+//!         .start()
+//!         .await;
+//!     let (_, addr) = proxy.handle().local_addrs()[0].clone();
+//!     println!("Forwarding from {}", addr);
+//!     proxy.shutdown().await;
+//! });
+//! ```
+
+use crate::bans::BanList;
+use crate::config::{AccessRule, Config, ForwardingRule};
+use crate::connections::KillTarget;
+use crate::filter::{FilterFactory, FilterRegistry};
+use crate::observer::{ConnectionInfo, ConnectionObserver, Observers};
+use crate::server::{
+    DEFAULT_DRAIN_TIMEOUT, DrainReport, Hooks, ListenerError, PauseMode, ReloadSummary, RuleState, RuleStatus, Server,
+    Trackers,
+};
+use futures_lite::future;
+use smol::Task;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// Builds a `Proxy` from a config, from rules added one by one, or both.
+#[derive(Default)]
+pub struct ProxyBuilder {
+    config: Config,
+    drain_timeout: Option<Duration>,
+    observers: Observers,
+    filters: FilterRegistry,
+}
+
+impl ProxyBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Uses the rules and settings of `config`, replacing everything set
+    /// so far.
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Adds a forwarding rule.
+    pub fn rule(mut self, rule: ForwardingRule) -> Self {
+        self.config.forwarding_rules.push(rule);
+        self
+    }
+
+    /// Adds an access rule that applies to every forwarding rule.
+    pub fn global_rule(mut self, rule: AccessRule) -> Self {
+        self.config.global_rules.push(rule);
+        self
+    }
+
+    /// How long shutdown waits for open connections. Takes precedence over
+    /// the `drain_timeout` of the config, including reloaded ones.
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = Some(timeout);
+        self
+    }
+
+    /// Reports the events of every connection to `observer`, in addition
+    /// to the observers added before.
+    pub fn observer(mut self, observer: Arc<dyn ConnectionObserver>) -> Self {
+        self.observers.push(observer);
+        self
+    }
+
+    /// Registers a filter that rules can use with
+    /// `{ type = "custom", name = "..." }` in their `filters` list.
+    pub fn filter(mut self, name: impl Into<String>, factory: Arc<dyn FilterFactory>) -> Self {
+        self.filters.register(name, factory);
+        self
+    }
+
+    /// Binds the listeners of all rules and starts forwarding. Rules that
+    /// fail to start are skipped; their errors are reported through
+    /// `ProxyHandle::errors`.
+    pub async fn start(self) -> Proxy {
+        let hooks = Hooks {
+            observer: Arc::new(self.observers),
+            filters: self.filters,
+        };
+        let server = Server::start(&self.config, hooks).await;
+        let (commands_tx, commands_rx) = async_channel::unbounded();
+        let handle = ProxyHandle {
+            commands: commands_tx,
+            errors: server.errors(),
+            trackers: server.trackers(),
+            bans: server.bans(),
+            rules: Arc::new(RwLock::new(Vec::new())),
+        };
+        handle.publish(&server, &self.config);
+        let task = smol::spawn(run(server, self.config, self.drain_timeout, commands_rx, handle.clone()));
+        Proxy { handle, task }
+    }
+}
+
+/// A running proxy. Dropping it stops every listener and closes all
+/// connections right away; use `shutdown` to let them drain first.
+pub struct Proxy {
+    handle: ProxyHandle,
+    task: Task<DrainReport>,
+}
+
+impl Proxy {
+    pub fn builder() -> ProxyBuilder {
+        ProxyBuilder::new()
+    }
+
+    /// A handle to control the proxy from other tasks.
+    pub fn handle(&self) -> ProxyHandle {
+        self.handle.clone()
+    }
+
+    /// Waits until the proxy has shut down, through `ProxyHandle::shutdown`
+    /// on any of its handles, and reports what was still open at the end.
+    pub async fn wait(self) -> DrainReport {
+        let Proxy { handle, task } = self;
+        let report = task.await;
+        drop(handle);
+        report
+    }
+
+    /// Shuts the proxy down and waits until it has stopped.
+    pub async fn shutdown(self) -> DrainReport {
+        self.handle.shutdown();
+        self.wait().await
+    }
+}
+
+enum Command {
+    Reload(Box<Config>, async_channel::Sender<ReloadSummary>),
+    Pause(SocketAddr, PauseMode, async_channel::Sender<Vec<RuleStatus>>),
+    Resume(SocketAddr, async_channel::Sender<Vec<RuleStatus>>),
+    Shutdown,
+}
+
+/// Controls a running `Proxy`. Clones control the same proxy.
+#[derive(Clone)]
+pub struct ProxyHandle {
+    commands: async_channel::Sender<Command>,
+    errors: async_channel::Receiver<ListenerError>,
+    trackers: Trackers,
+    bans: BanList,
+    rules: Arc<RwLock<Vec<RuleStatus>>>,
+}
+
+impl ProxyHandle {
+    /// The rules that currently have a running listener, with the address
+    /// it is bound to. For rules that bind port 0 this is the port the
+    /// system picked.
+    pub fn local_addrs(&self) -> Vec<(ForwardingRule, SocketAddr)> {
+        self.rules
+            .read()
+            .unwrap()
+            .iter()
+            .filter_map(|status| Some((status.rule.clone(), status.local_addr?)))
+            .collect()
+    }
+
+    /// Every rule of the current config with its state, including paused
+    /// and failed ones. Empty once the proxy has shut down.
+    pub fn rules(&self) -> Vec<RuleStatus> {
+        self.rules.read().unwrap().clone()
+    }
+
+    /// Applies a new config to the running listeners, see `Server::reload`.
+    /// Returns `None` once the proxy is shutting down.
+    pub async fn reload(&self, config: Config) -> Option<ReloadSummary> {
+        let (reply_tx, reply_rx) = async_channel::bounded(1);
+        self.commands.send(Command::Reload(Box::new(config), reply_tx)).await.ok()?;
+        reply_rx.recv().await.ok()
+    }
+
+    /// Pauses the rules that bind `addr`, see `Server::pause`, and returns
+    /// their new state. Returns `None` once the proxy is shutting down.
+    pub async fn pause(&self, addr: SocketAddr, mode: PauseMode) -> Option<Vec<RuleStatus>> {
+        let (reply_tx, reply_rx) = async_channel::bounded(1);
+        self.commands.send(Command::Pause(addr, mode, reply_tx)).await.ok()?;
+        reply_rx.recv().await.ok()
+    }
+
+    /// Resumes the paused or disabled rules that bind `addr`, see
+    /// `Server::resume`, and returns their new state. Returns `None` once
+    /// the proxy is shutting down.
+    pub async fn resume(&self, addr: SocketAddr) -> Option<Vec<RuleStatus>> {
+        let (reply_tx, reply_rx) = async_channel::bounded(1);
+        self.commands.send(Command::Resume(addr, reply_tx)).await.ok()?;
+        reply_rx.recv().await.ok()
+    }
+
+    /// Waits up to `timeout` for the connections accepted by the listener
+    /// bound to `addr` to end, then kills the rest and returns them.
+    pub async fn drain(&self, addr: SocketAddr, timeout: Duration) -> Vec<ConnectionInfo> {
+        let target = KillTarget::Listener(addr);
+        let deadline = Instant::now() + timeout;
+        while self.trackers.count(target) > 0 && Instant::now() < deadline {
+            smol::Timer::after(Duration::from_millis(50)).await;
+        }
+        self.trackers.kill(target)
+    }
+
+    /// Stops accepting new clients and lets open connections drain. A
+    /// second call closes whatever is still open right away.
+    pub fn shutdown(&self) {
+        let _ = self.commands.try_send(Command::Shutdown);
+    }
+
+    /// Ends the open connections and UDP sessions that match `target` and
+    /// returns them. Observers see them close as `Killed`. This also works
+    /// while the proxy drains.
+    pub fn kill(&self, target: KillTarget) -> Vec<ConnectionInfo> {
+        self.trackers.kill(target)
+    }
+
+    /// The clients banned through `auto_ban`, which can be lifted here.
+    pub fn bans(&self) -> BanList {
+        self.bans.clone()
+    }
+
+    /// Receives the errors of listeners that failed to start or stopped
+    /// accepting.
+    pub fn errors(&self) -> async_channel::Receiver<ListenerError> {
+        self.errors.clone()
+    }
+
+    fn publish(&self, server: &Server, config: &Config) {
+        // A rule listed twice only has a listener for the first copy
+        let mut known = server.status();
+        *self.rules.write().unwrap() = config
+            .forwarding_rules
+            .iter()
+            .map(|rule| match known.iter().position(|status| status.rule == *rule) {
+                Some(index) => known.swap_remove(index),
+                None => RuleStatus {
+                    rule: rule.clone(),
+                    state: RuleState::Failed,
+                    local_addr: None,
+                },
+            })
+            .collect();
+    }
+}
+
+async fn run(
+    mut server: Server,
+    mut config: Config,
+    drain_timeout: Option<Duration>,
+    commands: async_channel::Receiver<Command>,
+    handle: ProxyHandle,
+) -> DrainReport {
+    loop {
+        match commands.recv().await {
+            Ok(Command::Reload(new_config, reply)) => {
+                let summary = server.reload(&new_config).await;
+                handle.publish(&server, &new_config);
+                config = *new_config;
+                let _ = reply.try_send(summary);
+            }
+            Ok(Command::Pause(addr, mode, reply)) => {
+                let paused = server.pause(addr, mode).await;
+                handle.publish(&server, &config);
+                let _ = reply.try_send(paused);
+            }
+            Ok(Command::Resume(addr, reply)) => {
+                let resumed = server.resume(addr).await;
+                handle.publish(&server, &config);
+                let _ = reply.try_send(resumed);
+            }
+            Ok(Command::Shutdown) | Err(_) => break,
+        }
+    }
+
+    let drain = drain_timeout
+        .or(config.drain_timeout.map(Duration::from_secs))
+        .unwrap_or(DEFAULT_DRAIN_TIMEOUT);
+    // A second shutdown skips the rest of the drain period. Other commands
+    // that come in meanwhile are dropped, which their callers see as `None`.
+    let deadline = future::or(
+        async {
+            smol::Timer::after(drain).await;
+        },
+        async {
+            while let Ok(Command::Reload(..) | Command::Pause(..) | Command::Resume(..)) = commands.recv().await {}
+        },
+    );
+    let report = server.shutdown(deadline).await;
+    handle.rules.write().unwrap().clear();
+    report
+}
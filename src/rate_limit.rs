@@ -0,0 +1,233 @@
+//! Token bucket limits on how fast a forwarding rule takes new TCP
+//! connections and UDP sessions, overall and per client IP address.
+
+use crate::config::RateLimitConfig;
+use crate::limits::Limit;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// How many client buckets a limiter keeps at most. Past this, the bucket
+/// that was used least recently is dropped.
+const MAX_CLIENT_BUCKETS: usize = 65_536;
+
+/// How often buckets that have refilled completely are dropped; they hold
+/// nothing a new bucket would not.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A rate in tokens per second, and how many tokens can pile up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Rate {
+    per_second: f64,
+    burst: f64,
+}
+
+impl Rate {
+    fn new(per_second: Option<f64>, burst: Option<u32>) -> Option<Rate> {
+        let per_second = per_second?.max(0.0);
+        let burst = burst.map_or(per_second.ceil(), f64::from).max(1.0);
+        Some(Rate { per_second, burst })
+    }
+
+    /// How many tokens can pile up.
+    pub(crate) fn burst(&self) -> f64 {
+        self.burst
+    }
+
+    /// A rate in bytes per second, at least one. The burst defaults to one
+    /// second's worth.
+    pub(crate) fn bytes(per_second: Option<u64>, burst: Option<u64>) -> Option<Rate> {
+        let per_second = per_second?.max(1) as f64;
+        let burst = burst.map_or(per_second, |burst| burst as f64).max(1.0);
+        Some(Rate { per_second, burst })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn full(rate: Rate, now: Instant) -> Self {
+        TokenBucket {
+            tokens: rate.burst,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, rate: Rate, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate.per_second).min(rate.burst);
+        self.updated = now;
+    }
+
+    /// Takes a token if there is one.
+    fn take(&mut self, rate: Rate, now: Instant) -> bool {
+        self.refill(rate, now);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// Takes `amount` tokens, going into debt if there are not enough, and
+    /// returns how long paying the debt back takes.
+    pub(crate) fn spend(&mut self, rate: Rate, now: Instant, amount: f64) -> Duration {
+        self.refill(rate, now);
+        self.tokens -= amount;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate.per_second)
+        }
+    }
+
+    /// Whether there are `amount` tokens, or as many as the bucket holds.
+    pub(crate) fn holds(&mut self, rate: Rate, now: Instant, amount: f64) -> bool {
+        self.refill(rate, now);
+        self.tokens >= amount.min(rate.burst)
+    }
+
+    fn is_full(&self, rate: Rate, now: Instant) -> bool {
+        let mut bucket = *self;
+        bucket.refill(rate, now);
+        bucket.tokens >= rate.burst
+    }
+}
+
+/// The buckets of one forwarding rule.
+pub struct RateLimiter {
+    rule: Option<(Rate, TokenBucket)>,
+    client_rate: Option<Rate>,
+    clients: HashMap<IpAddr, TokenBucket>,
+    max_clients: usize,
+    swept: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        let now = Instant::now();
+        RateLimiter {
+            rule: Rate::new(config.rate, config.burst).map(|rate| (rate, TokenBucket::full(rate, now))),
+            client_rate: Rate::new(config.per_ip_rate, config.per_ip_burst),
+            clients: HashMap::new(),
+            max_clients: MAX_CLIENT_BUCKETS,
+            swept: now,
+        }
+    }
+
+    /// Takes a token for a new connection from `client`, or returns the
+    /// limit it ran into. A client refused by its own limit takes nothing
+    /// from the rule's.
+    pub fn check(&mut self, client: IpAddr, now: Instant) -> Result<(), Limit> {
+        if let Some(rate) = self.client_rate {
+            if !self.clients.contains_key(&client) {
+                self.make_room(rate, now);
+            }
+            let bucket = self.clients.entry(client).or_insert_with(|| TokenBucket::full(rate, now));
+            if !bucket.take(rate, now) {
+                return Err(Limit::ClientRate);
+            }
+        }
+        if let Some((rate, bucket)) = &mut self.rule
+            && !bucket.take(*rate, now)
+        {
+            return Err(Limit::RuleRate);
+        }
+        Ok(())
+    }
+
+    /// The number of clients with a bucket.
+    pub fn clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Drops the buckets that refilled completely once in a while, and the
+    /// least recently used one when there is no room for another.
+    fn make_room(&mut self, rate: Rate, now: Instant) {
+        if self.clients.len() >= self.max_clients || now.saturating_duration_since(self.swept) >= SWEEP_INTERVAL {
+            self.clients.retain(|_, bucket| !bucket.is_full(rate, now));
+            self.swept = now;
+        }
+        if self.clients.len() >= self.max_clients {
+            let oldest = self.clients.iter().min_by_key(|(_, bucket)| bucket.updated).map(|(ip, _)| *ip);
+            if let Some(oldest) = oldest {
+                self.clients.remove(&oldest);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::observer::tests::ip;
+
+    fn config(rate: Option<f64>, per_ip_rate: Option<f64>, per_ip_burst: Option<u32>) -> RateLimitConfig {
+        RateLimitConfig {
+            rate,
+            burst: None,
+            per_ip_rate,
+            per_ip_burst,
+        }
+    }
+
+    #[test]
+    fn clients_get_their_burst_then_the_rate() {
+        let mut limiter = RateLimiter::new(&config(None, Some(2.0), Some(3)));
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(limiter.check(ip(1), now), Ok(()));
+        }
+        assert_eq!(limiter.check(ip(1), now), Err(Limit::ClientRate));
+        assert_eq!(limiter.check(ip(2), now), Ok(()));
+
+        // Two tokens per second
+        let later = now + Duration::from_millis(500);
+        assert_eq!(limiter.check(ip(1), later), Ok(()));
+        assert_eq!(limiter.check(ip(1), later), Err(Limit::ClientRate));
+    }
+
+    #[test]
+    fn the_rule_rate_covers_all_clients() {
+        let mut limiter = RateLimiter::new(&config(Some(2.0), Some(1.0), None));
+        let now = Instant::now();
+        assert_eq!(limiter.check(ip(1), now), Ok(()));
+        // Refused by its own limit, so the rule's token is left
+        assert_eq!(limiter.check(ip(1), now), Err(Limit::ClientRate));
+        assert_eq!(limiter.check(ip(2), now), Ok(()));
+        assert_eq!(limiter.check(ip(3), now), Err(Limit::RuleRate));
+        assert_eq!(limiter.check(ip(3), now + Duration::from_secs(1)), Ok(()));
+    }
+
+    #[test]
+    fn fractional_rates_allow_one_at_a_time() {
+        let mut limiter = RateLimiter::new(&config(Some(0.5), None, None));
+        let now = Instant::now();
+        assert_eq!(limiter.check(ip(1), now), Ok(()));
+        assert_eq!(limiter.check(ip(1), now + Duration::from_secs(1)), Err(Limit::RuleRate));
+        assert_eq!(limiter.check(ip(1), now + Duration::from_secs(2)), Ok(()));
+    }
+
+    #[test]
+    fn client_buckets_are_bounded() {
+        let mut limiter = RateLimiter::new(&config(None, Some(1.0), None));
+        limiter.max_clients = 3;
+        let now = Instant::now();
+        for last in 1..=3 {
+            limiter.check(ip(last), now + Duration::from_millis(u64::from(last))).unwrap();
+        }
+        // All buckets are empty, so the least recently used one goes
+        limiter.check(ip(4), now + Duration::from_millis(10)).unwrap();
+        assert_eq!(limiter.clients(), 3);
+        assert_eq!(limiter.check(ip(1), now + Duration::from_millis(20)), Ok(()));
+        assert_eq!(limiter.check(ip(4), now + Duration::from_millis(20)), Err(Limit::ClientRate));
+
+        // Refilled buckets are swept once in a while
+        limiter.check(ip(5), now + SWEEP_INTERVAL + Duration::from_secs(1)).unwrap();
+        assert_eq!(limiter.clients(), 1);
+    }
+}
@@ -0,0 +1,741 @@
+//! Runs one listener per forwarding rule and applies reloaded configs to the
+//! running set.
+//!
+//! Connections are spawned as detached tasks, so stopping a listener only
+//! stops accepting: connections it already accepted keep running until they
+//! end on their own, or until `Server::shutdown` gives up waiting for them.
+//! UDP sessions live in their forwarder, which keeps its socket and drains
+//! them instead of stopping.
+
+use crate::access_control::{AccessPolicy, SharedAccessPolicy};
+use crate::bans::BanList;
+use crate::config::{Config, ForwardingRule, LimitAction, Protocol};
+use crate::connections::{ConnectionTracker, KillTarget};
+use crate::error::ProxyError;
+use crate::filter::{FilterPipeline, FilterRegistry};
+use crate::ip_list::{self, IpLists};
+use crate::limits::{ConnectionLimits, RuleLimit};
+use crate::logging;
+use crate::observer::{ConnectionInfo, ConnectionObserver, Observers};
+use crate::target::{SharedTarget, Target};
+use crate::tcp_handler::run_tcp_listener;
+use crate::udp_handler::UdpForwarder;
+use futures_lite::future;
+use smol::net::TcpListener;
+use smol::Task;
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long shutdown waits for open connections when neither the config
+/// nor the command line sets a drain timeout.
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The open connections of every listener of a `Server`.
+#[derive(Clone, Default)]
+pub(crate) struct Trackers {
+    tcp: ConnectionTracker,
+    udp: ConnectionTracker,
+}
+
+impl Trackers {
+    /// Kills the open connections and UDP sessions that match `target`,
+    /// and returns them.
+    pub(crate) fn kill(&self, target: KillTarget) -> Vec<ConnectionInfo> {
+        let mut killed = self.tcp.kill(target);
+        killed.extend(self.udp.kill(target));
+        killed
+    }
+
+    pub(crate) fn count(&self, target: KillTarget) -> usize {
+        self.tcp.count(target) + self.udp.count(target)
+    }
+}
+
+/// Code plugged into the listeners of a `Server`.
+#[derive(Clone)]
+pub struct Hooks {
+    /// Receives the events of every connection.
+    pub observer: Arc<dyn ConnectionObserver>,
+    /// The filters that `custom` entries in a rule's `filters` refer to.
+    pub filters: FilterRegistry,
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Hooks {
+            observer: Arc::new(Observers::default()),
+            filters: FilterRegistry::default(),
+        }
+    }
+}
+
+/// How a paused rule keeps new clients out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PauseMode {
+    /// Close the listening socket, so the system refuses new clients.
+    /// Resuming binds it again. A UDP socket stays open until its sessions
+    /// have expired, dropping datagrams from new clients meanwhile.
+    #[default]
+    Close,
+    /// Keep the socket and refuse new clients as the access rules do: TCP
+    /// clients are disconnected right after accept and datagrams from new
+    /// UDP clients are dropped.
+    Refuse,
+}
+
+/// The state of a forwarding rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleState {
+    Listening,
+    /// Paused through `Server::pause`, or disabled in the config, which
+    /// counts as paused with `PauseMode::Close`.
+    Paused(PauseMode),
+    /// The listener could not be started.
+    Failed,
+}
+
+/// A forwarding rule and the state of its listener.
+#[derive(Debug, Clone)]
+pub struct RuleStatus {
+    pub rule: ForwardingRule,
+    pub state: RuleState,
+    /// The address the listening socket is bound to, if it is open.
+    pub local_addr: Option<SocketAddr>,
+}
+
+/// The listener of a rule. Rules paused with `PauseMode::Close` keep a
+/// `Listener` without socket or task.
+struct Listener {
+    rule: ForwardingRule,
+    local_addr: Option<SocketAddr>,
+    /// Where the rule forwards to, which a reload swaps in place.
+    target: SharedTarget,
+    /// The rule's access policy, which `access` refuses new clients on top
+    /// of while paused.
+    policy: AccessPolicy,
+    access: SharedAccessPolicy,
+    /// Counts the rule's connections, also against the server's limits.
+    limit: RuleLimit,
+    task: Option<Task<()>>,
+    /// Set to let the UDP forwarder of `task` finish its open sessions
+    /// instead of stopping it.
+    drain: Arc<AtomicBool>,
+    /// The forwarder task of a closed UDP listener with the address it is
+    /// bound to, until its open sessions have expired.
+    draining: Option<(Task<()>, SocketAddr)>,
+    paused: Option<PauseMode>,
+}
+
+impl Listener {
+    /// Binds the rule's socket and spawns its accept loop. An error that
+    /// ends the accept loop later is sent to `errors`.
+    async fn start(
+        rule: &ForwardingRule,
+        policy: AccessPolicy,
+        limit: RuleLimit,
+        trackers: &Trackers,
+        hooks: &Hooks,
+        errors: &async_channel::Sender<ListenerError>,
+    ) -> Result<Listener, ProxyError> {
+        let bind_addr = format!("{}:{}", rule.bind_address, rule.bind_port);
+        let connect_addr = format!("{}:{}", rule.connect_address, rule.connect_port);
+
+        // Resolve bind address
+        let bind_socket_addr = bind_addr
+            .parse::<SocketAddr>()
+            .map_err(|error| ProxyError::InvalidAddress { addr: bind_addr.clone(), error })?;
+
+        let target = SharedTarget::new(Target::for_rule(rule, hooks.filters.pipeline(&rule.filters)?));
+        let access = SharedAccessPolicy::new(policy.clone());
+        let drain = Arc::new(AtomicBool::new(false));
+        let errors = errors.clone();
+        let failed_rule = rule.clone();
+        let (task, local_addr) = match rule.protocol {
+            Protocol::Tcp | Protocol::TcpToUdp => {
+                let listener = TcpListener::bind(bind_socket_addr)
+                    .await
+                    .map_err(|error| ProxyError::Bind { addr: bind_socket_addr, error })?;
+                let local_addr = listener.local_addr()?;
+                let message = format!("Starting TCP forwarding from {} to {}", bind_addr, connect_addr);
+                listener_started(message, rule, local_addr);
+                let target = target.clone();
+                let access = access.clone();
+                let tracker = trackers.tcp.clone();
+                let limit = limit.clone();
+                let observer = hooks.observer.clone();
+                let task = smol::spawn(async move {
+                    let result = run_tcp_listener(listener, target, access, tracker, limit, observer).await;
+                    if let Err(error) = result {
+                        let _ = errors.try_send(ListenerError { rule: failed_rule, error });
+                    }
+                });
+                (task, local_addr)
+            }
+            Protocol::Udp | Protocol::UdpToTcp => {
+                let mut forwarder = UdpForwarder::new(
+                    bind_socket_addr,
+                    connect_addr.clone(),
+                    rule.timeout,
+                    rule.protocol.clone(),
+                )
+                .await?;
+                forwarder.set_target(target.clone());
+                forwarder.set_access_policy(access.clone());
+                forwarder.set_session_tracker(trackers.udp.clone());
+                forwarder.set_drain(drain.clone());
+                forwarder.set_limit(limit.clone());
+                forwarder.set_observer(hooks.observer.clone());
+                let local_addr = forwarder.local_addr()?;
+                let message = format!("Starting UDP forwarding from {} to {}", bind_addr, connect_addr);
+                listener_started(message, rule, local_addr);
+                let task = smol::spawn(async move {
+                    if let Err(error) = forwarder.run().await {
+                        let _ = errors.try_send(ListenerError { rule: failed_rule, error });
+                    }
+                });
+                (task, local_addr)
+            }
+        };
+
+        Ok(Listener {
+            rule: rule.clone(),
+            local_addr: Some(local_addr),
+            target,
+            policy,
+            access,
+            limit,
+            task: Some(task),
+            drain,
+            draining: None,
+            paused: None,
+        })
+    }
+
+    /// A listener for a rule that is paused from the start, like a
+    /// disabled one.
+    fn closed(rule: &ForwardingRule, policy: AccessPolicy, limit: RuleLimit) -> Listener {
+        Listener {
+            rule: rule.clone(),
+            local_addr: None,
+            // Starting the listener makes the target it forwards to
+            target: SharedTarget::new(Target::for_rule(rule, FilterPipeline::default())),
+            access: SharedAccessPolicy::new(policy.clone()),
+            policy,
+            limit,
+            task: None,
+            drain: Arc::default(),
+            draining: None,
+            paused: Some(PauseMode::Close),
+        }
+    }
+
+    /// Replaces the access policy, keeping new clients out while paused.
+    fn set_policy(&mut self, policy: AccessPolicy) {
+        self.policy = policy;
+        self.apply_policy();
+    }
+
+    fn apply_policy(&self) {
+        match self.paused {
+            Some(PauseMode::Refuse) => self.access.store(self.policy.clone().refusing_new()),
+            _ => self.access.store(self.policy.clone()),
+        }
+    }
+
+    /// Pauses a listener that has a socket.
+    async fn pause(&mut self, mode: PauseMode) {
+        self.paused = Some(mode);
+        match mode {
+            PauseMode::Close => {
+                if let Some(task) = self.task.take() {
+                    if self.is_udp() {
+                        // The forwarder keeps receiving for its open sessions
+                        self.drain.store(true, Ordering::SeqCst);
+                        self.draining = self.local_addr.map(|addr| (task, addr));
+                    } else {
+                        task.cancel().await;
+                    }
+                }
+                self.local_addr = None;
+            }
+            PauseMode::Refuse => self.apply_policy(),
+        }
+    }
+
+    /// True if the rule binds `addr`, or its listener is bound to it.
+    fn binds(&self, addr: SocketAddr) -> bool {
+        self.local_addr == Some(addr)
+            || format!("{}:{}", self.rule.bind_address, self.rule.bind_port).parse() == Ok(addr)
+    }
+
+    fn status(&self) -> RuleStatus {
+        RuleStatus {
+            rule: self.rule.clone(),
+            state: self.paused.map_or(RuleState::Listening, RuleState::Paused),
+            local_addr: self.local_addr,
+        }
+    }
+
+    /// Takes new clients again on the socket of a UDP forwarder that is
+    /// still draining. Returns false if there is none.
+    fn undrain(&mut self) -> bool {
+        let Some((task, addr)) = self.draining.take().filter(|(task, _)| !task.is_finished()) else {
+            return false;
+        };
+        self.drain.store(false, Ordering::SeqCst);
+        self.task = Some(task);
+        self.local_addr = Some(addr);
+        true
+    }
+
+    /// Stops accepting and waits until the listening socket is closed.
+    async fn stop(self) {
+        if let Some(task) = self.task {
+            task.cancel().await;
+        }
+    }
+
+    /// Stops accepting like `stop`, but leaves a UDP forwarder draining its
+    /// open sessions. Returns its task, to be kept until they have expired.
+    async fn retire(mut self) -> Option<(Task<()>, SocketAddr)> {
+        self.pause(PauseMode::Close).await;
+        self.draining.filter(|(task, _)| !task.is_finished())
+    }
+
+    fn is_udp(&self) -> bool {
+        is_udp(&self.rule)
+    }
+}
+
+/// Logs that the listener of `rule` is bound to `local_addr`.
+fn listener_started(message: String, rule: &ForwardingRule, local_addr: SocketAddr) {
+    logging::info("listener_started", message)
+        .field("rule", rule.to_string())
+        .field("local", local_addr.to_string())
+        .log();
+}
+
+fn is_udp(rule: &ForwardingRule) -> bool {
+    matches!(rule.protocol, Protocol::Udp | Protocol::UdpToTcp)
+}
+
+/// Two rules share a listener when they bind the same address, port and
+/// transport.
+fn same_listener(a: &ForwardingRule, b: &ForwardingRule) -> bool {
+    a.bind_address == b.bind_address && a.bind_port == b.bind_port && is_udp(a) == is_udp(b)
+}
+
+/// True when the rules differ in nothing but their access rules, connection
+/// limits and bandwidth limits.
+fn same_forwarding(a: &ForwardingRule, b: &ForwardingRule) -> bool {
+    a.bind_address == b.bind_address
+        && a.bind_port == b.bind_port
+        && a.connect_address == b.connect_address
+        && a.connect_port == b.connect_port
+        && a.protocol == b.protocol
+        && a.timeout == b.timeout
+        && a.source_address == b.source_address
+        && a.filters == b.filters
+        && a.enabled == b.enabled
+}
+
+/// What a reload did to the running listeners.
+#[derive(Debug, Default)]
+pub struct ReloadSummary {
+    /// Rules that got a new listener.
+    pub added: Vec<ForwardingRule>,
+    /// Rules whose listener was stopped.
+    pub removed: Vec<ForwardingRule>,
+    /// Rules whose target, timeout or filters changed, which their listener
+    /// uses for new clients from now on, and rules that were enabled or
+    /// disabled, whose listener was started or stopped.
+    pub changed: Vec<ForwardingRule>,
+    /// Rules that kept their listener; their access rules were replaced.
+    /// Rules paused through `Server::pause` stay paused.
+    pub unchanged: usize,
+    /// Added or changed rules whose listener could not be started. Their
+    /// errors are reported through `Server::errors`. Changed rules keep
+    /// their listener: a rule whose new filters fail keeps forwarding as
+    /// before, and an enabled rule that cannot be bound stays paused.
+    pub failed: Vec<ForwardingRule>,
+}
+
+/// A rule whose listener could not be started, or stopped with an error.
+#[derive(Debug)]
+pub struct ListenerError {
+    pub rule: ForwardingRule,
+    pub error: ProxyError,
+}
+
+impl fmt::Display for ListenerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.rule, self.error)
+    }
+}
+
+impl std::error::Error for ListenerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Connections that were still open when shutdown stopped waiting.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct DrainReport {
+    pub tcp_connections: usize,
+    pub udp_sessions: usize,
+}
+
+/// The listeners of all forwarding rules of a config.
+pub struct Server {
+    listeners: Vec<Listener>,
+    trackers: Trackers,
+    limits: ConnectionLimits,
+    /// The config's `limit_action`, for rules that do not set their own.
+    limit_action: LimitAction,
+    bans: BanList,
+    /// The forwarder tasks of removed UDP rules with the address they are
+    /// bound to, until their open sessions have expired.
+    draining: Vec<(Task<()>, SocketAddr)>,
+    /// The list files of access rules, with the task refreshing them.
+    ip_lists: IpLists,
+    _refresh_lists: Task<()>,
+    hooks: Hooks,
+    errors_tx: async_channel::Sender<ListenerError>,
+    errors_rx: async_channel::Receiver<ListenerError>,
+}
+
+impl Server {
+    /// Starts a listener for every enabled forwarding rule, with the
+    /// observer and filters of `hooks`. Rules that fail to bind are skipped
+    /// so the others keep working; their errors are reported through
+    /// `errors`.
+    pub async fn start(config: &Config, hooks: Hooks) -> Self {
+        let (errors_tx, errors_rx) = async_channel::unbounded();
+        let bans = BanList::new();
+        bans.set(config.auto_ban.as_ref());
+        // The ban list counts strikes from the events of every connection
+        let mut observers = Observers::default();
+        observers.push(hooks.observer);
+        observers.push(Arc::new(bans.clone()));
+        let hooks = Hooks {
+            observer: Arc::new(observers),
+            ..hooks
+        };
+        let ip_lists = IpLists::new();
+        let lists = ip_lists.clone();
+        let refresh_lists = smol::spawn(async move { lists.refresh_every(ip_list::REFRESH_INTERVAL).await });
+        let mut server = Server {
+            listeners: Vec::new(),
+            trackers: Trackers::default(),
+            limits: ConnectionLimits::new(config.max_connections, config.max_connections_per_ip),
+            limit_action: config.limit_action,
+            bans,
+            draining: Vec::new(),
+            ip_lists,
+            _refresh_lists: refresh_lists,
+            hooks,
+            errors_tx,
+            errors_rx,
+        };
+        for rule in &config.forwarding_rules {
+            let policy = server.access_policy(config, rule);
+            let limit = server.rule_limit(rule);
+            if let Some(listener) = server.start_listener(rule, policy, limit).await {
+                server.listeners.push(listener);
+            }
+        }
+        server
+    }
+
+    /// The access policy of `rule`, with the server's bans and list files.
+    fn access_policy(&self, config: &Config, rule: &ForwardingRule) -> AccessPolicy {
+        AccessPolicy::for_rule(config, rule, &self.ip_lists).with_bans(self.bans.clone())
+    }
+
+    /// The limit action of `rule`, or the config's.
+    fn limit_action(&self, rule: &ForwardingRule) -> LimitAction {
+        rule.limit_action.unwrap_or(self.limit_action)
+    }
+
+    fn rule_limit(&self, rule: &ForwardingRule) -> RuleLimit {
+        let limit = self.limits.rule(rule.max_connections, self.limit_action(rule));
+        limit.set_rate_limit(rule.rate_limit.as_ref());
+        limit.set_bandwidth(rule.bandwidth.as_ref());
+        limit
+    }
+
+    /// Starts the listener of `rule`, or keeps it closed if the rule is
+    /// disabled.
+    async fn start_listener(&self, rule: &ForwardingRule, policy: AccessPolicy, limit: RuleLimit) -> Option<Listener> {
+        if !rule.enabled {
+            logging::info("rule_disabled", format!("Not starting disabled rule {}", rule))
+                .field("rule", rule.to_string())
+                .log();
+            return Some(Listener::closed(rule, policy, limit));
+        }
+        self.bind_listener(rule, policy, limit).await
+    }
+
+    async fn bind_listener(&self, rule: &ForwardingRule, policy: AccessPolicy, limit: RuleLimit) -> Option<Listener> {
+        match Listener::start(rule, policy, limit, &self.trackers, &self.hooks, &self.errors_tx).await {
+            Ok(listener) => Some(listener),
+            Err(error) => {
+                let _ = self.errors_tx.try_send(ListenerError { rule: rule.clone(), error });
+                None
+            }
+        }
+    }
+
+    /// Receives the errors of listeners that failed to start or stopped
+    /// accepting, in the order they happened.
+    pub fn errors(&self) -> async_channel::Receiver<ListenerError> {
+        self.errors_rx.clone()
+    }
+
+    /// Kills the open connections and UDP sessions that match `target`,
+    /// and returns them. Connections of stopped listeners are included.
+    pub fn kill(&self, target: KillTarget) -> Vec<ConnectionInfo> {
+        self.trackers.kill(target)
+    }
+
+    /// The clients banned from every rule.
+    pub fn bans(&self) -> BanList {
+        self.bans.clone()
+    }
+
+    pub(crate) fn trackers(&self) -> Trackers {
+        self.trackers.clone()
+    }
+
+    /// The rules that currently have a listening socket.
+    pub fn rules(&self) -> impl Iterator<Item = &ForwardingRule> {
+        self.local_addrs().map(|(rule, _)| rule)
+    }
+
+    /// The rules that currently have a listening socket, with the address
+    /// it is bound to. For rules that bind port 0 this is the port the
+    /// system picked.
+    pub fn local_addrs(&self) -> impl Iterator<Item = (&ForwardingRule, SocketAddr)> {
+        self.listeners
+            .iter()
+            .filter_map(|listener| Some((&listener.rule, listener.local_addr?)))
+    }
+
+    /// The state of every rule that is listening or paused. Rules whose
+    /// listener failed to start are left out.
+    pub fn status(&self) -> Vec<RuleStatus> {
+        self.listeners.iter().map(Listener::status).collect()
+    }
+
+    /// Pauses the rules that bind `addr`, or whose listener is bound to it.
+    /// Their open connections are left alone. Returns the new state of the
+    /// matching rules.
+    pub async fn pause(&mut self, addr: SocketAddr, mode: PauseMode) -> Vec<RuleStatus> {
+        let mut paused = Vec::new();
+        for index in 0..self.listeners.len() {
+            if !self.listeners[index].binds(addr) {
+                continue;
+            }
+            // Refusing needs the socket that a closed rule does not have
+            if mode == PauseMode::Refuse && self.listeners[index].task.is_none() {
+                self.resume_listener(index).await;
+            }
+            let listener = &mut self.listeners[index];
+            if listener.task.is_some() {
+                listener.pause(mode).await;
+                logging::info("rule_paused", format!("Paused rule {}", listener.rule))
+                    .field("rule", listener.rule.to_string())
+                    .field("mode", format!("{:?}", mode).to_lowercase())
+                    .log();
+            }
+            paused.push(listener.status());
+        }
+        paused
+    }
+
+    /// Resumes the paused or disabled rules that bind `addr`, or whose
+    /// listener is bound to it. Rules whose socket cannot be bound again
+    /// stay paused; the errors are reported through `errors`. Returns the
+    /// new state of the matching rules.
+    pub async fn resume(&mut self, addr: SocketAddr) -> Vec<RuleStatus> {
+        let mut resumed = Vec::new();
+        for index in 0..self.listeners.len() {
+            if self.listeners[index].binds(addr) {
+                self.resume_listener(index).await;
+                let listener = &self.listeners[index];
+                if listener.paused.is_none() {
+                    logging::info("rule_resumed", format!("Resumed rule {}", listener.rule))
+                        .field("rule", listener.rule.to_string())
+                        .log();
+                }
+                resumed.push(listener.status());
+            }
+        }
+        resumed
+    }
+
+    async fn resume_listener(&mut self, index: usize) {
+        let listener = &mut self.listeners[index];
+        if listener.task.is_some() || listener.undrain() {
+            listener.paused = None;
+            listener.apply_policy();
+            return;
+        }
+        let (rule, policy, limit) = (listener.rule.clone(), listener.policy.clone(), listener.limit.clone());
+        if let Some(started) = self.bind_listener(&rule, policy, limit).await {
+            self.listeners[index] = started;
+        }
+    }
+
+    /// Applies a new config: listeners of removed rules are stopped, added
+    /// rules get a listener, and rules that were enabled or disabled are
+    /// started or stopped. Other listeners keep their socket and get the new
+    /// target, access rules and connection limits swapped in place. UDP
+    /// forwarders of removed rules keep forwarding for their open sessions
+    /// until they expire, unless a new listener needs their address.
+    pub async fn reload(&mut self, config: &Config) -> ReloadSummary {
+        let mut summary = ReloadSummary::default();
+        self.draining.retain(|(task, _)| !task.is_finished());
+        self.limits.set(config.max_connections, config.max_connections_per_ip);
+        self.limit_action = config.limit_action;
+        self.bans.set(config.auto_ban.as_ref());
+        let mut old = std::mem::take(&mut self.listeners);
+        let mut to_start = Vec::new();
+
+        for rule in &config.forwarding_rules {
+            let policy = self.access_policy(config, rule);
+            match old.iter().position(|listener| same_listener(&listener.rule, rule)) {
+                Some(index) if old[index].rule.enabled == rule.enabled => {
+                    let mut listener = old.swap_remove(index);
+                    let changed = !same_forwarding(&listener.rule, rule);
+                    if changed {
+                        match self.hooks.filters.pipeline(&rule.filters) {
+                            Ok(filters) => listener.target.store(Target::for_rule(rule, filters)),
+                            Err(error) => {
+                                let _ = self.errors_tx.try_send(ListenerError { rule: rule.clone(), error });
+                                summary.failed.push(rule.clone());
+                                self.listeners.push(listener);
+                                continue;
+                            }
+                        }
+                    }
+                    listener.set_policy(policy);
+                    listener.limit.set(rule.max_connections, self.limit_action(rule));
+                    listener.limit.set_rate_limit(rule.rate_limit.as_ref());
+                    listener.limit.set_bandwidth(rule.bandwidth.as_ref());
+                    listener.rule = rule.clone();
+                    self.listeners.push(listener);
+                    if changed {
+                        summary.changed.push(rule.clone());
+                    } else {
+                        summary.unchanged += 1;
+                    }
+                }
+                Some(index) => {
+                    self.retire(old.swap_remove(index)).await;
+                    to_start.push((rule, policy, true));
+                }
+                None => to_start.push((rule, policy, false)),
+            }
+        }
+
+        // Close the sockets of removed rules before binding new ones, in
+        // case an added rule reuses a port.
+        for listener in old {
+            summary.removed.push(listener.rule.clone());
+            self.retire(listener).await;
+        }
+
+        for (rule, policy, changed) in to_start {
+            self.reclaim(rule).await;
+            let limit = self.rule_limit(rule);
+            match self.start_listener(rule, policy.clone(), limit.clone()).await {
+                Some(listener) => {
+                    self.listeners.push(listener);
+                    if changed {
+                        summary.changed.push(rule.clone());
+                    } else {
+                        summary.added.push(rule.clone());
+                    }
+                }
+                None => {
+                    // An enabled rule whose socket cannot be bound stays
+                    // paused, so that resuming can try again
+                    if changed {
+                        self.listeners.push(Listener::closed(rule, policy, limit));
+                    }
+                    summary.failed.push(rule.clone());
+                }
+            }
+        }
+        self.ip_lists.retain_used();
+
+        summary
+    }
+
+    async fn retire(&mut self, listener: Listener) {
+        self.draining.extend(listener.retire().await);
+    }
+
+    /// Stops the draining UDP forwarders of removed rules that are bound
+    /// where `rule` binds, ending their sessions, so that its socket can
+    /// be bound.
+    async fn reclaim(&mut self, rule: &ForwardingRule) {
+        let Ok(addr) = format!("{}:{}", rule.bind_address, rule.bind_port).parse::<SocketAddr>() else {
+            return;
+        };
+        if !is_udp(rule) || addr.port() == 0 {
+            return;
+        }
+        let (reclaimed, draining) = std::mem::take(&mut self.draining)
+            .into_iter()
+            .partition(|(_, bound)| *bound == addr);
+        self.draining = draining;
+        for (task, _) in reclaimed {
+            task.cancel().await;
+        }
+    }
+
+    /// Stops accepting new clients, then waits for open TCP connections and
+    /// UDP sessions to end until `deadline` resolves. Whatever is still open
+    /// at that point is closed and reported.
+    pub async fn shutdown(self, deadline: impl Future<Output = ()>) -> DrainReport {
+        // UDP listeners keep receiving for their open sessions
+        self.trackers.udp.start_draining();
+        let mut udp_tasks: Vec<_> = self.draining.into_iter().map(|(task, _)| task).collect();
+        for mut listener in self.listeners {
+            udp_tasks.extend(listener.draining.take().map(|(task, _)| task));
+            if listener.is_udp() {
+                udp_tasks.extend(listener.task);
+            } else {
+                listener.stop().await;
+            }
+        }
+
+        let drained = async {
+            self.trackers.tcp.wait_idle().await;
+            self.trackers.udp.wait_idle().await;
+        };
+        future::or(drained, deadline).await;
+
+        let report = DrainReport {
+            tcp_connections: self.trackers.tcp.active(),
+            udp_sessions: self.trackers.udp.active(),
+        };
+        self.trackers.tcp.close_all();
+        for task in udp_tasks {
+            task.cancel().await;
+        }
+        report
+    }
+
+    /// Stops every listener and closes all connections right away.
+    pub async fn stop(self) -> DrainReport {
+        self.shutdown(future::ready(())).await
+    }
+}
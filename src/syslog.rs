@@ -0,0 +1,349 @@
+//! Sends log messages to a syslog daemon, over the local `/dev/log` socket
+//! or UDP or TCP, in the RFC 3164 or RFC 5424 format.
+//!
+//! The logger hands its messages to a `SyslogWriter`, which sends them from
+//! a dedicated thread so that a slow or unreachable daemon never holds up
+//! the forwarding tasks.
+
+use crate::config::{SyslogConfig, SyslogFormat};
+use crate::logging::{Level, Timestamp};
+use std::io::{self, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{self, SyncSender};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long to wait for a TCP syslog daemon to accept a connection.
+const TCP_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a message may take to write before it is given up.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long to wait before connecting again to a TCP syslog daemon that
+/// could not be reached. Messages sent meanwhile are dropped.
+const TCP_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// How many messages may wait for the writer thread. Further messages are
+/// dropped until it catches up.
+const QUEUE_LEN: usize = 1024;
+
+enum Transport {
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixDatagram, std::path::PathBuf),
+    Udp(UdpSocket, SocketAddr),
+    /// Connected on first use, and again after a failed write, but not
+    /// before `retry_at` after a failed connect.
+    Tcp {
+        stream: Option<TcpStream>,
+        addr: SocketAddr,
+        retry_at: Option<Instant>,
+    },
+}
+
+/// A connection to a syslog daemon.
+pub struct Syslog {
+    transport: Transport,
+    format: SyslogFormat,
+    facility: u8,
+    ident: String,
+    hostname: String,
+    pid: u32,
+}
+
+impl Syslog {
+    pub fn connect(config: &SyslogConfig) -> io::Result<Self> {
+        let address = config.address.as_deref().unwrap_or("/dev/log");
+        let transport = if let Some(addr) = address.strip_prefix("udp://") {
+            let addr = resolve(addr)?;
+            let local: SocketAddr = if addr.is_ipv4() {
+                (Ipv4Addr::UNSPECIFIED, 0).into()
+            } else {
+                (Ipv6Addr::UNSPECIFIED, 0).into()
+            };
+            let socket = UdpSocket::bind(local)?;
+            socket.set_write_timeout(Some(WRITE_TIMEOUT))?;
+            Transport::Udp(socket, addr)
+        } else if let Some(addr) = address.strip_prefix("tcp://") {
+            Transport::Tcp {
+                stream: None,
+                addr: resolve(addr)?,
+                retry_at: None,
+            }
+        } else {
+            unix_transport(address)?
+        };
+        Ok(Syslog {
+            transport,
+            format: config.format,
+            facility: config.facility as u8,
+            ident: config.ident.clone().unwrap_or_else(|| "oi".to_string()),
+            hostname: hostname(),
+            pid: std::process::id(),
+        })
+    }
+
+    /// Sends `message` at the severity of `level`. `msgid` is the event
+    /// name, used by the RFC 5424 format.
+    pub fn send(&mut self, level: Level, msgid: &str, message: &str, time: SystemTime) -> io::Result<()> {
+        let message = self.format_message(level, msgid, message, time);
+        match &mut self.transport {
+            #[cfg(unix)]
+            Transport::Unix(socket, path) => socket.send_to(message.as_bytes(), &*path).map(drop),
+            Transport::Udp(socket, addr) => socket.send_to(message.as_bytes(), *addr).map(drop),
+            Transport::Tcp { stream, addr, retry_at } => {
+                if stream.is_none() {
+                    if retry_at.is_some_and(|retry_at| Instant::now() < retry_at) {
+                        return Err(io::Error::new(io::ErrorKind::NotConnected, "waiting to reconnect"));
+                    }
+                    let connected = TcpStream::connect_timeout(addr, TCP_CONNECT_TIMEOUT)
+                        .and_then(|connected| connected.set_write_timeout(Some(WRITE_TIMEOUT)).map(|_| connected));
+                    match connected {
+                        Ok(connected) => *stream = Some(connected),
+                        Err(e) => {
+                            *retry_at = Some(Instant::now() + TCP_RECONNECT_DELAY);
+                            return Err(e);
+                        }
+                    }
+                }
+                // Octet counting framing, RFC 6587
+                let framed = format!("{} {}", message.len(), message);
+                let written = stream.as_mut().expect("connected above").write_all(framed.as_bytes());
+                if written.is_err() {
+                    *stream = None;
+                }
+                written
+            }
+        }
+    }
+
+    fn format_message(&self, level: Level, msgid: &str, message: &str, time: SystemTime) -> String {
+        let priority = self.facility * 8 + severity(level);
+        let time = Timestamp::new(time);
+        match self.format {
+            SyslogFormat::Rfc3164 => {
+                // The local daemon adds the hostname itself
+                let hostname = match self.transport {
+                    #[cfg(unix)]
+                    Transport::Unix(..) => String::new(),
+                    _ => format!("{} ", self.hostname),
+                };
+                format!("<{}>{} {}{}[{}]: {}", priority, time.rfc3164(), hostname, self.ident, self.pid, message)
+            }
+            SyslogFormat::Rfc5424 => format!(
+                "<{}>1 {} {} {} {} {} - {}",
+                priority,
+                time.rfc3339(),
+                self.hostname,
+                self.ident,
+                self.pid,
+                msgid,
+                message
+            ),
+        }
+    }
+}
+
+/// A message waiting for the writer thread.
+struct Message {
+    level: Level,
+    msgid: &'static str,
+    message: String,
+    time: SystemTime,
+}
+
+/// Sends messages to a `Syslog` from a dedicated thread. The thread stops
+/// when the writer is dropped.
+pub struct SyslogWriter {
+    queue: SyncSender<Message>,
+}
+
+impl SyslogWriter {
+    pub fn spawn(mut syslog: Syslog) -> Self {
+        let (queue, messages) = mpsc::sync_channel::<Message>(QUEUE_LEN);
+        std::thread::spawn(move || {
+            for Message { level, msgid, message, time } in messages {
+                // A failing syslog daemon must not take the proxy down
+                let _ = syslog.send(level, msgid, &message, time);
+            }
+        });
+        SyslogWriter { queue }
+    }
+
+    /// Queues `message` for `Syslog::send`, or drops it when the queue is
+    /// full. Returns whether it was queued.
+    pub fn send(&self, level: Level, msgid: &'static str, message: String, time: SystemTime) -> bool {
+        self.queue.try_send(Message { level, msgid, message, time }).is_ok()
+    }
+}
+
+/// The syslog severity of `level`.
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+fn resolve(addr: &str) -> io::Result<SocketAddr> {
+    addr.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("cannot resolve {}", addr)))
+}
+
+#[cfg(unix)]
+fn unix_transport(path: &str) -> io::Result<Transport> {
+    let socket = std::os::unix::net::UnixDatagram::unbound()?;
+    socket.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    Ok(Transport::Unix(socket, path.into()))
+}
+
+#[cfg(not(unix))]
+fn unix_transport(path: &str) -> io::Result<Transport> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("cannot log to {}: only udp:// and tcp:// syslog addresses are supported here", path),
+    ))
+}
+
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is valid for its whole length, and gethostname
+    // writes at most that many bytes.
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return "-".to_string();
+    }
+    let len = buf.iter().position(|byte| *byte == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "-".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SyslogFacility;
+    use std::time::UNIX_EPOCH;
+
+    // 2026-10-08T12:34:56.789Z
+    fn time() -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(1_791_462_896_789)
+    }
+
+    fn udp_syslog(format: SyslogFormat) -> (Syslog, UdpSocket) {
+        let daemon = UdpSocket::bind("127.0.0.1:0").unwrap();
+        daemon.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let config = SyslogConfig {
+            address: Some(format!("udp://{}", daemon.local_addr().unwrap())),
+            format,
+            facility: SyslogFacility::Local3,
+            ident: Some("edge".to_string()),
+        };
+        (Syslog::connect(&config).unwrap(), daemon)
+    }
+
+    fn receive(daemon: &UdpSocket) -> String {
+        let mut buf = [0u8; 2048];
+        let len = daemon.recv(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    }
+
+    #[test]
+    fn rfc3164_messages_over_udp() {
+        let (mut syslog, daemon) = udp_syslog(SyslogFormat::Rfc3164);
+        syslog.hostname = "host".to_string();
+        syslog.send(Level::Warn, "listener_error", "bind failed", time()).unwrap();
+        // local3 is 19, warning is 4
+        assert_eq!(receive(&daemon), format!("<156>Oct  8 12:34:56 host edge[{}]: bind failed", syslog.pid));
+    }
+
+    #[test]
+    fn rfc5424_messages_over_udp() {
+        let (mut syslog, daemon) = udp_syslog(SyslogFormat::Rfc5424);
+        syslog.hostname = "host".to_string();
+        syslog.send(Level::Info, "connection_closed", "done", time()).unwrap();
+        assert_eq!(
+            receive(&daemon),
+            format!("<158>1 2026-10-08T12:34:56.789Z host edge {} connection_closed - done", syslog.pid)
+        );
+    }
+
+    #[test]
+    fn tcp_messages_are_octet_counted() {
+        let daemon = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = SyslogConfig {
+            address: Some(format!("tcp://{}", daemon.local_addr().unwrap())),
+            ..SyslogConfig::default()
+        };
+        let mut syslog = Syslog::connect(&config).unwrap();
+        syslog.send(Level::Error, "server_error", "boom", time()).unwrap();
+        let (mut stream, _) = daemon.accept().unwrap();
+        let mut buf = [0u8; 256];
+        let len = std::io::Read::read(&mut stream, &mut buf).unwrap();
+        let received = String::from_utf8_lossy(&buf[..len]);
+        let (length, message) = received.split_once(' ').unwrap();
+        assert_eq!(length.parse::<usize>().unwrap(), message.len());
+        // daemon is 3, err is 3
+        assert!(message.starts_with("<27>Oct  8 12:34:56 "), "{}", message);
+        assert!(message.ends_with(&format!("oi[{}]: boom", syslog.pid)), "{}", message);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn local_messages_leave_out_the_hostname() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        let daemon = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+        let config = SyslogConfig {
+            address: Some(path.to_str().unwrap().to_string()),
+            ..SyslogConfig::default()
+        };
+        let mut syslog = Syslog::connect(&config).unwrap();
+        syslog.send(Level::Info, "startup", "hello", time()).unwrap();
+        let mut buf = [0u8; 256];
+        let len = daemon.recv(&mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&buf[..len]),
+            format!("<30>Oct  8 12:34:56 oi[{}]: hello", syslog.pid)
+        );
+    }
+
+    #[test]
+    fn unreachable_tcp_daemons_are_retried_after_a_delay() {
+        let daemon = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = daemon.local_addr().unwrap();
+        drop(daemon);
+        let config = SyslogConfig {
+            address: Some(format!("tcp://{}", addr)),
+            ..SyslogConfig::default()
+        };
+        let mut syslog = Syslog::connect(&config).unwrap();
+        let error = syslog.send(Level::Info, "startup", "hello", time()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+
+        let _daemon = std::net::TcpListener::bind(addr).unwrap();
+        let error = syslog.send(Level::Info, "startup", "hello", time()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotConnected);
+    }
+
+    #[test]
+    fn writer_sends_from_its_thread() {
+        let (syslog, daemon) = udp_syslog(SyslogFormat::Rfc5424);
+        let writer = SyslogWriter::spawn(syslog);
+        assert!(writer.send(Level::Info, "startup", "hello".to_string(), time()));
+        assert!(receive(&daemon).ends_with(" startup - hello"));
+    }
+
+    #[test]
+    fn unknown_hosts_are_reported() {
+        let config = SyslogConfig {
+            address: Some("udp://no-such-host.invalid:514".to_string()),
+            ..SyslogConfig::default()
+        };
+        assert!(Syslog::connect(&config).is_err());
+    }
+}
@@ -0,0 +1,91 @@
+//! Where a listener forwards its clients to. Listeners read it for every new
+//! connection or UDP datagram, so a reload can point a rule elsewhere without
+//! closing its socket.
+
+use crate::config::{ForwardingRule, Protocol};
+use crate::filter::FilterPipeline;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// How long a UDP session lasts without traffic when the rule sets no
+/// `timeout`.
+pub const DEFAULT_UDP_TIMEOUT: Duration = Duration::from_secs(72);
+
+/// The upstream side of a forwarding rule.
+#[derive(Clone)]
+pub struct Target {
+    /// The `host:port` to connect to.
+    pub connect_addr: String,
+    pub protocol: Protocol,
+    /// How long a UDP session lasts without traffic.
+    pub timeout: Duration,
+    /// The filters that the data of new connections passes through.
+    pub filters: FilterPipeline,
+}
+
+impl Target {
+    pub fn new(connect_addr: String, protocol: Protocol, timeout: Option<u64>) -> Self {
+        Target {
+            connect_addr,
+            protocol,
+            timeout: timeout.map_or(DEFAULT_UDP_TIMEOUT, Duration::from_secs),
+            filters: FilterPipeline::default(),
+        }
+    }
+
+    /// The target of `rule`, passing the data through `filters`.
+    pub fn for_rule(rule: &ForwardingRule, filters: FilterPipeline) -> Self {
+        let connect_addr = format!("{}:{}", rule.connect_address, rule.connect_port);
+        Target {
+            filters,
+            ..Target::new(connect_addr, rule.protocol.clone(), rule.timeout)
+        }
+    }
+}
+
+/// A `Target` shared by a listener and the server, which swaps it on
+/// reload. Connections and UDP sessions keep the target they started with.
+#[derive(Clone)]
+pub struct SharedTarget(Arc<RwLock<Arc<Target>>>);
+
+impl SharedTarget {
+    pub fn new(target: Target) -> Self {
+        SharedTarget(Arc::new(RwLock::new(Arc::new(target))))
+    }
+
+    pub fn load(&self) -> Arc<Target> {
+        self.0.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn store(&self, target: Target) {
+        *self.0.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(target);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn target_of_rule() {
+        let mut rule: ForwardingRule = toml::from_str(
+            r#"bind_address = "0.0.0.0"
+bind_port = 53
+connect_address = "10.0.0.1"
+connect_port = 5353
+protocol = "udp"
+"#,
+        )
+        .unwrap();
+        let target = Target::for_rule(&rule, FilterPipeline::default());
+        assert_eq!(target.connect_addr, "10.0.0.1:5353");
+        assert_eq!(target.protocol, Protocol::Udp);
+        assert_eq!(target.timeout, DEFAULT_UDP_TIMEOUT);
+
+        rule.timeout = Some(5);
+        let shared = SharedTarget::new(target);
+        let listener_view = shared.clone();
+        shared.store(Target::for_rule(&rule, FilterPipeline::default()));
+        assert_eq!(listener_view.load().timeout, Duration::from_secs(5));
+    }
+}
@@ -1,29 +1,84 @@
+use crate::access_control::SharedAccessPolicy;
+use crate::bandwidth::Shaper;
+use crate::config::LimitAction;
+use crate::connections::ConnectionTracker;
+use crate::error::ProxyError;
+use crate::filter::{FilterChain, FilterPipeline};
+use crate::limits::{Limit, RuleLimit};
+use crate::observer::{
+    CloseReason, ConnectionInfo, ConnectionObserver, Direction, ObservedConnection, Observers,
+};
+use crate::target::{SharedTarget, Target};
 use smol::net::{TcpListener, TcpStream, UdpSocket};
-use smol::io;
 use smol::io::{AsyncReadExt, AsyncWriteExt};
-use std::net::SocketAddr;
-use std::error::Error;
+use std::sync::Arc;
+use std::time::Instant;
 
 pub async fn handle_tcp_connection(
-    mut client_stream: TcpStream,
+    client_stream: TcpStream,
     server_addr: String,
     protocol: crate::config::Protocol,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
+) -> Result<(), ProxyError> {
+    let info = ConnectionInfo::new(
+        protocol.clone(),
+        client_stream.peer_addr()?,
+        client_stream.local_addr()?,
+        server_addr.clone(),
+    );
+    let filters = FilterPipeline::default().start(&info);
+    let shaper = Shaper::unlimited(info.client_addr.ip());
+    let connection = ObservedConnection::accept(Arc::new(Observers::default()), info);
+    relay(client_stream, &server_addr, protocol, &connection, &filters, &shaper).await
+}
+
+/// Forwards one accepted connection through `filters` at the pace `shaper`
+/// allows, reporting the upstream connect and every transfer to
+/// `connection`.
+async fn relay(
+    mut client_stream: TcpStream,
+    server_addr: &str,
+    protocol: crate::config::Protocol,
+    connection: &ObservedConnection,
+    filters: &FilterChain,
+    shaper: &Shaper,
+) -> Result<(), ProxyError> {
+    let connect_error = |error| ProxyError::Connect { addr: server_addr.to_string(), error };
     match protocol {
         crate::config::Protocol::Tcp => {
-            let server_stream = TcpStream::connect(&server_addr).await?;
+            let started = Instant::now();
+            let server_stream = TcpStream::connect(server_addr).await.map_err(connect_error);
+            connection.connected(started, &server_stream);
+            let server_stream = server_stream?;
             
-            // Use smol's copy function to forward data in both directions
-            let client_to_server = io::copy(client_stream.clone(), server_stream.clone());
-            let server_to_client = io::copy(server_stream, client_stream);
+            // Forward data in both directions. When one side stops sending,
+            // pass the half-close on so the relay ends once both sides are
+            // done.
+            let client_to_server = copy_then_shutdown(
+                client_stream.clone(),
+                server_stream.clone(),
+                connection,
+                filters,
+                shaper,
+                Direction::ClientToUpstream,
+            );
+            let server_to_client = copy_then_shutdown(
+                server_stream,
+                client_stream,
+                connection,
+                filters,
+                shaper,
+                Direction::UpstreamToClient,
+            );
             
             futures_lite::future::try_zip(client_to_server, server_to_client).await?;
         },
         crate::config::Protocol::TcpToUdp => {
             // Create a UDP socket for forwarding
             let udp_socket = UdpSocket::bind("0.0.0.0:0").await?;
-            let server_socket_addr: SocketAddr = server_addr.parse()?;
-            udp_socket.connect(&server_socket_addr).await?;
+            let started = Instant::now();
+            let connected = udp_socket.connect(server_addr).await.map_err(connect_error);
+            connection.connected(started, &connected);
+            connected?;
             
             // Buffer for data transfer
             let mut tcp_buffer = vec![0; 65536];
@@ -35,64 +90,176 @@ pub async fn handle_tcp_connection(
                     Ok(0) => break, // Connection closed
                     Ok(n) => {
                         // Forward data to UDP server
-                        udp_socket.send(&tcp_buffer[..n]).await?;
+                        if let Some(data) = filters.process(Direction::ClientToUpstream, &tcp_buffer[..n])? {
+                            for chunk in data.chunks(shaper.chunk_size()) {
+                                shaper.throttle(chunk.len()).await;
+                                udp_socket.send(chunk).await?;
+                            }
+                            connection.transferred(Direction::ClientToUpstream, data.len());
+                        }
                         // Consume the data we just peeked at
                         let _ = client_stream.read(&mut tcp_buffer[..n]).await?;
                     },
                     Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                         // No data available right now, continue
                     },
-                    Err(e) => return Err(Box::new(e)),
+                    Err(e) => return Err(e.into()),
                 }
                 
                 // Try to read from UDP server with a timeout
                 let result = smol::future::or(
-                    async {
-                        let len = udp_socket.recv(&mut udp_buffer).await?;
-                        Ok::<usize, Box<dyn Error + Send + Sync>>(len)
-                    },
+                    udp_socket.recv(&mut udp_buffer),
                     async {
                         smol::Timer::after(std::time::Duration::from_millis(100)).await;
-                        Ok::<usize, Box<dyn Error + Send + Sync>>(0)
+                        Ok(0)
                     }
                 ).await;
                 
                 match result {
                     Ok(len) if len > 0 => {
                         // Forward data to TCP client
-                        client_stream.write_all(&udp_buffer[..len]).await?;
+                        if let Some(data) = filters.process(Direction::UpstreamToClient, &udp_buffer[..len])? {
+                            for chunk in data.chunks(shaper.chunk_size()) {
+                                shaper.throttle(chunk.len()).await;
+                                client_stream.write_all(chunk).await?;
+                            }
+                            connection.transferred(Direction::UpstreamToClient, data.len());
+                        }
                     },
                     Ok(_) => {}, // Timeout, no data
-                    Err(e) => return Err(e),
+                    Err(e) => return Err(e.into()),
                 }
             }
         },
-        _ => return Err("Invalid protocol for TCP handler".into()),
+        _ => return Err(ProxyError::ProtocolMismatch { handler: "TCP", protocol }),
     }
     
     Ok(())
 }
 
+async fn copy_then_shutdown(
+    mut reader: TcpStream,
+    mut writer: TcpStream,
+    connection: &ObservedConnection,
+    filters: &FilterChain,
+    shaper: &Shaper,
+    direction: Direction,
+) -> Result<u64, ProxyError> {
+    let mut buf = vec![0; 65536];
+    let mut copied = 0;
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        let Some(data) = filters.process(direction, &buf[..n])? else {
+            continue;
+        };
+        // Written a burst at a time, so that the limits pace the writes
+        for chunk in data.chunks(shaper.chunk_size()) {
+            shaper.throttle(chunk.len()).await;
+            writer.write_all(chunk).await?;
+        }
+        connection.transferred(direction, data.len());
+        copied += data.len() as u64;
+    }
+    // The peer may already be gone, in which case there is nothing to tell.
+    let _ = writer.shutdown(std::net::Shutdown::Write);
+    Ok(copied)
+}
+
 pub async fn start_tcp_forwarding(
     bind_addr: std::net::SocketAddr,
     connect_addr: String,
     protocol: crate::config::Protocol,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let listener = TcpListener::bind(bind_addr).await?;
-    
+) -> Result<(), ProxyError> {
+    let listener = TcpListener::bind(bind_addr)
+        .await
+        .map_err(|error| ProxyError::Bind { addr: bind_addr, error })?;
+    run_tcp_listener(
+        listener,
+        SharedTarget::new(Target::new(connect_addr, protocol, None)),
+        SharedAccessPolicy::default(),
+        ConnectionTracker::new(),
+        RuleLimit::unlimited(),
+        Arc::new(Observers::default()),
+    )
+    .await
+}
+
+/// Accepts connections on an already bound listener until an accept fails
+/// and forwards each to `target` as it is when the connection is accepted.
+/// Clients that `access` does not allow are refused. Every connection is
+/// registered with `tracker`, ends early on `ConnectionTracker::close_all`
+/// or when it is killed, and has its events reported to `observer`.
+///
+/// Clients over a connection limit of `limit` are refused, or with
+/// `LimitAction::Wait` left in the listen backlog until the rule and the
+/// server have room. Clients over the per-client limit or the rate limit are
+/// always refused. The data of the others is slowed to the bandwidth limits.
+pub async fn run_tcp_listener(
+    listener: TcpListener,
+    target: SharedTarget,
+    access: SharedAccessPolicy,
+    tracker: ConnectionTracker,
+    limit: RuleLimit,
+    observer: Arc<dyn ConnectionObserver>,
+) -> Result<(), ProxyError> {
+    let local_addr = listener.local_addr()?;
     loop {
+        let waits = limit.action() == LimitAction::Wait;
+        if waits {
+            limit.room().await;
+        }
         let (client_stream, client_addr) = listener.accept().await?;
-        println!("New connection from {}", client_addr);
+        let forward = target.load();
+        let info = ConnectionInfo::new(forward.protocol.clone(), client_addr, local_addr, forward.connect_addr.clone());
+        let observed = ObservedConnection::accept(observer.clone(), info);
+        let policy = access.load();
+        if !policy.admits(client_addr.ip()) {
+            observed.access(false);
+            policy.count_refusal(client_addr.ip());
+            observed.close(CloseReason::Denied(ProxyError::AccessDenied(client_addr)));
+            continue;
+        }
+        if let Err(exceeded) = limit.check_rate(client_addr.ip()) {
+            observed.close(CloseReason::Limited(exceeded));
+            continue;
+        }
+        // Without a permit the connection waits for one before connecting.
+        // Only connections that lost a race for the last room wait, as the
+        // backlog cannot hold back the clients over their own limit.
+        let permit = match limit.try_acquire(client_addr.ip()) {
+            Ok(permit) => Some(permit),
+            Err(exceeded) if waits && exceeded != Limit::Client => None,
+            Err(exceeded) => {
+                observed.close(CloseReason::Limited(exceeded));
+                continue;
+            }
+        };
+        observed.access(true);
         
-        // Clone the connect_addr for each connection
-        let connect_addr_clone = connect_addr.clone();
-        let protocol_clone = protocol.clone();
+        let limit = limit.clone();
+        let connection = tracker.open(observed.info());
+        let chain = forward.filters.start(observed.info());
         
         // Spawn a new task to handle this connection
         smol::spawn(async move {
-            if let Err(e) = handle_tcp_connection(client_stream, connect_addr_clone, protocol_clone).await {
-                eprintln!("Connection error: {}", e);
-            }
+            let relay = async {
+                let _permit = match permit {
+                    Some(permit) => permit,
+                    None => limit.acquire(client_addr.ip()).await,
+                };
+                let shaper = limit.shaper(client_addr.ip());
+                match relay(client_stream, &forward.connect_addr, forward.protocol.clone(), &observed, &chain, &shaper).await {
+                    Ok(()) => CloseReason::Finished,
+                    Err(ProxyError::ClosedByFilter) => CloseReason::Filtered,
+                    Err(error) => CloseReason::Error(error),
+                }
+            };
+            let closed = connection.closed();
+            let reason = futures_lite::future::or(relay, closed).await;
+            observed.close(reason);
         }).detach();
     }
 }
\ No newline at end of file
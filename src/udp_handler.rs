@@ -1,15 +1,38 @@
+use crate::access_control::{AccessPolicy, SharedAccessPolicy};
+use crate::bandwidth::Shaper;
+use crate::connections::{ConnectionTracker, TrackedConnection};
+use crate::error::ProxyError;
+use crate::filter::FilterChain;
+use crate::limits::{Permit, RuleLimit};
+use crate::logging;
+use crate::observer::{
+    CloseReason, ConnectionInfo, ConnectionObserver, Direction, ObservedConnection, Observers,
+};
+use crate::target::{SharedTarget, Target};
 use smol::net::{UdpSocket, TcpStream};
 use smol::io::{AsyncReadExt, AsyncWriteExt};
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
-use std::error::Error;
+
+/// How long the datagrams of a refused client are dropped without reporting
+/// the refusal again.
+const REFUSAL_REPORT_INTERVAL: Duration = Duration::from_secs(1);
 
 pub struct UdpForwarder {
     socket: UdpSocket,
+    local_addr: SocketAddr,
     connections: HashMap<SocketAddr, UdpConnection>,
-    timeout: Duration,
-    protocol: crate::config::Protocol,
+    /// When the last refusal of each recently refused client was reported.
+    refused: HashMap<SocketAddr, Instant>,
+    target: SharedTarget,
+    access: SharedAccessPolicy,
+    sessions: ConnectionTracker,
+    drain: Arc<AtomicBool>,
+    limit: RuleLimit,
+    observer: Arc<dyn ConnectionObserver>,
 }
 
 pub struct UdpConnection {
@@ -17,163 +40,424 @@ pub struct UdpConnection {
     last_activity: Instant,
     tcp_stream: Option<TcpStream>,
     buffer: Vec<u8>,
+    filters: FilterChain,
+    observed: ObservedConnection,
+    session: TrackedConnection,
+    /// Datagrams over the rule's bandwidth limits are dropped.
+    shaper: Shaper,
+    /// Counts the session against the connection limits until it ends.
+    _permit: Permit,
 }
 
 impl UdpForwarder {
-    pub async fn new(bind_addr: SocketAddr, _connect_addr: String, timeout: Option<u64>, protocol: crate::config::Protocol) -> Result<Self, Box<dyn std::error::Error>> {
-        let socket = UdpSocket::bind(bind_addr).await?;
-        
-        let timeout_duration = timeout
-            .map(Duration::from_secs)
-            .unwrap_or(Duration::from_secs(72)); // Default UDP timeout
+    pub async fn new(bind_addr: SocketAddr, connect_addr: String, timeout: Option<u64>, protocol: crate::config::Protocol) -> Result<Self, ProxyError> {
+        let socket = UdpSocket::bind(bind_addr)
+            .await
+            .map_err(|error| ProxyError::Bind { addr: bind_addr, error })?;
+        let local_addr = socket.local_addr()?;
         
         Ok(UdpForwarder {
             socket,
+            local_addr,
             connections: HashMap::new(),
-            timeout: timeout_duration,
-            protocol,
+            refused: HashMap::new(),
+            target: SharedTarget::new(Target::new(connect_addr, protocol, timeout)),
+            access: SharedAccessPolicy::default(),
+            sessions: ConnectionTracker::new(),
+            drain: Arc::default(),
+            limit: RuleLimit::unlimited(),
+            observer: Arc::new(Observers::default()),
         })
     }
+
+    /// The address the listening socket is bound to.
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    /// Restricts the clients whose datagrams are forwarded. Datagrams from
+    /// other clients are dropped.
+    pub fn set_access_policy(&mut self, access: SharedAccessPolicy) {
+        self.access = access;
+    }
+
+    /// Registers every client session with `sessions`. Once it is draining,
+    /// datagrams from new clients are dropped and `run` returns as soon as
+    /// the existing sessions have expired. Killed sessions end within a
+    /// second.
+    pub fn set_session_tracker(&mut self, sessions: ConnectionTracker) {
+        self.sessions = sessions;
+    }
+
+    /// Drains this forwarder alone, as a draining session tracker does,
+    /// while `drain` is set. Clearing it before `run` returned lets new
+    /// clients in again.
+    pub fn set_drain(&mut self, drain: Arc<AtomicBool>) {
+        self.drain = drain;
+    }
+
+    /// Counts every client session against `limit`. Datagrams from new
+    /// clients that would exceed it or its rate limit are dropped, whatever
+    /// its action, and so are datagrams over its bandwidth limits.
+    pub fn set_limit(&mut self, limit: RuleLimit) {
+        self.limit = limit;
+    }
+
+    /// Reports the events of every client session to `observer`.
+    pub fn set_observer(&mut self, observer: Arc<dyn ConnectionObserver>) {
+        self.observer = observer;
+    }
+
+    /// Forwards to `target` instead of the address, protocol and timeout
+    /// given to `new`. It is read again for every datagram, so it can be
+    /// swapped while running; sessions keep the filters they started with,
+    /// and `udptotcp` sessions their stream.
+    pub fn set_target(&mut self, target: SharedTarget) {
+        self.target = target;
+    }
+
+    /// Checks `src_addr` against the access rules and connection limits and
+    /// opens a session for a new client. Returns false if the datagram is
+    /// to be dropped.
+    fn admit(&mut self, src_addr: SocketAddr, target: &Target) -> bool {
+        // A client of a killed session starts over with a new one
+        if self.connections.get(&src_addr).is_some_and(|conn| conn.session.is_killed()) {
+            self.close_session(src_addr, CloseReason::Killed);
+        }
+        let access = self.access.load();
+        if let Some(connection) = self.connections.get(&src_addr) {
+            if access.is_allowed(src_addr.ip()) {
+                return true;
+            }
+            // Reported once: the following datagrams are refused as those
+            // of a new client
+            connection.observed.access(false);
+            access.count_refusal(src_addr.ip());
+            self.refused.insert(src_addr, Instant::now());
+            self.close_session(src_addr, CloseReason::Denied(ProxyError::AccessDenied(src_addr)));
+            return false;
+        }
+        let admitted = if access.admits(src_addr.ip()) {
+            self.limit
+                .check_rate(src_addr.ip())
+                .and_then(|()| self.limit.try_acquire(src_addr.ip()))
+                .map_err(CloseReason::Limited)
+        } else {
+            Err(CloseReason::Denied(ProxyError::AccessDenied(src_addr)))
+        };
+        let permit = match admitted {
+            Ok(permit) => permit,
+            Err(reason) => {
+                self.refuse(src_addr, target, &access, reason);
+                return false;
+            }
+        };
+
+        let info = ConnectionInfo::new(target.protocol.clone(), src_addr, self.local_addr, target.connect_addr.clone());
+        let observed = ObservedConnection::accept(self.observer.clone(), info);
+        observed.access(true);
+        self.connections.insert(
+            src_addr,
+            UdpConnection {
+                remote_addr: src_addr,
+                last_activity: Instant::now(),
+                tcp_stream: None,
+                buffer: Vec::new(),
+                filters: target.filters.start(observed.info()),
+                session: self.sessions.open(observed.info()),
+                shaper: self.limit.shaper(src_addr.ip()),
+                observed,
+                _permit: permit,
+            },
+        );
+        true
+    }
+
+    /// Reports that the datagram of a new client was dropped for `reason`.
+    /// Refused clients tend to keep sending, so their refusal is reported
+    /// at most once per `REFUSAL_REPORT_INTERVAL` rather than logged,
+    /// counted and taken as a ban strike for every datagram.
+    fn refuse(&mut self, src_addr: SocketAddr, target: &Target, access: &AccessPolicy, reason: CloseReason) {
+        let now = Instant::now();
+        if let Some(reported) = self.refused.get(&src_addr)
+            && now.duration_since(*reported) < REFUSAL_REPORT_INTERVAL
+        {
+            return;
+        }
+        self.refused.insert(src_addr, now);
+        let info = ConnectionInfo::new(target.protocol.clone(), src_addr, self.local_addr, target.connect_addr.clone());
+        let observed = ObservedConnection::accept(self.observer.clone(), info);
+        if matches!(reason, CloseReason::Denied(_)) {
+            observed.access(false);
+            access.count_refusal(src_addr.ip());
+        }
+        observed.close(reason);
+    }
+
+    /// Receives the next datagram to forward, expiring idle sessions while
+    /// waiting. Returns `None` when draining and no session is left.
+    async fn next_datagram(&mut self, buf: &mut [u8]) -> std::io::Result<Option<(usize, SocketAddr)>> {
+        loop {
+            // Clean up expired and killed connections
+            let timeout = self.target.load().timeout;
+            let now = Instant::now();
+            self.connections.retain(|_, conn| {
+                if conn.session.is_killed() {
+                    conn.observed.set_close_reason(CloseReason::Killed);
+                    return false;
+                }
+                let alive = now.duration_since(conn.last_activity) < timeout;
+                if !alive {
+                    conn.observed.set_close_reason(CloseReason::Expired);
+                }
+                alive
+            });
+            self.refused.retain(|_, reported| now.duration_since(*reported) < REFUSAL_REPORT_INTERVAL);
+
+            if self.is_draining() && self.connections.is_empty() {
+                return Ok(None);
+            }
+
+            // Wake up when the next session expires, or shortly after
+            // draining starts or a session is killed, to re-check the
+            // sessions
+            let wake_at = self
+                .connections
+                .values()
+                .map(|conn| conn.last_activity + timeout)
+                .min()
+                .unwrap_or(now + timeout)
+                .min(now + Duration::from_secs(1));
+
+            let socket = &self.socket;
+            let received = smol::future::or(
+                async { Some(socket.recv_from(buf).await) },
+                async {
+                    smol::Timer::at(wake_at).await;
+                    None
+                },
+            )
+            .await;
+
+            match received {
+                Some(Ok((len, src_addr))) => {
+                    // Draining may have started or ended while waiting
+                    if self.is_draining() && !self.connections.contains_key(&src_addr) {
+                        continue;
+                    }
+                    return Ok(Some((len, src_addr)));
+                }
+                Some(Err(e)) => return Err(e),
+                None => continue,
+            }
+        }
+    }
     
-    pub async fn run(&mut self, connect_addr: String) -> Result<(), Box<dyn std::error::Error>> {
+    fn is_draining(&self) -> bool {
+        self.sessions.is_draining() || self.drain.load(Ordering::SeqCst)
+    }
+
+    /// Ends the session of `client` for `reason`.
+    fn close_session(&mut self, client: SocketAddr, reason: CloseReason) {
+        if let Some(mut connection) = self.connections.remove(&client) {
+            connection.observed.set_close_reason(reason);
+        }
+    }
+    
+    pub async fn run(&mut self) -> Result<(), ProxyError> {
         let mut buf = vec![0; 65536];
+        check_protocol(&self.target.load().protocol)?;
+        loop {
+            let Some((len, src_addr)) = self.next_datagram(&mut buf).await? else {
+                return Ok(());
+            };
+            // The target may have changed since the last datagram
+            let target = self.target.load();
+            check_protocol(&target.protocol)?;
+            if !self.admit(src_addr, &target) {
+                continue;
+            }
+            match target.protocol {
+                crate::config::Protocol::UdpToTcp => self.forward_to_stream(&buf, len, src_addr, &target.connect_addr).await,
+                _ => self.forward_datagram(&mut buf, len, src_addr, &target.connect_addr).await?,
+            }
+        }
+    }
+
+    /// Forwards the `len` bytes in `buf` from the admitted `src_addr` as a
+    /// datagram, and relays the response if one arrives within a second.
+    async fn forward_datagram(&mut self, buf: &mut [u8], len: usize, src_addr: SocketAddr, connect_addr: &str) -> Result<(), ProxyError> {
+        let connection = self.connections.get_mut(&src_addr).expect("admitted client has a session");
+        if !connection.shaper.admit(len) {
+            return Ok(());
+        }
         
-        match self.protocol {
-            crate::config::Protocol::Udp => {
-                loop {
-                    // Clean up expired connections
-                    let now = Instant::now();
-                    self.connections.retain(|_, conn| {
-                        now.duration_since(conn.last_activity) < self.timeout
-                    });
-                    
-                    let (len, src_addr) = self.socket.recv_from(&mut buf).await?;
-                    
-                    // Create a new socket for each destination to maintain source IP
-                    let server_socket = UdpSocket::bind("0.0.0.0:0").await?;
-                    server_socket.connect(&connect_addr).await?;
-                    
-                    // Forward data to connected server
-                    server_socket.send(&buf[..len]).await?;
-                    
-                    // Update connection tracking
-                    self.connections.insert(
-                        src_addr,
-                        UdpConnection {
-                            remote_addr: src_addr,
-                            last_activity: Instant::now(),
-                            tcp_stream: None,
-                            buffer: buf[..len].to_vec(),
-                        }
-                    );
-                    
-                    // Try to receive response from server
-                    match smol::future::or(
-                        async {
-                            let response_len = server_socket.recv(&mut buf).await?;
-                            self.socket.send_to(&buf[..response_len], src_addr).await?;
-                            Ok::<(), Box<dyn std::error::Error>>(())
+        // Create a new socket for each destination to maintain source IP
+        let server_socket = UdpSocket::bind("0.0.0.0:0").await?;
+        let started = Instant::now();
+        let connected = server_socket
+            .connect(connect_addr)
+            .await
+            .map_err(|error| ProxyError::Connect { addr: connect_addr.to_string(), error });
+        connection.observed.connected(started, &connected);
+        connected?;
+        
+        // Forward data to connected server
+        server_socket.send(&buf[..len]).await?;
+        connection.observed.transferred(Direction::ClientToUpstream, len);
+        
+        // Update connection tracking
+        connection.last_activity = Instant::now();
+        connection.buffer = buf[..len].to_vec();
+        
+        // Try to receive response from server
+        let shaper = &connection.shaper;
+        match smol::future::or(
+            async {
+                let response_len = server_socket.recv(buf).await?;
+                if !shaper.admit(response_len) {
+                    return Ok(0);
+                }
+                self.socket.send_to(&buf[..response_len], src_addr).await?;
+                Ok::<usize, std::io::Error>(response_len)
+            },
+            async {
+                // Timeout after 1 second if no response
+                smol::Timer::after(Duration::from_secs(1)).await;
+                Ok(0)
+            }
+        ).await {
+            Ok(0) => {},
+            Ok(response_len) => {
+                connection.observed.transferred(Direction::UpstreamToClient, response_len);
+            },
+            Err(e) => {
+                forwarding_error(format!("UDP response error: {}", e), connection.observed.info());
+            }
+        }
+        Ok(())
+    }
+
+    /// Forwards the `len` bytes in `buf` from the admitted `src_addr` over
+    /// the TCP stream of its session, and relays what the server answers.
+    async fn forward_to_stream(&mut self, buf: &[u8], len: usize, src_addr: SocketAddr, connect_addr: &str) {
+        let connection = self.connections.get_mut(&src_addr).expect("admitted client has a session");
+        
+        connection.last_activity = Instant::now();
+        
+        // Connect to TCP server if not already connected
+        if connection.tcp_stream.is_none() {
+            let started = Instant::now();
+            let stream = TcpStream::connect(connect_addr)
+                .await
+                .map_err(|error| ProxyError::Connect { addr: connect_addr.to_string(), error });
+            connection.observed.connected(started, &stream);
+            match stream {
+                Ok(stream) => {
+                    connection.tcp_stream = Some(stream);
+                },
+                Err(_) => return,
+            }
+        }
+        
+        let data = match connection.filters.process(Direction::ClientToUpstream, &buf[..len]) {
+            Ok(Some(data)) => data,
+            Ok(None) => return,
+            Err(_) => {
+                self.close_session(src_addr, CloseReason::Filtered);
+                return;
+            }
+        };
+        if !connection.shaper.admit(data.len()) {
+            return;
+        }
+        
+        // Forward data to TCP server
+        if let Some(ref mut tcp_stream) = connection.tcp_stream {
+            if let Err(e) = tcp_stream.write_all(&data).await {
+                forwarding_error(format!("Failed to write to TCP stream: {}", e), connection.observed.info());
+                connection.tcp_stream = None; // Mark connection as broken
+                return;
+            }
+            connection.observed.transferred(Direction::ClientToUpstream, data.len());
+            
+            // Try to read response from TCP server with timeout
+            let result = smol::future::or(
+                async {
+                    let mut response_buf = vec![0; 65536];
+                    match tcp_stream.peek(&mut response_buf).await {
+                        Ok(0) => {
+                            // Connection closed
+                            Ok::<Vec<u8>, std::io::Error>(Vec::new())
                         },
-                        async {
-                            // Timeout after 1 second if no response
-                            smol::Timer::after(Duration::from_secs(1)).await;
-                            Ok::<(), Box<dyn std::error::Error>>(())
-                        }
-                    ).await {
-                        Ok(_) => {},
-                        Err(e) => {
-                            eprintln!("UDP response error: {}", e);
-                        }
+                        Ok(n) => {
+                            // Read the data we just peeked at
+                            let _ = tcp_stream.read(&mut response_buf[..n]).await?;
+                            Ok(response_buf[..n].to_vec())
+                        },
+                        Err(e) => Err(e),
                     }
+                },
+                async {
+                    smol::Timer::after(Duration::from_millis(100)).await;
+                    Ok(Vec::new())
                 }
-            },
-            crate::config::Protocol::UdpToTcp => {
-                loop {
-                    let (len, src_addr) = self.socket.recv_from(&mut buf).await?;
-                    
-                    // Get or create connection for this client
-                    let connection = self.connections.entry(src_addr).or_insert_with(|| {
-                        UdpConnection {
-                            remote_addr: src_addr,
-                            last_activity: Instant::now(),
-                            tcp_stream: None,
-                            buffer: Vec::new(),
-                        }
-                    });
-                    
-                    connection.last_activity = Instant::now();
-                    
-                    // Connect to TCP server if not already connected
-                    if connection.tcp_stream.is_none() {
-                        match TcpStream::connect(&connect_addr).await {
-                            Ok(stream) => {
-                                connection.tcp_stream = Some(stream);
-                            },
-                            Err(e) => {
-                                eprintln!("Failed to connect to TCP server: {}", e);
-                                continue;
-                            }
+            ).await;
+            
+            match result {
+                Ok(data) if !data.is_empty() => {
+                    let data = match connection.filters.process(Direction::UpstreamToClient, &data) {
+                        Ok(Some(data)) => data,
+                        Ok(None) => return,
+                        Err(_) => {
+                            self.close_session(src_addr, CloseReason::Filtered);
+                            return;
                         }
+                    };
+                    if !connection.shaper.admit(data.len()) {
+                        return;
                     }
-                    
-                    // Forward data to TCP server
-                    if let Some(ref mut tcp_stream) = connection.tcp_stream {
-                        if let Err(e) = tcp_stream.write_all(&buf[..len]).await {
-                            eprintln!("Failed to write to TCP stream: {}", e);
-                            connection.tcp_stream = None; // Mark connection as broken
-                            continue;
-                        }
-                        
-                        // Try to read response from TCP server with timeout
-                        let result = smol::future::or(
-                            async {
-                                let mut response_buf = vec![0; 65536];
-                                match tcp_stream.peek(&mut response_buf).await {
-                                    Ok(0) => {
-                                        // Connection closed
-                                        Ok::<Vec<u8>, Box<dyn Error + Send + Sync>>(Vec::new())
-                                    },
-                                    Ok(n) => {
-                                        // Read the data we just peeked at
-                                        let _ = tcp_stream.read(&mut response_buf[..n]).await?;
-                                        Ok::<Vec<u8>, Box<dyn Error + Send + Sync>>(response_buf[..n].to_vec())
-                                    },
-                                    Err(e) => Err(Box::new(e) as Box<dyn Error + Send + Sync>),
-                                }
-                            },
-                            async {
-                                smol::Timer::after(Duration::from_millis(100)).await;
-                                Ok::<Vec<u8>, Box<dyn Error + Send + Sync>>(Vec::new())
-                            }
-                        ).await;
-                        
-                        match result {
-                            Ok(data) if !data.is_empty() => {
-                                // Forward response to UDP client
-                                if let Err(e) = self.socket.send_to(&data, src_addr).await {
-                                    eprintln!("Failed to send response to UDP client: {}", e);
-                                }
-                            },
-                            Ok(_) => {}, // Timeout, no data
-                            Err(e) => {
-                                eprintln!("Error reading from TCP stream: {}", e);
-                                connection.tcp_stream = None; // Mark connection as broken
-                            },
-                        }
+                    // Forward response to UDP client
+                    match self.socket.send_to(&data, src_addr).await {
+                        Ok(sent) => connection.observed.transferred(Direction::UpstreamToClient, sent),
+                        Err(e) => forwarding_error(
+                            format!("Failed to send response to UDP client: {}", e),
+                            connection.observed.info(),
+                        ),
                     }
-                }
-            },
-            _ => return Err("Invalid protocol for UDP handler".into()),
+                },
+                Ok(_) => {}, // Timeout, no data
+                Err(e) => {
+                    forwarding_error(format!("Error reading from TCP stream: {}", e), connection.observed.info());
+                    connection.tcp_stream = None; // Mark connection as broken
+                },
+            }
         }
     }
 }
 
+fn check_protocol(protocol: &crate::config::Protocol) -> Result<(), ProxyError> {
+    match protocol {
+        crate::config::Protocol::Udp | crate::config::Protocol::UdpToTcp => Ok(()),
+        _ => Err(ProxyError::ProtocolMismatch { handler: "UDP", protocol: protocol.clone() }),
+    }
+}
+
+/// Logs a failure to forward data of the session `conn` that does not end
+/// the session.
+fn forwarding_error(message: String, conn: &ConnectionInfo) {
+    logging::warn("udp_forwarding_error", message)
+        .field("id", conn.id)
+        .field("client", conn.client_addr.to_string())
+        .log();
+}
+
 pub async fn start_udp_forwarding(
     bind_addr: std::net::SocketAddr,
     connect_addr: String,
     timeout: Option<u64>,
     protocol: crate::config::Protocol,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut forwarder = UdpForwarder::new(bind_addr, connect_addr.clone(), timeout, protocol).await?;
-    forwarder.run(connect_addr).await
+) -> Result<(), ProxyError> {
+    let mut forwarder = UdpForwarder::new(bind_addr, connect_addr, timeout, protocol).await?;
+    forwarder.run().await
 }
\ No newline at end of file
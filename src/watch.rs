@@ -0,0 +1,202 @@
+//! Polling of a config file and the files it includes, for `--watch`.
+//!
+//! Files are compared by modification time and size. Metadata is read
+//! through symlinks, so configs mounted from a Kubernetes ConfigMap, which
+//! are updated by swapping a symlink, are picked up too.
+
+use crate::config::Config;
+use crate::config_parser::{ConfigError, ConfigFormat, ConfigSources};
+use smol::Timer;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+/// Modification time and size of every watched file; `None` for files that
+/// do not exist.
+type Snapshot = Vec<(PathBuf, Option<(SystemTime, u64)>)>;
+
+pub struct ConfigWatcher {
+    path: String,
+    format: Option<ConfigFormat>,
+    sources: ConfigSources,
+    snapshot: Snapshot,
+}
+
+impl ConfigWatcher {
+    /// Watches the files in `sources`, as returned by
+    /// `Config::load_with_sources` for the config at `path`.
+    pub fn new(path: &str, format: Option<ConfigFormat>, sources: ConfigSources) -> Self {
+        let mut watcher = ConfigWatcher {
+            path: path.to_string(),
+            format,
+            sources,
+            snapshot: Vec::new(),
+        };
+        watcher.snapshot = watcher.take_snapshot();
+        watcher
+    }
+
+    fn take_snapshot(&self) -> Snapshot {
+        let mut files = self.sources.files.clone();
+        files.extend(self.sources.matched_files());
+        files.sort();
+        files.dedup();
+        files
+            .into_iter()
+            .map(|file| {
+                let stamp = fs::metadata(&file)
+                    .ok()
+                    .map(|meta| (meta.modified().unwrap_or(SystemTime::UNIX_EPOCH), meta.len()));
+                (file, stamp)
+            })
+            .collect()
+    }
+
+    /// True if any watched file changed since the last call, or since the
+    /// watcher was created.
+    pub fn poll(&mut self) -> bool {
+        let snapshot = self.take_snapshot();
+        if snapshot == self.snapshot {
+            return false;
+        }
+        self.snapshot = snapshot;
+        true
+    }
+
+    /// Polls every `interval` until a watched file changed, then waits until
+    /// the files stay unchanged for a further interval, so that an editor or
+    /// deployment tool writing several files triggers a single reload.
+    pub async fn changed(&mut self, interval: Duration) {
+        loop {
+            Timer::after(interval).await;
+            if self.poll() {
+                break;
+            }
+        }
+        loop {
+            Timer::after(interval).await;
+            if !self.poll() {
+                return;
+            }
+        }
+    }
+
+    /// Loads the config and, if it is valid, starts watching the files it
+    /// now includes.
+    pub fn load(&mut self) -> Result<Config, ConfigError> {
+        let (config, sources) = Config::load_with_sources(&self.path, self.format)?;
+        if sources != self.sources {
+            self.sources = sources;
+            self.snapshot = self.take_snapshot();
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule_toml(port: u16) -> String {
+        format!(r#"
+[[forwarding_rules]]
+bind_address = "127.0.0.1"
+bind_port = {}
+connect_address = "127.0.0.1"
+connect_port = 9090
+"#, port)
+    }
+
+    fn watcher_for(path: &std::path::Path) -> ConfigWatcher {
+        let path = path.to_str().unwrap();
+        let (_, sources) = Config::load_with_sources(path, None).unwrap();
+        ConfigWatcher::new(path, None, sources)
+    }
+
+    #[test]
+    fn poll_detects_modified_main_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let main = dir.path().join("oi.toml");
+        fs::write(&main, rule_toml(8080)).unwrap();
+        let mut watcher = watcher_for(&main);
+        assert!(!watcher.poll());
+
+        fs::write(&main, format!("{}{}", rule_toml(8080), rule_toml(8081))).unwrap();
+        assert!(watcher.poll());
+        assert!(!watcher.poll());
+    }
+
+    #[test]
+    fn poll_detects_added_and_removed_include_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("conf.d")).unwrap();
+        let main = dir.path().join("oi.toml");
+        fs::write(&main, "include = [\"conf.d/*.toml\"]\nforwarding_rules = []\n").unwrap();
+        let mut watcher = watcher_for(&main);
+
+        let fragment = dir.path().join("conf.d/web.toml");
+        fs::write(&fragment, rule_toml(8080)).unwrap();
+        assert!(watcher.poll());
+        assert_eq!(watcher.load().unwrap().forwarding_rules.len(), 1);
+
+        fs::remove_file(&fragment).unwrap();
+        assert!(watcher.poll());
+        assert!(watcher.load().unwrap().forwarding_rules.is_empty());
+    }
+
+    #[test]
+    fn load_follows_new_plain_includes() {
+        let dir = tempfile::tempdir().unwrap();
+        let main = dir.path().join("oi.toml");
+        fs::write(&main, rule_toml(8080)).unwrap();
+        let extra = dir.path().join("extra.toml");
+        fs::write(&extra, rule_toml(8081)).unwrap();
+        let mut watcher = watcher_for(&main);
+
+        // Not watched until the main file includes it
+        fs::write(&extra, rule_toml(8082)).unwrap();
+        assert!(!watcher.poll());
+
+        fs::write(&main, format!("include = [\"extra.toml\"]\n{}", rule_toml(8080))).unwrap();
+        assert!(watcher.poll());
+        assert_eq!(watcher.load().unwrap().forwarding_rules.len(), 2);
+
+        fs::write(&extra, format!("{}{}", rule_toml(8082), rule_toml(8083))).unwrap();
+        assert!(watcher.poll());
+    }
+
+    #[test]
+    fn load_keeps_sources_of_invalid_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let main = dir.path().join("oi.toml");
+        fs::write(&main, rule_toml(8080)).unwrap();
+        let mut watcher = watcher_for(&main);
+
+        fs::write(&main, "[[forwarding_rules]\n").unwrap();
+        assert!(watcher.poll());
+        assert!(watcher.load().is_err());
+        assert!(!watcher.poll());
+    }
+
+    #[test]
+    fn changed_waits_for_files_to_settle() {
+        let dir = tempfile::tempdir().unwrap();
+        let main = dir.path().join("oi.toml");
+        fs::write(&main, rule_toml(8080)).unwrap();
+        let mut watcher = watcher_for(&main);
+
+        let writer = {
+            let main = main.clone();
+            std::thread::spawn(move || {
+                for port in 8081..8084 {
+                    std::thread::sleep(Duration::from_millis(5));
+                    fs::write(&main, rule_toml(port)).unwrap();
+                }
+            })
+        };
+        smol::block_on(watcher.changed(Duration::from_millis(50)));
+        writer.join().unwrap();
+
+        assert_eq!(watcher.load().unwrap().forwarding_rules[0].bind_port, 8083);
+    }
+}
@@ -40,10 +40,16 @@ fn random_proxy_port() -> u16 {
 pub struct TestProxy {
     pub child: Child,
     pub bind_addr: SocketAddr,
+    pub config_path: std::path::PathBuf,
     pub _dir: tempfile::TempDir,
 }
 
 impl TestProxy {
+    /// Replaces the proxy's config file, e.g. before asking it to reload.
+    pub fn rewrite_config(&self, content: &str) {
+        std::fs::write(&self.config_path, content).expect("rewrite config");
+    }
+
     pub fn is_alive(&mut self) -> bool {
         match self.child.try_wait() {
             Ok(Some(_)) => false,
@@ -56,20 +62,27 @@ impl TestProxy {
 /// Stops a proxy process. On Unix the proxy receives SIGINT first (its
 /// ctrlc handler runs and the process exits cleanly), which matters for
 /// coverage runs: profile data is only flushed on a normal exit, while
-/// SIGKILL loses it. Falls back to a hard kill.
+/// SIGKILL loses it. A second SIGINT skips the proxy's connection draining.
+/// Falls back to a hard kill.
 pub fn terminate_proxy(child: &mut Child) {
     #[cfg(unix)]
     {
-        let _ = std::process::Command::new("kill")
-            .arg("-INT")
-            .arg(child.id().to_string())
-            .status();
-        let deadline = Instant::now() + Duration::from_secs(2);
-        while Instant::now() < deadline {
+        let pid = child.id().to_string();
+        let interrupt = || {
+            let _ = std::process::Command::new("kill").arg("-INT").arg(&pid).status();
+        };
+        interrupt();
+        let start = Instant::now();
+        let mut interrupted_twice = false;
+        while start.elapsed() < Duration::from_secs(2) {
             if let Ok(Some(_)) = child.try_wait() {
                 let _ = child.wait();
                 return;
             }
+            if !interrupted_twice && start.elapsed() >= Duration::from_millis(300) {
+                interrupt();
+                interrupted_twice = true;
+            }
             std::thread::sleep(Duration::from_millis(20));
         }
     }
@@ -84,11 +97,16 @@ impl Drop for TestProxy {
 }
 
 pub fn spawn_proxy(toml_config: &str) -> TestProxy {
-    spawn_proxy_with_file(toml_config, "proxy.toml")
+    spawn_proxy_with_file(toml_config, "proxy.toml", &[])
 }
 
 pub fn spawn_proxy_from_legacy_conf(conf: &str) -> TestProxy {
-    spawn_proxy_with_file(conf, "proxy.conf")
+    spawn_proxy_with_file(conf, "proxy.conf", &[])
+}
+
+/// Like `spawn_proxy`, passing extra command line arguments to the proxy.
+pub fn spawn_proxy_with_args(toml_config: &str, args: &[&str]) -> TestProxy {
+    spawn_proxy_with_file(toml_config, "proxy.toml", args)
 }
 
 /// The proxy prints "<proto> forwarding error: ..." when its bind fails.
@@ -98,7 +116,7 @@ const BIND_ERROR_MARKER: &str = "forwarding error";
 /// another concurrently running test grabbed the port first, the proxy
 /// reports the bind failure on stderr; the helper then kills it and retries
 /// with a fresh random port.
-fn spawn_proxy_with_file(content: &str, file_name: &str) -> TestProxy {
+fn spawn_proxy_with_file(content: &str, file_name: &str, args: &[&str]) -> TestProxy {
     let binds_tcp_listener = binds_tcp_listener(content);
     let mut port = extract_bind_port(content).expect("bind_port in config");
     let mut config = content.to_string();
@@ -111,6 +129,7 @@ fn spawn_proxy_with_file(content: &str, file_name: &str) -> TestProxy {
         let mut child = Command::new(BIN)
             .arg("-c")
             .arg(&path)
+            .args(args)
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .stdin(Stdio::null())
@@ -172,6 +191,7 @@ fn spawn_proxy_with_file(content: &str, file_name: &str) -> TestProxy {
             return TestProxy {
                 child,
                 bind_addr,
+                config_path: path,
                 _dir: dir,
             };
         }
@@ -269,7 +289,7 @@ pub fn tcp_round_trip(proxy_addr: SocketAddr, payload: &[u8]) -> Vec<u8> {
         .set_read_timeout(Some(Duration::from_secs(30)))
         .unwrap();
     stream.set_write_timeout(Some(Duration::from_secs(30))).unwrap();
-    // The proxy does not propagate half-close to the backend, so the echo
+    // The stream stays open for writing until the echo arrived, so the echo
     // server never sees EOF. Write in chunks and read the echoed bytes
     // interleaved, so neither direction stalls on saturated socket buffers.
     let mut response = Vec::with_capacity(payload.len());
@@ -287,6 +307,32 @@ pub fn tcp_round_trip(proxy_addr: SocketAddr, payload: &[u8]) -> Vec<u8> {
     response
 }
 
+/// True when the proxy accepts a connection and closes it without
+/// forwarding anything, as it does for clients its access rules refuse.
+pub fn connection_is_refused(proxy_addr: SocketAddr) -> bool {
+    let Ok(mut stream) = TcpStream::connect(proxy_addr) else {
+        return true;
+    };
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let _ = stream.write_all(b"ping");
+    let mut buf = [0u8; 16];
+    match stream.read(&mut buf) {
+        Ok(n) => n == 0,
+        Err(e) => !matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut),
+    }
+}
+
+/// Sends a plain HTTP/1.0 GET and returns the whole response, headers
+/// included.
+pub fn http_get(addr: SocketAddr, path: &str) -> String {
+    let mut stream = TcpStream::connect(addr).expect("connect to HTTP server");
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, addr).expect("send request");
+    let mut response = String::new();
+    stream.read_to_string(&mut response).expect("read response");
+    response
+}
+
 pub struct TcpEchoServer {
     pub addr: SocketAddr,
     pub connections: Arc<std::sync::atomic::AtomicUsize>,
@@ -85,6 +85,33 @@ connect_port = {}
     assert!(proxy.is_alive());
 }
 
+#[test]
+fn config_enforced_global_access_rules_refuse_other_clients() {
+    let echo = spawn_tcp_echo_server();
+    let port = reserve_proxy_port();
+    let config = format!(
+        r#"
+enforce_access_rules = true
+
+[[global_rules]]
+type = "allow"
+pattern = "10.0.0.0/8"
+
+[[forwarding_rules]]
+bind_address = "127.0.0.1"
+bind_port = {}
+connect_address = "127.0.0.1"
+connect_port = {}
+"#,
+        port, echo.addr.port()
+    );
+    let mut proxy = spawn_proxy(&config);
+    assert!(wait_for_port(proxy.bind_addr, Duration::from_secs(10)));
+    assert!(connection_is_refused(proxy.bind_addr));
+    assert_eq!(echo.connections.load(std::sync::atomic::Ordering::SeqCst), 0);
+    assert!(proxy.is_alive());
+}
+
 #[test]
 fn config_missing_forwarding_rules_exits_with_error() {
     let dir = tempfile::tempdir().unwrap();
@@ -207,3 +234,148 @@ fn no_rules_config_stays_alive() {
     terminate_proxy(&mut child);
     let _ = child.wait();
 }
+
+#[test]
+fn convert_subcommand_legacy_to_toml() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("rinetd.conf");
+    let output = dir.path().join("proxy.toml");
+    std::fs::write(&input, "# web\nallow 127.0.0.*\n127.0.0.1 8080 127.0.0.1 9090\n").unwrap();
+
+    let status = std::process::Command::new(BIN)
+        .arg("convert")
+        .arg(&input)
+        .arg("-o")
+        .arg(&output)
+        .status()
+        .expect("run oi binary");
+    assert!(status.success());
+
+    let converted = std::fs::read_to_string(&output).unwrap();
+    assert!(converted.contains("# web"));
+    let config = oxidinetd::config::Config::load_from_file(output.to_str().unwrap()).unwrap();
+    assert_eq!(config.global_rules.len(), 1);
+    assert_eq!(config.forwarding_rules.len(), 1);
+    assert_eq!(config.forwarding_rules[0].bind_port, 8080);
+}
+
+#[test]
+fn convert_subcommand_toml_to_legacy_warns_on_stderr() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("proxy.toml");
+    std::fs::write(
+        &input,
+        r#"
+[[forwarding_rules]]
+bind_address = "127.0.0.1"
+bind_port = 5353
+connect_address = "127.0.0.1"
+connect_port = 53
+protocol = "udp"
+"#,
+    )
+    .unwrap();
+
+    let output = std::process::Command::new(BIN)
+        .arg("convert")
+        .arg(&input)
+        .output()
+        .expect("run oi binary");
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(stdout, "# 127.0.0.1 5353 127.0.0.1 53\n");
+    assert!(stderr.contains("Warning:"), "expected a warning, got: {}", stderr);
+}
+
+#[test]
+fn toml_content_in_conf_file_is_detected() {
+    // A TOML config named `*.conf` must not be fed to the legacy parser.
+    let echo = spawn_tcp_echo_server();
+    let port = reserve_proxy_port();
+    let config = format!(
+        r#"
+[[forwarding_rules]]
+bind_address = "127.0.0.1"
+bind_port = {}
+connect_address = "127.0.0.1"
+connect_port = {}
+"#,
+        port, echo.addr.port()
+    );
+    let mut proxy = spawn_proxy_from_legacy_conf(&config);
+    assert!(wait_for_port(proxy.bind_addr, Duration::from_secs(10)));
+
+    let response = tcp_round_trip(proxy.bind_addr, b"sniffed toml");
+    assert_eq!(response, b"sniffed toml");
+    assert!(proxy.is_alive());
+}
+
+#[test]
+fn env_vars_are_interpolated_into_rules() {
+    let echo = spawn_tcp_echo_server();
+    let port = reserve_proxy_port();
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("proxy.toml");
+    std::fs::write(
+        &path,
+        r#"
+[[forwarding_rules]]
+bind_address = "${OI_BIND:-127.0.0.1}"
+bind_port = "${OI_PORT}"
+connect_address = "127.0.0.1"
+connect_port = "${OI_BACKEND_PORT}"
+"#,
+    )
+    .unwrap();
+
+    let mut child = std::process::Command::new(BIN)
+        .arg("-c")
+        .arg(&path)
+        .env("OI_PORT", port.to_string())
+        .env("OI_BACKEND_PORT", echo.addr.port().to_string())
+        .env_remove("OI_BIND")
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .expect("spawn oi binary");
+
+    let bind_addr = std::net::SocketAddr::from(([127, 0, 0, 1], port));
+    assert!(wait_for_port(bind_addr, Duration::from_secs(10)));
+    let response = tcp_round_trip(bind_addr, b"interpolated");
+    assert_eq!(response, b"interpolated");
+
+    terminate_proxy(&mut child);
+}
+
+#[test]
+fn undefined_env_var_exits_with_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("proxy.toml");
+    std::fs::write(
+        &path,
+        r#"
+[[forwarding_rules]]
+bind_address = "127.0.0.1"
+bind_port = 8080
+connect_address = "${OI_UNDEFINED_BACKEND}"
+connect_port = 9090
+"#,
+    )
+    .unwrap();
+
+    let output = std::process::Command::new(BIN)
+        .arg("-c")
+        .arg(&path)
+        .env_remove("OI_UNDEFINED_BACKEND")
+        .output()
+        .expect("run oi binary");
+
+    assert!(!output.status.success(), "expected non-zero exit code");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("OI_UNDEFINED_BACKEND"),
+        "expected the variable name on stderr, got: {}",
+        stderr
+    );
+}
@@ -0,0 +1,214 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::*;
+use std::io::{Read, Write};
+use std::time::Duration;
+
+/// Runs `oi ctl <command>` against `socket` and parses its output.
+fn ctl(socket: &std::path::Path, command: &str) -> serde_json::Value {
+    let output = std::process::Command::new(BIN)
+        .args(["ctl", command, "--socket"])
+        .arg(socket)
+        .output()
+        .expect("run oi ctl");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    serde_json::from_slice(&output.stdout).expect("JSON output")
+}
+
+#[test]
+fn ctl_lists_rules_connections_and_counters() {
+    let echo = spawn_tcp_echo_server();
+    let dir = tempfile::tempdir().unwrap();
+    let socket = dir.path().join("oi.sock");
+    let config = format!(
+        r#"
+control_socket = {:?}
+
+[[forwarding_rules]]
+bind_address = "127.0.0.1"
+bind_port = {}
+connect_address = "127.0.0.1"
+connect_port = {}
+"#,
+        socket.to_str().unwrap(),
+        reserve_proxy_port(),
+        echo.addr.port()
+    );
+    let mut proxy = spawn_proxy(&config);
+    assert!(wait_for_port(proxy.bind_addr, Duration::from_secs(10)));
+
+    let rules = ctl(&socket, "rules");
+    assert_eq!(rules["rules"][0]["state"], "listening");
+    assert_eq!(rules["rules"][0]["local_addr"], proxy.bind_addr.to_string());
+
+    let mut client = std::net::TcpStream::connect(proxy.bind_addr).unwrap();
+    client.write_all(b"open").unwrap();
+    let mut buf = [0u8; 4];
+    client.read_exact(&mut buf).unwrap();
+    let client_addr = client.local_addr().unwrap().to_string();
+
+    let connections = ctl(&socket, "connections");
+    let open = connections["connections"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|conn| conn["client"] == client_addr.as_str())
+        .expect("open connection listed");
+    assert_eq!(open["backend"], echo.addr.to_string());
+    assert_eq!(open["bytes_in"], 4);
+    assert_eq!(open["bytes_out"], 4);
+
+    let counters = ctl(&socket, "counters");
+    assert!(counters["accepted"].as_u64().unwrap() >= 1);
+    assert!(counters["active"].as_u64().unwrap() >= 1);
+
+    drop(client);
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    let closed = loop {
+        let answer = ctl(&socket, "closed");
+        let closed = answer["closed"].as_array().unwrap();
+        if let Some(closed) = closed.iter().find(|conn| conn["client"] == client_addr.as_str()) {
+            break closed.clone();
+        }
+        assert!(std::time::Instant::now() < deadline, "closed connection not listed");
+        std::thread::sleep(Duration::from_millis(50));
+    };
+    assert_eq!(closed["reason"], "finished");
+    assert_eq!(closed["bytes_in"], 4);
+    assert_eq!(closed["packets_in"], 1);
+    assert!(closed["duration_secs"].as_f64().unwrap() > 0.0);
+
+    assert_eq!(ctl(&socket, "reload"), serde_json::json!({ "reloaded": true }));
+    assert!(proxy.is_alive());
+}
+
+#[test]
+fn ctl_kill_closes_connection_by_id() {
+    let echo = spawn_tcp_echo_server();
+    let dir = tempfile::tempdir().unwrap();
+    let socket = dir.path().join("oi.sock");
+    let config = format!(
+        r#"
+control_socket = {:?}
+
+[[forwarding_rules]]
+bind_address = "127.0.0.1"
+bind_port = {}
+connect_address = "127.0.0.1"
+connect_port = {}
+"#,
+        socket.to_str().unwrap(),
+        reserve_proxy_port(),
+        echo.addr.port()
+    );
+    let mut proxy = spawn_proxy(&config);
+    assert!(wait_for_port(proxy.bind_addr, Duration::from_secs(10)));
+
+    let mut client = std::net::TcpStream::connect(proxy.bind_addr).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client.write_all(b"x").unwrap();
+    let mut buf = [0u8; 1];
+    client.read_exact(&mut buf).unwrap();
+    let client_addr = client.local_addr().unwrap().to_string();
+    let connections = ctl(&socket, "connections");
+    let id = connections["connections"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|conn| conn["client"] == client_addr.as_str())
+        .expect("open connection listed")["id"]
+        .as_u64()
+        .unwrap();
+
+    let output = std::process::Command::new(BIN)
+        .args(["ctl", "kill", "--id", &id.to_string(), "--socket"])
+        .arg(&socket)
+        .output()
+        .expect("run oi ctl kill");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let answer: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(answer["killed"][0]["id"], id);
+
+    let mut rest = Vec::new();
+    assert_eq!(client.read_to_end(&mut rest).unwrap(), 0);
+    assert!(proxy.is_alive());
+}
+
+/// Runs the proxy on `config` until it exits and returns its stderr.
+fn run_failing_proxy(dir: &std::path::Path, config: &str) -> String {
+    let path = dir.join("second.toml");
+    std::fs::write(&path, config).unwrap();
+    let output = std::process::Command::new(BIN)
+        .arg("-c")
+        .arg(&path)
+        .output()
+        .expect("run oi binary");
+    assert!(!output.status.success(), "expected non-zero exit code");
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+fn control_config(socket: &std::path::Path) -> String {
+    format!(
+        r#"
+control_socket = {:?}
+
+[[forwarding_rules]]
+bind_address = "127.0.0.1"
+bind_port = {}
+connect_address = "127.0.0.1"
+connect_port = 9
+"#,
+        socket.to_str().unwrap(),
+        reserve_proxy_port()
+    )
+}
+
+#[test]
+fn control_socket_of_a_running_instance_is_not_replaced() {
+    let dir = tempfile::tempdir().unwrap();
+    let socket = dir.path().join("oi.sock");
+    let mut proxy = spawn_proxy(&control_config(&socket));
+    assert!(wait_for_port(proxy.bind_addr, Duration::from_secs(10)));
+
+    let stderr = run_failing_proxy(dir.path(), &control_config(&socket));
+    assert!(stderr.contains("another instance is listening"), "{}", stderr);
+    assert_eq!(ctl(&socket, "rules")["rules"][0]["state"], "listening");
+    assert!(proxy.is_alive());
+}
+
+#[test]
+fn control_socket_path_that_is_not_a_socket_is_not_replaced() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("oi.sock");
+    std::fs::write(&path, "keep me").unwrap();
+
+    let stderr = run_failing_proxy(dir.path(), &control_config(&path));
+    assert!(stderr.contains("is not a socket"), "{}", stderr);
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "keep me");
+}
+
+#[test]
+fn stale_control_socket_is_replaced() {
+    let dir = tempfile::tempdir().unwrap();
+    let socket = dir.path().join("oi.sock");
+    drop(std::os::unix::net::UnixListener::bind(&socket).unwrap());
+    assert!(socket.exists());
+
+    let proxy = spawn_proxy(&control_config(&socket));
+    assert!(wait_for_port(proxy.bind_addr, Duration::from_secs(10)));
+    assert_eq!(ctl(&socket, "rules")["rules"][0]["state"], "listening");
+}
+
+#[test]
+fn control_socket_is_only_accessible_to_its_owner() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let socket = dir.path().join("oi.sock");
+    let proxy = spawn_proxy(&control_config(&socket));
+    assert!(wait_for_port(proxy.bind_addr, Duration::from_secs(10)));
+    let mode = std::fs::metadata(&socket).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+}
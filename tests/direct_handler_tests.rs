@@ -1,8 +1,10 @@
 //! Direct-call tests for code paths that the full binary never reaches:
-//! the "invalid protocol" fallback arms of the TCP/UDP handlers.
+//! the "invalid protocol" fallback arms of the TCP/UDP handlers and the
+//! errors they return.
 
 use oxidinetd::config::Protocol;
-use oxidinetd::tcp_handler::handle_tcp_connection;
+use oxidinetd::error::ProxyError;
+use oxidinetd::tcp_handler::{handle_tcp_connection, start_tcp_forwarding};
 use oxidinetd::udp_handler::start_udp_forwarding;
 
 #[test]
@@ -18,7 +20,10 @@ fn tcp_handler_rejects_udp_protocol() {
         });
         let _client = smol::net::TcpStream::connect(addr).await.expect("connect");
         let result = handle.await;
-        assert!(result.is_err(), "Udp protocol must be rejected by the TCP handler");
+        assert!(
+            matches!(result, Err(ProxyError::ProtocolMismatch { handler: "TCP", protocol: Protocol::Udp })),
+            "Udp protocol must be rejected by the TCP handler"
+        );
     });
 }
 
@@ -35,7 +40,10 @@ fn tcp_handler_rejects_udptotcp_protocol() {
         });
         let _client = smol::net::TcpStream::connect(addr).await.expect("connect");
         let result = handle.await;
-        assert!(result.is_err(), "UdpToTcp protocol must be rejected by the TCP handler");
+        assert!(
+            matches!(result, Err(ProxyError::ProtocolMismatch { handler: "TCP", protocol: Protocol::UdpToTcp })),
+            "UdpToTcp protocol must be rejected by the TCP handler"
+        );
     });
 }
 
@@ -49,7 +57,10 @@ fn udp_handler_rejects_tcp_protocol() {
             Protocol::Tcp,
         )
         .await;
-        assert!(result.is_err(), "Tcp protocol must be rejected by the UDP handler");
+        assert!(
+            matches!(result, Err(ProxyError::ProtocolMismatch { handler: "UDP", protocol: Protocol::Tcp })),
+            "Tcp protocol must be rejected by the UDP handler"
+        );
     });
 }
 
@@ -63,7 +74,52 @@ fn udp_handler_rejects_tcptoudp_protocol() {
             Protocol::TcpToUdp,
         )
         .await;
-        assert!(result.is_err(), "TcpToUdp protocol must be rejected by the UDP handler");
+        assert!(
+            matches!(result, Err(ProxyError::ProtocolMismatch { handler: "UDP", protocol: Protocol::TcpToUdp })),
+            "TcpToUdp protocol must be rejected by the UDP handler"
+        );
+    });
+}
+
+#[test]
+fn tcp_forwarding_reports_bind_error() {
+    smol::block_on(async {
+        let taken = smol::net::TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = taken.local_addr().unwrap();
+        let result = start_tcp_forwarding(addr, "127.0.0.1:1".to_string(), Protocol::Tcp).await;
+        match result {
+            Err(ProxyError::Bind { addr: failed, .. }) => assert_eq!(failed, addr),
+            other => panic!("expected a bind error, got {:?}", other),
+        }
+    });
+}
+
+#[test]
+fn udp_forwarding_reports_bind_error() {
+    smol::block_on(async {
+        let taken = smol::net::UdpSocket::bind("127.0.0.1:0").await.expect("bind");
+        let addr = taken.local_addr().unwrap();
+        let result = start_udp_forwarding(addr, "127.0.0.1:1".to_string(), None, Protocol::Udp).await;
+        assert!(matches!(result, Err(ProxyError::Bind { .. })));
+    });
+}
+
+#[test]
+fn tcp_handler_reports_connect_error() {
+    smol::block_on(async {
+        // Reserve a port and close it again so nothing listens there.
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let listener = smol::net::TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().unwrap();
+        let handle = smol::spawn(async move {
+            let (client, _) = listener.accept().await.expect("accept");
+            handle_tcp_connection(client, closed.to_string(), Protocol::Tcp).await
+        });
+        let _client = smol::net::TcpStream::connect(addr).await.expect("connect");
+        match handle.await {
+            Err(ProxyError::Connect { addr, .. }) => assert_eq!(addr, closed.to_string()),
+            other => panic!("expected a connect error, got {:?}", other),
+        }
     });
 }
 
@@ -82,10 +138,10 @@ fn udp_forwarder_default_timeout_is_72s() {
         // Run for a short while with no traffic; the loop just blocks on
         // recv_from. We race it against a timer and take whichever completes.
         let _ = smol::future::or(
-            forwarder.run("127.0.0.1:1".to_string()),
+            forwarder.run(),
             async {
                 smol::Timer::after(std::time::Duration::from_millis(100)).await;
-                Ok::<(), Box<dyn std::error::Error>>(())
+                Ok::<(), ProxyError>(())
             },
         )
         .await;
@@ -104,10 +160,10 @@ fn udp_forwarder_accepts_explicit_timeout() {
         .await
         .expect("create forwarder");
         let _ = smol::future::or(
-            forwarder.run("127.0.0.1:1".to_string()),
+            forwarder.run(),
             async {
                 smol::Timer::after(std::time::Duration::from_millis(100)).await;
-                Ok::<(), Box<dyn std::error::Error>>(())
+                Ok::<(), ProxyError>(())
             },
         )
         .await;
@@ -0,0 +1,245 @@
+mod common;
+
+use common::*;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Waits until the log file at `path` has a line containing `needle`.
+fn wait_for_log_line(path: &Path, needle: &str) -> String {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let content = std::fs::read_to_string(path).unwrap_or_default();
+        if let Some(line) = content.lines().find(|line| line.contains(needle)) {
+            return line.to_string();
+        }
+        assert!(Instant::now() < deadline, "no line with {} in:\n{}", needle, content);
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Opens one connection through the proxy and returns the client address.
+fn one_connection(proxy: &TestProxy) -> String {
+    let mut client = std::net::TcpStream::connect(proxy.bind_addr).unwrap();
+    client.write_all(b"logged").unwrap();
+    let mut buf = [0u8; 6];
+    client.read_exact(&mut buf).unwrap();
+    client.local_addr().unwrap().to_string()
+}
+
+fn config(log_file: &Path, log_format: &str, echo_port: u16) -> String {
+    format!(
+        r#"
+log_file = {:?}
+log_format = "{}"
+
+[[forwarding_rules]]
+bind_address = "127.0.0.1"
+bind_port = {}
+connect_address = "127.0.0.1"
+connect_port = {}
+"#,
+        log_file.to_str().unwrap(),
+        log_format,
+        reserve_proxy_port(),
+        echo_port
+    )
+}
+
+#[test]
+fn json_log_file_gets_one_object_per_event() {
+    let echo = spawn_tcp_echo_server();
+    let dir = tempfile::tempdir().unwrap();
+    let log_file = dir.path().join("oi.log");
+    let proxy = spawn_proxy(&config(&log_file, "json", echo.addr.port()));
+    assert!(wait_for_port(proxy.bind_addr, Duration::from_secs(10)));
+
+    let startup: serde_json::Value = serde_json::from_str(&wait_for_log_line(&log_file, "\"startup\"")).unwrap();
+    assert_eq!(startup["level"], "info");
+    assert_eq!(startup["rules"], 1);
+    assert!(startup["time"].as_str().unwrap().ends_with('Z'));
+    let started = wait_for_log_line(&log_file, "\"listener_started\"");
+    assert!(started.contains(&proxy.bind_addr.to_string()), "{}", started);
+
+    let client = one_connection(&proxy);
+    let needle = format!("\"client\":\"{}\"", client);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let closed = loop {
+        let content = std::fs::read_to_string(&log_file).unwrap();
+        let closed = content
+            .lines()
+            .find(|line| line.contains(&needle) && line.contains("\"connection_closed\""))
+            .map(str::to_string);
+        if let Some(closed) = closed {
+            break closed;
+        }
+        assert!(Instant::now() < deadline, "no close event in:\n{}", content);
+        std::thread::sleep(Duration::from_millis(20));
+    };
+    let closed: serde_json::Value = serde_json::from_str(&closed).unwrap();
+    assert_eq!(closed["reason"], "finished");
+    assert_eq!(closed["bytes_in"], 6);
+    assert_eq!(closed["bytes_out"], 6);
+    assert_eq!(closed["upstream"], echo.addr.to_string());
+}
+
+#[test]
+fn connection_events_are_filtered_by_their_handler() {
+    let echo = spawn_tcp_echo_server();
+    let dir = tempfile::tempdir().unwrap();
+    let log_file = dir.path().join("oi.log");
+    let config = format!("log_level = \"warn,tcp_handler=debug\"\n{}", config(&log_file, "json", echo.addr.port()));
+    let proxy = spawn_proxy(&config);
+    assert!(wait_for_port(proxy.bind_addr, Duration::from_secs(10)));
+
+    let client = one_connection(&proxy);
+    let accepted = wait_for_log_line(&log_file, &format!("\"client\":\"{}\"", client));
+    let accepted: serde_json::Value = serde_json::from_str(&accepted).unwrap();
+    assert_eq!(accepted["event"], "connection_accepted");
+    assert_eq!(accepted["module"], "tcp_handler");
+}
+
+#[test]
+fn rinetd_log_file_gets_connection_lines() {
+    let echo = spawn_tcp_echo_server();
+    let dir = tempfile::tempdir().unwrap();
+    let log_file = dir.path().join("oi.log");
+    let proxy = spawn_proxy(&config(&log_file, "rinetd", echo.addr.port()));
+    assert!(wait_for_port(proxy.bind_addr, Duration::from_secs(10)));
+
+    let client = one_connection(&proxy);
+    let client_ip = client.rsplit_once(':').unwrap().0;
+    let line = wait_for_log_line(&log_file, "\t6\t6\tfinished");
+    let columns: Vec<&str> = line.split('\t').collect();
+    assert_eq!(columns.len(), 9, "{}", line);
+    assert_eq!(columns[1], client_ip);
+    assert_eq!(columns[3], proxy.bind_addr.port().to_string());
+    assert_eq!(columns[5], echo.addr.port().to_string());
+    let content = std::fs::read_to_string(&log_file).unwrap();
+    assert!(!content.contains("Loaded 1 forwarding rules"), "{}", content);
+}
+
+/// Runs the proxy with `config` and `args` for a moment and returns what it
+/// printed to stdout.
+fn stdout_of(config: &str, args: &[&str]) -> String {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("proxy.toml");
+    std::fs::write(&path, config).unwrap();
+    let mut child = std::process::Command::new(BIN)
+        .arg("-c")
+        .arg(&path)
+        .args(args)
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .expect("spawn oi binary");
+    std::thread::sleep(Duration::from_millis(800));
+    terminate_proxy(&mut child);
+    String::from_utf8_lossy(&child.wait_with_output().unwrap().stdout).into_owned()
+}
+
+fn rule_config(log_level: &str) -> String {
+    format!(
+        r#"
+{}
+
+[[forwarding_rules]]
+bind_address = "127.0.0.1"
+bind_port = {}
+connect_address = "127.0.0.1"
+connect_port = 9
+"#,
+        log_level,
+        reserve_proxy_port()
+    )
+}
+
+#[test]
+fn quiet_flag_hides_info_messages() {
+    let stdout = stdout_of(&rule_config(""), &["-q"]);
+    assert!(!stdout.contains("Loaded 1 forwarding rules"), "{}", stdout);
+    assert!(!stdout.contains("Starting TCP forwarding"), "{}", stdout);
+}
+
+#[test]
+fn log_level_filters_by_module() {
+    let stdout = stdout_of(&rule_config(r#"log_level = "warn,server=info""#), &[]);
+    assert!(!stdout.contains("Loaded 1 forwarding rules"), "{}", stdout);
+    assert!(stdout.contains("Starting TCP forwarding"), "{}", stdout);
+
+    // The command line wins over the config
+    let stdout = stdout_of(&rule_config(r#"log_level = "warn""#), &["--log-level", "info"]);
+    assert!(stdout.contains("Loaded 1 forwarding rules"), "{}", stdout);
+}
+
+#[test]
+fn syslog_gets_connection_lines() {
+    let echo = spawn_tcp_echo_server();
+    let daemon = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    daemon.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let config = format!(
+        r#"
+[syslog]
+address = "udp://{}"
+facility = "local1"
+ident = "edge"
+
+[[forwarding_rules]]
+bind_address = "127.0.0.1"
+bind_port = {}
+connect_address = "127.0.0.1"
+connect_port = {}
+"#,
+        daemon.local_addr().unwrap(),
+        reserve_proxy_port(),
+        echo.addr.port()
+    );
+    let proxy = spawn_proxy(&config);
+    assert!(wait_for_port(proxy.bind_addr, Duration::from_secs(10)));
+
+    let client = one_connection(&proxy);
+    let client_ip = client.rsplit_once(':').unwrap().0;
+    let mut buf = [0u8; 2048];
+    let message = loop {
+        let len = daemon.recv(&mut buf).expect("a syslog message");
+        let message = String::from_utf8_lossy(&buf[..len]).into_owned();
+        // Skip the connection wait_for_port made
+        if message.contains("\t6\t6\tfinished") {
+            break message;
+        }
+    };
+    // local1 is 17, info is 6
+    assert!(message.starts_with("<142>"), "{}", message);
+    assert!(message.contains(" edge["), "{}", message);
+    assert!(message.contains(&format!("\t{}\t", client_ip)), "{}", message);
+}
+
+#[cfg(unix)]
+#[test]
+fn sigusr1_reopens_the_log_file() {
+    let echo = spawn_tcp_echo_server();
+    let dir = tempfile::tempdir().unwrap();
+    let log_file = dir.path().join("oi.log");
+    let proxy = spawn_proxy(&config(&log_file, "rinetd", echo.addr.port()));
+    assert!(wait_for_port(proxy.bind_addr, Duration::from_secs(10)));
+
+    // What logrotate does without copytruncate
+    let rotated = dir.path().join("oi.log.1");
+    std::fs::rename(&log_file, &rotated).unwrap();
+    let status = std::process::Command::new("kill")
+        .arg("-USR1")
+        .arg(proxy.child.id().to_string())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !log_file.exists() {
+        assert!(Instant::now() < deadline, "the log file was not reopened");
+        std::thread::sleep(Duration::from_millis(20));
+    }
+
+    one_connection(&proxy);
+    wait_for_log_line(&log_file, "\t6\t6\tfinished");
+    let old = std::fs::read_to_string(&rotated).unwrap();
+    assert!(!old.contains("\t6\t6\tfinished"), "{}", old);
+}
@@ -0,0 +1,59 @@
+mod common;
+
+use common::*;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// The value of the sample `series` in a metrics response.
+fn metric(response: &str, series: &str) -> u64 {
+    response
+        .lines()
+        .find_map(|line| line.strip_prefix(series))
+        .unwrap_or_else(|| panic!("no {} in {}", series, response))
+        .trim()
+        .parse()
+        .unwrap()
+}
+
+#[test]
+fn metrics_endpoint_reports_rule_counters() {
+    let echo = spawn_tcp_echo_server();
+    let metrics_addr = SocketAddr::from(([127, 0, 0, 1], reserve_proxy_port()));
+    let config = format!(
+        r#"
+metrics_address = "{}"
+
+[[forwarding_rules]]
+bind_address = "127.0.0.1"
+bind_port = {}
+connect_address = "127.0.0.1"
+connect_port = {}
+"#,
+        metrics_addr,
+        reserve_proxy_port(),
+        echo.addr.port()
+    );
+    let mut proxy = spawn_proxy(&config);
+    assert!(wait_for_port(proxy.bind_addr, Duration::from_secs(10)));
+    assert!(wait_for_port(metrics_addr, Duration::from_secs(10)));
+
+    assert_eq!(tcp_round_trip(proxy.bind_addr, b"metered"), b"metered");
+    std::thread::sleep(Duration::from_millis(200));
+
+    let response = http_get(metrics_addr, "/metrics");
+    assert!(response.starts_with("HTTP/1.0 200 OK"), "{}", response);
+    let labels = format!(
+        r#"bind_address="127.0.0.1",bind_port="{}",protocol="tcp""#,
+        proxy.bind_addr.port()
+    );
+    // The readiness probes of spawn_proxy are connections too
+    assert!(metric(&response, &format!("oi_connections_accepted_total{{{}}}", labels)) >= 1);
+    assert_eq!(metric(&response, &format!("oi_connections_active{{{}}}", labels)), 0);
+    assert!(response.contains(&format!("oi_bytes_in_total{{{}}} 7", labels)), "{}", response);
+    assert!(response.contains(&format!("oi_bytes_out_total{{{}}} 7", labels)), "{}", response);
+    assert!(metric(&response, &format!("oi_upstream_connect_seconds_count{{{}}}", labels)) >= 1);
+    assert!(response.contains("oi_config_reloads_total{result=\"success\"} 0"), "{}", response);
+
+    assert!(http_get(metrics_addr, "/other").starts_with("HTTP/1.0 404"));
+    assert!(proxy.is_alive());
+}
@@ -0,0 +1,870 @@
+mod common;
+
+use common::*;
+use oxidinetd::config::{
+    AccessRule, AutoBanConfig, BandwidthConfig, BanTrigger, Config, FilterConfig, ForwardingRule, LimitAction,
+    Protocol, RateLimitConfig, RuleType,
+};
+use oxidinetd::connections::KillTarget;
+use oxidinetd::error::ProxyError;
+use oxidinetd::filter::{FilterAction, StreamFilter};
+use oxidinetd::observer::{CloseReason, ConnectionInfo, ConnectionObserver, Direction, TransferTotals};
+use oxidinetd::proxy::Proxy;
+use oxidinetd::server::{PauseMode, RuleState};
+use std::io::Read;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+fn rule(bind_port: u16, connect_addr: SocketAddr, protocol: Protocol) -> ForwardingRule {
+    ForwardingRule {
+        bind_address: "127.0.0.1".to_string(),
+        bind_port,
+        connect_address: connect_addr.ip().to_string(),
+        connect_port: connect_addr.port(),
+        protocol,
+        timeout: None,
+        source_address: None,
+        rules: Vec::new(),
+        filters: Vec::new(),
+        enabled: true,
+        max_connections: None,
+        limit_action: None,
+        rate_limit: None,
+        bandwidth: None,
+    }
+}
+
+#[test]
+fn proxy_reports_addresses_bound_to_port_zero() {
+    let echo = spawn_tcp_echo_server();
+    let udp_echo = spawn_udp_echo_server();
+    smol::block_on(async {
+        let proxy = Proxy::builder()
+            .rule(rule(0, echo.addr, Protocol::Tcp))
+            .rule(rule(0, udp_echo.addr, Protocol::Udp))
+            .start()
+            .await;
+
+        let addrs = proxy.handle().local_addrs();
+        assert_eq!(addrs.len(), 2);
+        let (_, tcp_addr) = addrs.iter().find(|(rule, _)| rule.protocol == Protocol::Tcp).unwrap();
+        let (_, udp_addr) = addrs.iter().find(|(rule, _)| rule.protocol == Protocol::Udp).unwrap();
+        assert_ne!(tcp_addr.port(), 0);
+        assert_ne!(udp_addr.port(), 0);
+
+        assert_eq!(tcp_round_trip(*tcp_addr, b"embedded"), b"embedded");
+        assert_eq!(udp_round_trip_with_retries(*udp_addr, b"datagram"), b"datagram");
+
+        proxy.shutdown().await;
+    });
+}
+
+#[test]
+fn proxy_reload_applies_new_config() {
+    let echo = spawn_tcp_echo_server();
+    smol::block_on(async {
+        let kept = rule(reserve_proxy_port(), echo.addr, Protocol::Tcp);
+        let proxy = Proxy::builder().rule(kept.clone()).start().await;
+        let handle = proxy.handle();
+
+        let added = rule(reserve_proxy_port(), echo.addr, Protocol::Tcp);
+        let config = Config {
+            forwarding_rules: vec![kept, added.clone()],
+            ..Config::default()
+        };
+        let summary = handle.reload(config).await.expect("proxy is running");
+        assert_eq!(summary.added, vec![added.clone()]);
+        assert_eq!(summary.unchanged, 1);
+        assert_eq!(handle.local_addrs().len(), 2);
+
+        let added_addr = SocketAddr::from(([127, 0, 0, 1], added.bind_port));
+        assert_eq!(tcp_round_trip(added_addr, b"reloaded"), b"reloaded");
+
+        proxy.shutdown().await;
+        assert!(handle.local_addrs().is_empty());
+        assert!(handle.reload(Config::default()).await.is_none());
+    });
+}
+
+#[test]
+fn proxy_shutdown_drains_open_connections() {
+    let echo = spawn_tcp_echo_server();
+    smol::block_on(async {
+        let proxy = Proxy::builder()
+            .rule(rule(0, echo.addr, Protocol::Tcp))
+            .drain_timeout(Duration::from_secs(10))
+            .start()
+            .await;
+        let handle = proxy.handle();
+        let addr = handle.local_addrs()[0].1;
+
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        assert_eq!(tcp_round_trip(addr, b"warm up"), b"warm up");
+        let waiter = smol::spawn(proxy.wait());
+        handle.shutdown();
+
+        // The listener is gone while the open connection keeps working
+        smol::Timer::after(Duration::from_millis(200)).await;
+        assert!(std::net::TcpStream::connect(addr).is_err());
+        std::io::Write::write_all(&mut client, b"still open").unwrap();
+        let mut buf = [0u8; 10];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"still open");
+
+        drop(client);
+        let report = waiter.await;
+        assert_eq!(report.tcp_connections, 0);
+        assert_eq!(report.udp_sessions, 0);
+    });
+}
+
+#[test]
+fn proxy_second_shutdown_closes_remaining_connections() {
+    let echo = spawn_tcp_echo_server();
+    smol::block_on(async {
+        let proxy = Proxy::builder()
+            .rule(rule(0, echo.addr, Protocol::Tcp))
+            .drain_timeout(Duration::from_secs(60))
+            .start()
+            .await;
+        let handle = proxy.handle();
+        let addr = handle.local_addrs()[0].1;
+
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        std::io::Write::write_all(&mut client, b"x").unwrap();
+        let mut buf = [0u8; 1];
+        client.read_exact(&mut buf).unwrap();
+
+        handle.shutdown();
+        smol::Timer::after(Duration::from_millis(100)).await;
+        handle.shutdown();
+        let report = proxy.wait().await;
+        assert_eq!(report.tcp_connections, 1);
+    });
+}
+
+#[test]
+fn proxy_reports_bind_errors() {
+    let echo = spawn_tcp_echo_server();
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let taken_port = taken.local_addr().unwrap().port();
+    smol::block_on(async {
+        let proxy = Proxy::builder()
+            .rule(rule(taken_port, echo.addr, Protocol::Tcp))
+            .rule(rule(0, echo.addr, Protocol::Tcp))
+            .start()
+            .await;
+        let handle = proxy.handle();
+
+        let failure = handle.errors().try_recv().expect("bind error reported");
+        assert_eq!(failure.rule.bind_port, taken_port);
+        assert!(matches!(failure.error, ProxyError::Bind { .. }));
+        assert_eq!(handle.local_addrs().len(), 1);
+
+        proxy.shutdown().await;
+    });
+}
+
+#[derive(Default)]
+struct Recorder {
+    events: std::sync::Mutex<Vec<String>>,
+}
+
+impl ConnectionObserver for Recorder {
+    fn on_accept(&self, conn: &ConnectionInfo) {
+        self.events.lock().unwrap().push(format!("accept {:?}", conn.protocol));
+    }
+
+    fn on_access(&self, _conn: &ConnectionInfo, allowed: bool) {
+        self.events.lock().unwrap().push(format!("access {}", allowed));
+    }
+
+    fn on_connect(&self, _conn: &ConnectionInfo, result: Result<Duration, &ProxyError>) {
+        self.events.lock().unwrap().push(format!("connect {}", result.is_ok()));
+    }
+
+    fn on_close(&self, _conn: &ConnectionInfo, reason: &CloseReason, totals: &TransferTotals) {
+        self.events.lock().unwrap().push(format!(
+            "close {:?} {} {}",
+            reason, totals.client_to_upstream, totals.upstream_to_client
+        ));
+    }
+}
+
+impl Recorder {
+    fn wait_for(&self, count: usize) -> Vec<String> {
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while self.events.lock().unwrap().len() < count && std::time::Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        self.events.lock().unwrap().clone()
+    }
+}
+
+#[test]
+fn observer_sees_tcp_connection_lifecycle() {
+    let echo = spawn_tcp_echo_server();
+    smol::block_on(async {
+        let recorder = Arc::new(Recorder::default());
+        let proxy = Proxy::builder()
+            .rule(rule(0, echo.addr, Protocol::Tcp))
+            .observer(recorder.clone())
+            .start()
+            .await;
+        let addr = proxy.handle().local_addrs()[0].1;
+
+        assert_eq!(tcp_round_trip(addr, b"observed"), b"observed");
+        assert_eq!(
+            recorder.wait_for(4),
+            vec!["accept Tcp", "access true", "connect true", "close Finished 8 8"]
+        );
+        proxy.shutdown().await;
+    });
+}
+
+#[test]
+fn observer_sees_denied_client() {
+    let echo = spawn_tcp_echo_server();
+    smol::block_on(async {
+        let recorder = Arc::new(Recorder::default());
+        let proxy = Proxy::builder()
+            .config(Config { enforce_access_rules: true, ..Config::default() })
+            .rule(rule(0, echo.addr, Protocol::Tcp))
+            .global_rule(AccessRule {
+                rule_type: RuleType::Deny,
+                pattern: "127.0.0.1".to_string(),
+                file: None,
+            })
+            .observer(recorder.clone())
+            .start()
+            .await;
+        let addr = proxy.handle().local_addrs()[0].1;
+
+        assert!(connection_is_refused(addr));
+        let events = recorder.wait_for(3);
+        assert_eq!(events[..2], ["accept Tcp", "access false"]);
+        assert!(events[2].starts_with("close Denied(AccessDenied(127.0.0.1:"), "{:?}", events);
+        proxy.shutdown().await;
+    });
+}
+
+#[test]
+fn observer_sees_expired_udp_session() {
+    let udp_echo = spawn_udp_echo_server();
+    smol::block_on(async {
+        let recorder = Arc::new(Recorder::default());
+        let mut udp_rule = rule(0, udp_echo.addr, Protocol::Udp);
+        udp_rule.timeout = Some(1);
+        let proxy = Proxy::builder().rule(udp_rule).observer(recorder.clone()).start().await;
+        let addr = proxy.handle().local_addrs()[0].1;
+
+        assert_eq!(udp_round_trip_with_retries(addr, b"ping"), b"ping");
+        let events = recorder.wait_for(4);
+        assert_eq!(events[..3], ["accept Udp", "access true", "connect true"]);
+        assert!(events[3].starts_with("close Expired 4 4"), "{:?}", events);
+        proxy.shutdown().await;
+    });
+}
+
+#[test]
+fn kill_ends_tcp_connections_and_udp_sessions() {
+    let echo = spawn_tcp_echo_server();
+    let udp_echo = spawn_udp_echo_server();
+    smol::block_on(async {
+        let recorder = Arc::new(Recorder::default());
+        let proxy = Proxy::builder()
+            .rule(rule(0, echo.addr, Protocol::Tcp))
+            .rule(rule(0, udp_echo.addr, Protocol::Udp))
+            .observer(recorder.clone())
+            .start()
+            .await;
+        let handle = proxy.handle();
+        let addrs = handle.local_addrs();
+        let (_, tcp_addr) = addrs.iter().find(|(rule, _)| rule.protocol == Protocol::Tcp).unwrap();
+        let (_, udp_addr) = addrs.iter().find(|(rule, _)| rule.protocol == Protocol::Udp).unwrap();
+
+        let mut client = std::net::TcpStream::connect(tcp_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        std::io::Write::write_all(&mut client, b"x").unwrap();
+        let mut buf = [0u8; 1];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(udp_round_trip_with_retries(*udp_addr, b"ping"), b"ping");
+
+        let killed = handle.kill(KillTarget::Client("127.0.0.1".parse().unwrap()));
+        assert_eq!(killed.len(), 2);
+        let mut rest = Vec::new();
+        assert_eq!(client.read_to_end(&mut rest).unwrap(), 0);
+
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while recorder.events.lock().unwrap().iter().filter(|event| event.starts_with("close Killed")).count() < 2 {
+            assert!(std::time::Instant::now() < deadline, "{:?}", recorder.events.lock().unwrap());
+            smol::Timer::after(Duration::from_millis(10)).await;
+        }
+        assert!(handle.kill(KillTarget::Listener(*tcp_addr)).is_empty());
+        proxy.shutdown().await;
+    });
+}
+
+#[test]
+fn paused_and_disabled_rules_stop_accepting_clients() {
+    let echo = spawn_tcp_echo_server();
+    smol::block_on(async {
+        let port = reserve_proxy_port();
+        let addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+        let mut disabled = rule(port, echo.addr, Protocol::Tcp);
+        disabled.enabled = false;
+        let proxy = Proxy::builder().rule(disabled).start().await;
+        let handle = proxy.handle();
+        assert_eq!(handle.rules()[0].state, RuleState::Paused(PauseMode::Close));
+        assert!(std::net::TcpStream::connect(addr).is_err());
+
+        let resumed = handle.resume(addr).await.unwrap();
+        assert_eq!(resumed[0].state, RuleState::Listening);
+        assert_eq!(tcp_round_trip(addr, b"up"), b"up");
+
+        // Refusing keeps open connections and disconnects new clients
+        let mut open = std::net::TcpStream::connect(addr).unwrap();
+        open.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        std::io::Write::write_all(&mut open, b"x").unwrap();
+        let mut buf = [0u8; 1];
+        open.read_exact(&mut buf).unwrap();
+        let paused = handle.pause(addr, PauseMode::Refuse).await.unwrap();
+        assert_eq!(paused[0].state, RuleState::Paused(PauseMode::Refuse));
+        let mut refused = std::net::TcpStream::connect(addr).unwrap();
+        refused.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(refused.read(&mut buf).unwrap_or(0), 0);
+        std::io::Write::write_all(&mut open, b"y").unwrap();
+        open.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"y");
+
+        // Draining ends the connections still open after the timeout
+        let drained = handle.drain(addr, Duration::from_millis(100)).await;
+        assert_eq!(drained.len(), 1);
+        let mut rest = Vec::new();
+        assert_eq!(open.read_to_end(&mut rest).unwrap(), 0);
+
+        handle.resume(addr).await.unwrap();
+        handle.pause(addr, PauseMode::Close).await.unwrap();
+        assert!(std::net::TcpStream::connect(addr).is_err());
+        assert!(handle.pause("127.0.0.1:1".parse().unwrap(), PauseMode::Close).await.unwrap().is_empty());
+        proxy.shutdown().await;
+    });
+}
+
+#[test]
+fn proxy_reload_swaps_changed_targets_without_rebinding() {
+    let echo = spawn_tcp_echo_server();
+    let other_echo = spawn_tcp_echo_server();
+    let udp_echo = spawn_udp_echo_server();
+    let sink = spawn_udp_sink_server();
+    smol::block_on(async {
+        let tcp = rule(0, echo.addr, Protocol::Tcp);
+        let udp = rule(0, udp_echo.addr, Protocol::Udp);
+        let proxy = Proxy::builder().rule(tcp.clone()).rule(udp.clone()).start().await;
+        let handle = proxy.handle();
+        let addrs = handle.local_addrs();
+        let (_, tcp_addr) = *addrs.iter().find(|(rule, _)| rule.protocol == Protocol::Tcp).unwrap();
+        let (_, udp_addr) = *addrs.iter().find(|(rule, _)| rule.protocol == Protocol::Udp).unwrap();
+        let mut open = open_connection(tcp_addr);
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.send_to(b"ping", udp_addr).unwrap();
+        let mut buf = [0u8; 4];
+        client.recv(&mut buf).unwrap();
+
+        let mut moved_tcp = tcp.clone();
+        moved_tcp.connect_port = other_echo.addr.port();
+        let mut moved_udp = udp.clone();
+        moved_udp.connect_port = sink.addr.port();
+        let config = Config {
+            forwarding_rules: vec![moved_tcp.clone(), moved_udp.clone()],
+            ..Config::default()
+        };
+        let summary = handle.reload(config).await.expect("proxy is running");
+        assert_eq!(summary.changed, vec![moved_tcp.clone(), moved_udp.clone()]);
+        // Rules binding port 0 would have got new ports from a new socket
+        let mut kept: Vec<_> = handle.local_addrs().into_iter().map(|(_, addr)| addr).collect();
+        kept.sort();
+        let mut before = vec![tcp_addr, udp_addr];
+        before.sort();
+        assert_eq!(kept, before);
+
+        assert_eq!(tcp_round_trip(tcp_addr, b"moved"), b"moved");
+        std::io::Write::write_all(&mut open, b"y").unwrap();
+        open.read_exact(&mut buf[..1]).unwrap();
+        assert_eq!(&buf[..1], b"y");
+        // The open UDP session forwards to the new target
+        client.send_to(b"to sink", udp_addr).unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while sink.received.load(std::sync::atomic::Ordering::SeqCst) == 0 {
+            assert!(std::time::Instant::now() < deadline, "no datagram reached the new target");
+            smol::Timer::after(Duration::from_millis(10)).await;
+        }
+
+        // A change that cannot be applied keeps the rule forwarding as before
+        let mut broken = moved_tcp.clone();
+        broken.connect_port = echo.addr.port();
+        broken.filters = vec![FilterConfig::Custom { name: "missing".to_string() }];
+        let config = Config {
+            forwarding_rules: vec![broken.clone(), moved_udp],
+            ..Config::default()
+        };
+        let summary = handle.reload(config).await.expect("proxy is running");
+        assert_eq!(summary.failed, vec![broken]);
+        assert_eq!(tcp_round_trip(tcp_addr, b"kept"), b"kept");
+        proxy.shutdown().await;
+    });
+}
+
+#[test]
+fn closed_udp_rules_drain_their_sessions() {
+    let udp_echo = spawn_udp_echo_server();
+    smol::block_on(async {
+        let port = reserve_proxy_port();
+        let addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+        let proxy = Proxy::builder().rule(rule(port, udp_echo.addr, Protocol::Udp)).start().await;
+        let handle = proxy.handle();
+        let exchange = |socket: &std::net::UdpSocket, payload: &[u8]| {
+            socket.send_to(payload, addr).unwrap();
+            let mut buf = [0u8; 64];
+            socket.recv(&mut buf).map(|len| buf[..len].to_vec())
+        };
+        let in_session = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        in_session.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(exchange(&in_session, b"before").unwrap(), b"before");
+
+        let paused = handle.pause(addr, PauseMode::Close).await.unwrap();
+        assert_eq!(paused[0].state, RuleState::Paused(PauseMode::Close));
+        assert_eq!(exchange(&in_session, b"during").unwrap(), b"during");
+        let new_client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        new_client.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        assert!(exchange(&new_client, b"refused").is_err());
+
+        // Resuming takes new clients on the same socket
+        let resumed = handle.resume(addr).await.unwrap();
+        assert_eq!(resumed[0].state, RuleState::Listening);
+        assert_eq!(exchange(&new_client, b"resumed").unwrap(), b"resumed");
+        proxy.shutdown().await;
+    });
+}
+
+/// Connects to `addr` and checks that the connection forwards.
+fn open_connection(addr: SocketAddr) -> std::net::TcpStream {
+    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    std::io::Write::write_all(&mut stream, b"x").unwrap();
+    let mut buf = [0u8; 1];
+    stream.read_exact(&mut buf).unwrap();
+    stream
+}
+
+/// Retries until a round trip through `addr` succeeds, as freed connection
+/// slots are noticed asynchronously.
+fn eventually_forwards(addr: SocketAddr) {
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    while connection_is_refused(addr) {
+        assert!(std::time::Instant::now() < deadline, "{} still refuses clients", addr);
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn clients_over_the_rule_limit_are_refused() {
+    let echo = spawn_tcp_echo_server();
+    smol::block_on(async {
+        let mut limited = rule(0, echo.addr, Protocol::Tcp);
+        limited.max_connections = Some(1);
+        let proxy = Proxy::builder().rule(limited).start().await;
+        let addr = proxy.handle().local_addrs()[0].1;
+
+        let open = open_connection(addr);
+        assert!(connection_is_refused(addr));
+        drop(open);
+        eventually_forwards(addr);
+        proxy.shutdown().await;
+    });
+}
+
+/// Counts the accepted sockets the proxy still holds.
+#[derive(Default)]
+struct OpenSockets(std::sync::atomic::AtomicUsize);
+
+impl ConnectionObserver for OpenSockets {
+    fn on_accept(&self, _conn: &ConnectionInfo) {
+        self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    fn on_close(&self, _conn: &ConnectionInfo, _reason: &CloseReason, _totals: &TransferTotals) {
+        self.0.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn clients_over_the_per_ip_limit_are_refused_when_waiting() {
+    let echo = spawn_tcp_echo_server();
+    smol::block_on(async {
+        let config = Config {
+            forwarding_rules: vec![rule(0, echo.addr, Protocol::Tcp), rule(0, echo.addr, Protocol::Tcp)],
+            max_connections_per_ip: Some(2),
+            limit_action: LimitAction::Wait,
+            ..Config::default()
+        };
+        let sockets = Arc::new(OpenSockets::default());
+        let proxy = Proxy::builder().config(config).observer(sockets.clone()).start().await;
+        let addrs = proxy.handle().local_addrs();
+
+        // The limit counts over all rules
+        let _open = [open_connection(addrs[0].1), open_connection(addrs[1].1)];
+        let _over: Vec<_> = (0..20).map(|_| std::net::TcpStream::connect(addrs[1].1).unwrap()).collect();
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while sockets.0.load(std::sync::atomic::Ordering::SeqCst) > 2 {
+            assert!(std::time::Instant::now() < deadline, "refused clients are still held");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(connection_is_refused(addrs[0].1));
+        proxy.shutdown().await;
+    });
+}
+
+#[test]
+fn udp_sessions_count_towards_the_server_limit() {
+    let echo = spawn_tcp_echo_server();
+    let udp_echo = spawn_udp_echo_server();
+    smol::block_on(async {
+        let config = Config {
+            forwarding_rules: vec![rule(0, echo.addr, Protocol::Tcp), rule(0, udp_echo.addr, Protocol::Udp)],
+            max_connections: Some(1),
+            ..Config::default()
+        };
+        let proxy = Proxy::builder().config(config).start().await;
+        let addrs = proxy.handle().local_addrs();
+        let (tcp_addr, udp_addr) = (addrs[0].1, addrs[1].1);
+
+        let open = open_connection(tcp_addr);
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+        client.send_to(b"dropped", udp_addr).unwrap();
+        let mut buf = [0u8; 16];
+        assert!(client.recv_from(&mut buf).is_err());
+
+        drop(open);
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        let len = loop {
+            client.send_to(b"session", udp_addr).unwrap();
+            if let Ok((len, _)) = client.recv_from(&mut buf) {
+                break len;
+            }
+            assert!(std::time::Instant::now() < deadline, "the UDP client is still refused");
+        };
+        assert_eq!(&buf[..len], b"session");
+        assert!(connection_is_refused(tcp_addr));
+        proxy.shutdown().await;
+    });
+}
+
+#[test]
+fn clients_over_the_rate_limit_are_refused() {
+    let echo = spawn_tcp_echo_server();
+    let udp_echo = spawn_udp_echo_server();
+    smol::block_on(async {
+        let rate_limit = RateLimitConfig {
+            per_ip_rate: Some(0.001),
+            per_ip_burst: Some(2),
+            ..RateLimitConfig::default()
+        };
+        let mut tcp = rule(0, echo.addr, Protocol::Tcp);
+        tcp.rate_limit = Some(rate_limit.clone());
+        tcp.limit_action = Some(LimitAction::Wait);
+        let mut udp = rule(0, udp_echo.addr, Protocol::Udp);
+        udp.rate_limit = Some(RateLimitConfig { per_ip_burst: Some(1), ..rate_limit });
+        let proxy = Proxy::builder().rule(tcp).rule(udp).start().await;
+        let addrs = proxy.handle().local_addrs();
+
+        assert_eq!(tcp_round_trip(addrs[0].1, b"one"), b"one");
+        assert_eq!(tcp_round_trip(addrs[0].1, b"two"), b"two");
+        assert!(connection_is_refused(addrs[0].1));
+
+        assert_eq!(udp_round_trip(addrs[1].1, b"first").unwrap(), b"first");
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+        client.send_to(b"second", addrs[1].1).unwrap();
+        let mut buf = [0u8; 16];
+        assert!(client.recv_from(&mut buf).is_err());
+        proxy.shutdown().await;
+    });
+}
+
+#[test]
+fn refused_udp_clients_are_reported_once_per_interval() {
+    let udp_echo = spawn_udp_echo_server();
+    smol::block_on(async {
+        let mut udp = rule(0, udp_echo.addr, Protocol::Udp);
+        udp.rate_limit = Some(RateLimitConfig {
+            per_ip_rate: Some(0.001),
+            per_ip_burst: Some(1),
+            ..RateLimitConfig::default()
+        });
+        let recorder = Arc::new(Recorder::default());
+        let proxy = Proxy::builder().rule(udp).observer(recorder.clone()).start().await;
+        let addr = proxy.handle().local_addrs()[0].1;
+
+        assert_eq!(udp_round_trip(addr, b"first").unwrap(), b"first");
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        for _ in 0..10 {
+            client.send_to(b"refused", addr).unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(300));
+        let events = recorder.events.lock().unwrap().clone();
+        let refusals = events.iter().filter(|event| event.starts_with("close Limited")).count();
+        assert_eq!(refusals, 1, "{:?}", events);
+        proxy.shutdown().await;
+    });
+}
+
+#[test]
+fn relayed_data_is_slowed_to_the_bandwidth_limit() {
+    let echo = spawn_tcp_echo_server();
+    smol::block_on(async {
+        let mut limited = rule(0, echo.addr, Protocol::Tcp);
+        limited.bandwidth = Some(BandwidthConfig {
+            per_connection_rate: Some(100_000),
+            per_connection_burst: Some(10_000),
+            ..BandwidthConfig::default()
+        });
+        let proxy = Proxy::builder().rule(limited).start().await;
+        let addr = proxy.handle().local_addrs()[0].1;
+
+        let payload = vec![7u8; 100_000];
+        let started = std::time::Instant::now();
+        assert_eq!(tcp_round_trip(addr, &payload), payload);
+        // 200 kB relayed at 100 kB per second, less the burst
+        assert!(started.elapsed() >= Duration::from_millis(1500), "took {:?}", started.elapsed());
+        proxy.shutdown().await;
+    });
+}
+
+#[test]
+fn relayed_writes_are_paced_to_the_burst() {
+    let upstream = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    // When each read of the upstream arrived, and how many bytes it got
+    let reads = std::thread::spawn(move || {
+        let (mut stream, _) = upstream.accept().unwrap();
+        let started = std::time::Instant::now();
+        let mut reads = Vec::new();
+        let mut buf = [0u8; 65536];
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) | Err(_) => return reads,
+                Ok(n) => reads.push((started.elapsed(), n)),
+            }
+        }
+    });
+    smol::block_on(async {
+        let mut limited = rule(0, upstream_addr, Protocol::Tcp);
+        limited.bandwidth = Some(BandwidthConfig {
+            per_connection_rate: Some(10_000),
+            per_connection_burst: Some(1000),
+            ..BandwidthConfig::default()
+        });
+        let proxy = Proxy::builder().rule(limited).start().await;
+        let addr = proxy.handle().local_addrs()[0].1;
+
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        std::io::Write::write_all(&mut client, &[7u8; 5000]).unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let reads = reads.join().unwrap();
+        assert_eq!(reads.iter().map(|(_, n)| n).sum::<usize>(), 5000);
+        // A burst at a time, rather than everything at once after a wait
+        assert!(reads.iter().all(|(_, n)| *n <= 1000), "{:?}", reads);
+        assert!(reads[0].0 < Duration::from_millis(150), "{:?}", reads);
+        assert!(reads.last().unwrap().0 >= Duration::from_millis(300), "{:?}", reads);
+        proxy.shutdown().await;
+    });
+}
+
+#[test]
+fn clients_are_banned_from_every_rule_after_failed_upstream_connects() {
+    let echo = spawn_tcp_echo_server();
+    // Nothing listens there once the listener is dropped
+    let dead = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    smol::block_on(async {
+        let proxy = Proxy::builder()
+            .config(Config {
+                forwarding_rules: vec![rule(0, dead, Protocol::Tcp), rule(0, echo.addr, Protocol::Tcp)],
+                auto_ban: Some(AutoBanConfig {
+                    strikes: 2,
+                    window: 60,
+                    duration: 600,
+                    triggers: vec![BanTrigger::UpstreamFailed],
+                    state_file: None,
+                }),
+                ..Config::default()
+            })
+            .start()
+            .await;
+        let addrs = proxy.handle().local_addrs();
+        let bans = proxy.handle().bans();
+        let client = "127.0.0.1".parse().unwrap();
+        assert_eq!(tcp_round_trip(addrs[1].1, b"before"), b"before");
+
+        assert!(connection_is_refused(addrs[0].1));
+        assert!(connection_is_refused(addrs[0].1));
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while !bans.is_banned(client) {
+            assert!(std::time::Instant::now() < deadline, "the client was not banned");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert!(connection_is_refused(addrs[1].1));
+
+        assert!(bans.unban(client));
+        assert_eq!(tcp_round_trip(addrs[1].1, b"after"), b"after");
+        proxy.shutdown().await;
+    });
+}
+
+#[test]
+fn udp_sessions_of_clients_no_longer_allowed_are_closed_once() {
+    let udp_echo = spawn_udp_echo_server();
+    smol::block_on(async {
+        let config = Config {
+            forwarding_rules: vec![rule(0, udp_echo.addr, Protocol::Udp)],
+            enforce_access_rules: true,
+            ..Config::default()
+        };
+        let recorder = Arc::new(Recorder::default());
+        let proxy = Proxy::builder().config(config.clone()).observer(recorder.clone()).start().await;
+        let handle = proxy.handle();
+        let addr = handle.local_addrs()[0].1;
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.send_to(b"open", addr).unwrap();
+        let mut buf = [0u8; 16];
+        client.recv_from(&mut buf).unwrap();
+
+        let mut denying = config.forwarding_rules[0].clone();
+        denying.bind_port = addr.port();
+        denying.rules = vec![AccessRule {
+            rule_type: RuleType::Deny,
+            pattern: "127.0.0.1".to_string(),
+            file: None,
+        }];
+        handle
+            .reload(Config { forwarding_rules: vec![denying], ..config })
+            .await
+            .expect("proxy is running");
+        for _ in 0..10 {
+            client.send_to(b"denied", addr).unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(300));
+        let events = recorder.events.lock().unwrap().clone();
+        assert_eq!(events.iter().filter(|event| *event == "access false").count(), 1, "{:?}", events);
+        assert_eq!(events.iter().filter(|event| event.starts_with("close Denied")).count(), 1, "{:?}", events);
+        proxy.shutdown().await;
+    });
+}
+
+#[test]
+fn denied_udp_retries_are_one_strike() {
+    let udp_echo = spawn_udp_echo_server();
+    smol::block_on(async {
+        let mut denying = rule(0, udp_echo.addr, Protocol::Udp);
+        denying.rules = vec![AccessRule {
+            rule_type: RuleType::Deny,
+            pattern: "127.0.0.1".to_string(),
+            file: None,
+        }];
+        let proxy = Proxy::builder()
+            .config(Config {
+                forwarding_rules: vec![denying, rule(0, udp_echo.addr, Protocol::Udp)],
+                enforce_access_rules: true,
+                auto_ban: Some(AutoBanConfig {
+                    strikes: 3,
+                    window: 60,
+                    duration: 600,
+                    triggers: vec![BanTrigger::Denied],
+                    state_file: None,
+                }),
+                ..Config::default()
+            })
+            .start()
+            .await;
+        let addrs = proxy.handle().local_addrs();
+
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        for _ in 0..10 {
+            client.send_to(b"retry", addrs[0].1).unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(!proxy.handle().bans().is_banned("127.0.0.1".parse().unwrap()));
+        assert_eq!(udp_round_trip(addrs[1].1, b"allowed").unwrap(), b"allowed");
+        proxy.shutdown().await;
+    });
+}
+
+struct Uppercase;
+
+impl StreamFilter for Uppercase {
+    fn filter(&mut self, _conn: &ConnectionInfo, direction: Direction, data: &mut Vec<u8>) -> FilterAction {
+        if direction == Direction::ClientToUpstream {
+            data.make_ascii_uppercase();
+        }
+        FilterAction::Forward
+    }
+}
+
+#[test]
+fn custom_filter_transforms_forwarded_bytes() {
+    let echo = spawn_tcp_echo_server();
+    smol::block_on(async {
+        let mut filtered = rule(0, echo.addr, Protocol::Tcp);
+        filtered.filters = vec![FilterConfig::Custom { name: "uppercase".to_string() }];
+        let proxy = Proxy::builder()
+            .rule(filtered)
+            .filter("uppercase", Arc::new(|_: &ConnectionInfo| Box::new(Uppercase) as Box<dyn StreamFilter>))
+            .start()
+            .await;
+        let addr = proxy.handle().local_addrs()[0].1;
+
+        assert_eq!(tcp_round_trip(addr, b"shout"), b"SHOUT");
+        proxy.shutdown().await;
+    });
+}
+
+#[test]
+fn unknown_custom_filter_fails_the_rule() {
+    let echo = spawn_tcp_echo_server();
+    smol::block_on(async {
+        let mut filtered = rule(0, echo.addr, Protocol::Tcp);
+        filtered.filters = vec![FilterConfig::Custom { name: "missing".to_string() }];
+        let proxy = Proxy::builder().rule(filtered).start().await;
+        let handle = proxy.handle();
+
+        let failure = handle.errors().try_recv().expect("filter error reported");
+        assert!(matches!(failure.error, ProxyError::UnknownFilter(name) if name == "missing"));
+        assert!(handle.local_addrs().is_empty());
+        proxy.shutdown().await;
+    });
+}
+
+#[test]
+fn size_limit_filter_closes_connection() {
+    let echo = spawn_tcp_echo_server();
+    smol::block_on(async {
+        let recorder = Arc::new(Recorder::default());
+        let mut limited = rule(0, echo.addr, Protocol::Tcp);
+        limited.filters = vec![FilterConfig::SizeLimit { max_bytes: 4 }];
+        let proxy = Proxy::builder().rule(limited).observer(recorder.clone()).start().await;
+        let addr = proxy.handle().local_addrs()[0].1;
+
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        std::io::Write::write_all(&mut client, b"too long").unwrap();
+        let mut buf = Vec::new();
+        let _ = client.read_to_end(&mut buf);
+        assert!(buf.is_empty());
+        assert_eq!(recorder.wait_for(4)[3], "close Filtered 0 0");
+        proxy.shutdown().await;
+    });
+}
@@ -0,0 +1,236 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::*;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::time::{Duration, Instant};
+
+fn tcp_rule(bind_port: u16, connect_port: u16) -> String {
+    format!(
+        r#"
+[[forwarding_rules]]
+bind_address = "127.0.0.1"
+bind_port = {}
+connect_address = "127.0.0.1"
+connect_port = {}
+"#,
+        bind_port, connect_port
+    )
+}
+
+fn udp_rule(bind_port: u16, connect_port: u16) -> String {
+    format!(
+        r#"
+[[forwarding_rules]]
+bind_address = "127.0.0.1"
+bind_port = {}
+connect_address = "127.0.0.1"
+connect_port = {}
+protocol = "udp"
+"#,
+        bind_port, connect_port
+    )
+}
+
+fn send_sighup(proxy: &TestProxy) {
+    let status = std::process::Command::new("kill")
+        .arg("-HUP")
+        .arg(proxy.child.id().to_string())
+        .status()
+        .expect("run kill");
+    assert!(status.success(), "kill -HUP failed");
+}
+
+fn wait_for_port_closed(addr: SocketAddr, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if TcpStream::connect_timeout(&addr, Duration::from_millis(100)).is_err() {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    false
+}
+
+fn wait_until_refused(addr: SocketAddr, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if connection_is_refused(addr) {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    false
+}
+
+/// Sends `payload` from `socket` to `addr` until it comes back, as the
+/// proxy may not have bound `addr` yet.
+fn udp_echo_on(socket: &UdpSocket, addr: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    socket.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let mut buf = [0u8; 1024];
+    while Instant::now() < deadline {
+        socket.send_to(payload, addr).expect("send datagram");
+        if let Ok((len, _)) = socket.recv_from(&mut buf) {
+            return buf[..len].to_vec();
+        }
+    }
+    panic!("no answer from {}", addr);
+}
+
+fn echo_on(stream: &mut TcpStream, payload: &[u8]) -> Vec<u8> {
+    stream.write_all(payload).expect("write payload");
+    let mut response = vec![0u8; payload.len()];
+    stream.read_exact(&mut response).expect("read response");
+    response
+}
+
+#[test]
+fn sighup_starts_added_rule() {
+    let echo = spawn_tcp_echo_server();
+    let mut proxy = spawn_proxy(&tcp_rule(reserve_proxy_port(), echo.addr.port()));
+    assert!(wait_for_port(proxy.bind_addr, Duration::from_secs(10)));
+
+    let added = SocketAddr::from(([127, 0, 0, 1], reserve_proxy_port()));
+    proxy.rewrite_config(&format!(
+        "{}{}",
+        tcp_rule(proxy.bind_addr.port(), echo.addr.port()),
+        tcp_rule(added.port(), echo.addr.port())
+    ));
+    send_sighup(&proxy);
+
+    assert!(wait_for_port(added, Duration::from_secs(10)));
+    assert_eq!(tcp_round_trip(added, b"added rule"), b"added rule");
+    assert_eq!(tcp_round_trip(proxy.bind_addr, b"kept rule"), b"kept rule");
+    assert!(proxy.is_alive());
+}
+
+#[test]
+fn sighup_stops_removed_rule_and_keeps_its_connections() {
+    let echo = spawn_tcp_echo_server();
+    let removed = SocketAddr::from(([127, 0, 0, 1], reserve_proxy_port()));
+    let mut proxy = spawn_proxy(&format!(
+        "{}{}",
+        tcp_rule(reserve_proxy_port(), echo.addr.port()),
+        tcp_rule(removed.port(), echo.addr.port())
+    ));
+    assert!(wait_for_port(proxy.bind_addr, Duration::from_secs(10)));
+    assert!(wait_for_port(removed, Duration::from_secs(10)));
+
+    let mut in_flight = TcpStream::connect(removed).expect("connect to removed rule");
+    in_flight.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    assert_eq!(echo_on(&mut in_flight, b"before reload"), b"before reload");
+
+    proxy.rewrite_config(&tcp_rule(proxy.bind_addr.port(), echo.addr.port()));
+    send_sighup(&proxy);
+
+    assert!(wait_for_port_closed(removed, Duration::from_secs(10)));
+    assert_eq!(echo_on(&mut in_flight, b"after reload"), b"after reload");
+    assert_eq!(tcp_round_trip(proxy.bind_addr, b"kept rule"), b"kept rule");
+    assert!(proxy.is_alive());
+}
+
+#[test]
+fn sighup_keeps_sessions_of_removed_udp_rule() {
+    let echo = spawn_tcp_echo_server();
+    let udp_echo = spawn_udp_echo_server();
+    let removed = SocketAddr::from(([127, 0, 0, 1], reserve_proxy_port()));
+    let mut proxy = spawn_proxy(&format!(
+        "{}{}",
+        tcp_rule(reserve_proxy_port(), echo.addr.port()),
+        udp_rule(removed.port(), udp_echo.addr.port())
+    ));
+    assert!(wait_for_port(proxy.bind_addr, Duration::from_secs(10)));
+
+    let in_session = UdpSocket::bind("127.0.0.1:0").unwrap();
+    assert_eq!(udp_echo_on(&in_session, removed, b"before reload"), b"before reload");
+
+    let added = SocketAddr::from(([127, 0, 0, 1], reserve_proxy_port()));
+    proxy.rewrite_config(&format!(
+        "{}{}",
+        tcp_rule(proxy.bind_addr.port(), echo.addr.port()),
+        tcp_rule(added.port(), echo.addr.port())
+    ));
+    send_sighup(&proxy);
+    assert!(wait_for_port(added, Duration::from_secs(10)));
+
+    assert_eq!(udp_echo_on(&in_session, removed, b"after reload"), b"after reload");
+    // New clients are not taken any more
+    let new_client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    new_client.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    new_client.send_to(b"too late", removed).unwrap();
+    assert!(new_client.recv_from(&mut [0u8; 16]).is_err());
+    assert!(proxy.is_alive());
+}
+
+#[test]
+fn sighup_with_invalid_config_keeps_running_config() {
+    let echo = spawn_tcp_echo_server();
+    let mut proxy = spawn_proxy(&tcp_rule(reserve_proxy_port(), echo.addr.port()));
+    assert!(wait_for_port(proxy.bind_addr, Duration::from_secs(10)));
+
+    proxy.rewrite_config("[[forwarding_rules]]\nbind_address = \n");
+    send_sighup(&proxy);
+    std::thread::sleep(Duration::from_millis(300));
+
+    assert!(proxy.is_alive());
+    assert_eq!(tcp_round_trip(proxy.bind_addr, b"still here"), b"still here");
+}
+
+#[test]
+fn sighup_swaps_access_rules_of_unchanged_listener() {
+    let echo = spawn_tcp_echo_server();
+    let mut proxy = spawn_proxy(&tcp_rule(reserve_proxy_port(), echo.addr.port()));
+    assert!(wait_for_port(proxy.bind_addr, Duration::from_secs(10)));
+
+    let mut in_flight = TcpStream::connect(proxy.bind_addr).expect("connect to proxy");
+    in_flight.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    assert_eq!(echo_on(&mut in_flight, b"before reload"), b"before reload");
+
+    proxy.rewrite_config(&format!(
+        "enforce_access_rules = true\n[[global_rules]]\ntype = \"deny\"\npattern = \"127.0.0.1\"\n{}",
+        tcp_rule(proxy.bind_addr.port(), echo.addr.port())
+    ));
+    send_sighup(&proxy);
+
+    assert!(wait_until_refused(proxy.bind_addr, Duration::from_secs(10)));
+    assert_eq!(echo_on(&mut in_flight, b"after reload"), b"after reload");
+    assert!(proxy.is_alive());
+}
+
+#[test]
+fn reload_subcommand_signals_pid_file_owner() {
+    let echo = spawn_tcp_echo_server();
+    let pid_dir = tempfile::tempdir().unwrap();
+    let pid_file = pid_dir.path().join("oi.pid");
+    let pid_line = format!("pid_file = {:?}\n", pid_file.to_str().unwrap());
+    let mut proxy = spawn_proxy(&format!(
+        "{}{}",
+        pid_line,
+        tcp_rule(reserve_proxy_port(), echo.addr.port())
+    ));
+    assert!(wait_for_port(proxy.bind_addr, Duration::from_secs(10)));
+    let pid = std::fs::read_to_string(&pid_file).expect("pid file written at startup");
+    assert_eq!(pid.trim(), proxy.child.id().to_string());
+
+    let added = SocketAddr::from(([127, 0, 0, 1], reserve_proxy_port()));
+    proxy.rewrite_config(&format!(
+        "{}{}{}",
+        pid_line,
+        tcp_rule(proxy.bind_addr.port(), echo.addr.port()),
+        tcp_rule(added.port(), echo.addr.port())
+    ));
+    let output = std::process::Command::new(BIN)
+        .arg("-c")
+        .arg(&proxy.config_path)
+        .arg("reload")
+        .output()
+        .expect("run oi reload");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    assert!(wait_for_port(added, Duration::from_secs(10)));
+    assert_eq!(tcp_round_trip(added, b"added rule"), b"added rule");
+    assert!(proxy.is_alive());
+}
@@ -160,6 +160,142 @@ fn shutdown_during_active_connections() {
     drop(stream);
 }
 
+#[cfg(unix)]
+fn spawn_with_drain_timeout(path: &std::path::Path, seconds: u64) -> std::process::Child {
+    use std::process::Stdio;
+    std::process::Command::new(BIN)
+        .arg("-c")
+        .arg(path)
+        .arg("--drain-timeout")
+        .arg(seconds.to_string())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("spawn oi binary")
+}
+
+#[cfg(unix)]
+fn send_signal(child: &std::process::Child, signal: &str) {
+    let status = std::process::Command::new("kill")
+        .arg(signal)
+        .arg(child.id().to_string())
+        .status()
+        .expect("run kill");
+    assert!(status.success(), "kill {} failed", signal);
+}
+
+#[cfg(unix)]
+#[test]
+fn shutdown_drains_open_connections_before_exiting() {
+    use std::io::{Read, Write};
+
+    let echo = spawn_tcp_echo_server();
+    let port = reserve_proxy_port();
+    let (_dir, path) = write_proxy_config(port, echo.addr.port());
+    let mut child = spawn_with_drain_timeout(&path, 30);
+
+    let bind_addr = std::net::SocketAddr::from(([127, 0, 0, 1], port));
+    assert!(wait_for_port(bind_addr, Duration::from_secs(10)));
+    let mut stream = TcpStream::connect(bind_addr).expect("connect through proxy");
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    stream.write_all(b"before").unwrap();
+    let mut buf = [0u8; 6];
+    stream.read_exact(&mut buf).unwrap();
+
+    send_signal(&child, "-INT");
+    std::thread::sleep(Duration::from_millis(300));
+
+    // New clients are turned away while the open connection keeps working
+    assert!(TcpStream::connect_timeout(&bind_addr, Duration::from_millis(200)).is_err());
+    assert!(!child.has_exited(), "proxy exited before its connection ended");
+    stream.write_all(b"during").unwrap();
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"during");
+
+    drop(stream);
+    assert!(wait_for_exit(&mut child, Duration::from_secs(10)), "proxy did not exit after draining");
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "proxy exited with {:?}", output.status.code());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!stdout.contains("still open"), "unexpected leftover connections: {}", stdout);
+    assert!(stdout.contains("Server shut down successfully"), "got: {}", stdout);
+}
+
+#[cfg(unix)]
+#[test]
+fn shutdown_closes_connections_left_after_drain_timeout() {
+    use std::io::{Read, Write};
+
+    let echo = spawn_tcp_echo_server();
+    let port = reserve_proxy_port();
+    let (_dir, path) = write_proxy_config(port, echo.addr.port());
+    let mut child = spawn_with_drain_timeout(&path, 1);
+
+    let bind_addr = std::net::SocketAddr::from(([127, 0, 0, 1], port));
+    assert!(wait_for_port(bind_addr, Duration::from_secs(10)));
+    let mut stream = TcpStream::connect(bind_addr).expect("connect through proxy");
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    stream.write_all(b"held").unwrap();
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).unwrap();
+
+    send_signal(&child, "-INT");
+    assert!(wait_for_exit(&mut child, Duration::from_secs(10)), "proxy did not exit after the drain timeout");
+
+    // The proxy closed the connection it gave up on
+    assert_eq!(stream.read(&mut buf).unwrap_or(0), 0);
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "proxy exited with {:?}", output.status.code());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("Closing 1 TCP connections and 0 UDP sessions still open after draining"),
+        "got: {}",
+        stdout
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn second_signal_skips_draining() {
+    let echo = spawn_tcp_echo_server();
+    let port = reserve_proxy_port();
+    let (_dir, path) = write_proxy_config(port, echo.addr.port());
+    let mut child = spawn_with_drain_timeout(&path, 60);
+
+    let bind_addr = std::net::SocketAddr::from(([127, 0, 0, 1], port));
+    assert!(wait_for_port(bind_addr, Duration::from_secs(10)));
+    let _stream = TcpStream::connect(bind_addr).expect("connect through proxy");
+    std::thread::sleep(Duration::from_millis(200));
+
+    send_signal(&child, "-INT");
+    std::thread::sleep(Duration::from_millis(300));
+    assert!(!child.has_exited());
+    send_signal(&child, "-INT");
+
+    assert!(wait_for_exit(&mut child, Duration::from_secs(5)), "second signal did not end draining");
+    assert!(child.wait().unwrap().success());
+}
+
+#[cfg(unix)]
+#[test]
+fn sigterm_shuts_down_gracefully() {
+    let echo = spawn_tcp_echo_server();
+    let port = reserve_proxy_port();
+    let (_dir, path) = write_proxy_config(port, echo.addr.port());
+    let mut child = spawn_with_drain_timeout(&path, 5);
+
+    let bind_addr = std::net::SocketAddr::from(([127, 0, 0, 1], port));
+    assert!(wait_for_port(bind_addr, Duration::from_secs(10)));
+
+    send_signal(&child, "-TERM");
+    assert!(wait_for_exit(&mut child, Duration::from_secs(10)), "proxy did not shut down after SIGTERM");
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "proxy exited with {:?}", output.status.code());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Received SIGTERM, shutting down..."), "got: {}", stdout);
+    assert!(stdout.contains("Server shut down successfully"), "got: {}", stdout);
+}
+
 fn wait_for_exit<T>(child: &mut T, timeout: Duration) -> bool
 where
     T: ExitProbe,
@@ -0,0 +1,79 @@
+mod common;
+
+use common::*;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+const WATCH_ARGS: &[&str] = &["--watch", "--watch-interval", "100"];
+
+fn tcp_rule(bind_port: u16, connect_port: u16) -> String {
+    format!(
+        r#"
+[[forwarding_rules]]
+bind_address = "127.0.0.1"
+bind_port = {}
+connect_address = "127.0.0.1"
+connect_port = {}
+"#,
+        bind_port, connect_port
+    )
+}
+
+#[test]
+fn watch_applies_edited_config() {
+    let echo = spawn_tcp_echo_server();
+    let mut proxy = spawn_proxy_with_args(&tcp_rule(reserve_proxy_port(), echo.addr.port()), WATCH_ARGS);
+    assert!(wait_for_port(proxy.bind_addr, Duration::from_secs(10)));
+
+    let added = SocketAddr::from(([127, 0, 0, 1], reserve_proxy_port()));
+    proxy.rewrite_config(&format!(
+        "{}{}",
+        tcp_rule(proxy.bind_addr.port(), echo.addr.port()),
+        tcp_rule(added.port(), echo.addr.port())
+    ));
+
+    assert!(wait_for_port(added, Duration::from_secs(10)));
+    assert_eq!(tcp_round_trip(added, b"added rule"), b"added rule");
+    assert_eq!(tcp_round_trip(proxy.bind_addr, b"kept rule"), b"kept rule");
+    assert!(proxy.is_alive());
+}
+
+#[test]
+fn watch_picks_up_new_include_fragment() {
+    let echo = spawn_tcp_echo_server();
+    let mut proxy = spawn_proxy_with_args(
+        &format!("include = [\"conf.d/*.toml\"]\n{}", tcp_rule(reserve_proxy_port(), echo.addr.port())),
+        WATCH_ARGS,
+    );
+    assert!(wait_for_port(proxy.bind_addr, Duration::from_secs(10)));
+
+    let conf_d = proxy.config_path.parent().unwrap().join("conf.d");
+    std::fs::create_dir(&conf_d).unwrap();
+    let added = SocketAddr::from(([127, 0, 0, 1], reserve_proxy_port()));
+    std::fs::write(conf_d.join("web.toml"), tcp_rule(added.port(), echo.addr.port())).unwrap();
+
+    assert!(wait_for_port(added, Duration::from_secs(10)));
+    assert_eq!(tcp_round_trip(added, b"fragment rule"), b"fragment rule");
+    assert!(proxy.is_alive());
+}
+
+#[test]
+fn watch_ignores_invalid_config_until_fixed() {
+    let echo = spawn_tcp_echo_server();
+    let mut proxy = spawn_proxy_with_args(&tcp_rule(reserve_proxy_port(), echo.addr.port()), WATCH_ARGS);
+    assert!(wait_for_port(proxy.bind_addr, Duration::from_secs(10)));
+
+    proxy.rewrite_config("[[forwarding_rules]]\nbind_address = \n");
+    std::thread::sleep(Duration::from_millis(500));
+    assert!(proxy.is_alive());
+    assert_eq!(tcp_round_trip(proxy.bind_addr, b"still here"), b"still here");
+
+    let added = SocketAddr::from(([127, 0, 0, 1], reserve_proxy_port()));
+    proxy.rewrite_config(&format!(
+        "{}{}",
+        tcp_rule(proxy.bind_addr.port(), echo.addr.port()),
+        tcp_rule(added.port(), echo.addr.port())
+    ));
+    assert!(wait_for_port(added, Duration::from_secs(10)));
+    assert!(proxy.is_alive());
+}